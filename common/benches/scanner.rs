@@ -0,0 +1,61 @@
+//! Benchmarks the pattern scanner on a buffer the size of ntoskrnl's `.text` section.
+
+use {
+    common::scanner::Pattern,
+    criterion::{black_box, criterion_group, criterion_main, Criterion},
+};
+
+/// The size of the searched buffer, close to the `.text` section of ntoskrnl.
+const DATA_SIZE: usize = 8 * 1024 * 1024;
+
+/// The bytes of `KiSystemServiceStart` that the SSDT signatures match, placed at the end of the buffer.
+const KI_SYSTEM_SERVICE_START: [u8; 27] = [
+    0x8B, 0xF8, 0xC1, 0xEF, 0x07, 0x83, 0xE7, 0x20, 0x25, 0xFF, 0x0F, 0x00, 0x00, 0x4C, 0x8D, 0x15,
+    0x55, 0x5E, 0x9D, 0x00, 0x4C, 0x8D, 0x1D, 0x8E, 0x36, 0x8F, 0x00,
+];
+
+/// Generates pseudo-random data with the target bytes at the end, so every benchmark scans the whole buffer.
+fn data() -> Vec<u8> {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut data: Vec<u8> = (0..DATA_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+
+    let start = data.len() - KI_SYSTEM_SERVICE_START.len();
+    data[start..].copy_from_slice(&KI_SYSTEM_SERVICE_START);
+    data
+}
+
+fn scanner(c: &mut Criterion) {
+    let data = data();
+
+    let patterns = [
+        (
+            "exact",
+            "8B F8 C1 EF 07 83 E7 20 25 FF 0F 00 00 4C 8D 15 ?? ?? ?? ?? 4C 8D 1D ?? ?? ?? ??",
+        ),
+        (
+            "gap",
+            "C1 EF 07 83 E7 20 25 FF 0F 00 00 [0-16] 4C 8D 15 ?? ?? ?? ?? 4C 8D 1D ?? ?? ?? ??",
+        ),
+        (
+            "leading wildcards",
+            "?? ?? C1 EF 07 83 E7 20 25 FF 0F 00 00",
+        ),
+    ];
+
+    for (name, pattern) in patterns {
+        let pattern = Pattern::parse(pattern).unwrap();
+        assert!(pattern.find(&data).is_some());
+
+        c.bench_function(name, |b| b.iter(|| pattern.find(black_box(&data))));
+    }
+}
+
+criterion_group!(benches, scanner);
+criterion_main!(benches);
@@ -0,0 +1,508 @@
+//! Computes the `CPUID` results returned to the guest from a policy of rules.
+//!
+//! The results returned to the guest are described by a `CpuidPolicy`: a table of rules per leaf and subleaf
+//! applied to the result of `CPUID` on the host. The policy is evaluated against a function returning the host
+//! results, so it can be exercised with fake results without executing `CPUID`.
+
+use alloc::vec::Vec;
+
+/// The result of `CPUID`: the values of EAX, EBX, ECX and EDX.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Enum representing the various CPUID leaves for feature and interface discovery.
+/// Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CpuidLeaf {
+    /// CPUID function number to retrieve the processor's vendor identification string.
+    VendorInfo = 0x0,
+
+    /// CPUID function for feature information, including hypervisor presence.
+    FeatureInformation = 0x1,
+
+    /// CPUID function for extended feature information.
+    ExtendedFeatureInformation = 0x7,
+
+    /// Hypervisor vendor information leaf.
+    HypervisorVendor = 0x40000000,
+
+    /// Hypervisor interface identification leaf.
+    HypervisorInterface = 0x40000001,
+
+    /// Hypervisor system identity information leaf.
+    HypervisorSystemIdentity = 0x40000002,
+
+    /// Hypervisor feature identification leaf.
+    HypervisorFeatureIdentification = 0x40000003,
+
+    /// Hypervisor implementation recommendations leaf.
+    ImplementationRecommendations = 0x40000004,
+
+    /// Hypervisor implementation limits leaf.
+    HypervisorImplementationLimits = 0x40000005,
+
+    /// Hardware-specific features in use by the hypervisor leaf.
+    ImplementationHardwareFeatures = 0x40000006,
+
+    /// Nested hypervisor feature identification leaf.
+    NestedHypervisorFeatureIdentification = 0x40000009,
+
+    /// Nested virtualization features available leaf.
+    HypervisorNestedVirtualizationFeatures = 0x4000000A,
+}
+
+/// Enumerates specific feature bits in the ECX register for CPUID instruction results.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FeatureBits {
+    /// Bit 5 of ECX for CPUID with EAX=1, indicating VMX support.
+    HypervisorVmxSupportBit = 5,
+    /// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
+    HypervisorPresentBit = 31,
+}
+
+/// The first leaf of the range reserved for software use, such as hypervisor identification.
+const HYPERVISOR_LEAF_START: u32 = 0x4000_0000;
+
+/// The last leaf of the range reserved for software use.
+const HYPERVISOR_LEAF_END: u32 = 0x4FFF_FFFF;
+
+/// Checks whether a leaf is in the range reserved for software use, where hypervisors report their interface.
+fn is_hypervisor_leaf(leaf: u32) -> bool {
+    (HYPERVISOR_LEAF_START..=HYPERVISOR_LEAF_END).contains(&leaf)
+}
+
+/// A register of a `CPUID` result.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// The modification applied to a `CPUID` result by a rule.
+#[derive(Debug, Copy, Clone)]
+pub enum CpuidAction {
+    /// Restores the result of `CPUID` on the host, discarding the modifications of the previous rules.
+    PassThrough,
+
+    /// Clears the register bits that are clear in the mask.
+    And(CpuidRegister, u32),
+
+    /// Sets the register bits that are set in the mask.
+    Or(CpuidRegister, u32),
+
+    /// Replaces the register value.
+    Replace(CpuidRegister, u32),
+
+    /// Computes the register value for each processor from its index and the current value, such as an APIC ID.
+    PerProcessor(CpuidRegister, fn(processor_index: u32, value: u32) -> u32),
+}
+
+/// A rule of a `CpuidPolicy`, applied to the results of a leaf and subleaf.
+#[derive(Debug, Copy, Clone)]
+pub struct CpuidRule {
+    /// The leaf, the value of EAX when `CPUID` is executed.
+    pub leaf: u32,
+
+    /// The subleaf, the value of ECX when `CPUID` is executed, or `None` to match every subleaf.
+    pub subleaf: Option<u32>,
+
+    /// The modification applied to the result.
+    pub action: CpuidAction,
+}
+
+/// The `CPUID` results returned to the guest.
+///
+/// Rules are applied in order to the result of `CPUID` on the host, so a later rule for the same leaf builds on
+/// the earlier ones. The default policy hides the hypervisor-present and VMX feature bits and reports an interface
+/// signature that doesn't conform to the Microsoft hypervisor interface in leaf 0x40000001.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: CPUID—CPU Identification
+#[derive(Debug, Clone)]
+pub struct CpuidPolicy {
+    /// The rules, in the order they are applied.
+    rules: Vec<CpuidRule>,
+
+    /// Whether the leaves reserved for software use are hidden, as on a processor without a hypervisor.
+    hide_hypervisor_leaves: bool,
+}
+
+impl Default for CpuidPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuidPolicy {
+    /// Creates the default policy, which hides the hypervisor-present and VMX feature bits.
+    pub fn new() -> Self {
+        Self::pass_through_all()
+            .and(
+                CpuidLeaf::FeatureInformation as u32,
+                None,
+                CpuidRegister::Ecx,
+                !(1 << FeatureBits::HypervisorPresentBit as u32),
+            )
+            .and(
+                CpuidLeaf::FeatureInformation as u32,
+                None,
+                CpuidRegister::Ecx,
+                !(1 << FeatureBits::HypervisorVmxSupportBit as u32),
+            )
+            // Interface signature indicating non-conformance to the Microsoft hypervisor interface ("Hv#1").
+            .replace(
+                CpuidLeaf::HypervisorInterface as u32,
+                None,
+                CpuidRegister::Eax,
+                0x00000001,
+            )
+            .replace(
+                CpuidLeaf::HypervisorInterface as u32,
+                None,
+                CpuidRegister::Ebx,
+                0,
+            )
+            .replace(
+                CpuidLeaf::HypervisorInterface as u32,
+                None,
+                CpuidRegister::Ecx,
+                0,
+            )
+            .replace(
+                CpuidLeaf::HypervisorInterface as u32,
+                None,
+                CpuidRegister::Edx,
+                0,
+            )
+    }
+
+    /// Creates a policy without rules, which returns the results of `CPUID` on the host.
+    pub fn pass_through_all() -> Self {
+        Self {
+            rules: Vec::new(),
+            hide_hypervisor_leaves: false,
+        }
+    }
+
+    /// Adds a rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `rule` - The rule, applied after the rules added before it. Rules for the leaves reserved for software use
+    ///   are discarded once they are hidden.
+    pub fn rule(mut self, rule: CpuidRule) -> Self {
+        if !(self.hide_hypervisor_leaves && is_hypervisor_leaf(rule.leaf)) {
+            self.rules.push(rule);
+        }
+        self
+    }
+
+    /// Restores the host result of a leaf, discarding the modifications of the rules added before.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf the rule applies to.
+    /// * `subleaf` - The subleaf the rule applies to, or `None` for every subleaf.
+    pub fn pass_through(self, leaf: u32, subleaf: Option<u32>) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            action: CpuidAction::PassThrough,
+        })
+    }
+
+    /// Clears the bits of a register of a leaf that are clear in the mask.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf the rule applies to.
+    /// * `subleaf` - The subleaf the rule applies to, or `None` for every subleaf.
+    /// * `register` - The register to modify.
+    /// * `mask` - The bits to keep.
+    pub fn and(self, leaf: u32, subleaf: Option<u32>, register: CpuidRegister, mask: u32) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            action: CpuidAction::And(register, mask),
+        })
+    }
+
+    /// Sets the bits of a register of a leaf that are set in the mask.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf the rule applies to.
+    /// * `subleaf` - The subleaf the rule applies to, or `None` for every subleaf.
+    /// * `register` - The register to modify.
+    /// * `mask` - The bits to set.
+    pub fn or(self, leaf: u32, subleaf: Option<u32>, register: CpuidRegister, mask: u32) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            action: CpuidAction::Or(register, mask),
+        })
+    }
+
+    /// Replaces the value of a register of a leaf.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf the rule applies to.
+    /// * `subleaf` - The subleaf the rule applies to, or `None` for every subleaf.
+    /// * `register` - The register to modify.
+    /// * `value` - The value returned to the guest.
+    pub fn replace(
+        self,
+        leaf: u32,
+        subleaf: Option<u32>,
+        register: CpuidRegister,
+        value: u32,
+    ) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            action: CpuidAction::Replace(register, value),
+        })
+    }
+
+    /// Computes the value of a register of a leaf for each processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf the rule applies to.
+    /// * `subleaf` - The subleaf the rule applies to, or `None` for every subleaf.
+    /// * `register` - The register to modify.
+    /// * `value` - Computes the value from the processor index and the current value of the register.
+    pub fn per_processor(
+        self,
+        leaf: u32,
+        subleaf: Option<u32>,
+        register: CpuidRegister,
+        value: fn(u32, u32) -> u32,
+    ) -> Self {
+        self.rule(CpuidRule {
+            leaf,
+            subleaf,
+            action: CpuidAction::PerProcessor(register, value),
+        })
+    }
+
+    /// Hides the leaves reserved for software use (0x40000000 to 0x4FFFFFFF).
+    ///
+    /// Like leaves above the maximum basic leaf on a processor without a hypervisor, they return the result of the
+    /// maximum basic leaf. The rules for these leaves are removed, including the interface signature of the default
+    /// policy, and rules added afterwards are discarded, as any value they report would reveal the hypervisor.
+    pub fn hide_hypervisor_leaves(mut self) -> Self {
+        self.rules.retain(|rule| !is_hypervisor_leaf(rule.leaf));
+        self.hide_hypervisor_leaves = true;
+        self
+    }
+
+    /// Reports VMX support to the guest, for nested virtualization, after the rules that hide it.
+    pub fn expose_vmx(self) -> Self {
+        self.or(
+            CpuidLeaf::FeatureInformation as u32,
+            None,
+            CpuidRegister::Ecx,
+            1 << FeatureBits::HypervisorVmxSupportBit as u32,
+        )
+    }
+
+    /// Computes the result returned to the guest for a leaf and subleaf.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf requested by the guest in EAX.
+    /// * `subleaf` - The subleaf requested by the guest in ECX.
+    /// * `processor_index` - The index of the processor executing `CPUID`.
+    /// * `host_cpuid` - Returns the result of `CPUID` on the host for a leaf and subleaf, which a test can fake.
+    ///
+    /// # Returns
+    ///
+    /// * `CpuidResult` - The result returned to the guest.
+    pub fn evaluate(
+        &self,
+        leaf: u32,
+        subleaf: u32,
+        processor_index: u32,
+        host_cpuid: impl Fn(u32, u32) -> CpuidResult,
+    ) -> CpuidResult {
+        let host_result = match self.hide_hypervisor_leaves && is_hypervisor_leaf(leaf) {
+            true => host_cpuid(host_cpuid(CpuidLeaf::VendorInfo as u32, 0).eax, subleaf),
+            false => host_cpuid(leaf, subleaf),
+        };
+
+        let mut result = host_result;
+
+        for rule in self.rules.iter().filter(|rule| {
+            rule.leaf == leaf
+                && rule
+                    .subleaf
+                    .is_none_or(|rule_subleaf| rule_subleaf == subleaf)
+        }) {
+            match rule.action {
+                CpuidAction::PassThrough => result = host_result,
+                CpuidAction::And(register, mask) => *Self::register(&mut result, register) &= mask,
+                CpuidAction::Or(register, mask) => *Self::register(&mut result, register) |= mask,
+                CpuidAction::Replace(register, value) => {
+                    *Self::register(&mut result, register) = value
+                }
+                CpuidAction::PerProcessor(register, value) => {
+                    let register = Self::register(&mut result, register);
+                    *register = value(processor_index, *register);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Gets a mutable reference to a register of a result.
+    fn register(result: &mut CpuidResult, register: CpuidRegister) -> &mut u32 {
+        match register {
+            CpuidRegister::Eax => &mut result.eax,
+            CpuidRegister::Ebx => &mut result.ebx,
+            CpuidRegister::Ecx => &mut result.ecx,
+            CpuidRegister::Edx => &mut result.edx,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The maximum basic leaf reported by the fake host.
+    const MAX_BASIC_LEAF: u32 = 0x16;
+
+    /// The feature information ECX of the fake host, with the hypervisor-present and VMX bits set.
+    const HOST_FEATURE_ECX: u32 = 0x8000_0020 | 0x1214;
+
+    /// A fake host returning the maximum basic leaf from leaf 0 and results derived from the leaf and subleaf
+    /// otherwise.
+    fn host_cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+        match leaf {
+            0 => CpuidResult {
+                eax: MAX_BASIC_LEAF,
+                ..Default::default()
+            },
+            1 => CpuidResult {
+                eax: 0x906EA,
+                ecx: HOST_FEATURE_ECX,
+                ..Default::default()
+            },
+            _ => CpuidResult {
+                eax: leaf,
+                ebx: subleaf,
+                ecx: leaf ^ subleaf,
+                edx: !leaf,
+            },
+        }
+    }
+
+    #[test]
+    fn default_hides_hypervisor_and_vmx_bits() {
+        let result = CpuidPolicy::new().evaluate(1, 0, 0, host_cpuid);
+
+        assert_eq!(result.ecx, 0x1214);
+        assert_eq!(result.eax, 0x906EA);
+    }
+
+    #[test]
+    fn default_reports_non_conforming_interface() {
+        let result = CpuidPolicy::new().evaluate(0x4000_0001, 0, 0, host_cpuid);
+
+        assert_eq!(
+            result,
+            CpuidResult {
+                eax: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn exposes_vmx() {
+        let result = CpuidPolicy::new()
+            .expose_vmx()
+            .evaluate(1, 0, 0, host_cpuid);
+
+        assert_eq!(result.ecx, 0x1214 | 0x20);
+    }
+
+    #[test]
+    fn passes_through_unmodified_leaves() {
+        let policy = CpuidPolicy::new();
+
+        for leaf in [0x7, 0x8000_0001, 0x4000_0000] {
+            assert_eq!(policy.evaluate(leaf, 3, 0, host_cpuid), host_cpuid(leaf, 3));
+        }
+    }
+
+    #[test]
+    fn pass_through_discards_previous_rules() {
+        let result = CpuidPolicy::new()
+            .pass_through(1, None)
+            .evaluate(1, 0, 0, host_cpuid);
+
+        assert_eq!(result.ecx, HOST_FEATURE_ECX);
+    }
+
+    #[test]
+    fn matches_subleaves() {
+        let policy = CpuidPolicy::pass_through_all()
+            .replace(0x7, Some(0), CpuidRegister::Ebx, 0xAA)
+            .or(0x7, None, CpuidRegister::Edx, 0x1);
+
+        let subleaf0 = policy.evaluate(0x7, 0, 0, host_cpuid);
+        assert_eq!(subleaf0.ebx, 0xAA);
+        assert_eq!(subleaf0.edx, !0x7 | 0x1);
+
+        let subleaf1 = policy.evaluate(0x7, 1, 0, host_cpuid);
+        assert_eq!(subleaf1.ebx, 1);
+        assert_eq!(subleaf1.edx, !0x7 | 0x1);
+    }
+
+    #[test]
+    fn computes_per_processor_values() {
+        let policy = CpuidPolicy::pass_through_all().per_processor(
+            0xB,
+            None,
+            CpuidRegister::Edx,
+            |processor_index, _| processor_index * 2,
+        );
+
+        assert_eq!(policy.evaluate(0xB, 0, 0, host_cpuid).edx, 0);
+        assert_eq!(policy.evaluate(0xB, 0, 5, host_cpuid).edx, 10);
+    }
+
+    #[test]
+    fn hides_hypervisor_leaves() {
+        let policy = CpuidPolicy::new()
+            .replace(0x4000_0000, None, CpuidRegister::Ebx, 0x1234)
+            .hide_hypervisor_leaves()
+            .replace(0x4000_0002, None, CpuidRegister::Eax, 0x5678);
+
+        assert!(policy
+            .rules
+            .iter()
+            .all(|rule| !is_hypervisor_leaf(rule.leaf)));
+
+        for leaf in [0x4000_0000, 0x4000_0001, 0x4000_0002, 0x4FFF_FFFF] {
+            assert_eq!(
+                policy.evaluate(leaf, 0, 0, host_cpuid),
+                host_cpuid(MAX_BASIC_LEAF, 0)
+            );
+        }
+
+        assert_eq!(policy.evaluate(1, 0, 0, host_cpuid).ecx, 0x1214);
+    }
+}
@@ -0,0 +1,18 @@
+//! This crate provides the parts of the hypervisor that don't depend on the kernel or the processor,
+//! such as image parsing, pattern scanning, instruction decoding, the `CPUID` policy,
+//! the MSR bitmap layout and the TSC compensation arithmetic.
+//!
+//! It is `no_std` so the hypervisor can use it, and builds with the standard library under `cargo test`,
+//! so its logic can be tested on any host.
+
+#![cfg_attr(not(test), no_std)]
+#![feature(allocator_api)]
+
+extern crate alloc;
+
+pub mod cpuid;
+pub mod msr_bitmap;
+pub mod pe;
+pub mod scanner;
+pub mod syscall_stub;
+pub mod tsc;
@@ -0,0 +1,431 @@
+//! Locates the bits of MSRs in the MSR bitmap of a VMCS.
+//!
+//! The MSR bitmap is a 4-KByte page made of four contiguous 1-KByte bitmaps: the read bitmaps for the low
+//! (00000000H to 00001FFFH) and high (C0000000H to C0001FFFH) MSRs, followed by the write bitmaps for the same
+//! ranges. RDMSR and WRMSR of the MSRs outside of these ranges always cause a VM exit.
+//!
+//! The bitmap is shared by the VMCS of every processor, and its bits are updated atomically, so interception
+//! can be changed at runtime and applies to all processors.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.9 MSR-Bitmap Address
+
+use {
+    alloc::boxed::Box,
+    core::{
+        alloc::Allocator,
+        ops::RangeInclusive,
+        sync::atomic::{AtomicU8, Ordering},
+    },
+};
+
+/// The size of the MSR bitmap, in bytes.
+pub const MSR_BITMAP_SIZE: usize = 0x1000;
+
+/// The size of each of the four bitmaps making up the MSR bitmap, in bytes.
+pub const MSR_BITMAP_PART_SIZE: usize = 0x400;
+
+/// The MSRs covered by the low bitmaps.
+pub const LOW_MSRS: RangeInclusive<u32> = 0x0000_0000..=0x0000_1FFF;
+
+/// The MSRs covered by the high bitmaps.
+pub const HIGH_MSRS: RangeInclusive<u32> = 0xC000_0000..=0xC000_1FFF;
+
+/// The access to an MSR selected in the MSR bitmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrBitmapAccess {
+    Read,
+    Write,
+}
+
+/// Finds the byte and bit of an MSR in the MSR bitmap.
+///
+/// # Arguments
+///
+/// * `access` - The access selecting the read or write bitmaps.
+/// * `msr` - The MSR to locate.
+///
+/// # Returns
+///
+/// * `Option<(usize, u8)>` - The offset of the byte in the MSR bitmap and the mask of the bit, or `None` for MSRs
+///   outside of the low and high ranges.
+pub fn locate(access: MsrBitmapAccess, msr: u32) -> Option<(usize, u8)> {
+    let (part, index) = match msr {
+        0x0000_0000..=0x0000_1FFF => (0, msr),
+        0xC000_0000..=0xC000_1FFF => (1, msr - HIGH_MSRS.start()),
+        _ => return None,
+    };
+
+    let part = match access {
+        MsrBitmapAccess::Read => part,
+        MsrBitmapAccess::Write => part + 2,
+    };
+
+    Some((
+        part * MSR_BITMAP_PART_SIZE + (index / 8) as usize,
+        1 << (index % 8),
+    ))
+}
+
+/// Iterates over the MSRs of a range that are in the low or high ranges.
+///
+/// # Arguments
+///
+/// * `msrs` - The MSRs to iterate over.
+///
+/// # Returns
+///
+/// * `impl Iterator<Item = u32>` - The MSRs of the range covered by the MSR bitmap, in ascending order.
+pub fn covered(msrs: RangeInclusive<u32>) -> impl Iterator<Item = u32> {
+    let clamp = move |range: RangeInclusive<u32>| {
+        *msrs.start().max(range.start())..=*msrs.end().min(range.end())
+    };
+
+    clamp(LOW_MSRS).chain(clamp(HIGH_MSRS))
+}
+
+/// Represents the MSR Bitmap structure used in VMX.
+///
+/// In processors that support the 1-setting of the “use MSR bitmaps” VM-execution control,
+/// the VM-execution control fields include the 64-bit physical address of four contiguous
+/// MSR bitmaps, which are each 1-KByte in size.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.9 MSR-Bitmap Address
+#[repr(C, align(4096))]
+pub struct MsrBitmap {
+    /// Read bitmap for low MSRs. Contains one bit for each MSR address in the range 00000000H to 00001FFFH.
+    /// Determines whether an execution of RDMSR applied to that MSR causes a VM exit.
+    pub read_low_msrs: [AtomicU8; 0x400],
+
+    /// Read bitmap for high MSRs. Contains one bit for each MSR address in the range C0000000H to C0001FFFH.
+    /// Determines whether an execution of RDMSR applied to that MSR causes a VM exit.
+    pub read_high_msrs: [AtomicU8; 0x400],
+
+    /// Write bitmap for low MSRs. Contains one bit for each MSR address in the range 00000000H to 00001FFFH.
+    /// Determines whether an execution of WRMSR applied to that MSR causes a VM exit.
+    pub write_low_msrs: [AtomicU8; 0x400],
+
+    /// Write bitmap for high MSRs. Contains one bit for each MSR address in the range C0000000H to C0001FFFH.
+    /// Determines whether an execution of WRMSR applied to that MSR causes a VM exit.
+    pub write_high_msrs: [AtomicU8; 0x400],
+}
+
+const _: () = assert!(core::mem::size_of::<MsrBitmap>() == MSR_BITMAP_SIZE);
+
+impl MsrBitmap {
+    /// Sets up the MSR Bitmap in the given allocator, with no MSR in the low and high ranges causing a VM exit.
+    ///
+    /// # Arguments
+    /// * `allocator` - The allocator of the bitmap, which must provide physically contiguous memory when the bitmap is used by a VMCS.
+    pub fn new_in<A: Allocator>(allocator: A) -> Box<MsrBitmap, A> {
+        // An all-zero bitmap is valid, as `AtomicU8` has the same representation as `u8`.
+        unsafe { Box::<Self, A>::new_zeroed_in(allocator).assume_init() }
+    }
+
+    /// Causes both RDMSR and WRMSR of the given MSR to trigger a VM exit.
+    ///
+    /// MSRs outside of the low (00000000H to 00001FFFH) and high (C0000000H to C0001FFFH) ranges always
+    /// cause a VM exit and are ignored.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to intercept.
+    pub fn hook_msr(&self, msr: u32) {
+        self.intercept_read(msr);
+        self.intercept_write(msr);
+    }
+
+    /// Causes RDMSR of the given MSR to trigger a VM exit.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to intercept.
+    pub fn intercept_read(&self, msr: u32) {
+        self.set(MsrBitmapAccess::Read, msr, true);
+    }
+
+    /// Causes WRMSR of the given MSR to trigger a VM exit.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to intercept.
+    pub fn intercept_write(&self, msr: u32) {
+        self.set(MsrBitmapAccess::Write, msr, true);
+    }
+
+    /// Stops RDMSR of the given MSR from triggering a VM exit.
+    ///
+    /// MSRs outside of the low and high ranges always cause a VM exit and are ignored.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to stop intercepting.
+    pub fn pass_through_read(&self, msr: u32) {
+        self.set(MsrBitmapAccess::Read, msr, false);
+    }
+
+    /// Stops WRMSR of the given MSR from triggering a VM exit.
+    ///
+    /// MSRs outside of the low and high ranges always cause a VM exit and are ignored.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to stop intercepting.
+    pub fn pass_through_write(&self, msr: u32) {
+        self.set(MsrBitmapAccess::Write, msr, false);
+    }
+
+    /// Causes RDMSR of the MSRs of the given range to trigger a VM exit.
+    ///
+    /// # Arguments
+    /// * `msrs` - The MSRs to intercept. The parts of the range outside of the low and high ranges are ignored.
+    pub fn intercept_read_range(&self, msrs: RangeInclusive<u32>) {
+        covered(msrs).for_each(|msr| self.set(MsrBitmapAccess::Read, msr, true));
+    }
+
+    /// Causes WRMSR of the MSRs of the given range to trigger a VM exit.
+    ///
+    /// # Arguments
+    /// * `msrs` - The MSRs to intercept. The parts of the range outside of the low and high ranges are ignored.
+    pub fn intercept_write_range(&self, msrs: RangeInclusive<u32>) {
+        covered(msrs).for_each(|msr| self.set(MsrBitmapAccess::Write, msr, true));
+    }
+
+    /// Stops RDMSR of the MSRs of the given range from triggering a VM exit.
+    ///
+    /// # Arguments
+    /// * `msrs` - The MSRs to stop intercepting. The parts of the range outside of the low and high ranges are ignored.
+    pub fn pass_through_read_range(&self, msrs: RangeInclusive<u32>) {
+        covered(msrs).for_each(|msr| self.set(MsrBitmapAccess::Read, msr, false));
+    }
+
+    /// Stops WRMSR of the MSRs of the given range from triggering a VM exit.
+    ///
+    /// # Arguments
+    /// * `msrs` - The MSRs to stop intercepting. The parts of the range outside of the low and high ranges are ignored.
+    pub fn pass_through_write_range(&self, msrs: RangeInclusive<u32>) {
+        covered(msrs).for_each(|msr| self.set(MsrBitmapAccess::Write, msr, false));
+    }
+
+    /// Checks whether RDMSR of the given MSR triggers a VM exit.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to check.
+    ///
+    /// # Returns
+    /// * `true` if the MSR is intercepted or outside of the low and high ranges.
+    pub fn is_read_intercepted(&self, msr: u32) -> bool {
+        self.get(MsrBitmapAccess::Read, msr)
+    }
+
+    /// Checks whether WRMSR of the given MSR triggers a VM exit.
+    ///
+    /// # Arguments
+    /// * `msr` - The MSR to check.
+    ///
+    /// # Returns
+    /// * `true` if the MSR is intercepted or outside of the low and high ranges.
+    pub fn is_write_intercepted(&self, msr: u32) -> bool {
+        self.get(MsrBitmapAccess::Write, msr)
+    }
+
+    /// Finds the byte and bit of an MSR in the bitmap of an access, or `None` for MSRs outside of the low and high ranges.
+    fn locate(&self, access: MsrBitmapAccess, msr: u32) -> Option<(&AtomicU8, u8)> {
+        let (offset, bit) = locate(access, msr)?;
+
+        let bitmaps = [
+            &self.read_low_msrs,
+            &self.read_high_msrs,
+            &self.write_low_msrs,
+            &self.write_high_msrs,
+        ];
+
+        Some((
+            &bitmaps[offset / MSR_BITMAP_PART_SIZE][offset % MSR_BITMAP_PART_SIZE],
+            bit,
+        ))
+    }
+
+    /// Sets or clears the bit of an MSR in the bitmap of an access.
+    fn set(&self, access: MsrBitmapAccess, msr: u32, intercept: bool) {
+        let Some((byte, bit)) = self.locate(access, msr) else {
+            return;
+        };
+
+        match intercept {
+            true => byte.fetch_or(bit, Ordering::Relaxed),
+            false => byte.fetch_and(!bit, Ordering::Relaxed),
+        };
+    }
+
+    /// Gets the bit of an MSR in the bitmap of an access.
+    fn get(&self, access: MsrBitmapAccess, msr: u32) -> bool {
+        self.locate(access, msr)
+            .is_none_or(|(byte, bit)| byte.load(Ordering::Relaxed) & bit != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, alloc::alloc::Global};
+
+    /// The offset of the write bitmaps in the MSR bitmap.
+    const WRITE_OFFSET: usize = 2 * MSR_BITMAP_PART_SIZE;
+
+    #[test]
+    fn locates_low_boundaries() {
+        assert_eq!(locate(MsrBitmapAccess::Read, 0x0), Some((0x0, 0x01)));
+        assert_eq!(locate(MsrBitmapAccess::Read, 0x1FFF), Some((0x3FF, 0x80)));
+        assert_eq!(
+            locate(MsrBitmapAccess::Write, 0x0),
+            Some((WRITE_OFFSET, 0x01))
+        );
+        assert_eq!(
+            locate(MsrBitmapAccess::Write, 0x1FFF),
+            Some((WRITE_OFFSET + 0x3FF, 0x80))
+        );
+    }
+
+    #[test]
+    fn locates_high_boundaries() {
+        assert_eq!(
+            locate(MsrBitmapAccess::Read, 0xC000_0000),
+            Some((0x400, 0x01))
+        );
+        assert_eq!(
+            locate(MsrBitmapAccess::Read, 0xC000_1FFF),
+            Some((0x7FF, 0x80))
+        );
+        assert_eq!(
+            locate(MsrBitmapAccess::Write, 0xC000_0000),
+            Some((WRITE_OFFSET + 0x400, 0x01))
+        );
+        assert_eq!(
+            locate(MsrBitmapAccess::Write, 0xC000_1FFF),
+            Some((WRITE_OFFSET + 0x7FF, 0x80))
+        );
+    }
+
+    #[test]
+    fn locates_inside_the_page() {
+        for access in [MsrBitmapAccess::Read, MsrBitmapAccess::Write] {
+            for msr in covered(0..=u32::MAX) {
+                let (offset, bit) = locate(access, msr).unwrap();
+                assert!(offset < MSR_BITMAP_SIZE);
+                assert_eq!(bit.count_ones(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_msrs() {
+        for access in [MsrBitmapAccess::Read, MsrBitmapAccess::Write] {
+            for msr in [0x2000, 0xBFFF_FFFF, 0xC000_2000, 0x4000_0000, u32::MAX] {
+                assert_eq!(locate(access, msr), None);
+            }
+        }
+    }
+
+    #[test]
+    fn covers_only_low_and_high_ranges() {
+        assert!(covered(0x1FFE..=0xC000_0001).eq([0x1FFE, 0x1FFF, 0xC000_0000, 0xC000_0001]));
+        assert!(covered(0x2000..=0xBFFF_FFFF).eq([]));
+        assert!(covered(0xC000_1FFF..=u32::MAX).eq([0xC000_1FFF]));
+        assert_eq!(covered(0..=u32::MAX).count(), 2 * 0x2000);
+    }
+
+    /// Allocates a bitmap with no MSR intercepted.
+    fn bitmap() -> Box<MsrBitmap, Global> {
+        MsrBitmap::new_in(Global)
+    }
+
+    #[test]
+    fn starts_with_no_interception() {
+        let bitmap = bitmap();
+
+        for msr in [0x0, 0x1FFF, 0xC000_0000, 0xC000_1FFF] {
+            assert!(!bitmap.is_read_intercepted(msr));
+            assert!(!bitmap.is_write_intercepted(msr));
+        }
+    }
+
+    #[test]
+    fn intercepts_and_passes_through_both_ranges() {
+        let bitmap = bitmap();
+
+        for msr in [0x0, 0x10, 0x1FFF, 0xC000_0000, 0xC000_0080, 0xC000_1FFF] {
+            bitmap.intercept_read(msr);
+            assert!(bitmap.is_read_intercepted(msr));
+            assert!(!bitmap.is_write_intercepted(msr));
+
+            bitmap.intercept_write(msr);
+            bitmap.pass_through_read(msr);
+            assert!(!bitmap.is_read_intercepted(msr));
+            assert!(bitmap.is_write_intercepted(msr));
+
+            bitmap.pass_through_write(msr);
+            assert!(!bitmap.is_write_intercepted(msr));
+
+            bitmap.hook_msr(msr);
+            assert!(bitmap.is_read_intercepted(msr));
+            assert!(bitmap.is_write_intercepted(msr));
+        }
+    }
+
+    #[test]
+    fn keeps_neighbouring_msrs() {
+        let bitmap = bitmap();
+
+        bitmap.hook_msr(0x11);
+        bitmap.hook_msr(0xC000_0011);
+        bitmap.pass_through_read(0x10);
+        bitmap.pass_through_write(0xC000_0012);
+
+        assert!(bitmap.is_read_intercepted(0x11));
+        assert!(bitmap.is_write_intercepted(0xC000_0011));
+        assert!(!bitmap.is_read_intercepted(0x10));
+        assert!(!bitmap.is_read_intercepted(0x12));
+        assert!(!bitmap.is_read_intercepted(0xC000_0010));
+        // The low and high ranges don't alias.
+        assert!(!bitmap.is_read_intercepted(0xC000_0000 + 0x10));
+        bitmap.pass_through_read(0x11);
+        assert!(bitmap.is_read_intercepted(0xC000_0011));
+    }
+
+    #[test]
+    fn handles_range_boundaries() {
+        let bitmap = bitmap();
+
+        bitmap.intercept_read_range(0x1FFE..=0xC000_0001);
+        for msr in [0x1FFE, 0x1FFF, 0xC000_0000, 0xC000_0001] {
+            assert!(bitmap.is_read_intercepted(msr));
+            assert!(!bitmap.is_write_intercepted(msr));
+        }
+        assert!(!bitmap.is_read_intercepted(0x1FFD));
+        assert!(!bitmap.is_read_intercepted(0xC000_0002));
+
+        bitmap.intercept_write_range(0xC000_1FFE..=u32::MAX);
+        assert!(bitmap.is_write_intercepted(0xC000_1FFE));
+        assert!(bitmap.is_write_intercepted(0xC000_1FFF));
+        assert!(!bitmap.is_write_intercepted(0xC000_1FFD));
+
+        bitmap.pass_through_read_range(0..=u32::MAX);
+        bitmap.pass_through_write_range(0..=u32::MAX);
+        for msr in covered(0..=u32::MAX) {
+            assert!(!bitmap.is_read_intercepted(msr));
+            assert!(!bitmap.is_write_intercepted(msr));
+        }
+    }
+
+    #[test]
+    fn always_intercepts_out_of_range_msrs() {
+        let bitmap = bitmap();
+
+        for msr in [0x2000, 0xBFFF_FFFF, 0xC000_2000, 0x4000_0000, u32::MAX] {
+            bitmap.pass_through_read(msr);
+            bitmap.pass_through_write(msr);
+            assert!(bitmap.is_read_intercepted(msr));
+            assert!(bitmap.is_write_intercepted(msr));
+        }
+
+        // Intercepting an out-of-range MSR doesn't set the bit of any covered MSR.
+        bitmap.hook_msr(0xC000_2000);
+        bitmap.hook_msr(0x2000);
+        assert!(covered(0..=u32::MAX).all(|msr| !bitmap.is_read_intercepted(msr)));
+        assert!(covered(0..=u32::MAX).all(|msr| !bitmap.is_write_intercepted(msr)));
+    }
+}
@@ -0,0 +1,348 @@
+//! Provides minimal parsing of mapped Portable Executable (PE) images.
+//!
+//! The parsing operates on byte slices of mapped images, so it doesn't depend on the kernel
+//! and can be used for any image, such as `ntoskrnl.exe` or a mapped `ntdll.dll`.
+//!
+//! Reference: https://learn.microsoft.com/en-us/windows/win32/debug/pe-format
+
+use alloc::vec::Vec;
+
+/// Offset of `e_lfanew` in the `IMAGE_DOS_HEADER`.
+const DOS_HEADER_E_LFANEW: usize = 0x3C;
+
+/// The `MZ` signature of the `IMAGE_DOS_HEADER`.
+const DOS_SIGNATURE: u16 = 0x5A4D;
+
+/// The headers of an image are read from its first page.
+const HEADERS_MAX_SIZE: usize = 0x1000;
+
+/// The `PE\0\0` signature of the `IMAGE_NT_HEADERS64`.
+const NT_SIGNATURE: u32 = 0x0000_4550;
+
+/// Offset of `NumberOfSections` in the `IMAGE_NT_HEADERS64`.
+const NT_HEADERS_NUMBER_OF_SECTIONS: usize = 0x6;
+
+/// Offset of `SizeOfOptionalHeader` in the `IMAGE_NT_HEADERS64`.
+const NT_HEADERS_SIZE_OF_OPTIONAL_HEADER: usize = 0x14;
+
+/// Offset of the `IMAGE_OPTIONAL_HEADER64` in the `IMAGE_NT_HEADERS64`.
+const NT_HEADERS_OPTIONAL_HEADER: usize = 0x18;
+
+/// Offset of `SizeOfImage` in the `IMAGE_NT_HEADERS64`.
+const NT_HEADERS_SIZE_OF_IMAGE: usize = 0x50;
+
+/// Offset of the export entry of the data directory in the `IMAGE_NT_HEADERS64`.
+const NT_HEADERS_EXPORT_DIRECTORY: usize = 0x88;
+
+/// Offset of `NumberOfNames` in the `IMAGE_EXPORT_DIRECTORY`.
+const EXPORT_DIRECTORY_NUMBER_OF_NAMES: usize = 0x18;
+
+/// Offset of `AddressOfFunctions` in the `IMAGE_EXPORT_DIRECTORY`.
+const EXPORT_DIRECTORY_ADDRESS_OF_FUNCTIONS: usize = 0x1C;
+
+/// Offset of `AddressOfNames` in the `IMAGE_EXPORT_DIRECTORY`.
+const EXPORT_DIRECTORY_ADDRESS_OF_NAMES: usize = 0x20;
+
+/// Offset of `AddressOfNameOrdinals` in the `IMAGE_EXPORT_DIRECTORY`.
+const EXPORT_DIRECTORY_ADDRESS_OF_NAME_ORDINALS: usize = 0x24;
+
+/// The size of an `IMAGE_SECTION_HEADER`.
+const SECTION_HEADER_SIZE: usize = 0x28;
+
+/// Offset of `VirtualSize` in the `IMAGE_SECTION_HEADER`.
+const SECTION_HEADER_VIRTUAL_SIZE: usize = 0x8;
+
+/// Offset of `VirtualAddress` in the `IMAGE_SECTION_HEADER`.
+const SECTION_HEADER_VIRTUAL_ADDRESS: usize = 0xC;
+
+/// Offset of `Characteristics` in the `IMAGE_SECTION_HEADER`.
+const SECTION_HEADER_CHARACTERISTICS: usize = 0x24;
+
+/// The section can be discarded after the image is loaded, such as `INIT` of drivers.
+pub const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x0200_0000;
+
+/// The section can be executed as code.
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+
+/// A section of a mapped image.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSection {
+    /// The name of the section, padded with zeroes.
+    pub name: [u8; 8],
+
+    /// The RVA of the section.
+    pub virtual_address: u32,
+
+    /// The size of the section in memory.
+    pub virtual_size: u32,
+
+    /// The `IMAGE_SCN_*` flags of the section.
+    pub characteristics: u32,
+}
+
+impl ImageSection {
+    /// Gets the name of the section without padding, such as `.text` or `PAGE`.
+    pub fn name(&self) -> &[u8] {
+        let length = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..length]
+    }
+
+    /// Checks whether the section contains code that stays mapped after the image is loaded.
+    pub fn is_resident_code(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+            && self.characteristics & IMAGE_SCN_MEM_DISCARDABLE == 0
+    }
+}
+
+/// Reads a little-endian `u16` at the given offset.
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        image.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a little-endian `u32` at the given offset.
+fn read_u32(image: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        image.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a null-terminated ASCII string at the given offset.
+fn read_c_str(image: &[u8], offset: usize) -> Option<&[u8]> {
+    let bytes = image.get(offset..)?;
+    let length = bytes.iter().position(|&byte| byte == 0)?;
+    Some(&bytes[..length])
+}
+
+/// Gets the offset of the `IMAGE_NT_HEADERS64` after validating the DOS and NT signatures.
+///
+/// # Arguments
+///
+/// * `image` - The mapped image.
+///
+/// # Returns
+///
+/// * `Option<usize>` - The offset of the NT headers, or `None` if the image is not a valid PE.
+pub fn nt_headers_offset(image: &[u8]) -> Option<usize> {
+    if read_u16(image, 0)? != DOS_SIGNATURE {
+        return None;
+    }
+
+    let nt_headers = read_u32(image, DOS_HEADER_E_LFANEW)? as usize;
+
+    if read_u32(image, nt_headers)? != NT_SIGNATURE {
+        return None;
+    }
+
+    Some(nt_headers)
+}
+
+/// Gets the `SizeOfImage` of a mapped image.
+///
+/// The DOS signature is validated before `e_lfanew` is read, and `e_lfanew` must point into the first page,
+/// so that only the headers of a valid image are ever read.
+///
+/// # Arguments
+///
+/// * `image_base` - The base address of the mapped image.
+///
+/// # Returns
+///
+/// * `Option<usize>` - The size of the image, or `None` if the image is not a valid PE.
+///
+/// # Safety
+///
+/// The caller must ensure that the headers of the image are mapped and readable.
+pub unsafe fn image_size(image_base: *const u8) -> Option<usize> {
+    if (image_base as *const u16).read_unaligned() != DOS_SIGNATURE {
+        return None;
+    }
+
+    let e_lfanew = (image_base.add(DOS_HEADER_E_LFANEW) as *const u32).read_unaligned() as usize;
+
+    if e_lfanew + NT_HEADERS_SIZE_OF_IMAGE + 4 > HEADERS_MAX_SIZE {
+        return None;
+    }
+
+    let headers = core::slice::from_raw_parts(image_base, e_lfanew + NT_HEADERS_SIZE_OF_IMAGE + 4);
+    let nt_headers = nt_headers_offset(headers)?;
+
+    Some(read_u32(headers, nt_headers + NT_HEADERS_SIZE_OF_IMAGE)? as usize)
+}
+
+/// Gets the relative virtual address (RVA) of an export by name.
+///
+/// Forwarded exports are returned as-is, pointing to the forwarder string.
+///
+/// # Arguments
+///
+/// * `image` - The mapped image.
+/// * `export_name` - The name of the export to find.
+///
+/// # Returns
+///
+/// * `Option<u32>` - The RVA of the export, or `None` if it doesn't exist.
+pub fn get_export_rva(image: &[u8], export_name: &str) -> Option<u32> {
+    let nt_headers = nt_headers_offset(image)?;
+    let export_directory = read_u32(image, nt_headers + NT_HEADERS_EXPORT_DIRECTORY)? as usize;
+
+    if export_directory == 0 {
+        return None;
+    }
+
+    let number_of_names = read_u32(image, export_directory + EXPORT_DIRECTORY_NUMBER_OF_NAMES)?;
+    let functions = read_u32(
+        image,
+        export_directory + EXPORT_DIRECTORY_ADDRESS_OF_FUNCTIONS,
+    )? as usize;
+    let names = read_u32(image, export_directory + EXPORT_DIRECTORY_ADDRESS_OF_NAMES)? as usize;
+    let ordinals = read_u32(
+        image,
+        export_directory + EXPORT_DIRECTORY_ADDRESS_OF_NAME_ORDINALS,
+    )? as usize;
+
+    for i in 0..number_of_names as usize {
+        let name_rva = read_u32(image, names + i * 4)? as usize;
+
+        if read_c_str(image, name_rva)? == export_name.as_bytes() {
+            let ordinal = read_u16(image, ordinals + i * 2)? as usize;
+            return read_u32(image, functions + ordinal * 4);
+        }
+    }
+
+    None
+}
+
+/// Gets the sections of a mapped image.
+///
+/// # Arguments
+///
+/// * `image` - The mapped image.
+///
+/// # Returns
+///
+/// * `Option<Vec<ImageSection>>` - The sections in image order, or `None` if the image is not a valid PE.
+pub fn sections(image: &[u8]) -> Option<Vec<ImageSection>> {
+    let nt_headers = nt_headers_offset(image)?;
+    let number_of_sections = read_u16(image, nt_headers + NT_HEADERS_NUMBER_OF_SECTIONS)? as usize;
+    let size_of_optional_header =
+        read_u16(image, nt_headers + NT_HEADERS_SIZE_OF_OPTIONAL_HEADER)? as usize;
+    let section_headers = nt_headers + NT_HEADERS_OPTIONAL_HEADER + size_of_optional_header;
+
+    (0..number_of_sections)
+        .map(|i| {
+            let header = section_headers + i * SECTION_HEADER_SIZE;
+
+            Some(ImageSection {
+                name: image.get(header..header + 8)?.try_into().ok()?,
+                virtual_address: read_u32(image, header + SECTION_HEADER_VIRTUAL_ADDRESS)?,
+                virtual_size: read_u32(image, header + SECTION_HEADER_VIRTUAL_SIZE)?,
+                characteristics: read_u32(image, header + SECTION_HEADER_CHARACTERISTICS)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The `e_lfanew` of the test image.
+    const E_LFANEW: usize = 0x80;
+
+    /// The `SizeOfImage` of the test image.
+    const SIZE_OF_IMAGE: u32 = 0x3000;
+
+    /// Builds an image with a `.text` section at 0x1000 and an `INIT` section at 0x2000, both 0x800 bytes.
+    pub(crate) fn image() -> Vec<u8> {
+        let mut image = vec![0u8; SIZE_OF_IMAGE as usize];
+        let size_of_optional_header = 0xF0u16;
+
+        image[..2].copy_from_slice(&DOS_SIGNATURE.to_le_bytes());
+        image[DOS_HEADER_E_LFANEW..DOS_HEADER_E_LFANEW + 4]
+            .copy_from_slice(&(E_LFANEW as u32).to_le_bytes());
+        image[E_LFANEW..E_LFANEW + 4].copy_from_slice(&NT_SIGNATURE.to_le_bytes());
+        image[E_LFANEW + NT_HEADERS_NUMBER_OF_SECTIONS..][..2].copy_from_slice(&2u16.to_le_bytes());
+        image[E_LFANEW + NT_HEADERS_SIZE_OF_OPTIONAL_HEADER..][..2]
+            .copy_from_slice(&size_of_optional_header.to_le_bytes());
+        image[E_LFANEW + NT_HEADERS_SIZE_OF_IMAGE..][..4]
+            .copy_from_slice(&SIZE_OF_IMAGE.to_le_bytes());
+
+        let section_headers =
+            E_LFANEW + NT_HEADERS_OPTIONAL_HEADER + size_of_optional_header as usize;
+        let sections = [
+            (&b".text"[..], 0x1000u32, IMAGE_SCN_MEM_EXECUTE),
+            (
+                b"INIT",
+                0x2000,
+                IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_DISCARDABLE,
+            ),
+        ];
+        for (i, (name, virtual_address, characteristics)) in sections.into_iter().enumerate() {
+            let header = section_headers + i * SECTION_HEADER_SIZE;
+            image[header..header + name.len()].copy_from_slice(name);
+            image[header + SECTION_HEADER_VIRTUAL_SIZE..][..4]
+                .copy_from_slice(&0x800u32.to_le_bytes());
+            image[header + SECTION_HEADER_VIRTUAL_ADDRESS..][..4]
+                .copy_from_slice(&virtual_address.to_le_bytes());
+            image[header + SECTION_HEADER_CHARACTERISTICS..][..4]
+                .copy_from_slice(&characteristics.to_le_bytes());
+        }
+
+        image
+    }
+
+    #[test]
+    fn reads_image_size() {
+        let image = image();
+
+        assert_eq!(nt_headers_offset(&image), Some(E_LFANEW));
+        assert_eq!(
+            unsafe { image_size(image.as_ptr()) },
+            Some(SIZE_OF_IMAGE as usize)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_dos_signature() {
+        let mut image = image();
+        image[0] = 0;
+
+        assert_eq!(nt_headers_offset(&image), None);
+        assert_eq!(unsafe { image_size(image.as_ptr()) }, None);
+    }
+
+    #[test]
+    fn rejects_e_lfanew_outside_headers() {
+        let mut image = image();
+        image[DOS_HEADER_E_LFANEW..DOS_HEADER_E_LFANEW + 4]
+            .copy_from_slice(&0x7FFF_FFF0u32.to_le_bytes());
+
+        assert_eq!(nt_headers_offset(&image), None);
+        assert_eq!(unsafe { image_size(image.as_ptr()) }, None);
+    }
+
+    #[test]
+    fn reads_sections() {
+        let image = image();
+        let sections = sections(&image).unwrap();
+
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name(), b".text");
+        assert_eq!(sections[0].virtual_address, 0x1000);
+        assert_eq!(sections[1].name(), b"INIT");
+        assert_eq!(sections[1].virtual_size, 0x800);
+        assert!(sections[0].is_resident_code());
+        assert!(!sections[1].is_resident_code());
+    }
+
+    #[test]
+    fn rejects_missing_export_directory() {
+        assert_eq!(get_export_rva(&image(), "NtCreateFile"), None);
+    }
+}
@@ -0,0 +1,542 @@
+//! Provides a pattern-scanning engine for mapped images.
+//!
+//! Patterns are parsed once into a list of byte segments separated by gaps and can then be searched
+//! for any number of times. The syntax is a space-separated list of tokens:
+//!
+//! - `4C` - An exact byte.
+//! - `4?` / `?C` - A byte with a wildcard nibble.
+//! - `?` / `??` - A wildcard byte.
+//! - `[2-8]` / `[4]` - A gap of a variable (or fixed) number of arbitrary bytes.
+//!
+//! Scans can be restricted to named PE sections, such as `.text` or `PAGE`, and signatures that differ
+//! between Windows builds can be grouped in an ordered `SignatureSet`.
+
+use {crate::pe::sections, alloc::vec::Vec, core::ops::Range, thiserror_no_std::Error};
+
+/// The reasons a pattern fails to parse.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternError {
+    #[error("Failed to parse hexadecimal byte")]
+    HexParseError,
+
+    #[error("Invalid pattern")]
+    InvalidPattern,
+}
+
+/// A pattern byte compared under a mask, where mask bits that are clear are wildcards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MaskedByte {
+    value: u8,
+    mask: u8,
+}
+
+impl MaskedByte {
+    /// Checks whether the byte matches.
+    fn matches(&self, byte: u8) -> bool {
+        byte & self.mask == self.value
+    }
+}
+
+/// A run of consecutive pattern bytes, preceded by a gap of `min_gap..=max_gap` arbitrary bytes.
+#[derive(Debug, Clone)]
+struct Segment {
+    min_gap: usize,
+    max_gap: usize,
+    bytes: Vec<MaskedByte>,
+}
+
+impl Segment {
+    /// Checks whether the segment matches at the start of `data`.
+    fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(data)
+                .all(|(pattern_byte, &byte)| pattern_byte.matches(byte))
+    }
+}
+
+/// A parsed byte pattern.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The byte segments; the gap of the first segment is always zero.
+    segments: Vec<Segment>,
+
+    /// The index and value of the first exact byte of the first segment, used to skip through the data.
+    anchor: Option<(usize, u8)>,
+}
+
+impl Pattern {
+    /// Parses a pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The pattern, such as `"4C 8D 15 ?? ?? ?? ?? [0-8] 4C 8D 1D"`.
+    ///
+    /// # Returns
+    ///
+    /// The parsed pattern, `PatternError::HexParseError` for an invalid byte token, or
+    /// `PatternError::InvalidPattern` for an empty pattern, an invalid gap, or a gap at the start or end.
+    pub fn parse(pattern: &str) -> Result<Self, PatternError> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut current = Segment {
+            min_gap: 0,
+            max_gap: 0,
+            bytes: Vec::new(),
+        };
+
+        for token in pattern.split_whitespace() {
+            if let Some(gap) = token
+                .strip_prefix('[')
+                .and_then(|token| token.strip_suffix(']'))
+            {
+                let (min_gap, max_gap) = Self::parse_gap(gap)?;
+
+                // A gap must follow pattern bytes.
+                if current.bytes.is_empty() {
+                    if segments.is_empty() {
+                        return Err(PatternError::InvalidPattern);
+                    }
+
+                    // Consecutive gaps add up.
+                    current.min_gap += min_gap;
+                    current.max_gap += max_gap;
+                    continue;
+                }
+
+                segments.push(core::mem::replace(
+                    &mut current,
+                    Segment {
+                        min_gap,
+                        max_gap,
+                        bytes: Vec::new(),
+                    },
+                ));
+                continue;
+            }
+
+            current.bytes.push(Self::parse_byte(token)?);
+        }
+
+        // The pattern must not be empty or end with a gap.
+        if current.bytes.is_empty() {
+            return Err(PatternError::InvalidPattern);
+        }
+
+        segments.push(current);
+
+        let anchor = segments[0]
+            .bytes
+            .iter()
+            .position(|byte| byte.mask == 0xFF)
+            .map(|index| (index, segments[0].bytes[index].value));
+
+        Ok(Self { segments, anchor })
+    }
+
+    /// Parses a byte token with optional wildcard nibbles.
+    fn parse_byte(token: &str) -> Result<MaskedByte, PatternError> {
+        if token == "?" || token == "??" {
+            return Ok(MaskedByte { value: 0, mask: 0 });
+        }
+
+        let mut nibbles = token.chars();
+
+        let (Some(high), Some(low), None) = (nibbles.next(), nibbles.next(), nibbles.next()) else {
+            return Err(PatternError::HexParseError);
+        };
+
+        let parse_nibble = |nibble: char| -> Result<(u8, u8), PatternError> {
+            match nibble {
+                '?' => Ok((0, 0)),
+                _ => nibble
+                    .to_digit(16)
+                    .map(|value| (value as u8, 0xF))
+                    .ok_or(PatternError::HexParseError),
+            }
+        };
+
+        let (high_value, high_mask) = parse_nibble(high)?;
+        let (low_value, low_mask) = parse_nibble(low)?;
+
+        Ok(MaskedByte {
+            value: high_value << 4 | low_value,
+            mask: high_mask << 4 | low_mask,
+        })
+    }
+
+    /// Parses the inside of a `[min-max]` or `[count]` gap.
+    fn parse_gap(gap: &str) -> Result<(usize, usize), PatternError> {
+        let parse = |count: &str| {
+            count
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| PatternError::InvalidPattern)
+        };
+
+        let (min_gap, max_gap) = match gap.split_once('-') {
+            Some((min_gap, max_gap)) => (parse(min_gap)?, parse(max_gap)?),
+            None => (parse(gap)?, parse(gap)?),
+        };
+
+        match min_gap <= max_gap {
+            true => Ok((min_gap, max_gap)),
+            false => Err(PatternError::InvalidPattern),
+        }
+    }
+
+    /// Matches the segments starting from `index` at `position`, trying the shortest gaps first.
+    ///
+    /// # Returns
+    ///
+    /// The end of the match, or `None` if the segments don't match at `position`.
+    fn match_segments(&self, data: &[u8], position: usize, index: usize) -> Option<usize> {
+        let segment = &self.segments[index];
+
+        if !segment.matches(data.get(position..)?) {
+            return None;
+        }
+
+        let end = position + segment.bytes.len();
+
+        let Some(next) = self.segments.get(index + 1) else {
+            return Some(end);
+        };
+
+        (next.min_gap..=next.max_gap)
+            .find_map(|gap| self.match_segments(data, end + gap, index + 1))
+    }
+
+    /// Finds the first match of the pattern.
+    ///
+    /// The data is skipped through by searching for the first exact byte of the pattern, and the full pattern
+    /// is only compared where that byte occurs.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data to search.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Range<usize>>` - The range of the first match, or `None` if the pattern doesn't occur.
+    pub fn find(&self, data: &[u8]) -> Option<Range<usize>> {
+        let Some((anchor_index, anchor_value)) = self.anchor else {
+            return (0..data.len())
+                .find_map(|start| self.match_segments(data, start, 0).map(|end| start..end));
+        };
+
+        let mut search = anchor_index;
+
+        while let Some(found) = data
+            .get(search..)?
+            .iter()
+            .position(|&byte| byte == anchor_value)
+        {
+            let start = search + found - anchor_index;
+
+            if let Some(end) = self.match_segments(data, start, 0) {
+                return Some(start..end);
+            }
+
+            search += found + 1;
+        }
+
+        None
+    }
+
+    /// Finds the first match of the pattern within the named sections of a mapped image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The mapped image.
+    /// * `section_names` - The sections to search, in image order. The whole image is searched if empty.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Range<usize>>` - The range of the first match as image offsets, or `None` if the pattern doesn't occur.
+    pub fn find_in_sections(&self, image: &[u8], section_names: &[&str]) -> Option<Range<usize>> {
+        if section_names.is_empty() {
+            return self.find(image);
+        }
+
+        sections(image)?
+            .iter()
+            .filter(|section| {
+                section_names
+                    .iter()
+                    .any(|name| section.name() == name.as_bytes())
+            })
+            .find_map(|section| {
+                let start = section.virtual_address as usize;
+                let end = (start + section.virtual_size as usize).min(image.len());
+                let found = self.find(image.get(start..end)?)?;
+
+                Some(start + found.start..start + found.end)
+            })
+    }
+}
+
+/// Where the result of a signature is located relative to its match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extraction {
+    /// The result is at the given offset from the start of the match.
+    FromStart(usize),
+
+    /// The result is at the given (usually negative) offset from the end of the match,
+    /// for signatures whose gaps make the distance from the start vary.
+    FromEnd(isize),
+}
+
+/// A named signature, such as one per Windows build, and the location of its result.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    /// The name reported when the signature matches.
+    pub name: &'static str,
+
+    /// The parsed pattern.
+    pub pattern: Pattern,
+
+    /// The location of the result relative to the match.
+    pub extraction: Extraction,
+}
+
+/// A match of a signature from a `SignatureSet`.
+#[derive(Debug, Clone)]
+pub struct SignatureMatch {
+    /// The name of the signature that matched.
+    pub name: &'static str,
+
+    /// The range of the match as image offsets.
+    pub range: Range<usize>,
+
+    /// The image offset of the extracted result.
+    pub offset: usize,
+}
+
+/// An ordered list of signatures for the same location, tried in order until one matches.
+#[derive(Debug, Clone, Default)]
+pub struct SignatureSet {
+    /// The signatures, in the order they are tried.
+    signatures: Vec<Signature>,
+
+    /// The sections that are searched. The whole image is searched if empty.
+    sections: Vec<&'static str>,
+}
+
+impl SignatureSet {
+    /// Creates an empty signature set that searches the given sections.
+    ///
+    /// # Arguments
+    ///
+    /// * `sections` - The sections to search, such as `&[".text", "PAGE"]`. The whole image is searched if empty.
+    pub fn new(sections: &[&'static str]) -> Self {
+        Self {
+            signatures: Vec::new(),
+            sections: sections.to_vec(),
+        }
+    }
+
+    /// Adds a signature that is tried after the existing ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name reported when the signature matches.
+    /// * `pattern` - The pattern to parse.
+    /// * `extraction` - The location of the result relative to the match.
+    ///
+    /// # Returns
+    ///
+    /// The signature set, or the error of parsing the pattern.
+    pub fn signature(
+        mut self,
+        name: &'static str,
+        pattern: &str,
+        extraction: Extraction,
+    ) -> Result<Self, PatternError> {
+        self.signatures.push(Signature {
+            name,
+            pattern: Pattern::parse(pattern)?,
+            extraction,
+        });
+
+        Ok(self)
+    }
+
+    /// Finds the first signature that matches in a mapped image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The mapped image.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<SignatureMatch>` - The match of the first matching signature, or `None` if none matches.
+    pub fn find(&self, image: &[u8]) -> Option<SignatureMatch> {
+        self.signatures.iter().find_map(|signature| {
+            let range = signature.pattern.find_in_sections(image, &self.sections)?;
+
+            let offset = match signature.extraction {
+                Extraction::FromStart(offset) => range.start.checked_add(offset)?,
+                Extraction::FromEnd(offset) => range.end.checked_add_signed(offset)?,
+            };
+
+            Some(SignatureMatch {
+                name: signature.name,
+                range,
+                offset,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tokens() {
+        assert!(Pattern::parse("4C 8d 15 ?? ? 4? ?C [2-8] 4C [4] 8D").is_ok());
+        assert!(Pattern::parse("?? ?? 4C").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_bytes() {
+        assert_eq!(
+            Pattern::parse("4G").unwrap_err(),
+            PatternError::HexParseError
+        );
+        assert_eq!(
+            Pattern::parse("4C8D").unwrap_err(),
+            PatternError::HexParseError
+        );
+        assert_eq!(
+            Pattern::parse("4").unwrap_err(),
+            PatternError::HexParseError
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_gaps() {
+        assert_eq!(
+            Pattern::parse("").unwrap_err(),
+            PatternError::InvalidPattern
+        );
+        assert_eq!(
+            Pattern::parse("[2] 4C").unwrap_err(),
+            PatternError::InvalidPattern
+        );
+        assert_eq!(
+            Pattern::parse("4C [2]").unwrap_err(),
+            PatternError::InvalidPattern
+        );
+        assert_eq!(
+            Pattern::parse("4C [8-2] 8D").unwrap_err(),
+            PatternError::InvalidPattern
+        );
+        assert_eq!(
+            Pattern::parse("4C [x] 8D").unwrap_err(),
+            PatternError::InvalidPattern
+        );
+    }
+
+    #[test]
+    fn finds_exact_bytes() {
+        let pattern = Pattern::parse("4C 8D 15").unwrap();
+
+        assert_eq!(pattern.find(&[0x90, 0x4C, 0x8D, 0x15, 0x90]), Some(1..4));
+        assert_eq!(pattern.find(&[0x90, 0x4C, 0x8D, 0x16, 0x90]), None);
+        assert_eq!(pattern.find(&[0x4C, 0x8D]), None);
+    }
+
+    #[test]
+    fn finds_wildcards() {
+        let pattern = Pattern::parse("4? ?? ?5").unwrap();
+
+        assert_eq!(pattern.find(&[0x90, 0x4C, 0xAA, 0x15]), Some(1..4));
+        assert_eq!(pattern.find(&[0x90, 0x5C, 0xAA, 0x15]), None);
+        assert_eq!(pattern.find(&[0x90, 0x4C, 0xAA, 0x16]), None);
+    }
+
+    #[test]
+    fn finds_leading_wildcards() {
+        // The anchor is the first exact byte, so the match starts before it.
+        let pattern = Pattern::parse("?? ?? 4C").unwrap();
+
+        assert_eq!(pattern.find(&[0x4C, 0x01, 0x02, 0x4C]), Some(1..4));
+        assert_eq!(pattern.find(&[0x01, 0x4C]), None);
+    }
+
+    #[test]
+    fn finds_shortest_gap_first() {
+        let pattern = Pattern::parse("4C [1-3] 8D").unwrap();
+
+        assert_eq!(pattern.find(&[0x4C, 0x00, 0x8D, 0x8D]), Some(0..3));
+        assert_eq!(pattern.find(&[0x4C, 0x00, 0x00, 0x00, 0x8D]), Some(0..5));
+        assert_eq!(pattern.find(&[0x4C, 0x8D]), None);
+        assert_eq!(pattern.find(&[0x4C, 0x00, 0x00, 0x00, 0x00, 0x8D]), None);
+    }
+
+    #[test]
+    fn adds_up_consecutive_gaps() {
+        let pattern = Pattern::parse("4C [1] [1-2] 8D").unwrap();
+
+        assert_eq!(pattern.find(&[0x4C, 0x00, 0x8D]), None);
+        assert_eq!(pattern.find(&[0x4C, 0x00, 0x00, 0x8D]), Some(0..4));
+        assert_eq!(pattern.find(&[0x4C, 0x00, 0x00, 0x00, 0x8D]), Some(0..5));
+    }
+
+    #[test]
+    fn finds_in_sections() {
+        let mut image = crate::pe::tests::image();
+        image[0x2100..0x2103].copy_from_slice(&[0x4C, 0x8D, 0x15]);
+        image[0x1200..0x1203].copy_from_slice(&[0x4C, 0x8D, 0x15]);
+
+        let pattern = Pattern::parse("4C 8D 15").unwrap();
+
+        assert_eq!(
+            pattern.find_in_sections(&image, &["INIT"]),
+            Some(0x2100..0x2103)
+        );
+        assert_eq!(
+            pattern.find_in_sections(&image, &[".text"]),
+            Some(0x1200..0x1203)
+        );
+        assert_eq!(pattern.find_in_sections(&image, &["PAGE"]), None);
+        assert_eq!(pattern.find_in_sections(&image, &[]), Some(0x1200..0x1203));
+    }
+
+    #[test]
+    fn finds_first_matching_signature() {
+        let mut image = crate::pe::tests::image();
+        image[0x1100..0x1108].copy_from_slice(&[0x4C, 0x8D, 0x15, 0x00, 0x00, 0x4C, 0x8D, 0x1D]);
+
+        let signatures = SignatureSet::new(&[".text"])
+            .signature("missing", "4C 8D 15 11 22", Extraction::FromStart(0))
+            .unwrap()
+            .signature(
+                "relaxed",
+                "4C 8D 15 [0-4] 4C 8D 1D",
+                Extraction::FromEnd(-3),
+            )
+            .unwrap();
+
+        let signature_match = signatures.find(&image).unwrap();
+
+        assert_eq!(signature_match.name, "relaxed");
+        assert_eq!(signature_match.range, 0x1100..0x1108);
+        assert_eq!(signature_match.offset, 0x1105);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_extraction() {
+        let mut image = crate::pe::tests::image();
+        image[0x1100..0x1103].copy_from_slice(&[0x4C, 0x8D, 0x15]);
+
+        let signatures = SignatureSet::new(&[".text"])
+            .signature("underflow", "4C 8D 15", Extraction::FromEnd(-0x2000))
+            .unwrap();
+
+        assert!(signatures.find(&image).is_none());
+    }
+}
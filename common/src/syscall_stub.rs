@@ -0,0 +1,243 @@
+//! Decodes syscall numbers from system service stubs.
+//!
+//! Every `Zw*` export of ntoskrnl and every `Nt*`/`Zw*` export of ntdll is a small stub that loads the
+//! syscall number into `eax` before dispatching, which allows resolving syscall numbers by name
+//! instead of hardcoding them per Windows build.
+
+use iced_x86::{Code, Decoder, DecoderOptions, FlowControl, Instruction, Register};
+
+/// The number of bytes of a stub that are decoded, covering both the ntoskrnl and the ntdll stub layouts.
+pub const SYSCALL_STUB_SIZE: usize = 0x20;
+
+/// The highest valid syscall number (12-bit index, bit 12 selecting the win32k table).
+pub const MAX_SYSCALL_NUMBER: u32 = 0x1FFF;
+
+/// The first syscall number of the win32k shadow service table.
+pub const WIN32K_SYSCALL_BASE: u32 = 0x1000;
+
+/// Checks whether a syscall number indexes the win32k shadow service table instead of the ntoskrnl one.
+///
+/// # Arguments
+///
+/// * `number` - The syscall number.
+///
+/// # Returns
+///
+/// * `bool` - `true` if bit 12 of the syscall number is set.
+pub fn is_win32k_syscall_number(number: u32) -> bool {
+    number & WIN32K_SYSCALL_BASE != 0
+}
+
+/// Returns the immediate if the instruction is `mov eax, imm32`.
+fn mov_eax_imm32(instruction: &Instruction) -> Option<u32> {
+    match instruction.code() == Code::Mov_r32_imm32 && instruction.op0_register() == Register::EAX {
+        true => Some(instruction.immediate32()),
+        false => None,
+    }
+}
+
+/// Decodes the syscall number from the bytes of a system service stub.
+///
+/// Two stub layouts are recognized:
+///
+/// - ntdll/win32u (user-mode):
+///   `mov r10, rcx` / `mov eax, imm32` / `test byte ptr [SharedUserData+0x308], 1` / ... / `syscall`
+/// - ntoskrnl `Zw*` (kernel-mode):
+///   `mov rax, rsp` / `cli` / ... / `lea rax, [KiServiceLinkage]` / `push rax` / `mov eax, imm32` / `jmp KiServiceInternal`
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes at the start of the stub, usually `SYSCALL_STUB_SIZE` bytes.
+///
+/// # Returns
+///
+/// * `Option<u32>` - The syscall number, or `None` if the bytes are not a recognized stub.
+pub fn decode_syscall_number(bytes: &[u8]) -> Option<u32> {
+    let mut decoder = Decoder::with_ip(64, bytes, 0, DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    let mut previous: Option<Instruction> = None;
+    let mut index = 0;
+
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+
+        if instruction.is_invalid() {
+            return None;
+        }
+
+        // User-mode stub: the syscall number directly follows `mov r10, rcx`.
+        if index == 1
+            && previous.is_some_and(|previous| {
+                previous.code() == Code::Mov_r64_rm64
+                    && previous.op0_register() == Register::R10
+                    && previous.op1_register() == Register::RCX
+            })
+        {
+            return mov_eax_imm32(&instruction).filter(|&number| number <= MAX_SYSCALL_NUMBER);
+        }
+
+        // Kernel-mode stub: the syscall number is loaded right before the jump to KiServiceInternal.
+        if instruction.flow_control() != FlowControl::Next {
+            if instruction.flow_control() != FlowControl::UnconditionalBranch {
+                return None;
+            }
+
+            return previous
+                .as_ref()
+                .and_then(mov_eax_imm32)
+                .filter(|&number| number <= MAX_SYSCALL_NUMBER);
+        }
+
+        previous = Some(instruction);
+        index += 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The branch and `lea` displacements of the ntoskrnl stubs differ on every build and don't affect decoding.
+
+    /// `NtCreateFile` of ntdll on Windows 7 SP1 (7601), before the `int 2Eh` fallback was added.
+    const NTDLL_7601_NT_CREATE_FILE: [u8; 16] = [
+        0x4C, 0x8B, 0xD1, 0xB8, 0x52, 0x00, 0x00, 0x00, 0x0F, 0x05, 0xC3, 0x0F, 0x1F, 0x44, 0x00,
+        0x00,
+    ];
+
+    /// `NtCreateFile` of ntdll on Windows 10 22H2 (19045).
+    const NTDLL_19045_NT_CREATE_FILE: [u8; 32] = [
+        0x4C, 0x8B, 0xD1, 0xB8, 0x55, 0x00, 0x00, 0x00, 0xF6, 0x04, 0x25, 0x08, 0x03, 0xFE, 0x7F,
+        0x01, 0x75, 0x03, 0x0F, 0x05, 0xC3, 0xCD, 0x2E, 0xC3, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    /// `NtQuerySystemInformation` of ntdll on Windows 11 23H2 (22631).
+    const NTDLL_22631_NT_QUERY_SYSTEM_INFORMATION: [u8; 32] = [
+        0x4C, 0x8B, 0xD1, 0xB8, 0x36, 0x00, 0x00, 0x00, 0xF6, 0x04, 0x25, 0x08, 0x03, 0xFE, 0x7F,
+        0x01, 0x75, 0x03, 0x0F, 0x05, 0xC3, 0xCD, 0x2E, 0xC3, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    /// `NtUserGetForegroundWindow` of win32u on Windows 10 22H2 (19045).
+    const WIN32U_19045_NT_USER_GET_FOREGROUND_WINDOW: [u8; 32] = [
+        0x4C, 0x8B, 0xD1, 0xB8, 0x3C, 0x10, 0x00, 0x00, 0xF6, 0x04, 0x25, 0x08, 0x03, 0xFE, 0x7F,
+        0x01, 0x75, 0x03, 0x0F, 0x05, 0xC3, 0xCD, 0x2E, 0xC3, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    /// `ZwCreateFile` of ntoskrnl on Windows 7 SP1 (7601).
+    const NTOSKRNL_7601_ZW_CREATE_FILE: [u8; 32] = [
+        0x48, 0x8B, 0xC4, 0xFA, 0x48, 0x83, 0xEC, 0x10, 0x50, 0x9C, 0x6A, 0x10, 0x48, 0x8D, 0x05,
+        0x3D, 0x2A, 0x00, 0x00, 0x50, 0xB8, 0x52, 0x00, 0x00, 0x00, 0xE9, 0x22, 0x1F, 0x00, 0x00,
+        0x66, 0x90,
+    ];
+
+    /// `ZwCreateFile` of ntoskrnl on Windows 10 22H2 (19045).
+    const NTOSKRNL_19045_ZW_CREATE_FILE: [u8; 32] = [
+        0x48, 0x8B, 0xC4, 0xFA, 0x48, 0x83, 0xEC, 0x10, 0x50, 0x9C, 0x6A, 0x10, 0x48, 0x8D, 0x05,
+        0x1D, 0x76, 0x00, 0x00, 0x50, 0xB8, 0x55, 0x00, 0x00, 0x00, 0xE9, 0xE2, 0x83, 0x00, 0x00,
+        0x0F, 0x1F,
+    ];
+
+    /// `ZwQuerySystemInformation` of ntoskrnl on Windows 11 23H2 (22631).
+    const NTOSKRNL_22631_ZW_QUERY_SYSTEM_INFORMATION: [u8; 32] = [
+        0x48, 0x8B, 0xC4, 0xFA, 0x48, 0x83, 0xEC, 0x10, 0x50, 0x9C, 0x6A, 0x10, 0x48, 0x8D, 0x05,
+        0x5D, 0x8B, 0x00, 0x00, 0x50, 0xB8, 0x36, 0x00, 0x00, 0x00, 0xE9, 0x02, 0x9A, 0x00, 0x00,
+        0x0F, 0x1F,
+    ];
+
+    #[test]
+    fn decodes_ntdll_stubs() {
+        assert_eq!(
+            decode_syscall_number(&NTDLL_7601_NT_CREATE_FILE),
+            Some(0x52)
+        );
+        assert_eq!(
+            decode_syscall_number(&NTDLL_19045_NT_CREATE_FILE),
+            Some(0x55)
+        );
+        assert_eq!(
+            decode_syscall_number(&NTDLL_22631_NT_QUERY_SYSTEM_INFORMATION),
+            Some(0x36)
+        );
+    }
+
+    #[test]
+    fn decodes_win32u_stubs() {
+        let number = decode_syscall_number(&WIN32U_19045_NT_USER_GET_FOREGROUND_WINDOW);
+
+        assert_eq!(number, Some(0x103C));
+        assert!(is_win32k_syscall_number(number.unwrap()));
+    }
+
+    #[test]
+    fn decodes_ntoskrnl_stubs() {
+        assert_eq!(
+            decode_syscall_number(&NTOSKRNL_7601_ZW_CREATE_FILE),
+            Some(0x52)
+        );
+        assert_eq!(
+            decode_syscall_number(&NTOSKRNL_19045_ZW_CREATE_FILE),
+            Some(0x55)
+        );
+        assert_eq!(
+            decode_syscall_number(&NTOSKRNL_22631_ZW_QUERY_SYSTEM_INFORMATION),
+            Some(0x36)
+        );
+    }
+
+    #[test]
+    fn rejects_hooked_stubs() {
+        // An inline hook overwrote `mov r10, rcx` / `mov eax, imm32` with `jmp rel32`.
+        let mut stub = NTDLL_19045_NT_CREATE_FILE;
+        stub[..5].copy_from_slice(&[0xE9, 0x00, 0x10, 0x00, 0x00]);
+
+        assert_eq!(decode_syscall_number(&stub), None);
+    }
+
+    #[test]
+    fn rejects_regular_functions() {
+        // mov [rsp+8], rbx / push rdi / sub rsp, 20h / mov eax, 55h / call rel32
+        let function = [
+            0x48, 0x89, 0x5C, 0x24, 0x08, 0x57, 0x48, 0x83, 0xEC, 0x20, 0xB8, 0x55, 0x00, 0x00,
+            0x00, 0xE8, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        assert_eq!(decode_syscall_number(&function), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_numbers() {
+        let mut stub = NTDLL_19045_NT_CREATE_FILE;
+        stub[4..8].copy_from_slice(&(MAX_SYSCALL_NUMBER + 1).to_le_bytes());
+        assert_eq!(decode_syscall_number(&stub), None);
+
+        let mut stub = NTOSKRNL_19045_ZW_CREATE_FILE;
+        stub[21..25].copy_from_slice(&(MAX_SYSCALL_NUMBER + 1).to_le_bytes());
+        assert_eq!(decode_syscall_number(&stub), None);
+    }
+
+    #[test]
+    fn rejects_truncated_stubs() {
+        assert_eq!(decode_syscall_number(&[]), None);
+        assert_eq!(
+            decode_syscall_number(&NTDLL_19045_NT_CREATE_FILE[..3]),
+            None
+        );
+        assert_eq!(
+            decode_syscall_number(&NTOSKRNL_19045_ZW_CREATE_FILE[..25]),
+            None
+        );
+    }
+
+    #[test]
+    fn selects_service_table() {
+        assert!(!is_win32k_syscall_number(0x55));
+        assert!(!is_win32k_syscall_number(WIN32K_SYSCALL_BASE - 1));
+        assert!(is_win32k_syscall_number(WIN32K_SYSCALL_BASE));
+        assert!(is_win32k_syscall_number(MAX_SYSCALL_NUMBER));
+    }
+}
@@ -0,0 +1,183 @@
+//! Computes the TSC offsets that hide the latency of VM exits from the guest.
+//!
+//! The VM exits of all processors are accounted in one shared count of hidden cycles. Each VM exit adds the part of
+//! its interval that isn't covered by a VM exit already hidden, so VM exits that overlap in time on several
+//! processors are hidden once, and the shared count never moves faster than the host TSC. At VM entry, a processor
+//! moves its TSC offset back by the cycles hidden since its previous VM entry, but never by more than the length of
+//! its own VM exit, so the TSC it reads never goes backward. A processor that fell behind the shared count catches
+//! up over its next VM exits.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.6.5 Timestamp-Counter Offsetting and
+//! Scaling.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// The cycles of VM exits hidden from the guest, shared by all processors.
+#[derive(Debug, Default)]
+pub struct HiddenCycles {
+    /// The number of processors that haven't measured the latency of a VM exit yet. No VM exit is hidden until all
+    /// of them have, so that every processor starts from the same TSC offset.
+    uncalibrated: AtomicU32,
+
+    /// The host TSC at the end of the latest hidden VM exit.
+    hidden_until: AtomicU64,
+
+    /// The cycles hidden from the guest so far.
+    hidden: AtomicU64,
+}
+
+impl HiddenCycles {
+    /// Constructs the hidden cycles, waiting for the given number of processors to calibrate.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor_count` - The number of processors.
+    pub const fn new(processor_count: u32) -> Self {
+        Self {
+            uncalibrated: AtomicU32::new(processor_count),
+            hidden_until: AtomicU64::new(0),
+            hidden: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that a processor measured the latency of a VM exit. Must be called once per processor.
+    pub fn calibrated(&self) {
+        let _ = self
+            .uncalibrated
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            });
+    }
+
+    /// Checks whether all processors calibrated, so that VM exits are hidden.
+    pub fn is_active(&self) -> bool {
+        self.uncalibrated.load(Ordering::Relaxed) == 0
+    }
+
+    /// Hides a VM exit from the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_start` - The host TSC at the start of the VM exit.
+    /// * `exit_end` - The host TSC at the end of the VM exit.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The cycles hidden so far, including this VM exit.
+    pub fn hide(&self, exit_start: u64, exit_end: u64) -> u64 {
+        let hidden_until = self.hidden_until.fetch_max(exit_end, Ordering::Relaxed);
+        let cycles = exit_end.saturating_sub(exit_start.max(hidden_until));
+
+        self.hidden.fetch_add(cycles, Ordering::Relaxed) + cycles
+    }
+}
+
+/// Computes the hidden cycles a processor applies to its TSC offset at VM entry.
+///
+/// # Arguments
+///
+/// * `applied` - The hidden cycles applied by the processor at its previous VM entry.
+/// * `hidden` - The cycles hidden so far, as returned by `HiddenCycles::hide`.
+/// * `exit_start` - The host TSC at the start of the VM exit.
+/// * `exit_end` - The host TSC at the end of the VM exit.
+///
+/// # Returns
+///
+/// * `u64` - The hidden cycles to apply, at most the length of the VM exit more than `applied`, so that the guest
+///   TSC of the processor doesn't go backward.
+pub fn applied_hidden_cycles(applied: u64, hidden: u64, exit_start: u64, exit_end: u64) -> u64 {
+    let exit_cycles = exit_end.saturating_sub(exit_start);
+
+    hidden.min(applied.saturating_add(exit_cycles)).max(applied)
+}
+
+/// Computes the TSC offset of a processor after applying more hidden cycles.
+///
+/// # Arguments
+///
+/// * `tsc_offset` - The current TSC offset.
+/// * `applied` - The hidden cycles included in the current TSC offset.
+/// * `new_applied` - The hidden cycles to include, as returned by `applied_hidden_cycles`.
+///
+/// # Returns
+///
+/// * `u64` - The TSC offset.
+pub fn compensated_tsc_offset(tsc_offset: u64, applied: u64, new_applied: u64) -> u64 {
+    tsc_offset.wrapping_sub(new_applied.wrapping_sub(applied))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_every_processor_to_calibrate() {
+        let hidden = HiddenCycles::new(2);
+
+        assert!(!hidden.is_active());
+        hidden.calibrated();
+        assert!(!hidden.is_active());
+        hidden.calibrated();
+        assert!(hidden.is_active());
+        hidden.calibrated();
+        assert!(hidden.is_active());
+    }
+
+    #[test]
+    fn hides_disjoint_exits_fully() {
+        let hidden = HiddenCycles::new(0);
+
+        assert_eq!(hidden.hide(100, 150), 50);
+        assert_eq!(hidden.hide(200, 230), 80);
+    }
+
+    #[test]
+    fn hides_overlapping_exits_once() {
+        let hidden = HiddenCycles::new(0);
+
+        assert_eq!(hidden.hide(100, 150), 50);
+        // Overlaps the previous exit from 120 to 150.
+        assert_eq!(hidden.hide(120, 180), 80);
+        // Covered by the previous exits.
+        assert_eq!(hidden.hide(130, 170), 80);
+    }
+
+    #[test]
+    fn applies_the_hidden_cycles_of_the_exit() {
+        // Only this processor exited since its previous VM entry.
+        assert_eq!(applied_hidden_cycles(50, 80, 200, 230), 80);
+    }
+
+    #[test]
+    fn clamps_to_the_length_of_the_exit() {
+        // Other processors hid 1000 cycles while this one ran, more than the 30 cycles of its own exit.
+        assert_eq!(applied_hidden_cycles(50, 1080, 200, 230), 80);
+        // The next exit catches up further.
+        assert_eq!(applied_hidden_cycles(80, 1100, 300, 340), 120);
+    }
+
+    #[test]
+    fn never_applies_fewer_cycles() {
+        // A processor that read a stale count doesn't move its offset forward.
+        assert_eq!(applied_hidden_cycles(80, 60, 200, 230), 80);
+    }
+
+    #[test]
+    fn keeps_the_guest_tsc_monotonic() {
+        let (tsc_offset, applied, exit_start, exit_end) = (0x1000, 50, 200, 230);
+        let new_applied = applied_hidden_cycles(applied, 1080, exit_start, exit_end);
+        let new_tsc_offset = compensated_tsc_offset(tsc_offset, applied, new_applied);
+
+        assert_eq!(new_tsc_offset, 0x1000 - 30);
+        assert!(exit_end + new_tsc_offset >= exit_start + tsc_offset);
+    }
+
+    #[test]
+    fn wraps_negative_offsets() {
+        assert_eq!(compensated_tsc_offset(10, 0, 30), (-20i64) as u64);
+        assert_eq!(
+            compensated_tsc_offset((-20i64) as u64, 30, 30),
+            (-20i64) as u64
+        );
+    }
+}
@@ -154,7 +154,7 @@ fn virtualize_system() -> Result<(), HypervisorError> {
     // Example 2: Syscall EPT Hook NtCreateFile via SSDT Function Entry
     //
     //
-    let ssdt_nt_create_file_addy = SsdtHook::find_ssdt_function_address(0x0055, false)?;
+    let ssdt_nt_create_file_addy = SsdtHook::find_by_name("NtCreateFile")?;
 
     let nt_create_file_syscall_hook = Hook::hook_function_ptr(
         ssdt_nt_create_file_addy.function_address as _,
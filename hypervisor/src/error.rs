@@ -27,6 +27,9 @@ pub enum HypervisorError {
     #[error("Failed to execute VMXOFF")]
     VMXOFFFailed,
 
+    #[error("The hypervisor refused to devirtualize the processor")]
+    DevirtualizationRefused,
+
     #[error("Failed to execute VMCLEAR")]
     VMCLEARFailed,
 
@@ -132,6 +135,15 @@ pub enum HypervisorError {
     #[error("Page already split")]
     PageAlreadySplit,
 
+    #[error("Page not split")]
+    PageNotSplit,
+
+    #[error("Single-step view is full")]
+    SingleStepViewFull,
+
+    #[error("Nested EPT is full")]
+    NestedEptFull,
+
     #[error("Hook manager not provided")]
     HookManagerNotProvided,
 
@@ -141,12 +153,27 @@ pub enum HypervisorError {
     #[error("ExAllocatePoolFailed failed")]
     ExAllocatePoolFailed,
 
+    #[error("Failed to allocate MDL")]
+    MdlAllocationFailed,
+
+    #[error("Page is not resident")]
+    PageNotResident,
+
     #[error("Pattern not found")]
     PatternNotFound,
 
     #[error("SSDT not found")]
     SsdtNotFound,
 
+    #[error("SSDT not found: {0}")]
+    SsdtFindFailed(crate::utils::ssdt::ssdt_find::SsdtFindFailures),
+
+    #[error("IA32_LSTAR is outside of the code sections of ntoskrnl")]
+    LstarOutsideKernel,
+
+    #[error("Service table references not found")]
+    ServiceTableReferencesNotFound,
+
     #[error("Failed create a C String")]
     FailedToCreateCString(#[from] NulError),
 
@@ -155,4 +182,31 @@ pub enum HypervisorError {
 
     #[error("Failed to parse hexadecimal string")]
     HexParseError,
+
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(#[from] common::scanner::PatternError),
+
+    #[error("Syscall stub not found")]
+    SyscallStubNotFound,
+
+    #[error("Failed to decode syscall number from stub")]
+    SyscallNumberNotFound,
+
+    #[error("Syscall number {0:#x} is not in the service table")]
+    SyscallNumberOutOfRange(u32),
+
+    #[error("Invalid PE image")]
+    InvalidPeImage,
+
+    #[error("GUI session process not found")]
+    SessionProcessNotFound,
+
+    #[error("Process not found")]
+    ProcessNotFound,
+
+    #[error("Invalid hardware breakpoint")]
+    InvalidBreakpoint,
+
+    #[error("Event queue is full")]
+    EventQueueFull,
 }
@@ -4,6 +4,7 @@
 use {
     crate::{
         error::HypervisorError,
+        intel::nmi::host_nmi_entry,
         utils::alloc::KernelAlloc,
         utils::instructions::{sgdt, sidt},
     },
@@ -59,6 +60,7 @@ impl DescriptorTables {
 
         descriptor_tables.copy_current_gdt();
         descriptor_tables.copy_current_idt();
+        descriptor_tables.install_host_nmi_handler();
 
         log::trace!("Initialized descriptor tables for host");
         Ok(())
@@ -108,6 +110,52 @@ impl DescriptorTables {
         log::trace!("Copied current IDT");
     }
 
+    /// Points the NMI entry of the host IDT to the host NMI handler.
+    fn install_host_nmi_handler(&mut self) {
+        log::trace!("Installing host NMI handler");
+
+        const NMI_VECTOR: usize = 2;
+
+        Self::set_gate_handler(
+            &mut self.interrupt_descriptor_table,
+            NMI_VECTOR,
+            host_nmi_entry as *const () as u64,
+        );
+
+        log::trace!("Installed host NMI handler");
+    }
+
+    /// Gets the handler offset of an interrupt gate of an IDT.
+    ///
+    /// # Arguments
+    /// * `idt` - The entries of the IDT.
+    /// * `vector` - The vector of the gate.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 6-8. 64-Bit IDT Gate Descriptors
+    pub fn gate_handler(idt: &[u64], vector: usize) -> u64 {
+        // Each 64-bit gate descriptor is 16 bytes, made of two entries of the table.
+        let low = idt[vector * 2];
+        (low & 0xFFFF) | ((low >> 32) & 0xFFFF_0000) | (idt[vector * 2 + 1] << 32)
+    }
+
+    /// Points an interrupt gate of an IDT to a handler.
+    ///
+    /// The selector, attributes and IST index of the gate are kept, only the handler offset is replaced.
+    ///
+    /// # Arguments
+    /// * `idt` - The entries of the IDT.
+    /// * `vector` - The vector of the gate.
+    /// * `handler` - The address of the handler.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 6-8. 64-Bit IDT Gate Descriptors
+    pub fn set_gate_handler(idt: &mut [u64], vector: usize, handler: u64) {
+        // Each 64-bit gate descriptor is 16 bytes, made of two entries of the table.
+        let low = &mut idt[vector * 2];
+        *low =
+            (*low & 0x0000_FFFF_FFFF_0000) | (handler & 0xFFFF) | ((handler & 0xFFFF_0000) << 32);
+        idt[vector * 2 + 1] = handler >> 32;
+    }
+
     /// Gets the table as a slice from the pointer.
     pub fn from_pointer(pointer: &DescriptorTablePointer<u64>) -> &[u64] {
         unsafe {
@@ -8,15 +8,25 @@
 use {
     crate::{
         error::HypervisorError,
-        intel::ept::paging::{AccessType, Ept},
+        intel::ept::{
+            paging::{AccessType, Ept},
+            statistics::{HookCountersTotal, HookStatistics},
+        },
         utils::{
             addresses::PhysicalAddress,
             alloc::PhysicalAllocator,
             function_hook::FunctionHook,
+            guest_memory::{locate_guest_pte, read_guest_pte, translate_guest_virtual_address},
+            mdl::LockedPages,
             nt::{get_ntoskrnl_export, RtlCopyMemory},
+            process::TargetProcess,
+            session::SessionProcess,
+            ssdt::ssdt_hook::SsdtHook,
         },
     },
     alloc::{boxed::Box, vec::Vec},
+    common::pe::get_export_rva,
+    core::ops::Range,
     x86::current::paging::{PAddr, VAddr, BASE_PAGE_SIZE},
     x86_64::instructions::interrupts::without_interrupts,
 };
@@ -26,10 +36,63 @@ pub enum HookType {
     /// Hook for intercepting and possibly modifying function execution.
     Function { inline_hook: FunctionHook },
 
+    /// Hook for intercepting a win32k syscall implementation, which lives in session space.
+    ///
+    /// The page of the function is locked for the lifetime of the hook, so that its physical page, which the
+    /// EPT redirects, is neither paged out nor moved. The session process is kept referenced so that the session
+    /// space stays mapped until the page is unlocked. The physical page is the one mapped by `cr3`, the CR3 of
+    /// the session process, since session space maps different pages in every session.
+    Win32kFunction {
+        inline_hook: FunctionHook,
+        locked_page: LockedPages,
+        session: SessionProcess,
+        cr3: u64,
+    },
+
+    /// Hook for intercepting a user-mode function of a specific process.
+    ///
+    /// The hook only takes effect while the guest runs with one of the CR3 values of the process.
+    /// Other processes that share the page, such as a copy-on-write DLL page that has not been
+    /// privatized, execute the original code. The page table entry mapping the page in the process is
+    /// watched, so the hook follows the page when the process privatizes it.
+    ProcessFunction {
+        inline_hook: FunctionHook,
+        process: TargetProcess,
+        pte: u64,
+    },
+
     /// Hook for hiding or monitoring access to a specific page.
     Page,
 }
 
+impl HookType {
+    /// Gets the inline hook of a function hook.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&FunctionHook>` - The inline hook, or `None` for page hooks.
+    pub fn inline_hook(&self) -> Option<&FunctionHook> {
+        match self {
+            HookType::Function { inline_hook }
+            | HookType::Win32kFunction { inline_hook, .. }
+            | HookType::ProcessFunction { inline_hook, .. } => Some(inline_hook),
+            HookType::Page => None,
+        }
+    }
+
+    /// Gets the target process of a process-scoped hook.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&TargetProcess>` - The target process, or `None` for global hooks.
+    pub fn process(&self) -> Option<&TargetProcess> {
+        match self {
+            HookType::ProcessFunction { process, .. } => Some(process),
+            _ => None,
+        }
+    }
+}
+
 /// Represents a hook in the system, either on a function or a page.
 pub struct Hook {
     /// Original virtual address of the target function or page.
@@ -55,6 +118,12 @@ pub struct Hook {
 
     /// Type of the hook (Function or Page).
     pub hook_type: HookType,
+
+    /// Per-processor counters of how often the hook fires and how much time its VM exits take.
+    pub statistics: HookStatistics,
+
+    /// Whether execution of the original page is redirected to the hooked copy, changed at runtime by hypercalls.
+    pub enabled: bool,
 }
 
 impl Hook {
@@ -110,6 +179,36 @@ impl Hook {
         page_start + base_offset
     }
 
+    /// Checks whether the hook takes effect for a guest CR3.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_cr3` - The guest CR3.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` for global hooks, or if the CR3 belongs to the target process of a process-scoped hook.
+    pub fn matches_cr3(&self, guest_cr3: u64) -> bool {
+        self.hook_type
+            .process()
+            .map_or(true, |process| process.matches_cr3(guest_cr3))
+    }
+
+    /// Gets the range of the copied page that is overwritten by the hook shellcode.
+    ///
+    /// # Returns
+    ///
+    /// * `Range<usize>` - The offsets of the hook bytes within the page, empty for page hooks.
+    pub fn hook_bytes(&self) -> Range<usize> {
+        let offset = (self.hook_va - self.page_va) as usize;
+        let length = self
+            .hook_type
+            .inline_hook()
+            .map_or(0, |inline_hook| inline_hook.shellcode_len());
+
+        offset..offset + length
+    }
+
     /// Creates a hook on a function by its pointer.
     ///
     /// This function sets up a hook directly using the function's pointer. It copies the page where the function resides,
@@ -158,6 +257,8 @@ impl Hook {
             page_va,
             page_pa,
             hook_type: HookType::Function { inline_hook },
+            statistics: HookStatistics::new(),
+            enabled: true,
         })
     }
 
@@ -190,6 +291,172 @@ impl Hook {
         Self::hook_function_ptr(address as u64, handler)
     }
 
+    /// Creates a hook on a win32k syscall by its number in the shadow SSDT (`W32pServiceTable`).
+    ///
+    /// Win32k's session space is not mapped in the system process, so this attaches to a process of a GUI
+    /// session to resolve the function, lock its page and copy it. The page of the function must be resident,
+    /// which `LockedPages::lock` checks in the page tables of the session process before locking it, since
+    /// `MmProbeAndLockPages` bugchecks on pages that aren't. The physical page is translated with the CR3 of the
+    /// session process.
+    ///
+    /// # Arguments
+    ///
+    /// * `api_number` - The win32k syscall number (0x1000 and above).
+    /// * `handler` - A pointer to the handler function.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Self>` - An instance of `Hook` if successful, or `None` if the function cannot be found or an error occurred.
+    pub fn hook_win32k_syscall(api_number: i32, handler: *const ()) -> Option<Self> {
+        let session = SessionProcess::find_gui_process()
+            .map_err(|err| log::error!("Failed to find a GUI session process: {}", err))
+            .ok()?;
+
+        let (hook, locked_page) = {
+            let _attach = session.attach();
+
+            let ssdt_hook = SsdtHook::find_ssdt_function_address(api_number, true)
+                .map_err(|err| {
+                    log::error!("Failed to find win32k syscall {:#x}: {}", api_number, err)
+                })
+                .ok()?;
+
+            // Lock the page before its physical address is resolved, so the EPT keeps redirecting the page that was copied.
+            let function_address = ssdt_hook.function_address as u64;
+            let page_address = VAddr::from(function_address).align_down_to_base_page();
+            let locked_page = LockedPages::lock(page_address.as_u64(), BASE_PAGE_SIZE)
+                .map_err(|err| log::error!("Failed to lock win32k syscall page: {}", err))
+                .ok()?;
+
+            (
+                Self::hook_function_ptr(function_address, handler)?,
+                locked_page,
+            )
+        };
+
+        let cr3 = session.cr3();
+        let Some(original_pa) = translate_guest_virtual_address(cr3, hook.original_va) else {
+            log::error!("Win32k syscall page is not mapped with CR3 {:#x}", cr3);
+            return None;
+        };
+
+        log::debug!(
+            "Win32k syscall {:#x} hooked in session {}",
+            api_number,
+            session.session_id()
+        );
+
+        let HookType::Function { inline_hook } = hook.hook_type else {
+            return None;
+        };
+
+        Some(Self {
+            original_va: hook.original_va,
+            original_pa: PhysicalAddress::from_pa(original_pa),
+            hook_va: hook.hook_va,
+            hook_pa: hook.hook_pa,
+            page: hook.page,
+            page_va: hook.page_va,
+            page_pa: hook.page_pa,
+            hook_type: HookType::Win32kFunction {
+                inline_hook,
+                locked_page,
+                session,
+                cr3,
+            },
+            statistics: hook.statistics,
+            enabled: hook.enabled,
+        })
+    }
+
+    /// Creates a process-scoped hook on an exported user-mode function of a module loaded in a process.
+    ///
+    /// The function is resolved while attached to the process, so its physical address is the one mapped by
+    /// the page tables of the process. If the page is a private copy-on-write copy of the process, only the
+    /// process maps it. If the page is still shared with other processes, the hook is filtered by CR3 and the
+    /// other processes execute the original code. When the process privatizes the page after the hook was
+    /// installed, the hook is moved to the private copy, see `HookManager::rehook_remapped_pages`.
+    ///
+    /// # Arguments
+    ///
+    /// * `process` - The target process, see `TargetProcess::from_process_id` and `TargetProcess::from_eprocess`.
+    /// * `module_name` - The name of the module, such as `ntdll.dll`.
+    /// * `export_name` - The name of the exported function.
+    /// * `handler` - The user-mode address in the target process that execution is transferred to.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Self>` - An instance of `Hook` if successful, or `None` if the function cannot be found or an error occurred.
+    pub fn hook_process_function(
+        process: TargetProcess,
+        module_name: &str,
+        export_name: &str,
+        handler: u64,
+    ) -> Option<Self> {
+        let hook = {
+            let _attach = process.attach();
+
+            let Some((module_base, module_size)) = process.find_module(module_name) else {
+                log::error!("Failed to find module: {}", module_name);
+                return None;
+            };
+
+            let module =
+                unsafe { core::slice::from_raw_parts(module_base as *const u8, module_size) };
+
+            let Some(export_rva) = get_export_rva(module, export_name) else {
+                log::error!("Failed to find function: {}!{}", module_name, export_name);
+                return None;
+            };
+
+            let function_ptr = module_base + export_rva as u64;
+
+            log::debug!(
+                "Function to be hooked: {}!{} {:#x}",
+                module_name,
+                export_name,
+                function_ptr
+            );
+
+            // Fault in the user-mode page before its physical address is resolved and it is copied
+            // with interrupts disabled.
+            let _ = unsafe { core::ptr::read_volatile(function_ptr as *const u8) };
+
+            let Some(pte) = locate_guest_pte(process.directory_table_base(), function_ptr) else {
+                log::error!("Failed to find page table entry of: {:#x}", function_ptr);
+                return None;
+            };
+
+            (
+                Self::hook_function_ptr(function_ptr, handler as *const ())?,
+                pte,
+            )
+        };
+
+        let (hook, pte) = hook;
+
+        let HookType::Function { inline_hook } = hook.hook_type else {
+            return None;
+        };
+
+        Some(Self {
+            original_va: hook.original_va,
+            original_pa: hook.original_pa,
+            hook_va: hook.hook_va,
+            hook_pa: hook.hook_pa,
+            page: hook.page,
+            page_va: hook.page_va,
+            page_pa: hook.page_pa,
+            hook_type: HookType::ProcessFunction {
+                inline_hook,
+                process,
+                pte,
+            },
+            statistics: hook.statistics,
+            enabled: hook.enabled,
+        })
+    }
+
     /// Creates a hook on a specific page.
     ///
     /// This function sets up a hook on a specific memory page, allowing for monitoring or altering the page's content.
@@ -219,10 +486,56 @@ impl Hook {
             hook_pa: page_pa,
             page,
             hook_type: HookType::Page,
+            statistics: HookStatistics::new(),
+            enabled: true,
         })
     }
 }
 
+/// Controls how EPT violations on hooked pages are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EptViolationMode {
+    /// Swap the whole EPTP between the primary (RW) and secondary (X) views on every violation.
+    #[default]
+    SwapView,
+
+    /// Stay in the current view and single-step data accesses to hooked pages with the monitor trap flag.
+    ///
+    /// The original page is mapped with full access in the single-step view of the processor for exactly one
+    /// instruction, after which the monitor trap flag VM exit switches back to the secondary EPT. The secondary
+    /// EPT itself is never changed, so other processors keep executing the hooked copy.
+    SingleStep,
+}
+
+/// Describes a guest write to the original page of a function hook that was applied to the hooked copy.
+#[derive(Debug, Clone)]
+pub struct PageWriteEvent {
+    /// The guest physical address of the original page.
+    pub original_page: u64,
+
+    /// The offsets within the page that were modified.
+    pub modified: Range<usize>,
+
+    /// The guest RIP of the instruction that performed the write.
+    pub guest_rip: u64,
+
+    /// Whether the write overlapped the hook bytes, which are kept in the hooked copy.
+    pub overlaps_hook: bool,
+}
+
+/// A callback invoked after a guest write to a hooked original page was applied to the hooked copy.
+pub type PageWriteCallback = fn(&PageWriteEvent);
+
+/// A guest write to a hooked original page that is single-stepped with the monitor trap flag.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingPageWrite {
+    /// The guest physical address of the original page.
+    pub original_page: u64,
+
+    /// The guest RIP of the instruction that performs the write.
+    pub guest_rip: u64,
+}
+
 /// Manages the lifecycle and control of various hooks.
 ///
 /// `HookManager` is a container for multiple hooks and provides an interface
@@ -231,6 +544,15 @@ impl Hook {
 pub struct HookManager {
     /// A collection of hooks managed by the HookManager.
     pub hooks: Vec<Hook>,
+
+    /// How EPT violations on the hooked pages are resolved.
+    pub violation_mode: EptViolationMode,
+
+    /// Whether guest writes to the original pages of function hooks are intercepted and applied to the hooked copies.
+    pub write_tracking: bool,
+
+    /// The callback invoked for every write applied to a hooked copy.
+    pub write_callback: Option<PageWriteCallback>,
 }
 
 impl HookManager {
@@ -240,11 +562,40 @@ impl HookManager {
     ///
     /// * `hooks` - A vector of `Hook` instances to be managed.
     pub fn new(hooks: Vec<Hook>) -> Box<Self> {
-        let hooks = Self { hooks };
+        let hooks = Self {
+            hooks,
+            violation_mode: EptViolationMode::default(),
+            write_tracking: false,
+            write_callback: None,
+        };
         let instance = Box::new(hooks);
         instance
     }
 
+    /// Sets how EPT violations on the hooked pages are resolved.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - The mode to use for resolving EPT violations.
+    pub fn set_violation_mode(&mut self, mode: EptViolationMode) {
+        self.violation_mode = mode;
+    }
+
+    /// Enables tracking of guest writes to the original pages of function hooks.
+    ///
+    /// The original pages are mapped Read-Only in the primary EPT. A write is single-stepped with the monitor
+    /// trap flag in the single-step view of the writing processor, so the page stays protected for the other
+    /// processors. The modified bytes are then copied to the hooked copy, except for the hook bytes, so that code
+    /// hot-patched by the kernel doesn't go stale in the hooked copy. Must be called before `enable_hooks`.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - An optional callback invoked with every applied write.
+    pub fn enable_write_tracking(&mut self, callback: Option<PageWriteCallback>) {
+        self.write_tracking = true;
+        self.write_callback = callback;
+    }
+
     /// Enables all the hooks managed by the `HookManager`.
     ///
     /// It sets the necessary permissions on the primary and secondary Extended Page Tables (EPTs)
@@ -275,7 +626,7 @@ impl HookManager {
         for hook in &self.hooks {
             // Enable the hook if it is a function hook, which involves
             // modifying the targeted function's instructions.
-            if let HookType::Function { inline_hook } = &hook.hook_type {
+            if let Some(inline_hook) = hook.hook_type.inline_hook() {
                 inline_hook.enable();
             }
 
@@ -298,13 +649,16 @@ impl HookManager {
             let original_page = hook.original_pa.align_down_to_base_page().as_u64();
             let hooked_copy_page = hook.hook_pa.align_down_to_base_page().as_u64();
 
+            let primary_access = self.primary_access(original_page);
+
             log::debug!(
-                "Changing permissions for page to Read-Write (RW) only: {:#x}",
+                "Changing permissions for page to {:?} only: {:#x}",
+                primary_access,
                 original_page
             );
 
-            // Modify the page permission in the primary EPT to ReadWrite.
-            primary_ept.change_page_flags(original_page, AccessType::READ_WRITE)?;
+            // Modify the page permission in the primary EPT to ReadWrite (or Read-Only with write tracking).
+            primary_ept.change_page_flags(original_page, primary_access)?;
 
             log::debug!(
                 "Changing permissions for hook page to Execute (X) only: {:#x}",
@@ -319,6 +673,25 @@ impl HookManager {
             secondary_ept.remap_page(original_page, hooked_copy_page, AccessType::EXECUTE)?;
         }
 
+        // Write-protect the page tables mapping the pages of process-scoped hooks, so that a hook can follow its page
+        // when the process remaps it. This is done last, since the page tables may share a 2MB page with a hooked page.
+        for hook in &self.hooks {
+            if let HookType::ProcessFunction { pte, .. } = hook.hook_type {
+                let pte_page = PAddr::from(pte).align_down_to_base_page().as_u64();
+
+                log::debug!(
+                    "Changing permissions for page table to Read-Only: {:#x}",
+                    pte_page
+                );
+
+                primary_ept.split_large_page(pte_page)?;
+                primary_ept.change_page_flags(pte_page, AccessType::READ)?;
+
+                secondary_ept.split_large_page(pte_page)?;
+                secondary_ept.change_page_flags(pte_page, AccessType::READ)?;
+            }
+        }
+
         Ok(())
     }
 
@@ -340,4 +713,353 @@ impl HookManager {
 
         None
     }
+
+    /// Tries to find a hook for the specified hook virtual address that takes effect for a guest CR3.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The hook virtual address to search for.
+    /// * `guest_cr3` - The guest CR3, used to filter process-scoped hooks.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&Hook>` - A reference to the hook if found, or `None` if not found.
+    pub fn find_hook_by_address_for_cr3(&self, address: u64, guest_cr3: u64) -> Option<&Hook> {
+        self.hooks
+            .iter()
+            .find(|hook| hook.original_va == address && hook.matches_cr3(guest_cr3))
+    }
+
+    /// Checks whether a guest CR3 must bypass the hooked copy of a page.
+    ///
+    /// This is the case when all hooks on the page are process-scoped and none of them targets the CR3.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    /// * `guest_cr3` - The guest CR3.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the original page should be executed instead of the hooked copy.
+    pub fn bypasses_page(&self, guest_pa: u64, guest_cr3: u64) -> bool {
+        let mut hooks = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.original_pa.align_down_to_base_page().as_u64() == guest_pa)
+            .peekable();
+
+        hooks.peek().is_some()
+            && hooks.all(|hook| hook.hook_type.process().is_some() && !hook.matches_cr3(guest_cr3))
+    }
+
+    /// Checks whether a guest physical page is a page table that maps the page of a process-scoped hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the page.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if writes to the page must be checked with `rehook_remapped_pages`.
+    pub fn watches_pte_page(&self, guest_pa: u64) -> bool {
+        self.hooks.iter().any(|hook| match hook.hook_type {
+            HookType::ProcessFunction { pte, .. } => {
+                PAddr::from(pte).align_down_to_base_page().as_u64() == guest_pa
+            }
+            _ => false,
+        })
+    }
+
+    /// Moves the process-scoped hooks mapped by a page table to the physical page their entry maps now.
+    ///
+    /// A process that writes to a shared copy-on-write page, such as when a debugger sets a breakpoint, gets a
+    /// private copy of the page at a new physical address. The hooked copy is refreshed from the new page except for
+    /// the hook bytes, the new page is redirected to the hooked copy and the previous page is mapped with full access
+    /// again if no other hook is placed on it. An entry that is not present keeps the hook until the page is mapped again.
+    ///
+    /// The shared EPTs are changed, so the caller must invalidate the cached EPT translations of all processors if
+    /// a hook was moved.
+    ///
+    /// # Arguments
+    ///
+    /// * `pte_page` - The 4KB aligned guest physical address of the written page table.
+    /// * `primary_ept` - A mutable reference to the primary EPT.
+    /// * `secondary_ept` - A mutable reference to the secondary EPT.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool, HypervisorError>` - Whether any hook was moved, or an error if the EPTs couldn't be changed.
+    pub fn rehook_remapped_pages(
+        &mut self,
+        pte_page: u64,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+    ) -> Result<bool, HypervisorError> {
+        let mut remapped = false;
+
+        for index in 0..self.hooks.len() {
+            let hook = &mut self.hooks[index];
+
+            let HookType::ProcessFunction { pte, .. } = hook.hook_type else {
+                continue;
+            };
+
+            if PAddr::from(pte).align_down_to_base_page().as_u64() != pte_page {
+                continue;
+            }
+
+            let Some(new_page) = read_guest_pte(pte) else {
+                continue;
+            };
+
+            let old_page = hook.original_pa.align_down_to_base_page().as_u64();
+
+            if new_page == old_page {
+                continue;
+            }
+
+            log::info!(
+                "Page of process-scoped hook {:#x} was remapped: {:#x} -> {:#x}",
+                hook.original_va,
+                old_page,
+                new_page
+            );
+
+            let new_contents = unsafe {
+                core::slice::from_raw_parts(
+                    PhysicalAddress::va_from_pa(new_page) as *const u8,
+                    BASE_PAGE_SIZE,
+                )
+            };
+
+            let hook_bytes = hook.hook_bytes();
+            for offset in (0..BASE_PAGE_SIZE).filter(|offset| !hook_bytes.contains(offset)) {
+                hook.page[offset] = new_contents[offset];
+            }
+
+            let page_offset = hook.original_pa.pa() - old_page;
+            hook.original_pa = PhysicalAddress::from_pa(new_page + page_offset);
+            let hooked_copy_page = hook.hook_pa.align_down_to_base_page().as_u64();
+
+            primary_ept.split_large_page(new_page)?;
+            primary_ept.change_page_flags(new_page, self.primary_access(new_page))?;
+
+            secondary_ept.split_large_page(new_page)?;
+            secondary_ept.swap_page(new_page, hooked_copy_page, AccessType::EXECUTE)?;
+
+            if self.find_hook_by_page(old_page).is_none() {
+                primary_ept.change_page_flags(old_page, AccessType::READ_WRITE_EXECUTE)?;
+                secondary_ept.swap_page(old_page, old_page, AccessType::READ_WRITE_EXECUTE)?;
+            }
+
+            remapped = true;
+        }
+
+        Ok(remapped)
+    }
+
+    /// Tries to find a hook for the specified guest physical page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&Hook>` - A reference to the hook if found, or `None` if not found.
+    pub fn find_hook_by_page(&self, guest_pa: u64) -> Option<&Hook> {
+        self.hooks
+            .iter()
+            .find(|hook| hook.original_pa.align_down_to_base_page().as_u64() == guest_pa)
+    }
+
+    /// Gets the access of a hooked original page in the primary EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    ///
+    /// # Returns
+    ///
+    /// * `AccessType` - Read-Write, or Read-Only if writes to the page are tracked, with Execute if its hooks are disabled.
+    pub fn primary_access(&self, guest_pa: u64) -> AccessType {
+        let access_type = match self.find_write_tracked_hook(guest_pa) {
+            // With write tracking, writes to the original page of a function hook are intercepted as well.
+            Some(_) => AccessType::READ,
+            None => AccessType::READ_WRITE,
+        };
+
+        // The original page of disabled hooks is executed without switching to the hooked copy.
+        match self.is_page_enabled(guest_pa) {
+            true => access_type,
+            false => access_type | AccessType::EXECUTE,
+        }
+    }
+
+    /// Checks whether the hooks on a page are enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    pub fn is_page_enabled(&self, guest_pa: u64) -> bool {
+        self.hooks
+            .iter()
+            .filter(|hook| hook.original_pa.align_down_to_base_page().as_u64() == guest_pa)
+            .all(|hook| hook.enabled)
+    }
+
+    /// Enables or disables the hooks on a page at runtime.
+    ///
+    /// Only the access of the page in the primary EPT changes, which must then be applied with `primary_access`.
+    /// Writes to the page are still tracked while its hooks are disabled, so the hooked copy stays up to date.
+    /// The state is kept in the hooks themselves, so nothing is allocated in VMX root operation, and follows the
+    /// hooks of a process when they are moved to another page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    /// * `enabled` - Whether execution of the page is redirected to the hooked copy.
+    pub fn set_page_enabled(&mut self, guest_pa: u64, enabled: bool) {
+        self.hooks
+            .iter_mut()
+            .filter(|hook| hook.original_pa.align_down_to_base_page().as_u64() == guest_pa)
+            .for_each(|hook| hook.enabled = enabled);
+    }
+
+    /// Tries to find a function hook whose original page is write-tracked.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&Hook>` - A reference to the hook if write tracking is enabled and a function hook is found.
+    pub fn find_write_tracked_hook(&self, guest_pa: u64) -> Option<&Hook> {
+        if !self.write_tracking {
+            return None;
+        }
+
+        self.hooks.iter().find(|hook| {
+            hook.hook_type.inline_hook().is_some()
+                && hook.original_pa.align_down_to_base_page().as_u64() == guest_pa
+        })
+    }
+
+    /// Applies a completed guest write on an original page to the hooked copies of that page.
+    ///
+    /// The modified range is found by comparing the original page against its contents before the write.
+    /// The modified bytes are copied to the hooked copy of every function hook on the page, except for
+    /// the hook bytes, and the write callback is invoked.
+    ///
+    /// # Arguments
+    ///
+    /// * `write` - The write that was single-stepped.
+    /// * `snapshot` - The contents of the original page before the write.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<PageWriteEvent>` - The event describing the write, or `None` if no byte changed.
+    pub fn resync_page_write(
+        &mut self,
+        write: &PendingPageWrite,
+        snapshot: &[u8],
+    ) -> Option<PageWriteEvent> {
+        let original = unsafe {
+            core::slice::from_raw_parts(
+                PhysicalAddress::va_from_pa(write.original_page) as *const u8,
+                BASE_PAGE_SIZE,
+            )
+        };
+
+        let changed = |(current, previous): (&u8, &u8)| current != previous;
+        let first = original.iter().zip(snapshot).position(changed)?;
+        let last = original.iter().zip(snapshot).rposition(changed)?;
+        let modified = first..last + 1;
+        let mut overlaps_hook = false;
+
+        for hook in self.hooks.iter_mut().filter(|hook| {
+            hook.hook_type.inline_hook().is_some()
+                && hook.original_pa.align_down_to_base_page().as_u64() == write.original_page
+        }) {
+            let hook_bytes = hook.hook_bytes();
+            overlaps_hook |= modified.start < hook_bytes.end && hook_bytes.start < modified.end;
+
+            for offset in modified
+                .clone()
+                .filter(|offset| !hook_bytes.contains(offset))
+            {
+                hook.page[offset] = original[offset];
+            }
+        }
+
+        let event = PageWriteEvent {
+            original_page: write.original_page,
+            modified,
+            guest_rip: write.guest_rip,
+            overlaps_hook,
+        };
+
+        if event.overlaps_hook {
+            log::warn!(
+                "Guest write overlapped hook bytes, which are kept in the hooked copy: {:x?}",
+                event
+            );
+        }
+
+        if let Some(callback) = self.write_callback {
+            callback(&event);
+        }
+
+        Some(event)
+    }
+
+    /// Records an EPT violation on a hooked page for every hook on the page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    /// * `exit_tsc` - The TSC ticks since the VM exit stub sampled the TSC.
+    pub fn record_ept_violation(&self, guest_pa: u64, exit_tsc: u64) {
+        self.hooks
+            .iter()
+            .filter(|hook| hook.original_pa.align_down_to_base_page().as_u64() == guest_pa)
+            .for_each(|hook| hook.statistics.record_ept_violation(exit_tsc));
+    }
+
+    /// Records another VM exit attributable to a hooked page for every hook on the page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address of the original page.
+    /// * `exit_tsc` - The TSC ticks since the VM exit stub sampled the TSC.
+    pub fn record_exit(&self, guest_pa: u64, exit_tsc: u64) {
+        self.hooks
+            .iter()
+            .filter(|hook| hook.original_pa.align_down_to_base_page().as_u64() == guest_pa)
+            .for_each(|hook| hook.statistics.record_exit(exit_tsc));
+    }
+
+    /// Aggregates the statistics of every hook across processors.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(u64, HookCountersTotal)>` - The original virtual address and the aggregated counters of every hook,
+    ///   sorted by the cumulative TSC spent in VM exits, highest first.
+    pub fn statistics(&self) -> Vec<(u64, HookCountersTotal)> {
+        let mut statistics: Vec<_> = self
+            .hooks
+            .iter()
+            .map(|hook| (hook.original_va, hook.statistics.total()))
+            .collect();
+
+        statistics.sort_unstable_by(|(_, a), (_, b)| b.exit_tsc.cmp(&a.exit_tsc));
+        statistics
+    }
+
+    /// Resets the statistics of every hook.
+    pub fn reset_statistics(&self) {
+        self.hooks.iter().for_each(|hook| hook.statistics.reset());
+    }
 }
@@ -1,3 +1,4 @@
 pub mod hooks;
 pub mod mtrr;
 pub mod paging;
+pub mod statistics;
@@ -426,6 +426,56 @@ impl Ept {
         Ok(())
     }
 
+    /// Splits a large 2MB page into 512 smaller 4KB pages that inherit its memory type and permissions.
+    ///
+    /// Unlike `split_2mb_to_4kb`, the MTRRs are not consulted and the page directory entry is replaced with a single
+    /// write once the page table is filled, so processors walking the EPT concurrently never observe the pages as not
+    /// present. This makes it usable in VMX root operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address within the 2MB page that needs to be split.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful. A page that is already split is left unchanged.
+    pub fn split_large_page(&mut self, guest_pa: u64) -> Result<(), HypervisorError> {
+        let guest_pa = VAddr::from(guest_pa);
+
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+        let pd_entry = self.pd[pdpt_index].0.entries[pd_index];
+
+        if !pd_entry.large() {
+            return Ok(());
+        }
+
+        for (index, pt_entry) in self.pt[pdpt_index][pd_index]
+            .0
+            .entries
+            .iter_mut()
+            .enumerate()
+        {
+            let mut entry = pd_entry;
+            entry.set_large(false);
+            entry.set_pfn(pd_entry.pfn() + index as u64);
+            *pt_entry = entry;
+        }
+
+        let mut table_entry = Entry(0);
+        table_entry.set_readable(true);
+        table_entry.set_writable(true);
+        table_entry.set_executable(true);
+        table_entry.set_pfn(
+            PhysicalAddress::pa_from_va(addr_of!(self.pt[pdpt_index][pd_index]) as u64)
+                >> BASE_PAGE_SHIFT,
+        );
+
+        self.pd[pdpt_index].0.entries[pd_index] = table_entry;
+
+        Ok(())
+    }
+
     /// Remaps the given guest physical address and changes it to the given host physical address.
     ///
     /// # Arguments
@@ -448,6 +498,94 @@ impl Ept {
         Ok(())
     }
 
+    /// Swaps the host physical page backing an already split 4KB guest page and changes its permissions.
+    ///
+    /// Unlike `remap_page`, this does not query the MTRRs or allocate, and it overwrites mapped entries,
+    /// keeping the memory type of the existing entry. This makes it suitable for use in VM exit handlers.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The 4KB aligned guest physical address to remap.
+    /// * `host_pa`: The 4KB aligned host physical address to remap to.
+    /// * `access_type`: The type of access allowed for this page (read, write, execute).
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
+    pub fn swap_page(
+        &mut self,
+        guest_pa: u64,
+        host_pa: u64,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        let guest_va = VAddr::from(guest_pa);
+
+        if !guest_va.is_base_page_aligned() || !VAddr::from(host_pa).is_base_page_aligned() {
+            log::error!("Page is not aligned: {:#x} -> {:#x}", guest_pa, host_pa);
+            return Err(HypervisorError::UnalignedAddressError);
+        }
+
+        let pdpt_index = pdpt_index(guest_va);
+        let pd_index = pd_index(guest_va);
+        let pt_index = pt_index(guest_va);
+
+        if self.pd[pdpt_index].0.entries[pd_index].large() {
+            log::error!("Page is not split: {:#x}", guest_pa);
+            return Err(HypervisorError::PageNotSplit);
+        }
+
+        let pt_entry = &mut self.pt[pdpt_index][pd_index].0.entries[pt_index];
+        pt_entry.set_readable(access_type.contains(AccessType::READ));
+        pt_entry.set_writable(access_type.contains(AccessType::WRITE));
+        pt_entry.set_executable(access_type.contains(AccessType::EXECUTE));
+        pt_entry.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+
+        Ok(())
+    }
+
+    /// Translates a guest physical address with the EPT, as the processor would.
+    ///
+    /// Unlike the mapping functions, this does not query the MTRRs or allocate, which makes it suitable for use
+    /// in VM exit handlers.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address to translate.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(u64, AccessType, u64)>` - The host physical address, the access allowed and the memory type of the
+    ///   page, or `None` if the address is not mapped.
+    pub fn translate(&self, guest_pa: u64) -> Option<(u64, AccessType, u64)> {
+        // The whole identity map is below 512GB, so only the first PML4 entry is used.
+        if guest_pa >= _512GB {
+            return None;
+        }
+
+        let guest_va = VAddr::from(guest_pa);
+        let pdpt_index = pdpt_index(guest_va);
+        let pd_index = pd_index(guest_va);
+
+        let pd_entry = &self.pd[pdpt_index].0.entries[pd_index];
+        let (entry, page_size) = match pd_entry.large() {
+            true => (pd_entry, LARGE_PAGE_SIZE as u64),
+            false => (
+                &self.pt[pdpt_index][pd_index].0.entries[pt_index(guest_va)],
+                BASE_PAGE_SIZE as u64,
+            ),
+        };
+
+        let access_type = AccessType::from_bits_truncate(entry.0 as u8);
+        if access_type.is_empty() {
+            return None;
+        }
+
+        let host_pa =
+            ((entry.pfn() << BASE_PAGE_SHIFT) & !(page_size - 1)) | (guest_pa & (page_size - 1));
+
+        Some((host_pa, access_type, entry.memory_type()))
+    }
+
     /// Unmaps a 2MB page by clearing the corresponding page directory entry.
     ///
     /// This function clears the entry, effectively removing any mapping for the 2MB page.
@@ -497,26 +635,286 @@ impl Ept {
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.6 EPT Paging-Structure Entries
     pub fn create_eptp_with_wb_and_4lvl_walk(&self) -> Result<u64, HypervisorError> {
-        // Get the virtual address of the PML4 table for EPT.
-        let addr = addr_of!(self.pml4) as u64;
+        create_eptp_with_wb_and_4lvl_walk(&self.pml4)
+    }
+}
 
-        // Get the physical address of the PML4 table for EPT.
-        let ept_pml4_base_addr = PhysicalAddress::pa_from_va(addr);
+/// Creates an Extended Page Table Pointer (EPTP) with a Write-Back memory type and a 4-level page walk for a PML4 table.
+fn create_eptp_with_wb_and_4lvl_walk(pml4: &Pml4) -> Result<u64, HypervisorError> {
+    // Get the virtual address of the PML4 table for EPT.
+    let addr = pml4 as *const _ as u64;
 
-        // Represents the EPT page walk length for Intel VT-x, specifically for a 4-level page walk.
-        // The value is 3 (encoded as '3 << 3' in EPTP) because the EPTP encoding requires "number of levels minus one".
-        const EPT_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
+    // Get the physical address of the PML4 table for EPT.
+    let ept_pml4_base_addr = PhysicalAddress::pa_from_va(addr);
 
-        // Represents the memory type setting for Write-Back (WB) in the EPTP.
-        const EPT_MEMORY_TYPE_WB: u64 = MemoryType::WriteBack as u64;
+    // Represents the EPT page walk length for Intel VT-x, specifically for a 4-level page walk.
+    // The value is 3 (encoded as '3 << 3' in EPTP) because the EPTP encoding requires "number of levels minus one".
+    const EPT_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
 
-        // Check if the base address is 4KB aligned (the lower 12 bits should be zero).
-        if ept_pml4_base_addr.trailing_zeros() >= 12 {
-            // Construct the EPTP with the page walk length and memory type for WB.
-            Ok(ept_pml4_base_addr | EPT_PAGE_WALK_LENGTH_4 | EPT_MEMORY_TYPE_WB)
-        } else {
-            Err(HypervisorError::InvalidEptPml4BaseAddress)
+    // Represents the memory type setting for Write-Back (WB) in the EPTP.
+    const EPT_MEMORY_TYPE_WB: u64 = MemoryType::WriteBack as u64;
+
+    // Check if the base address is 4KB aligned (the lower 12 bits should be zero).
+    if ept_pml4_base_addr.trailing_zeros() >= 12 {
+        // Construct the EPTP with the page walk length and memory type for WB.
+        Ok(ept_pml4_base_addr | EPT_PAGE_WALK_LENGTH_4 | EPT_MEMORY_TYPE_WB)
+    } else {
+        Err(HypervisorError::InvalidEptPml4BaseAddress)
+    }
+}
+
+/// The number of pages a `SingleStepView` can map differently from the EPT it is based on.
+pub const SINGLE_STEP_VIEW_PAGES: usize = 4;
+
+/// A private EPT of a processor that maps a few pages differently from an EPT shared by every processor,
+/// used while the processor single-steps the guest over an access to a hooked page.
+///
+/// The PML4 and PDPT of the view are copies of the shared ones, and only the page directories and page tables
+/// on the paths to the pages mapped differently are private copies. The other entries reference the paging
+/// structures of the shared EPT. Other processors keep running with the shared EPT, so they never observe
+/// the permissions granted for the single step.
+#[repr(C, align(4096))]
+pub struct SingleStepView {
+    /// The copy of the PML4 of the shared EPT, referencing the private PDPT.
+    pml4: Pml4,
+    /// The copy of the PDPT of the shared EPT, referencing the private page directories.
+    pdpt: Pdpt,
+    /// The private copies of page directories of the shared EPT.
+    pd: [Pd; SINGLE_STEP_VIEW_PAGES],
+    /// The private copies of page tables of the shared EPT.
+    pt: [Pt; SINGLE_STEP_VIEW_PAGES],
+    /// The PDPT index of each private page directory.
+    pd_indices: [Option<usize>; SINGLE_STEP_VIEW_PAGES],
+    /// The PDPT and PD indices of each private page table.
+    pt_indices: [Option<(usize, usize)>; SINGLE_STEP_VIEW_PAGES],
+}
+
+impl SingleStepView {
+    /// Bases the view on a shared EPT, discarding the pages mapped differently before.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The shared EPT the processor runs with.
+    pub fn reset(&mut self, ept: &Ept) {
+        self.pml4 = ept.pml4;
+        self.pdpt = ept.pdpt;
+        self.pd_indices = [None; SINGLE_STEP_VIEW_PAGES];
+        self.pt_indices = [None; SINGLE_STEP_VIEW_PAGES];
+
+        // The whole identity map is below 512GB, so only the first PML4 entry is used.
+        self.pml4.0.entries[0]
+            .set_pfn(PhysicalAddress::pa_from_va(addr_of!(self.pdpt) as u64) >> BASE_PAGE_SHIFT);
+    }
+
+    /// Maps a page of the view differently from the shared EPT it is based on.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The shared EPT the view was last reset with.
+    /// * `guest_pa` - The 4KB aligned guest physical address to map.
+    /// * `host_pa` - The 4KB aligned host physical address to map to.
+    /// * `access_type` - The type of access allowed for this page in the view.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful, or
+    /// `HypervisorError::SingleStepViewFull` if the view has no private paging structure left for the page.
+    pub fn map_page(
+        &mut self,
+        ept: &Ept,
+        guest_pa: u64,
+        host_pa: u64,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        let guest_va = VAddr::from(guest_pa);
+
+        if !guest_va.is_base_page_aligned() || !VAddr::from(host_pa).is_base_page_aligned() {
+            log::error!("Page is not aligned: {:#x} -> {:#x}", guest_pa, host_pa);
+            return Err(HypervisorError::UnalignedAddressError);
+        }
+
+        let pdpt_index = pdpt_index(guest_va);
+        let pd_index = pd_index(guest_va);
+        let pt_index = pt_index(guest_va);
+
+        if ept.pd[pdpt_index].0.entries[pd_index].large() {
+            log::error!("Page is not split: {:#x}", guest_pa);
+            return Err(HypervisorError::PageNotSplit);
         }
+
+        let pd_slot = match self
+            .pd_indices
+            .iter()
+            .position(|&index| index == Some(pdpt_index))
+        {
+            Some(slot) => slot,
+            None => {
+                let slot = self
+                    .pd_indices
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(HypervisorError::SingleStepViewFull)?;
+
+                self.pd[slot] = ept.pd[pdpt_index];
+                self.pd_indices[slot] = Some(pdpt_index);
+                self.pdpt.0.entries[pdpt_index].set_pfn(
+                    PhysicalAddress::pa_from_va(addr_of!(self.pd[slot]) as u64) >> BASE_PAGE_SHIFT,
+                );
+
+                slot
+            }
+        };
+
+        let pt_slot = match self
+            .pt_indices
+            .iter()
+            .position(|&index| index == Some((pdpt_index, pd_index)))
+        {
+            Some(slot) => slot,
+            None => {
+                let slot = self
+                    .pt_indices
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(HypervisorError::SingleStepViewFull)?;
+
+                self.pt[slot] = ept.pt[pdpt_index][pd_index];
+                self.pt_indices[slot] = Some((pdpt_index, pd_index));
+                self.pd[pd_slot].0.entries[pd_index].set_pfn(
+                    PhysicalAddress::pa_from_va(addr_of!(self.pt[slot]) as u64) >> BASE_PAGE_SHIFT,
+                );
+
+                slot
+            }
+        };
+
+        let pt_entry = &mut self.pt[pt_slot].0.entries[pt_index];
+        pt_entry.set_readable(access_type.contains(AccessType::READ));
+        pt_entry.set_writable(access_type.contains(AccessType::WRITE));
+        pt_entry.set_executable(access_type.contains(AccessType::EXECUTE));
+        pt_entry.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+
+        Ok(())
+    }
+
+    /// Creates the Extended Page Table Pointer (EPTP) of the view, with a Write-Back memory type and a 4-level page walk.
+    pub fn create_eptp_with_wb_and_4lvl_walk(&self) -> Result<u64, HypervisorError> {
+        create_eptp_with_wb_and_4lvl_walk(&self.pml4)
+    }
+}
+
+/// The number of paging structures below the PML4 a `NestedEpt` can allocate before it must be reset.
+pub const NESTED_EPT_TABLES: usize = 512;
+
+/// The EPT a nested guest runs with, combining the EPT of its hypervisor with the EPT of this hypervisor.
+///
+/// It starts empty and maps 4KB pages on demand, allocating its paging structures from a fixed pool. Once the
+/// pool is exhausted, it must be reset and filled again. Non-leaf entries allow any access, so the permissions
+/// of a page are the ones of its page table entry.
+#[repr(C, align(4096))]
+pub struct NestedEpt {
+    /// The PML4 of the EPT.
+    pml4: Pml4,
+    /// The pool of the PDPTs, page directories and page tables, allocated in order.
+    tables: [Table; NESTED_EPT_TABLES],
+    /// The number of tables allocated from the pool.
+    used: usize,
+}
+
+impl NestedEpt {
+    /// Discards every mapping of the EPT, releasing its paging structures to the pool.
+    ///
+    /// The translations cached from the EPT must be invalidated afterwards.
+    pub fn reset(&mut self) {
+        self.pml4.0.entries = [Entry(0); 512];
+        self.used = 0;
+    }
+
+    /// Maps a 4KB page of the EPT, replacing any previous mapping.
+    ///
+    /// The pool is physically contiguous, so the table an entry references is found from its page frame number.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4KB aligned guest physical address to map.
+    /// * `host_pa` - The 4KB aligned host physical address to map to.
+    /// * `access_type` - The type of access allowed for this page.
+    /// * `memory_type` - The memory type of this page.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful, or
+    /// `HypervisorError::NestedEptFull` if the pool has no paging structure left for the page.
+    pub fn map_page(
+        &mut self,
+        guest_pa: u64,
+        host_pa: u64,
+        access_type: AccessType,
+        memory_type: u64,
+    ) -> Result<(), HypervisorError> {
+        let guest_va = VAddr::from(guest_pa);
+
+        if !guest_va.is_base_page_aligned() || !VAddr::from(host_pa).is_base_page_aligned() {
+            log::error!("Page is not aligned: {:#x} -> {:#x}", guest_pa, host_pa);
+            return Err(HypervisorError::UnalignedAddressError);
+        }
+
+        let tables_pa = PhysicalAddress::pa_from_va(addr_of!(self.tables) as u64);
+        let mut slot = None;
+
+        for index in [
+            pml4_index(guest_va),
+            pdpt_index(guest_va),
+            pd_index(guest_va),
+        ] {
+            let entry = self.table(slot).entries[index];
+
+            let next = match entry.readable() {
+                true => ((entry.pfn() << BASE_PAGE_SHIFT) - tables_pa) as usize / BASE_PAGE_SIZE,
+                false => {
+                    let next = self.used;
+                    if next == NESTED_EPT_TABLES {
+                        return Err(HypervisorError::NestedEptFull);
+                    }
+
+                    self.used += 1;
+                    self.tables[next].entries = [Entry(0); 512];
+
+                    let entry = &mut self.table(slot).entries[index];
+                    entry.set_readable(true);
+                    entry.set_writable(true);
+                    entry.set_executable(true);
+                    entry.set_pfn((tables_pa >> BASE_PAGE_SHIFT) + next as u64);
+
+                    next
+                }
+            };
+
+            slot = Some(next);
+        }
+
+        let pt_entry = &mut self.table(slot).entries[pt_index(guest_va)];
+        *pt_entry = Entry(0);
+        pt_entry.set_readable(access_type.contains(AccessType::READ));
+        pt_entry.set_writable(access_type.contains(AccessType::WRITE));
+        pt_entry.set_executable(access_type.contains(AccessType::EXECUTE));
+        pt_entry.set_memory_type(memory_type);
+        pt_entry.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+
+        Ok(())
+    }
+
+    /// Gets a table of the pool, or the PML4 for `None`.
+    fn table(&mut self, slot: Option<usize>) -> &mut Table {
+        match slot {
+            Some(slot) => &mut self.tables[slot],
+            None => &mut self.pml4.0,
+        }
+    }
+
+    /// Creates the Extended Page Table Pointer (EPTP) of the EPT, with a Write-Back memory type and a 4-level page walk.
+    pub fn create_eptp_with_wb_and_4lvl_walk(&self) -> Result<u64, HypervisorError> {
+        create_eptp_with_wb_and_4lvl_walk(&self.pml4)
     }
 }
 
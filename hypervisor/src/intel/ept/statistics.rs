@@ -0,0 +1,154 @@
+//! Per-hook, per-processor statistics on how often a hook fires and how much time its VM exits take.
+//!
+//! Every processor updates its own counters, so the VM exit path doesn't contend on shared cache lines.
+//! The counters are atomics only so that they can be aggregated from any processor while they are updated.
+
+use {
+    crate::utils::processor::{current_processor_index, processor_count},
+    alloc::{boxed::Box, vec::Vec},
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The counters of a hook on a single processor.
+#[derive(Debug, Default)]
+pub struct HookCounters {
+    /// The number of times execution was transferred to the hook handler.
+    pub invocations: AtomicU64,
+
+    /// The number of EPT violations on the page of the hook.
+    pub ept_violations: AtomicU64,
+
+    /// The cumulative number of TSC ticks spent in VM exits attributable to the hook, from the VM exit stub until the
+    /// VM exit was handled. The VM exit and VM entry transitions themselves are not included.
+    pub exit_tsc: AtomicU64,
+}
+
+/// The counters of a hook aggregated across processors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HookCountersTotal {
+    /// The number of times execution was transferred to the hook handler.
+    pub invocations: u64,
+
+    /// The number of EPT violations on the page of the hook.
+    pub ept_violations: u64,
+
+    /// The cumulative number of TSC ticks spent in VM exits attributable to the hook, see `HookCounters::exit_tsc`.
+    pub exit_tsc: u64,
+}
+
+impl HookCountersTotal {
+    /// Gets the average number of TSC ticks spent per VM exit attributable to the hook.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The average, or 0 if there were no VM exits.
+    pub fn average_exit_tsc(&self) -> u64 {
+        self.exit_tsc
+            .checked_div(self.invocations + self.ept_violations)
+            .unwrap_or(0)
+    }
+}
+
+/// The statistics of a hook, with one set of counters per processor.
+#[derive(Debug)]
+pub struct HookStatistics {
+    /// The counters, indexed by processor index.
+    per_processor: Box<[HookCounters]>,
+}
+
+impl HookStatistics {
+    /// Creates zeroed statistics for every active processor.
+    pub fn new() -> Self {
+        Self {
+            per_processor: (0..processor_count())
+                .map(|_| HookCounters::default())
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        }
+    }
+
+    /// Gets the counters of the current processor.
+    fn current(&self) -> Option<&HookCounters> {
+        self.per_processor.get(current_processor_index() as usize)
+    }
+
+    /// Records a transfer of execution to the hook handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_tsc` - The TSC ticks since the VM exit stub sampled the TSC.
+    pub fn record_invocation(&self, exit_tsc: u64) {
+        if let Some(counters) = self.current() {
+            counters.invocations.fetch_add(1, Ordering::Relaxed);
+            counters.exit_tsc.fetch_add(exit_tsc, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an EPT violation on the page of the hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_tsc` - The TSC ticks since the VM exit stub sampled the TSC.
+    pub fn record_ept_violation(&self, exit_tsc: u64) {
+        if let Some(counters) = self.current() {
+            counters.ept_violations.fetch_add(1, Ordering::Relaxed);
+            counters.exit_tsc.fetch_add(exit_tsc, Ordering::Relaxed);
+        }
+    }
+
+    /// Records another VM exit attributable to the hook, such as a monitor trap flag VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_tsc` - The TSC ticks since the VM exit stub sampled the TSC.
+    pub fn record_exit(&self, exit_tsc: u64) {
+        if let Some(counters) = self.current() {
+            counters.exit_tsc.fetch_add(exit_tsc, Ordering::Relaxed);
+        }
+    }
+
+    /// Gets the counters of a single processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor_index` - The index of the processor.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<HookCountersTotal>` - The counters, or `None` if the index is out of range.
+    pub fn processor(&self, processor_index: u32) -> Option<HookCountersTotal> {
+        self.per_processor
+            .get(processor_index as usize)
+            .map(|counters| HookCountersTotal {
+                invocations: counters.invocations.load(Ordering::Relaxed),
+                ept_violations: counters.ept_violations.load(Ordering::Relaxed),
+                exit_tsc: counters.exit_tsc.load(Ordering::Relaxed),
+            })
+    }
+
+    /// Aggregates the counters across processors.
+    ///
+    /// # Returns
+    ///
+    /// * `HookCountersTotal` - The sum of the counters of every processor.
+    pub fn total(&self) -> HookCountersTotal {
+        (0..self.per_processor.len() as u32)
+            .filter_map(|processor_index| self.processor(processor_index))
+            .fold(HookCountersTotal::default(), |total, counters| {
+                HookCountersTotal {
+                    invocations: total.invocations + counters.invocations,
+                    ept_violations: total.ept_violations + counters.ept_violations,
+                    exit_tsc: total.exit_tsc + counters.exit_tsc,
+                }
+            })
+    }
+
+    /// Resets the counters of every processor to zero.
+    pub fn reset(&self) {
+        for counters in self.per_processor.iter() {
+            counters.invocations.store(0, Ordering::Relaxed);
+            counters.ept_violations.store(0, Ordering::Relaxed);
+            counters.exit_tsc.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -4,9 +4,12 @@
 #![allow(dead_code)]
 
 use {
-    crate::intel::{
-        support::vmwrite,
-        vmerror::{ExceptionInterrupt, InterruptionType},
+    crate::{
+        error::HypervisorError,
+        intel::{
+            support::{vmread, vmwrite},
+            vmerror::{ExceptionInterrupt, InterruptionType},
+        },
     },
     bitfield::bitfield,
     x86::vmx::vmcs,
@@ -47,6 +50,24 @@ bitfield! {
 const VALID: u32 = 1;
 const INVALID: u32 = 0;
 
+/// Bit 0 of the guest interruptibility state, indicating blocking by STI.
+pub const BLOCKING_BY_STI: u64 = 1 << 0;
+
+/// Bit 1 of the guest interruptibility state, indicating blocking by MOV SS or POP SS.
+pub const BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+
+/// Bit 3 of the guest interruptibility state, indicating blocking by NMI.
+pub const BLOCKING_BY_NMI: u64 = 1 << 3;
+
+/// The wait-for-SIPI guest activity state, in which no event can be injected.
+pub const ACTIVITY_STATE_WAIT_FOR_SIPI: u64 = 3;
+
+/// The interrupt enable flag of RFLAGS.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// The maximum number of events queued per processor.
+pub const EVENT_QUEUE_CAPACITY: usize = 16;
+
 /// Provides methods for event injection in VMX.
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.6 EVENT INJECTION
@@ -74,6 +95,17 @@ impl EventInjection {
         event.0
     }
 
+    /// Inject Debug (#DB) to the guest (Event Injection).
+    fn debug() -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(ExceptionInterrupt::Debug as u32);
+        event.set_type(InterruptionType::HardwareException as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
     /// Inject Page Fault (#PF) to the guest (Event Injection).
     fn page_fault() -> u32 {
         let mut event = EventInjection(0);
@@ -148,6 +180,19 @@ impl EventInjection {
         );
     }
 
+    /// Injects a debug exception into the guest.
+    ///
+    /// The caller is responsible for updating DR6, which is not part of the VMCS.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_db() {
+        vmwrite(
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+            EventInjection::debug(),
+        );
+    }
+
     /// Injects an undefined opcode exception into the guest.
     ///
     /// This function is used to signal to the guest that an invalid or undefined opcode
@@ -161,4 +206,435 @@ impl EventInjection {
             EventInjection::undefined_opcode(),
         );
     }
+
+    /// Injects any event into the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt or exception.
+    /// * `interruption_type` - The type of the event.
+    /// * `error_code` - The error code to be delivered, if the exception has one.
+    /// * `instruction_length` - The length of the instruction for software interrupts and exceptions,
+    ///   which is added to RIP to form the return address pushed by the event delivery.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_event(
+        vector: u8,
+        interruption_type: InterruptionType,
+        error_code: Option<u32>,
+        instruction_length: u32,
+    ) {
+        let mut event = EventInjection(0);
+
+        event.set_vector(vector as u32);
+        event.set_type(interruption_type as u32);
+        event.set_valid(VALID);
+
+        if let Some(error_code) = error_code {
+            event.set_deliver_error_code(1);
+            vmwrite(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
+        }
+
+        if matches!(
+            interruption_type,
+            InterruptionType::SoftwareInterrupt
+                | InterruptionType::PrivilegedSoftwareException
+                | InterruptionType::SoftwareException
+        ) {
+            vmwrite(vmcs::control::VMENTRY_INSTRUCTION_LEN, instruction_length);
+        }
+
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, event.0);
+    }
+}
+
+/// An event to be delivered to the guest.
+///
+/// Represents the event being delivered when a VM exit occurred, read from the IDT-vectoring information fields,
+/// or an event injected on the next VM entry.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingEvent {
+    /// The vector of the interrupt or exception.
+    pub vector: u8,
+
+    /// The type of the event.
+    pub interruption_type: InterruptionType,
+
+    /// The error code of the exception, if it has one.
+    pub error_code: Option<u32>,
+
+    /// The length of the instruction of software interrupts and exceptions.
+    pub instruction_length: u32,
+}
+
+impl PendingEvent {
+    /// Constructs a hardware exception, such as #GP or #PF.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the exception.
+    /// * `error_code` - The error code, for the exceptions that deliver one.
+    pub fn hardware_exception(vector: ExceptionInterrupt, error_code: Option<u32>) -> Self {
+        Self::new(
+            vector as u8,
+            InterruptionType::HardwareException,
+            error_code,
+            0,
+        )
+    }
+
+    /// Constructs a non-maskable interrupt.
+    pub fn nmi() -> Self {
+        Self::new(
+            ExceptionInterrupt::NonMaskableInterrupt as u8,
+            InterruptionType::NonMaskableInterrupt,
+            None,
+            0,
+        )
+    }
+
+    /// Constructs an external interrupt.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt, from 32 to 255.
+    pub fn external_interrupt(vector: u8) -> Self {
+        Self::new(vector, InterruptionType::ExternalInterrupt, None, 0)
+    }
+
+    /// Constructs a software interrupt, as raised by `INT n`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt.
+    /// * `instruction_length` - The length of the `INT n` instruction.
+    pub fn software_interrupt(vector: u8, instruction_length: u32) -> Self {
+        Self::new(
+            vector,
+            InterruptionType::SoftwareInterrupt,
+            None,
+            instruction_length,
+        )
+    }
+
+    /// Constructs a software exception, as raised by `INT3` or `INTO`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - Either `ExceptionInterrupt::Breakpoint` or `ExceptionInterrupt::Overflow`.
+    /// * `instruction_length` - The length of the instruction.
+    pub fn software_exception(vector: ExceptionInterrupt, instruction_length: u32) -> Self {
+        Self::new(
+            vector as u8,
+            InterruptionType::SoftwareException,
+            None,
+            instruction_length,
+        )
+    }
+
+    /// Constructs a privileged software exception, a debug exception as raised by `INT1`.
+    ///
+    /// # Arguments
+    ///
+    /// * `instruction_length` - The length of the `INT1` instruction.
+    pub fn privileged_software_exception(instruction_length: u32) -> Self {
+        Self::new(
+            ExceptionInterrupt::Debug as u8,
+            InterruptionType::PrivilegedSoftwareException,
+            None,
+            instruction_length,
+        )
+    }
+
+    /// Constructs an event from its fields.
+    fn new(
+        vector: u8,
+        interruption_type: InterruptionType,
+        error_code: Option<u32>,
+        instruction_length: u32,
+    ) -> Self {
+        Self {
+            vector,
+            interruption_type,
+            error_code,
+            instruction_length,
+        }
+    }
+
+    /// Checks whether the event can be injected on VM entry with the current guest interruptibility.
+    ///
+    /// Exceptions and software interrupts are always delivered. NMIs are blocked by a previous NMI and
+    /// by MOV SS or STI, and external interrupts by RFLAGS.IF = 0 and by MOV SS or STI.
+    ///
+    /// # Arguments
+    ///
+    /// * `interruptibility_state` - The guest interruptibility state.
+    /// * `rflags` - The guest RFLAGS.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4.2 Guest Non-Register State
+    /// (Table 25-3. Format of Interruptibility State) and 27.3.1.5 Checks on Guest Non-Register State.
+    pub fn is_deliverable(&self, interruptibility_state: u64, rflags: u64) -> bool {
+        match self.interruption_type {
+            InterruptionType::NonMaskableInterrupt => {
+                interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS | BLOCKING_BY_NMI)
+                    == 0
+            }
+            InterruptionType::ExternalInterrupt => {
+                rflags & RFLAGS_IF != 0
+                    && interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0
+            }
+            _ => true,
+        }
+    }
+
+    /// Gets the delivery priority of the event, where lower values are delivered first.
+    fn priority(&self) -> u8 {
+        match self.interruption_type {
+            InterruptionType::NonMaskableInterrupt => 1,
+            InterruptionType::ExternalInterrupt => 2,
+            _ => 0,
+        }
+    }
+
+    /// Reads the event whose delivery caused the current VM exit from the IDT-vectoring information fields.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<PendingEvent>` - The event, or `None` if the VM exit did not occur during event delivery.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.4 Information for VM Exits During Event Delivery
+    pub fn from_idt_vectoring() -> Option<Self> {
+        Self::from_fields(
+            vmread(vmcs::ro::IDT_VECTORING_INFO) as u32,
+            vmcs::ro::IDT_VECTORING_ERR_CODE,
+        )
+    }
+
+    /// Reads the event to be injected on the next VM entry.
+    ///
+    /// The VM-entry interruption-information field is invalidated on every VM exit, so this only returns
+    /// events injected while handling the current VM exit.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<PendingEvent>` - The event, or `None` if no event is injected.
+    pub fn from_vmentry() -> Option<Self> {
+        Self::from_fields(
+            vmread(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD) as u32,
+            vmcs::control::VMENTRY_EXCEPTION_ERR_CODE,
+        )
+    }
+
+    /// Decodes an event from an interruption-information field and its error code field.
+    fn from_fields(interruption_info: u32, error_code_field: u32) -> Option<Self> {
+        let event = EventInjection(interruption_info);
+
+        if event.get_valid() == INVALID {
+            return None;
+        }
+
+        Some(Self {
+            vector: event.get_vector() as u8,
+            interruption_type: InterruptionType::from_bits(event.get_type() as u8)?,
+            error_code: (event.get_deliver_error_code() != 0)
+                .then(|| vmread(error_code_field) as u32),
+            instruction_length: vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN) as u32,
+        })
+    }
+
+    /// Injects the event into the guest on the next VM entry.
+    pub fn inject(&self) {
+        EventInjection::vmentry_inject_event(
+            self.vector,
+            self.interruption_type,
+            self.error_code,
+            self.instruction_length,
+        );
+    }
+}
+
+/// The events waiting to be injected into the guest of a processor.
+///
+/// Only one event can be injected per VM entry, and NMIs and external interrupts can only be injected when the
+/// guest interruptibility state allows them. Queued events are injected at the end of the VM exits, ordered by
+/// priority: exceptions and software interrupts first, then NMIs, then external interrupts, each in queue order.
+/// While a blocked event is queued, interrupt-window or NMI-window exiting causes a VM exit as soon as the guest
+/// can receive it. The queue has a fixed capacity, as VM exit handlers can't allocate memory, and its last slot is
+/// reserved for an NMI so that NMIs are never dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct EventQueue {
+    /// The queued events, in queue order.
+    events: [Option<PendingEvent>; EVENT_QUEUE_CAPACITY],
+
+    /// The number of queued events.
+    len: usize,
+}
+
+impl EventQueue {
+    /// Constructs an empty event queue.
+    pub fn new() -> Self {
+        Self {
+            events: [None; EVENT_QUEUE_CAPACITY],
+            len: 0,
+        }
+    }
+
+    /// Queues an event behind the queued events of the same priority.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to be injected.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `HypervisorError::EventQueueFull` if the queue is full.
+    pub fn push(&mut self, event: PendingEvent) -> Result<(), HypervisorError> {
+        self.insert(self.len, event)
+    }
+
+    /// Queues an event ahead of the queued events, such as an event whose delivery was interrupted by a VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to be injected.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `HypervisorError::EventQueueFull` if the queue is full.
+    pub fn push_front(&mut self, event: PendingEvent) -> Result<(), HypervisorError> {
+        self.insert(0, event)
+    }
+
+    /// Queues an NMI unless one is already queued.
+    ///
+    /// Like the processor, which holds at most one pending NMI, NMIs received while one is waiting are merged into it.
+    /// The slot reserved for an NMI is free while none is queued, so the NMI is always queued.
+    pub fn push_nmi(&mut self) {
+        if self.is_nmi_queued() {
+            return;
+        }
+
+        self.len += 1;
+        self.events[self.len - 1] = Some(PendingEvent::nmi());
+    }
+
+    /// Discards the queued events, such as when an INIT signal resets the processor.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Gets the number of queued events.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether no event is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over the queued events in queue order.
+    pub fn iter(&self) -> impl Iterator<Item = &PendingEvent> {
+        self.events[..self.len].iter().flatten()
+    }
+
+    /// Injects the next deliverable event, unless an event is already injected on the next VM entry,
+    /// and enables the window exiting controls for the events that remain blocked.
+    ///
+    /// Called at the end of every VM exit, after the guest state was updated by the handler.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.7.5 Interrupt-Window Exiting and Virtual-Interrupt Delivery
+    /// and 26.7.6 NMI-Window Exiting.
+    #[rustfmt::skip]
+    pub fn deliver(&mut self) {
+        // Events queued while the guest waits for a SIPI are injected once it is started.
+        let waiting_for_sipi = vmread(vmcs::guest::ACTIVITY_STATE) == ACTIVITY_STATE_WAIT_FOR_SIPI;
+
+        if !self.is_empty() && !waiting_for_sipi && PendingEvent::from_vmentry().is_none() {
+            let interruptibility_state = vmread(vmcs::guest::INTERRUPTIBILITY_STATE);
+            let rflags = vmread(vmcs::guest::RFLAGS);
+
+            let next = (0..self.len)
+                .filter(|&index| self.events[index].is_some_and(|event| event.is_deliverable(interruptibility_state, rflags)))
+                .min_by_key(|&index| self.events[index].map_or(u8::MAX, |event| event.priority()));
+
+            if let Some(event) = next.and_then(|index| self.remove(index)) {
+                log::trace!("Injecting queued event: {:?}", event);
+                event.inject();
+            }
+        }
+
+        self.update_window_exiting();
+    }
+
+    /// Enables interrupt-window exiting while an external interrupt is queued, and NMI-window exiting while an NMI is queued.
+    ///
+    /// NMI-window exiting requires the virtual NMIs pin-based control. Without it, queued NMIs are retried on every VM exit.
+    #[rustfmt::skip]
+    fn update_window_exiting(&self) {
+        use vmcs::control::{PinbasedControls, PrimaryControls};
+
+        let queued = |interruption_type: InterruptionType| self.iter().any(|event| event.interruption_type == interruption_type);
+        let virtual_nmis = vmread(vmcs::control::PINBASED_EXEC_CONTROLS) & PinbasedControls::VIRTUAL_NMIS.bits() as u64 != 0;
+
+        let mut primary_controls = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+        primary_controls &= !((PrimaryControls::INTERRUPT_WINDOW_EXITING | PrimaryControls::NMI_WINDOW_EXITING).bits() as u64);
+
+        if queued(InterruptionType::ExternalInterrupt) {
+            primary_controls |= PrimaryControls::INTERRUPT_WINDOW_EXITING.bits() as u64;
+        }
+
+        if virtual_nmis && queued(InterruptionType::NonMaskableInterrupt) {
+            primary_controls |= PrimaryControls::NMI_WINDOW_EXITING.bits() as u64;
+        }
+
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls);
+    }
+
+    /// Checks whether an NMI is queued.
+    fn is_nmi_queued(&self) -> bool {
+        self.iter()
+            .any(|event| event.interruption_type == InterruptionType::NonMaskableInterrupt)
+    }
+
+    /// Inserts an event at a position of the queue.
+    ///
+    /// Other events than the first queued NMI can't take the last slot, which is reserved for `push_nmi`.
+    fn insert(&mut self, index: usize, event: PendingEvent) -> Result<(), HypervisorError> {
+        let capacity = match event.interruption_type == InterruptionType::NonMaskableInterrupt
+            && !self.is_nmi_queued()
+        {
+            true => EVENT_QUEUE_CAPACITY,
+            false => EVENT_QUEUE_CAPACITY - 1,
+        };
+
+        if self.len >= capacity {
+            log::error!("Event queue full, dropping event: {:?}", event);
+            return Err(HypervisorError::EventQueueFull);
+        }
+
+        self.events.copy_within(index..self.len, index + 1);
+        self.events[index] = Some(event);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes the event at a position of the queue.
+    fn remove(&mut self, index: usize) -> Option<PendingEvent> {
+        let event = self.events[index].take();
+
+        self.events.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.events[self.len] = None;
+
+        event
+    }
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
 }
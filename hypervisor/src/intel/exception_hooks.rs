@@ -0,0 +1,178 @@
+//! This module provides the interception of guest exceptions through the exception bitmap.
+//!
+//! The vectors of the registered callbacks are set in the exception bitmap of the selected processors, so guest
+//! exceptions with these vectors cause a VM exit. Exceptions not handled by the hypervisor itself are passed to the
+//! callback registered for their vector, which decides whether the exception is reflected to the guest, emulated or
+//! swallowed. Page faults can be filtered by their error code, so that only the page faults of interest cause VM exits.
+
+use {
+    crate::{intel::vmerror::ExceptionInterrupt, utils::capture::GuestRegisters},
+    alloc::boxed::Box,
+};
+
+/// The number of exception vectors covered by the exception bitmap.
+const EXCEPTION_VECTORS: usize = 32;
+
+/// The processor mask selecting every processor.
+pub const ALL_PROCESSORS: u64 = u64::MAX;
+
+/// An exception raised by the guest and intercepted by the hypervisor.
+#[derive(Debug, Clone, Copy)]
+pub struct InterceptedException {
+    /// The exception vector.
+    pub vector: ExceptionInterrupt,
+
+    /// The error code pushed by the exception, if it has one.
+    pub error_code: Option<u32>,
+
+    /// The exit qualification: the faulting linear address for page faults, and the debug conditions for debug exceptions.
+    pub exit_qualification: u64,
+}
+
+/// The action taken after a callback observed an intercepted exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionAction {
+    /// Delivers the exception to the guest, as if it had not caused a VM exit.
+    Reflect,
+
+    /// Resumes the guest with the registers updated by the callback, which emulated the faulting instruction.
+    Emulate,
+
+    /// Discards the exception. Faults restart the faulting instruction, while `INT3` and `INTO` are skipped.
+    Swallow,
+}
+
+/// A callback invoked with an intercepted exception.
+pub type ExceptionCallback =
+    fn(guest_registers: &mut GuestRegisters, exception: &InterceptedException) -> ExceptionAction;
+
+/// A callback registered for an exception vector.
+#[derive(Debug, Clone, Copy)]
+struct ExceptionHook {
+    /// The processors on which the vector is intercepted, one bit per processor index.
+    processor_mask: u64,
+
+    /// The callback invoked with the exception.
+    callback: ExceptionCallback,
+}
+
+/// Manages the per-vector callbacks used by the exception VM exit handler.
+pub struct ExceptionHooks {
+    /// The callbacks indexed by exception vector.
+    hooks: [Option<ExceptionHook>; EXCEPTION_VECTORS],
+
+    /// The page-fault error code mask, selecting the error code bits compared with `page_fault_error_code_match`.
+    page_fault_error_code_mask: u32,
+
+    /// The page-fault error code match, which the masked error code of a page fault must equal to cause a VM exit.
+    page_fault_error_code_match: u32,
+}
+
+impl ExceptionHooks {
+    /// Constructs a new, empty `ExceptionHooks` table.
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            hooks: [None; EXCEPTION_VECTORS],
+            page_fault_error_code_mask: 0,
+            page_fault_error_code_match: 0,
+        })
+    }
+
+    /// Registers a callback for an exception vector on every processor, replacing any previous one.
+    ///
+    /// NMIs are intercepted through NMI exiting rather than the exception bitmap, so they can't be hooked.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The exception vector to intercept.
+    /// * `callback` - The callback invoked with the exception.
+    pub fn register(&mut self, vector: ExceptionInterrupt, callback: ExceptionCallback) {
+        self.register_on(vector, ALL_PROCESSORS, callback);
+    }
+
+    /// Registers a callback for an exception vector on the selected processors, replacing any previous one.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The exception vector to intercept.
+    /// * `processor_mask` - The processors on which the vector is intercepted, one bit per processor index.
+    /// * `callback` - The callback invoked with the exception.
+    pub fn register_on(
+        &mut self,
+        vector: ExceptionInterrupt,
+        processor_mask: u64,
+        callback: ExceptionCallback,
+    ) {
+        self.hooks[vector as usize] = Some(ExceptionHook {
+            processor_mask,
+            callback,
+        });
+    }
+
+    /// Filters the intercepted page faults by their error code.
+    ///
+    /// A page fault causes a VM exit if its error code masked with `mask` equals `match_`. For example, a mask and
+    /// match of `P | U/S | I/D` (`0x15`) intercepts only instruction fetches from user mode on present pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - The error code bits to compare.
+    /// * `match_` - The value the masked error code must equal.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.2 Other Causes of VM Exits (Exceptions)
+    pub fn filter_page_faults(&mut self, mask: u32, match_: u32) {
+        self.page_fault_error_code_mask = mask;
+        self.page_fault_error_code_match = match_ & mask;
+    }
+
+    /// Gets the page-fault error code mask and match written to the VMCS.
+    pub fn page_fault_filter(&self) -> (u32, u32) {
+        (
+            self.page_fault_error_code_mask,
+            self.page_fault_error_code_match,
+        )
+    }
+
+    /// Gets the exception bitmap bits of the vectors intercepted on a processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor_index` - The index of the processor.
+    pub fn exception_bitmap(&self, processor_index: u32) -> u32 {
+        self.hooks
+            .iter()
+            .enumerate()
+            .filter(|(vector, hook)| {
+                *vector != ExceptionInterrupt::NonMaskableInterrupt as usize
+                    && hook.is_some_and(|hook| Self::selects(hook.processor_mask, processor_index))
+            })
+            .fold(0, |bitmap, (vector, _)| bitmap | (1 << vector))
+    }
+
+    /// Finds the callback registered for a vector on a processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The exception vector raised by the guest.
+    /// * `processor_index` - The index of the processor.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ExceptionCallback>` - The callback if one is registered for the vector on the processor.
+    pub fn find_callback(&self, vector: u8, processor_index: u32) -> Option<ExceptionCallback> {
+        self.hooks
+            .get(vector as usize)
+            .copied()
+            .flatten()
+            .filter(|hook| Self::selects(hook.processor_mask, processor_index))
+            .map(|hook| hook.callback)
+    }
+
+    /// Checks whether a processor mask selects a processor. Processors past the first 64 are only selected by `ALL_PROCESSORS`.
+    fn selects(processor_mask: u64, processor_index: u32) -> bool {
+        match processor_index < u64::BITS {
+            true => processor_mask & (1 << processor_index) != 0,
+            false => processor_mask == ALL_PROCESSORS,
+        }
+    }
+}
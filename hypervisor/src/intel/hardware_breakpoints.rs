@@ -0,0 +1,252 @@
+//! This module provides hypervisor-owned hardware breakpoints.
+//!
+//! Debug registers DR0-DR3 that are reserved by the hypervisor hold its breakpoint addresses on every processor,
+//! and their fields in DR7 are owned by the hypervisor. Guest accesses to the debug registers cause MOV DR VM exits,
+//! so the guest reads and writes virtualized values for the reserved registers, and the resulting debug exceptions
+//! (#DB) are routed to the hypervisor callbacks instead of the guest.
+//!
+//! Unlike EPT hooks, hardware breakpoints don't require splitting the page of the target code or data.
+
+use {
+    crate::{error::HypervisorError, utils::capture::GuestRegisters},
+    alloc::boxed::Box,
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86::debugregs::{BreakCondition, BreakSize, Breakpoint, Dr7},
+};
+
+/// The B0-B3 bits of DR6, indicating the breakpoint conditions that were detected.
+pub const DR6_BREAKPOINT_CONDITIONS: u64 = 0xF;
+
+/// The bit of DR7 that enables exact breakpoint detection for global breakpoints.
+const DR7_GE: u64 = 1 << Dr7::GE_BIT;
+
+/// A callback invoked with the guest registers when a hypervisor-owned hardware breakpoint is hit.
+///
+/// Execute breakpoints are reported before the instruction executes, and data breakpoints after the access.
+/// The callback may change the guest registers, including RIP, RSP and RFLAGS.
+pub type BreakpointCallback =
+    fn(guest_registers: &mut GuestRegisters, breakpoint: &HardwareBreakpoint);
+
+/// A hardware breakpoint owned by the hypervisor.
+#[derive(Debug, Clone, Copy)]
+pub struct HardwareBreakpoint {
+    /// The debug register holding the breakpoint address.
+    pub slot: Breakpoint,
+
+    /// The linear address of the breakpoint.
+    pub address: u64,
+
+    /// The access that triggers the breakpoint.
+    pub condition: BreakCondition,
+
+    /// The size of the breakpoint, which must be one byte for execute breakpoints.
+    pub size: BreakSize,
+
+    /// The callback invoked when the breakpoint is hit.
+    pub callback: BreakpointCallback,
+}
+
+impl HardwareBreakpoint {
+    /// Checks whether the breakpoint is triggered by instruction execution.
+    pub fn is_execute(&self) -> bool {
+        self.condition == BreakCondition::Instructions
+    }
+}
+
+/// Manages the debug registers reserved for hypervisor-owned hardware breakpoints.
+pub struct HardwareBreakpoints {
+    /// The breakpoints, indexed by debug register number.
+    breakpoints: [Option<HardwareBreakpoint>; 4],
+
+    /// The B0-B3 bits of the debug registers released with `clear`, which the processors return to the guest.
+    released: AtomicU64,
+}
+
+impl HardwareBreakpoints {
+    /// Constructs a new `HardwareBreakpoints` instance without reserved debug registers.
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            breakpoints: [None; 4],
+            released: AtomicU64::new(0),
+        })
+    }
+
+    /// Reserves a debug register for a hardware breakpoint, replacing any previous breakpoint in it.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The debug register to reserve.
+    /// * `address` - The linear address of the breakpoint, aligned to its size.
+    /// * `condition` - The access that triggers the breakpoint. I/O breakpoints are not supported.
+    /// * `size` - The size of the breakpoint, which must be `BreakSize::Bytes1` for execute breakpoints.
+    /// * `callback` - The callback invoked when the breakpoint is hit.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `HypervisorError::InvalidBreakpoint` if the breakpoint can't be encoded in DR7.
+    pub fn set(
+        &mut self,
+        slot: Breakpoint,
+        address: u64,
+        condition: BreakCondition,
+        size: BreakSize,
+        callback: BreakpointCallback,
+    ) -> Result<(), HypervisorError> {
+        let alignment = match size {
+            BreakSize::Bytes1 => 1,
+            BreakSize::Bytes2 => 2,
+            BreakSize::Bytes4 => 4,
+            BreakSize::Bytes8 => 8,
+        };
+
+        if condition == BreakCondition::IoReadsWrites
+            || (condition == BreakCondition::Instructions && size != BreakSize::Bytes1)
+            || address % alignment != 0
+        {
+            return Err(HypervisorError::InvalidBreakpoint);
+        }
+
+        self.breakpoints[slot as usize] = Some(HardwareBreakpoint {
+            slot,
+            address,
+            condition,
+            size,
+            callback,
+        });
+        *self.released.get_mut() &= !(1 << slot as u64);
+
+        Ok(())
+    }
+
+    /// Releases a debug register reserved for a hardware breakpoint.
+    ///
+    /// Running processors load the value written by the guest into the debug register and its DR7 fields on their
+    /// next VM exit, see `sync_hardware_breakpoints`. A hit of the breakpoint that is pending on a processor until then
+    /// is discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The debug register to release.
+    pub fn clear(&self, slot: Breakpoint) {
+        self.released.fetch_or(1 << slot as u64, Ordering::AcqRel);
+    }
+
+    /// Gets the B0-B3 bits of DR6 that belong to the debug registers released with `clear`.
+    pub fn released_conditions(&self) -> u64 {
+        self.released.load(Ordering::Acquire)
+    }
+
+    /// Gets the breakpoint of a debug register.
+    ///
+    /// # Arguments
+    ///
+    /// * `debug_register` - The number of the debug register.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&HardwareBreakpoint>` - The breakpoint, or `None` if the debug register is not reserved.
+    pub fn get(&self, debug_register: u8) -> Option<&HardwareBreakpoint> {
+        self.breakpoints.get(debug_register as usize)?.as_ref()
+    }
+
+    /// Checks whether a debug register is reserved by the hypervisor.
+    pub fn is_reserved(&self, debug_register: u8) -> bool {
+        self.get(debug_register).is_some()
+    }
+
+    /// Gets the B0-B3 bits of DR6 that belong to reserved debug registers.
+    pub fn reserved_conditions(&self) -> u64 {
+        self.iter()
+            .fold(0, |mask, breakpoint| mask | 1 << breakpoint.slot as u64)
+    }
+
+    /// Iterates over the breakpoints that are not released.
+    pub fn iter(&self) -> impl Iterator<Item = &HardwareBreakpoint> {
+        let released = self.released_conditions();
+
+        self.breakpoints
+            .iter()
+            .flatten()
+            .filter(move |breakpoint| released & (1 << breakpoint.slot as u64) == 0)
+    }
+
+    /// Iterates over the breakpoints whose conditions were detected.
+    ///
+    /// # Arguments
+    ///
+    /// * `conditions` - The DR6-format bits from the exit qualification of the debug exception.
+    pub fn hits(&self, conditions: u64) -> impl Iterator<Item = &HardwareBreakpoint> {
+        self.iter()
+            .filter(move |breakpoint| conditions & (1 << breakpoint.slot as u64) != 0)
+    }
+
+    /// Loads the addresses of the breakpoints into the reserved debug registers of the current processor.
+    pub fn load(&self) {
+        for breakpoint in self.iter() {
+            unsafe { breakpoint.slot.write(breakpoint.address as usize) };
+        }
+    }
+
+    /// Combines the DR7 value of the guest with the fields of the reserved debug registers.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_dr7` - The DR7 value seen by the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The DR7 value loaded into the processor while the guest runs.
+    pub fn apply_dr7(&self, guest_dr7: u64) -> u64 {
+        let mut dr7 = Dr7(guest_dr7 as usize);
+
+        for breakpoint in self.iter() {
+            dr7.disable_bp(breakpoint.slot, false);
+            dr7.configure_bp(breakpoint.slot, breakpoint.condition, breakpoint.size);
+            dr7.enable_bp(breakpoint.slot, true);
+        }
+
+        dr7.0 as u64 | Dr7::EMPTY as u64 | DR7_GE
+    }
+
+    /// Removes the fields of the reserved debug registers from a DR7 value loaded into the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `dr7` - The DR7 value loaded into the processor.
+    /// * `guest_dr7` - The DR7 value last written by the guest, providing the fields of the reserved debug registers.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The DR7 value seen by the guest.
+    pub fn hide_dr7(&self, dr7: u64, guest_dr7: u64) -> u64 {
+        let reserved_fields = Self::dr7_fields(self.reserved_conditions());
+
+        (dr7 & !reserved_fields & !DR7_GE) | (guest_dr7 & (reserved_fields | DR7_GE))
+    }
+
+    /// Replaces the fields of released debug registers in a DR7 value loaded into the processor with the guest's.
+    ///
+    /// # Arguments
+    ///
+    /// * `dr7` - The DR7 value loaded into the processor.
+    /// * `guest_dr7` - The DR7 value last written by the guest, providing the fields of the released debug registers.
+    /// * `released_conditions` - The B0-B3 bits of the released debug registers.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The DR7 value loaded into the processor while the guest runs.
+    pub fn release_dr7(dr7: u64, guest_dr7: u64, released_conditions: u64) -> u64 {
+        let released_fields = Self::dr7_fields(released_conditions);
+
+        (dr7 & !released_fields) | (guest_dr7 & released_fields)
+    }
+
+    /// Gets the L, G, R/W and LEN fields of DR7 that belong to the debug registers selected by B0-B3 bits.
+    fn dr7_fields(conditions: u64) -> u64 {
+        (0..4u64)
+            .filter(|slot| conditions & (1 << slot) != 0)
+            .fold(0, |mask, slot| {
+                mask | 0b11 << (slot * 2) | 0b1111 << (16 + slot * 4)
+            })
+    }
+}
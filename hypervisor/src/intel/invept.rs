@@ -4,6 +4,8 @@
 //! that cache translations derived from EPT. It's used to ensure that modifications to EPT entries don't cause
 //! inconsistencies due to stale cached translations.
 
+use {crate::intel::vmx::Vmx, core::sync::atomic::Ordering};
+
 /// Represents the types of INVEPT operations.
 #[repr(u64)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -69,3 +71,43 @@ pub fn invept_all_contexts() {
     // The EPT pointer is irrelevant for this type of operation and is thus set to 0.
     invept(InveptType::AllContexts, 0);
 }
+
+/// Invalidates the cached EPT translations of all processors after the shared EPTs changed in VMX root operation.
+///
+/// The translations of the current processor are invalidated immediately. The other processors invalidate theirs
+/// on their next VM exit, when they observe the incremented EPT generation. The shadow EPT of a nested guest is
+/// derived from the shared EPTs, so it is reset as well.
+///
+/// # Arguments
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+pub fn invept_all_processors(vmx: &mut Vmx) {
+    let shared_data = unsafe { vmx.shared_data.as_ref() };
+    vmx.ept_generation = shared_data.ept_generation.fetch_add(1, Ordering::AcqRel) + 1;
+
+    if let Some(nested) = vmx.nested.as_deref_mut() {
+        nested.nested_ept.reset();
+    }
+
+    invept_all_contexts();
+}
+
+/// Invalidates the cached EPT translations of the current processor if the shared EPTs changed since its last VM exit.
+///
+/// # Arguments
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+pub fn sync_ept_generation(vmx: &mut Vmx) {
+    let ept_generation = unsafe { vmx.shared_data.as_ref() }
+        .ept_generation
+        .load(Ordering::Acquire);
+
+    if ept_generation != vmx.ept_generation {
+        log::trace!("Shared EPTs changed, invalidating cached translations");
+        vmx.ept_generation = ept_generation;
+
+        if let Some(nested) = vmx.nested.as_deref_mut() {
+            nested.nested_ept.reset();
+        }
+
+        invept_all_contexts();
+    }
+}
@@ -0,0 +1,110 @@
+//! This module provides I/O port interception through the I/O bitmaps.
+//!
+//! The ports of the registered callbacks are set in the I/O bitmaps, so guest IN, OUT, INS and OUTS instructions
+//! accessing them cause a VM exit. The callback registered for the port and direction of the access can observe or
+//! modify the value, and either forward the access to the port or complete it without touching the device.
+
+use {
+    crate::{
+        intel::vmerror::IoAccessDirection,
+        utils::instructions::{inb, inl, inw, outb, outl, outw},
+    },
+    alloc::{boxed::Box, collections::BTreeMap},
+};
+
+/// An access to an I/O port by the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct IoAccess {
+    /// The port accessed by the guest.
+    pub port: u16,
+
+    /// The size of the access in bytes: 1, 2 or 4.
+    pub size: u8,
+
+    /// Whether the guest reads from or writes to the port.
+    pub direction: IoAccessDirection,
+
+    /// The value written by the guest for OUT, or the value returned to the guest for IN.
+    pub value: u32,
+}
+
+/// The action taken after a callback observed an I/O access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoAction {
+    /// Performs the access on the port: OUT writes `IoAccess::value`, and IN returns the value read from the port.
+    Forward,
+
+    /// Completes the access without touching the port: OUT is dropped, and IN returns `IoAccess::value`.
+    Complete,
+}
+
+/// A callback invoked before an intercepted I/O access is performed.
+pub type IoCallback = fn(access: &mut IoAccess) -> IoAction;
+
+/// Manages the per-port callbacks used by the I/O instruction VM exit handler.
+pub struct IoHooks {
+    /// The callbacks keyed by port and direction.
+    callbacks: BTreeMap<(u16, IoAccessDirection), IoCallback>,
+}
+
+impl IoHooks {
+    /// Constructs a new, empty `IoHooks` table.
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            callbacks: BTreeMap::new(),
+        })
+    }
+
+    /// Registers a callback for accesses to the given port in the given direction, replacing any previous one.
+    ///
+    /// Accesses to the port in the other direction are forwarded to the port unless a callback is registered for them.
+    /// Multi-byte accesses are matched by the first port they access.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port to intercept.
+    /// * `direction` - The direction of the accesses to intercept.
+    /// * `callback` - The callback invoked with the access.
+    pub fn register(&mut self, port: u16, direction: IoAccessDirection, callback: IoCallback) {
+        self.callbacks.insert((port, direction), callback);
+    }
+
+    /// Finds the callback registered for the given port and direction.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port accessed by the guest.
+    /// * `direction` - The direction of the access.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<IoCallback>` - The callback if one is registered.
+    pub fn find_callback(&self, port: u16, direction: IoAccessDirection) -> Option<IoCallback> {
+        self.callbacks.get(&(port, direction)).copied()
+    }
+
+    /// Iterates over the ports with registered callbacks, which must be set in the I/O bitmaps.
+    pub fn ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.callbacks.keys().map(|(port, _)| *port)
+    }
+}
+
+impl IoAccess {
+    /// Performs the access on the port, storing the value read for IN.
+    pub fn forward(&mut self) {
+        match self.direction {
+            IoAccessDirection::In => {
+                self.value = match self.size {
+                    1 => inb(self.port) as u32,
+                    2 => inw(self.port) as u32,
+                    _ => inl(self.port),
+                }
+            }
+            IoAccessDirection::Out => match self.size {
+                1 => outb(self.port, self.value as u8),
+                2 => outw(self.port, self.value as u16),
+                _ => outl(self.port, self.value),
+            },
+        }
+    }
+}
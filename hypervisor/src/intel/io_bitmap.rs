@@ -0,0 +1,60 @@
+//! This module provides utilities and structures to manage the I/O Bitmaps in VMX.
+//! The I/O Bitmaps are used to control the behavior of IN, OUT, INS and OUTS instructions
+//! in a virtualized environment.
+
+use {crate::utils::alloc::PhysicalAllocator, alloc::boxed::Box};
+
+/// Represents the I/O Bitmaps used in VMX.
+///
+/// When the “use I/O bitmaps” VM-execution control is 1, the VM-execution control fields include
+/// the 64-bit physical addresses of I/O bitmaps A and B, which are each 4 KBytes in size.
+/// An I/O instruction causes a VM exit if the bit of any port it accesses is set.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.4 I/O-Bitmap Addresses
+#[repr(C, align(4096))]
+pub struct IoBitmap {
+    /// I/O bitmap A. Contains one bit for each I/O port in the range 0000H through 7FFFH.
+    pub bitmap_a: [u8; 0x1000],
+
+    /// I/O bitmap B. Contains one bit for each I/O port in the range 8000H through FFFFH.
+    pub bitmap_b: [u8; 0x1000],
+}
+
+impl IoBitmap {
+    /// Sets up the I/O Bitmaps, with no port causing a VM exit.
+    pub fn new() -> Box<IoBitmap, PhysicalAllocator> {
+        log::trace!("Setting up I/O Bitmaps");
+
+        let instance = unsafe { Box::<Self, _>::new_zeroed_in(PhysicalAllocator).assume_init() };
+
+        log::trace!("I/O Bitmaps setup successfully!");
+
+        instance
+    }
+
+    /// Causes IN, OUT, INS and OUTS instructions accessing the given port to trigger a VM exit.
+    ///
+    /// # Arguments
+    /// * `port` - The I/O port to intercept.
+    pub fn hook_port(&mut self, port: u16) {
+        let (bitmap, index) = match port {
+            0x0000..=0x7FFF => (&mut self.bitmap_a, port),
+            _ => (&mut self.bitmap_b, port - 0x8000),
+        };
+
+        bitmap[(index / 8) as usize] |= 1 << (index % 8);
+    }
+
+    /// Stops IN, OUT, INS and OUTS instructions accessing only the given port from triggering a VM exit.
+    ///
+    /// # Arguments
+    /// * `port` - The I/O port to stop intercepting.
+    pub fn unhook_port(&mut self, port: u16) {
+        let (bitmap, index) = match port {
+            0x0000..=0x7FFF => (&mut self.bitmap_a, port),
+            _ => (&mut self.bitmap_b, port - 0x8000),
+        };
+
+        bitmap[(index / 8) as usize] &= !(1 << (index % 8));
+    }
+}
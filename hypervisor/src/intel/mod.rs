@@ -2,14 +2,23 @@ pub mod controls;
 pub mod descriptor;
 pub mod ept;
 pub mod events;
+pub mod exception_hooks;
+pub mod hardware_breakpoints;
 pub mod invept;
+pub mod io;
+pub mod io_bitmap;
 pub mod invvpid;
 pub mod msr_bitmap;
+pub mod nested;
+pub mod nmi;
 pub mod paging;
 pub mod segmentation;
 pub mod shared_data;
 pub mod support;
+pub mod syscall;
+pub mod tsc;
 pub mod vcpu;
+pub mod virtual_msrs;
 pub mod vmcs;
 pub mod vmerror;
 pub mod vmexit;
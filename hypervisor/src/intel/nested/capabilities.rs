@@ -0,0 +1,182 @@
+//! The VMX capabilities reported to the guest hypervisor, and the checks of the controls it configures in vmcs12.
+//!
+//! The capability MSRs report the processor capabilities, without the controls and the EPT features that aren't
+//! supported for nested guests. The VMX capability MSRs not listed here are read from the processor.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: APPENDIX A VMX CAPABILITY REPORTING FACILITY
+
+use {
+    crate::{intel::nested::vmcs12::Vmcs12, utils::instructions::rdmsr},
+    x86::{
+        msr,
+        vmx::vmcs::control::{
+            self, EntryControls, ExitControls, PrimaryControls, SecondaryControls,
+        },
+    },
+};
+
+/// IA32_VMX_BASIC bit 55, reporting the IA32_VMX_TRUE_* capability MSRs.
+const VMX_BASIC_TRUE_CONTROLS: u64 = 1 << 55;
+
+/// The secondary processor-based controls of the features that aren't supported for nested guests.
+///
+/// The EPT features that depend on the bits of EPT12 entries which the shadow EPT doesn't carry are not supported.
+const UNSUPPORTED_SECONDARY_CONTROLS: u32 = SecondaryControls::VMCS_SHADOWING.bits()
+    | SecondaryControls::ENABLE_VM_FUNCTIONS.bits()
+    | SecondaryControls::ENABLE_PML.bits()
+    | SecondaryControls::EPT_VIOLATION_VE.bits()
+    | SecondaryControls::MODE_BASED_EPT.bits()
+    | SecondaryControls::ENCLS_EXITING.bits()
+    | SecondaryControls::ENCLV_EXITING.bits()
+    | SecondaryControls::USE_TSC_SCALING.bits()
+    | SecondaryControls::SUB_PAGE_EPT.bits()
+    | SecondaryControls::INTEL_PT_GUEST_PHYSICAL.bits()
+    | SecondaryControls::CONCEAL_VMX_FROM_PT.bits();
+
+/// The VM-entry controls of the features that aren't supported for nested guests.
+const UNSUPPORTED_ENTRY_CONTROLS: u32 = EntryControls::ENTRY_TO_SMM.bits()
+    | EntryControls::DEACTIVATE_DUAL_MONITOR.bits()
+    | EntryControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits()
+    | EntryControls::LOAD_IA32_BNDCFGS.bits()
+    | EntryControls::CONCEAL_VMX_FROM_PT.bits()
+    | EntryControls::LOAD_IA32_RTIT_CTL.bits();
+
+/// The VM-exit controls of the features that aren't supported for nested guests.
+const UNSUPPORTED_EXIT_CONTROLS: u32 = ExitControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits()
+    | ExitControls::CLEAR_IA32_BNDCFGS.bits()
+    | ExitControls::CONCEAL_VMX_FROM_PT.bits()
+    | ExitControls::CLEAR_IA32_RTIT_CTL.bits();
+
+/// The number of CR3-target values supported by the processor.
+const CR3_TARGET_VALUES: u64 = 4;
+
+/// The number of MSRs recommended per MSR area, for each unit of IA32_VMX_MISC bits 27:25.
+const MSR_AREA_UNIT: u64 = 512;
+
+/// IA32_VMX_EPT_VPID_CAP bit 21, reporting the accessed and dirty flags for EPT, which the shadow EPT doesn't
+/// propagate to EPT12.
+const EPT_ACCESSED_DIRTY: u64 = 1 << 21;
+
+/// Gets the VMX capability MSRs that differ from the processor for the guest hypervisor.
+///
+/// # Returns
+///
+/// * An iterator of the MSRs and the values they read as.
+pub fn capability_msrs() -> impl Iterator<Item = (u32, u64)> {
+    let true_controls = rdmsr(msr::IA32_VMX_BASIC) & VMX_BASIC_TRUE_CONTROLS != 0;
+
+    [
+        Some((
+            msr::IA32_VMX_PROCBASED_CTLS2,
+            UNSUPPORTED_SECONDARY_CONTROLS,
+        )),
+        Some((msr::IA32_VMX_EXIT_CTLS, UNSUPPORTED_EXIT_CONTROLS)),
+        Some((msr::IA32_VMX_ENTRY_CTLS, UNSUPPORTED_ENTRY_CONTROLS)),
+        true_controls.then_some((msr::IA32_VMX_TRUE_EXIT_CTLS, UNSUPPORTED_EXIT_CONTROLS)),
+        true_controls.then_some((msr::IA32_VMX_TRUE_ENTRY_CTLS, UNSUPPORTED_ENTRY_CONTROLS)),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|(msr, unsupported)| (msr, without_controls(rdmsr(msr), unsupported)))
+    .chain([
+        (msr::IA32_VMX_VMFUNC, 0),
+        (
+            msr::IA32_VMX_EPT_VPID_CAP,
+            rdmsr(msr::IA32_VMX_EPT_VPID_CAP) & !EPT_ACCESSED_DIRTY,
+        ),
+    ])
+}
+
+/// Gets the maximum number of entries in an MSR-load or MSR-store area.
+///
+/// # Returns
+///
+/// * `u64` - 512 MSRs for each unit of IA32_VMX_MISC bits 27:25, plus one.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.6 MISCELLANEOUS DATA
+pub fn msr_area_limit() -> u64 {
+    MSR_AREA_UNIT * (((rdmsr(msr::IA32_VMX_MISC) >> 25) & 0x7) + 1)
+}
+
+/// Clears controls from the allowed 1-settings of a capability MSR, in bits 63:32.
+fn without_controls(capability: u64, controls: u32) -> u64 {
+    capability & !((controls as u64) << 32)
+}
+
+/// Checks whether a control value is allowed by a capability MSR value.
+///
+/// The bits set in the allowed 0-settings (bits 31:0) must be set, and the bits clear in the allowed 1-settings
+/// (bits 63:32) must be clear.
+fn is_allowed(value: u64, capability: u64) -> bool {
+    let allowed0 = capability & 0xFFFF_FFFF;
+    let allowed1 = capability >> 32;
+
+    value & allowed0 == allowed0 && value & !allowed1 == 0
+}
+
+/// Checks the VM-execution, VM-exit and VM-entry control fields of vmcs12, before the nested guest is entered.
+///
+/// # Arguments
+///
+/// * `vmcs12` - The VMCS configured by the guest hypervisor.
+///
+/// # Returns
+///
+/// `true` if the controls are valid, or `false` if VM entry fails with an invalid control field.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.2.1 Checks on VMX Controls
+pub fn check_controls(vmcs12: &Vmcs12) -> bool {
+    let true_controls = rdmsr(msr::IA32_VMX_BASIC) & VMX_BASIC_TRUE_CONTROLS != 0;
+    let capability = |msr: u32, true_msr: u32, unsupported: u32| match true_controls {
+        true => without_controls(rdmsr(true_msr), unsupported),
+        false => without_controls(rdmsr(msr), unsupported),
+    };
+
+    let primary_controls = vmcs12.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    let secondary_controls =
+        match primary_controls & PrimaryControls::SECONDARY_CONTROLS.bits() as u64 != 0 {
+            true => vmcs12.get(control::SECONDARY_PROCBASED_EXEC_CONTROLS),
+            false => 0,
+        };
+
+    let msr_area_limit = msr_area_limit();
+
+    is_allowed(
+        vmcs12.get(control::PINBASED_EXEC_CONTROLS),
+        capability(
+            msr::IA32_VMX_PINBASED_CTLS,
+            msr::IA32_VMX_TRUE_PINBASED_CTLS,
+            0,
+        ),
+    ) && is_allowed(
+        primary_controls,
+        capability(
+            msr::IA32_VMX_PROCBASED_CTLS,
+            msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+            0,
+        ),
+    ) && is_allowed(
+        secondary_controls,
+        without_controls(
+            rdmsr(msr::IA32_VMX_PROCBASED_CTLS2),
+            UNSUPPORTED_SECONDARY_CONTROLS,
+        ),
+    ) && is_allowed(
+        vmcs12.get(control::VMEXIT_CONTROLS),
+        capability(
+            msr::IA32_VMX_EXIT_CTLS,
+            msr::IA32_VMX_TRUE_EXIT_CTLS,
+            UNSUPPORTED_EXIT_CONTROLS,
+        ),
+    ) && is_allowed(
+        vmcs12.get(control::VMENTRY_CONTROLS),
+        capability(
+            msr::IA32_VMX_ENTRY_CTLS,
+            msr::IA32_VMX_TRUE_ENTRY_CTLS,
+            UNSUPPORTED_ENTRY_CONTROLS,
+        ),
+    ) && vmcs12.get(control::CR3_TARGET_COUNT) <= CR3_TARGET_VALUES
+        && vmcs12.get(control::VMEXIT_MSR_STORE_COUNT) <= msr_area_limit
+        && vmcs12.get(control::VMEXIT_MSR_LOAD_COUNT) <= msr_area_limit
+        && vmcs12.get(control::VMENTRY_MSR_LOAD_COUNT) <= msr_area_limit
+}
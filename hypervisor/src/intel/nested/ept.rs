@@ -0,0 +1,225 @@
+//! The shadow EPT of the nested guest, used when the guest hypervisor enables EPT.
+//!
+//! The EPT of the guest hypervisor (EPT12) translates the guest physical addresses of the nested guest to guest
+//! physical addresses of the guest hypervisor, which the EPT of this hypervisor (EPT01) translates to host physical
+//! addresses. vmcs02 can't use EPT12 as it is, as the processor would then take its addresses as host physical
+//! addresses. It uses a shadow EPT (EPT02) instead, which starts empty and maps the pages of the nested guest on its
+//! EPT violations: EPT12 is walked in the memory of the guest hypervisor, and the page it maps is translated by EPT01.
+//!
+//! EPT02 is reset when the guest hypervisor enters the nested guest with another EPTP or executes INVEPT, and when
+//! EPT01 changes.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.2 EPT Translation Mechanism
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::paging::{AccessType, Ept},
+            events::BLOCKING_BY_NMI,
+            nested::is_guest_hypervisor_memory,
+            support::{vmread, vmwrite},
+            vmx::Vmx,
+        },
+        utils::{addresses::PhysicalAddress, instructions::rdmsr},
+    },
+    x86::{
+        bits64::paging::{
+            pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SIZE, HUGE_PAGE_SIZE,
+            LARGE_PAGE_SIZE,
+        },
+        cpuid::cpuid,
+        msr,
+        vmx::vmcs::{guest, ro},
+    },
+};
+
+/// Mask for the physical address bits (51:12) of an EPTP or an EPT paging-structure entry.
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Bit 12 of the exit qualification of an EPT violation, set when the access was made by an IRET unblocking NMIs.
+const NMI_UNBLOCKING_DUE_TO_IRET: u64 = 1 << 12;
+
+/// Page size (bit 7) of an EPT PDPTE or PDE.
+const ENTRY_PAGE_SIZE: u64 = 1 << 7;
+
+/// The page-walk length field of an EPTP (bits 5:3) for a 4-level EPT.
+const EPTP_WALK_LENGTH_4: u64 = 3 << 3;
+
+/// The bits of an EPTP below its address that must be clear: the accessed and dirty flags enable (bit 6), which
+/// isn't reported to the guest hypervisor, the supervisor shadow-stack enable (bit 7) and the reserved bits 11:8.
+const EPTP_RESERVED: u64 = 0xFC0;
+
+/// The memory types of an EPTP and the bits of IA32_VMX_EPT_VPID_CAP reporting them: uncacheable and write-back.
+const EPTP_MEMORY_TYPES: [(u64, u64); 2] = [(0, 1 << 8), (6, 1 << 14)];
+
+/// Checks the EPTP of vmcs12, as VM entry does.
+///
+/// # Arguments
+///
+/// * `eptp` - The EPTP of vmcs12.
+///
+/// # Returns
+///
+/// `true` if the EPTP is valid, or `false` if VM entry fails with an invalid control field.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.11 Extended-Page-Table Pointer (EPTP)
+pub fn is_valid_eptp(eptp: u64) -> bool {
+    let physical_address_width = cpuid!(0x8000_0008).eax & 0xFF;
+    let ept_vpid_capabilities = rdmsr(msr::IA32_VMX_EPT_VPID_CAP);
+
+    EPTP_MEMORY_TYPES.iter().any(|&(memory_type, capability)| {
+        eptp & 0x7 == memory_type && ept_vpid_capabilities & capability != 0
+    }) && eptp & (0x7 << 3) == EPTP_WALK_LENGTH_4
+        && eptp & EPTP_RESERVED == 0
+        && eptp >> physical_address_width == 0
+}
+
+/// Walks EPT12 to translate a guest physical address of the nested guest.
+///
+/// The paging structures of EPT12 must be memory of the guest hypervisor, or the address is not translated.
+///
+/// # Arguments
+///
+/// * `ept01` - The EPT of the guest hypervisor.
+/// * `eptp12` - The EPTP of vmcs12.
+/// * `guest_pa` - The guest physical address of the nested guest.
+///
+/// # Returns
+///
+/// * `Option<(u64, AccessType)>` - The guest physical address of the guest hypervisor and the access allowed by
+///   every level of EPT12, or `None` if the address is not mapped.
+pub fn walk_nested_ept(ept01: &Ept, eptp12: u64, guest_pa: u64) -> Option<(u64, AccessType)> {
+    let va = VAddr::from(guest_pa);
+
+    // The index of the entry at each level, with the size of the page mapped by a leaf entry.
+    let levels = [
+        (pml4_index(va), None),
+        (pdpt_index(va), Some(HUGE_PAGE_SIZE)),
+        (pd_index(va), Some(LARGE_PAGE_SIZE)),
+        (pt_index(va), Some(BASE_PAGE_SIZE)),
+    ];
+
+    let mut table_pa = eptp12 & ENTRY_ADDRESS_MASK;
+    let mut access_type = AccessType::READ_WRITE_EXECUTE;
+
+    for (index, page_size) in levels {
+        let entry_pa = table_pa + (index * core::mem::size_of::<u64>()) as u64;
+
+        if !is_guest_hypervisor_memory(ept01, entry_pa, AccessType::READ) {
+            return None;
+        }
+
+        let entry = match PhysicalAddress::va_from_pa(entry_pa) {
+            0 => return None,
+            entry_va => unsafe { (entry_va as *const u64).read_volatile() },
+        };
+
+        // An entry allowing writes without reads is misconfigured, and never translates.
+        let entry_access = AccessType::from_bits_truncate(entry as u8);
+        if entry_access.is_empty() || entry_access.bits() & 0b011 == 0b010 {
+            return None;
+        }
+
+        access_type &= entry_access;
+
+        match page_size {
+            Some(page_size) if page_size == BASE_PAGE_SIZE || entry & ENTRY_PAGE_SIZE != 0 => {
+                let offset_mask = page_size as u64 - 1;
+                return Some((
+                    (entry & ENTRY_ADDRESS_MASK & !offset_mask) | (guest_pa & offset_mask),
+                    access_type,
+                ));
+            }
+            _ => table_pa = entry & ENTRY_ADDRESS_MASK,
+        }
+    }
+
+    None
+}
+
+/// Resolves an EPT violation of the nested guest, mapping the page it accessed in EPT02 if EPT12 allows the access.
+///
+/// The page is mapped with the access allowed by both EPT12 and EPT01. The hooks of this hypervisor restrict the
+/// access to the pages of the guest hypervisor in EPT01, and don't apply to the nested guest: if EPT01 doesn't allow
+/// the access, the page is mapped with the access allowed by EPT12.
+///
+/// # Arguments
+///
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// `true` if the nested guest can retry the access, or `false` if the EPT violation is caused by EPT12 and is
+/// reflected to the guest hypervisor.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-7. Exit Qualification for EPT Violations
+pub fn resolve_nested_ept_violation(vmx: &mut Vmx) -> bool {
+    let ept01 = &unsafe { vmx.shared_data.as_ref() }.primary_ept;
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return false;
+    };
+    let Some(eptp12) = nested.eptp12 else {
+        return false;
+    };
+
+    let guest_pa = vmread(ro::GUEST_PHYSICAL_ADDR_FULL);
+    let exit_qualification = vmread(ro::EXIT_QUALIFICATION);
+    let access = AccessType::from_bits_truncate(exit_qualification as u8);
+
+    let Some((guest_hypervisor_pa, access12)) = walk_nested_ept(ept01, eptp12, guest_pa) else {
+        return false;
+    };
+
+    if !access12.contains(access) {
+        return false;
+    }
+
+    let Some((host_pa, access01, memory_type)) = ept01.translate(guest_hypervisor_pa) else {
+        log::trace!(
+            "Nested EPT maps {:#x} outside of the guest hypervisor",
+            guest_hypervisor_pa
+        );
+        return false;
+    };
+
+    let access_type = match access01.contains(access) {
+        true => access12 & access01,
+        false => access12,
+    };
+
+    let guest_page = guest_pa & !(BASE_PAGE_SIZE as u64 - 1);
+    let host_page = host_pa & !(BASE_PAGE_SIZE as u64 - 1);
+
+    let mut result = nested
+        .nested_ept
+        .map_page(guest_page, host_page, access_type, memory_type);
+
+    // Once the pool of paging structures is exhausted, the nested guest starts over with an empty EPT02.
+    if let Err(HypervisorError::NestedEptFull) = result {
+        log::trace!("Nested EPT is full, resetting it");
+        nested.reset_nested_ept();
+        result = nested
+            .nested_ept
+            .map_page(guest_page, host_page, access_type, memory_type);
+    }
+
+    if let Err(err) = result {
+        log::error!("Failed to map nested guest page {:#x}: {}", guest_page, err);
+        return false;
+    }
+
+    // An IRET unblocking NMIs is restarted after the EPT violation with NMIs blocked again, as for EPT01.
+    // Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.3 Information About NMI Unblocking Due to IRET
+    if exit_qualification & NMI_UNBLOCKING_DUE_TO_IRET != 0
+        && vmread(ro::IDT_VECTORING_INFO) & (1 << 31) == 0
+    {
+        let interruptibility_state = vmread(guest::INTERRUPTIBILITY_STATE);
+        vmwrite(
+            guest::INTERRUPTIBILITY_STATE,
+            interruptibility_state | BLOCKING_BY_NMI,
+        );
+    }
+
+    true
+}
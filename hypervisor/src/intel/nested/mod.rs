@@ -0,0 +1,259 @@
+//! This module provides nested virtualization, which runs another VT-x hypervisor in the guest.
+//!
+//! The guest hypervisor (L1) enters VMX operation and runs its own guests (L2) with emulated VMX instructions.
+//! The VMCS it configures (vmcs12) is merged with the controls this hypervisor requires into a VMCS of its own
+//! (vmcs02), which the processor runs the nested guest with. VM exits of the nested guest are reflected to the
+//! guest hypervisor, except those caused by the controls of this hypervisor, by loading the host state of vmcs12
+//! into the VMCS of the guest hypervisor (vmcs01).
+//!
+//! The EPT identity maps guest physical addresses, so the addresses configured by the guest hypervisor, such as
+//! its MSR bitmap and I/O bitmaps, are used by vmcs02 as they are once they are checked to reference its memory.
+//! Its EPT is not: the nested guest runs with a shadow EPT combining it with the EPT of this hypervisor. The nested
+//! guest is governed by the controls of the guest hypervisor: the hooks and policies of this hypervisor only apply
+//! to the guest hypervisor, and EPT hooks don't apply to memory accessed by the nested guest.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: CHAPTER 31 VMX INSTRUCTION REFERENCE
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::paging::{AccessType, Ept, NestedEpt},
+            events::EventQueue,
+            invept::invept_single_context,
+            invvpid::VPID_TAG,
+            nested::{shadowing::VmcsShadowing, vmcs12::Vmcs12},
+            support::{vmread, vmwrite},
+            vmcs::Vmcs,
+            vmerror::VmInstructionError,
+        },
+        utils::{addresses::PhysicalAddress, alloc::PhysicalAllocator, capture::GuestRegisters},
+    },
+    alloc::boxed::Box,
+    x86::vmx::vmcs::{
+        control::{self, PrimaryControls},
+        guest, ro,
+    },
+};
+
+pub mod capabilities;
+pub mod ept;
+pub mod shadowing;
+pub mod transitions;
+pub mod vmcs12;
+
+/// The arithmetic flags of RFLAGS in which VMX instructions report their outcome: CF, PF, AF, ZF, SF and OF.
+const RFLAGS_VMX_STATUS: u64 = (1 << 0) | (1 << 2) | (1 << 4) | (1 << 6) | (1 << 7) | (1 << 11);
+
+/// The carry flag of RFLAGS, set by VMfailInvalid.
+const RFLAGS_CF: u64 = 1 << 0;
+
+/// The zero flag of RFLAGS, set by VMfailValid.
+const RFLAGS_ZF: u64 = 1 << 6;
+
+/// The VPID of the nested guests, whose translations are invalidated when their hypervisor executes INVVPID.
+pub const NESTED_VPID_TAG: u16 = VPID_TAG + 1;
+
+/// The state of nested virtualization on a processor.
+pub struct NestedVmx {
+    /// The guest physical address of the VMXON region of the guest hypervisor, present in VMX operation.
+    pub vmxon_pointer: Option<u64>,
+
+    /// The guest physical address of the current VMCS of the guest hypervisor.
+    pub current_vmcs: Option<u64>,
+
+    /// Whether the nested guest is running, with vmcs02 as the current VMCS.
+    pub in_nested_guest: bool,
+
+    /// The VMCS the nested guest runs with, merged from vmcs12 and the controls of this hypervisor.
+    /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub vmcs02: Box<Vmcs, PhysicalAllocator>,
+
+    /// Whether vmcs02 was launched, so VM entries to the nested guest use VMRESUME.
+    pub vmcs02_launched: bool,
+
+    /// Whether the next VM entry launches vmcs02.
+    pub launch_pending: bool,
+
+    /// The vmcs12 that last ran on vmcs02, whose translations are tagged with the nested VPID.
+    pub last_vmcs12: Option<u64>,
+
+    /// The events queued for the guest hypervisor while the nested guest runs.
+    pub l1_events: EventQueue,
+
+    /// The shadow VMCS for the current vmcs12, present when the processor supports VMCS shadowing.
+    pub shadowing: Option<VmcsShadowing>,
+
+    /// The shadow EPT the nested guest runs with when the guest hypervisor enables EPT.
+    /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub nested_ept: Box<NestedEpt, PhysicalAllocator>,
+
+    /// The EPTP of the shadow EPT.
+    pub nested_eptp: u64,
+
+    /// The EPTP of vmcs12 the shadow EPT translates with, present while the guest hypervisor enables EPT.
+    pub eptp12: Option<u64>,
+}
+
+impl NestedVmx {
+    /// Creates the nested virtualization state of a processor.
+    ///
+    /// Must be called before the processor is virtualized, as it allocates memory.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` with the boxed state or an `HypervisorError`.
+    pub fn new() -> Result<Box<Self>, HypervisorError> {
+        let mut vmcs02: Box<Vmcs, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+        vmcs02.revision_id = Vmcs::get_vmcs_revision_id();
+
+        let shadowing = match VmcsShadowing::is_supported() {
+            true => Some(VmcsShadowing::new()?),
+            false => None,
+        };
+
+        let nested_ept: Box<NestedEpt, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+        let nested_eptp = nested_ept.create_eptp_with_wb_and_4lvl_walk()?;
+
+        Ok(Box::new(Self {
+            vmxon_pointer: None,
+            current_vmcs: None,
+            in_nested_guest: false,
+            vmcs02,
+            vmcs02_launched: false,
+            launch_pending: false,
+            last_vmcs12: None,
+            l1_events: EventQueue::new(),
+            shadowing,
+            nested_ept,
+            nested_eptp,
+            eptp12: None,
+        }))
+    }
+
+    /// Checks whether the guest hypervisor is in VMX operation.
+    pub fn in_vmx_operation(&self) -> bool {
+        self.vmxon_pointer.is_some()
+    }
+
+    /// Takes the request to launch vmcs02 on the next VM entry.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the next VM entry must use VMLAUNCH instead of VMRESUME.
+    pub fn take_launch(&mut self) -> bool {
+        core::mem::take(&mut self.launch_pending)
+    }
+
+    /// Discards the translations of the shadow EPT, which the EPT violations of the nested guest map again.
+    pub fn reset_nested_ept(&mut self) {
+        self.nested_ept.reset();
+        invept_single_context(self.nested_eptp);
+    }
+}
+
+/// Checks whether a guest physical address of the guest hypervisor references its memory, so it can be accessed on
+/// its behalf: the EPT of the guest hypervisor must map it to the same host physical address, with the access allowed.
+///
+/// The identity map of the EPT ends at 512 GB, and the hooks of this hypervisor restrict the access to some pages.
+///
+/// # Arguments
+///
+/// * `ept01` - The EPT of the guest hypervisor.
+/// * `guest_pa` - The guest physical address to check.
+/// * `access_type` - The access made to the address.
+pub fn is_guest_hypervisor_memory(ept01: &Ept, guest_pa: u64, access_type: AccessType) -> bool {
+    ept01
+        .translate(guest_pa)
+        .is_some_and(|(host_pa, allowed, _)| host_pa == guest_pa && allowed.contains(access_type))
+}
+
+/// Requests the interrupt-window and NMI-window VM exits of the guest hypervisor while the nested guest runs.
+///
+/// The event queue clears the window exiting controls it doesn't need at the end of every VM exit, so the controls
+/// requested in vmcs12 are applied again afterwards.
+///
+/// # Arguments
+///
+/// * `nested` - The nested virtualization state of the current processor.
+pub fn apply_nested_window_exiting(nested: &NestedVmx) {
+    const WINDOW_EXITING: u64 = (PrimaryControls::INTERRUPT_WINDOW_EXITING.bits()
+        | PrimaryControls::NMI_WINDOW_EXITING.bits()) as u64;
+
+    if !nested.in_nested_guest {
+        return;
+    }
+
+    let Some(vmcs12) = nested.current_vmcs.and_then(Vmcs12::from_guest_pa) else {
+        return;
+    };
+
+    let requested = vmcs12.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS) & WINDOW_EXITING;
+    let primary_controls = vmread(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+
+    vmwrite(
+        control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+        primary_controls | requested,
+    );
+}
+
+/// Gets the physical address of a VMCS region allocated by this hypervisor.
+///
+/// # Arguments
+///
+/// * `vmcs` - The VMCS region.
+pub fn vmcs_pa(vmcs: &Vmcs) -> u64 {
+    PhysicalAddress::pa_from_va(vmcs as *const _ as _)
+}
+
+/// Completes an emulated VMX instruction with VMsucceed, clearing the status flags.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.2 CONVENTIONS
+pub fn vm_succeed(guest_registers: &mut GuestRegisters) {
+    set_vmx_status(guest_registers, 0);
+}
+
+/// Completes an emulated VMX instruction with VMfailInvalid, setting CF.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+pub fn vm_fail_invalid(guest_registers: &mut GuestRegisters) {
+    set_vmx_status(guest_registers, RFLAGS_CF);
+}
+
+/// Completes an emulated VMX instruction with VMfailValid, setting ZF and the VM-instruction error field of the
+/// current vmcs12, or with VMfailInvalid if there is no current vmcs12.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `nested` - The nested virtualization state of the current processor.
+/// * `error` - The VM-instruction error.
+pub fn vm_fail(
+    guest_registers: &mut GuestRegisters,
+    nested: &NestedVmx,
+    error: VmInstructionError,
+) {
+    log::trace!("Emulated VMX instruction failed: {}", error);
+
+    match nested.current_vmcs.and_then(Vmcs12::from_guest_pa) {
+        Some(vmcs12) => {
+            vmcs12.set(ro::VM_INSTRUCTION_ERROR, error as u64);
+            set_vmx_status(guest_registers, RFLAGS_ZF);
+        }
+        None => vm_fail_invalid(guest_registers),
+    }
+}
+
+/// Sets the status flags of the guest RFLAGS, clearing the others.
+fn set_vmx_status(guest_registers: &mut GuestRegisters, status: u64) {
+    guest_registers.rflags = (guest_registers.rflags & !RFLAGS_VMX_STATUS) | status;
+    vmwrite(guest::RFLAGS, guest_registers.rflags);
+}
@@ -0,0 +1,211 @@
+//! VMCS shadowing, which lets the guest hypervisor execute VMREAD and VMWRITE without VM exits.
+//!
+//! While the guest hypervisor has a current VMCS, vmcs01 links to a shadow VMCS holding the fields of vmcs12.
+//! VMREAD and VMWRITE access the shadow VMCS for the fields clear in the VMREAD and VMWRITE bitmaps, and cause
+//! VM exits for the others. The shadow VMCS is synchronized with vmcs12 whenever this hypervisor accesses vmcs12.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.10 VMCS TYPES: ORDINARY AND SHADOW
+//! and 25.6.15 VMCS Shadowing Bitmap Addresses.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            nested::{
+                vmcs12::{field_width, is_read_only, FieldWidth, Vmcs12, FIELDS},
+                vmcs_pa,
+            },
+            support::{try_vmread, try_vmwrite, vmclear, vmptrld, vmread, vmwrite},
+            vmcs::Vmcs,
+        },
+        utils::{addresses::PhysicalAddress, alloc::PhysicalAllocator, instructions::rdmsr},
+    },
+    alloc::boxed::Box,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        msr,
+        vmx::vmcs::{
+            control::{self, SecondaryControls},
+            guest,
+        },
+    },
+};
+
+/// The shadow-VMCS indicator, in bit 31 of the revision identifier of a VMCS region.
+const SHADOW_VMCS_INDICATOR: u32 = 1 << 31;
+
+/// The VMCS link pointer value when no shadow VMCS is linked.
+const NO_LINK_POINTER: u64 = u64::MAX;
+
+/// A VMREAD or VMWRITE bitmap, with one bit for each field encoding from 0 to 7FFFH.
+#[repr(C, align(4096))]
+pub struct VmcsFieldBitmap {
+    pub bits: [u8; BASE_PAGE_SIZE],
+}
+
+impl VmcsFieldBitmap {
+    /// Sets whether accesses to a field cause a VM exit.
+    fn set(&mut self, field: u32, exiting: bool) {
+        let (byte, bit) = ((field as usize & 0x7FFF) / 8, field % 8);
+
+        match exiting {
+            true => self.bits[byte] |= 1 << bit,
+            false => self.bits[byte] &= !(1 << bit),
+        }
+    }
+}
+
+/// The shadow VMCS of a processor and its VMREAD and VMWRITE bitmaps.
+pub struct VmcsShadowing {
+    /// The shadow VMCS, holding the shadowed fields of the current vmcs12.
+    /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub shadow_vmcs: Box<Vmcs, PhysicalAllocator>,
+
+    /// The VMREAD bitmap. Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub vmread_bitmap: Box<VmcsFieldBitmap, PhysicalAllocator>,
+
+    /// The VMWRITE bitmap. Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub vmwrite_bitmap: Box<VmcsFieldBitmap, PhysicalAllocator>,
+
+    /// Whether each field of `FIELDS` is held in the shadow VMCS.
+    shadowed: [bool; FIELDS.len()],
+}
+
+impl VmcsShadowing {
+    /// Checks whether the processor supports VMCS shadowing.
+    pub fn is_supported() -> bool {
+        let allowed1 = rdmsr(msr::IA32_VMX_PROCBASED_CTLS2) >> 32;
+        allowed1 & SecondaryControls::VMCS_SHADOWING.bits() as u64 != 0
+    }
+
+    /// Creates the shadow VMCS and the bitmaps, with every VMREAD and VMWRITE causing a VM exit.
+    pub fn new() -> Result<Self, HypervisorError> {
+        let mut shadow_vmcs: Box<Vmcs, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+        let mut vmread_bitmap: Box<VmcsFieldBitmap, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+        let mut vmwrite_bitmap: Box<VmcsFieldBitmap, PhysicalAllocator> =
+            unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+
+        shadow_vmcs.revision_id = Vmcs::get_vmcs_revision_id() | SHADOW_VMCS_INDICATOR;
+        vmread_bitmap.bits.fill(u8::MAX);
+        vmwrite_bitmap.bits.fill(u8::MAX);
+
+        Ok(Self {
+            shadow_vmcs,
+            vmread_bitmap,
+            vmwrite_bitmap,
+            shadowed: [false; FIELDS.len()],
+        })
+    }
+
+    /// Enables VMCS shadowing in vmcs01, which must be the current VMCS, when the guest hypervisor executes VMXON.
+    ///
+    /// The writable fields supported by the processor are shadowed. Read-only fields are written by this hypervisor
+    /// on nested VM exits, so accesses to them cause VM exits.
+    pub fn enable(&mut self) {
+        for (index, &field) in FIELDS.iter().enumerate() {
+            self.shadowed[index] = !is_read_only(field) && try_vmread(field).is_some();
+
+            self.vmread_bitmap.set(field, !self.shadowed[index]);
+            self.vmwrite_bitmap.set(field, !self.shadowed[index]);
+
+            // The high 32 bits of 64-bit fields are accessed with the encoding of the full field plus one.
+            if field_width(field) == FieldWidth::Quadword {
+                self.vmread_bitmap.set(field + 1, !self.shadowed[index]);
+                self.vmwrite_bitmap.set(field + 1, !self.shadowed[index]);
+            }
+        }
+
+        vmclear(vmcs_pa(&self.shadow_vmcs));
+
+        vmwrite(
+            control::VMREAD_BITMAP_ADDR_FULL,
+            bitmap_pa(&self.vmread_bitmap),
+        );
+        vmwrite(
+            control::VMWRITE_BITMAP_ADDR_FULL,
+            bitmap_pa(&self.vmwrite_bitmap),
+        );
+        vmwrite(guest::LINK_PTR_FULL, NO_LINK_POINTER);
+
+        let secondary_controls = vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS);
+        vmwrite(
+            control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            secondary_controls | SecondaryControls::VMCS_SHADOWING.bits() as u64,
+        );
+    }
+
+    /// Disables VMCS shadowing in vmcs01, which must be the current VMCS, when the guest hypervisor executes VMXOFF.
+    pub fn disable(&self) {
+        let secondary_controls = vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS);
+        vmwrite(
+            control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            secondary_controls & !(SecondaryControls::VMCS_SHADOWING.bits() as u64),
+        );
+        vmwrite(guest::LINK_PTR_FULL, NO_LINK_POINTER);
+    }
+
+    /// Links the shadow VMCS to vmcs01, which must be the current VMCS, with the fields of a new current vmcs12.
+    ///
+    /// # Arguments
+    ///
+    /// * `vmcs12` - The new current VMCS of the guest hypervisor.
+    /// * `vmcs01_pa` - The physical address of vmcs01.
+    pub fn activate(&self, vmcs12: &Vmcs12, vmcs01_pa: u64) {
+        self.load(vmcs12, vmcs01_pa);
+        vmwrite(guest::LINK_PTR_FULL, vmcs_pa(&self.shadow_vmcs));
+    }
+
+    /// Unlinks the shadow VMCS from vmcs01, which must be the current VMCS, when the guest hypervisor has no current VMCS.
+    pub fn deactivate(&self) {
+        vmwrite(guest::LINK_PTR_FULL, NO_LINK_POINTER);
+    }
+
+    /// Copies the shadowed fields of vmcs12 to the shadow VMCS, then makes vmcs01 current again.
+    ///
+    /// # Arguments
+    ///
+    /// * `vmcs12` - The current VMCS of the guest hypervisor.
+    /// * `vmcs01_pa` - The physical address of vmcs01.
+    pub fn load(&self, vmcs12: &Vmcs12, vmcs01_pa: u64) {
+        vmptrld(vmcs_pa(&self.shadow_vmcs));
+
+        for (&field, _) in FIELDS
+            .iter()
+            .zip(self.shadowed)
+            .filter(|(_, shadowed)| *shadowed)
+        {
+            try_vmwrite(field, vmcs12.get(field));
+        }
+
+        vmptrld(vmcs01_pa);
+    }
+
+    /// Copies the shadowed fields of the shadow VMCS to vmcs12, then makes vmcs01 current again.
+    ///
+    /// # Arguments
+    ///
+    /// * `vmcs12` - The current VMCS of the guest hypervisor.
+    /// * `vmcs01_pa` - The physical address of vmcs01.
+    pub fn store(&self, vmcs12: &mut Vmcs12, vmcs01_pa: u64) {
+        vmptrld(vmcs_pa(&self.shadow_vmcs));
+
+        for (&field, _) in FIELDS
+            .iter()
+            .zip(self.shadowed)
+            .filter(|(_, shadowed)| *shadowed)
+        {
+            if let Some(value) = try_vmread(field) {
+                vmcs12.set(field, value);
+            }
+        }
+
+        vmptrld(vmcs01_pa);
+    }
+}
+
+/// Gets the physical address of a VMREAD or VMWRITE bitmap.
+fn bitmap_pa(bitmap: &VmcsFieldBitmap) -> u64 {
+    PhysicalAddress::pa_from_va(bitmap as *const _ as _)
+}
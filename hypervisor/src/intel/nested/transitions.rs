@@ -0,0 +1,763 @@
+//! VM entries to the nested guest and VM exits from it, emulated for the guest hypervisor.
+//!
+//! On VMLAUNCH and VMRESUME, vmcs02 is built from the guest state and controls of vmcs12, the host state of vmcs01
+//! and the controls this hypervisor requires, and becomes the current VMCS. On a VM exit of the nested guest that
+//! is reflected, the guest state and exit information of vmcs02 are stored in vmcs12, and vmcs01 becomes the
+//! current VMCS again with the host state of vmcs12 loaded as the guest state of the guest hypervisor.
+//!
+//! The MSR-load and MSR-store areas of vmcs12 are emulated, as the processor would access them with the MSR values
+//! of this hypervisor. The MSRs they load and store are accessed as the guest hypervisor would with WRMSR and RDMSR.
+//!
+//! The physical addresses of vmcs12 are addresses of the guest hypervisor, which vmcs02 and the emulation only
+//! use once they are checked to reference its memory, so the nested guest never accesses other memory.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: CHAPTER 27 VM ENTRIES and CHAPTER 28 VM EXITS
+
+use {
+    crate::{
+        intel::{
+            controls::{adjust_vmx_controls, VmxControl},
+            ept::paging::{AccessType, Ept},
+            events::{EventQueue, BLOCKING_BY_MOV_SS, BLOCKING_BY_STI},
+            invvpid::invvpid_single_context,
+            nested::{
+                capabilities::msr_area_limit,
+                ept::{is_valid_eptp, walk_nested_ept},
+                is_guest_hypervisor_memory, vm_fail,
+                vmcs12::{field_type, FieldType, LaunchState, Vmcs12, FIELDS},
+                vmcs_pa, NESTED_VPID_TAG,
+            },
+            support::{try_vmread, try_vmwrite, vmclear, vmptrld, vmread, vmwrite},
+            tsc::guest_tsc,
+            virtual_msrs::{read_guest_msr, write_guest_msr},
+            vmerror::{InterruptionType, VmInstructionError, VmxBasicExitReason},
+            vmexit::{
+                cr::adjust_fixed_bits,
+                msr::{read_virtual_msr, write_virtual_msr},
+                ExitType,
+            },
+            vmx::Vmx,
+        },
+        utils::{addresses::PhysicalAddress, capture::GuestRegisters, instructions::rdmsr},
+    },
+    x86::{
+        msr,
+        vmx::vmcs::{
+            control::{self, EntryControls, ExitControls, PrimaryControls, SecondaryControls},
+            guest, host, ro,
+        },
+    },
+};
+
+/// The control fields of vmcs02 that are merged with the controls of this hypervisor or emulated, instead of being
+/// copied from vmcs12.
+const MERGED_CONTROL_FIELDS: [u32; 24] = [
+    control::VPID,
+    control::IO_BITMAP_A_ADDR_FULL,
+    control::IO_BITMAP_B_ADDR_FULL,
+    control::MSR_BITMAPS_ADDR_FULL,
+    control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+    control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+    control::VMEXIT_CONTROLS,
+    control::VMENTRY_CONTROLS,
+    control::TSC_OFFSET_FULL,
+    control::EPTP_FULL,
+    control::VMEXIT_MSR_STORE_COUNT,
+    control::VMEXIT_MSR_LOAD_COUNT,
+    control::VMENTRY_MSR_LOAD_COUNT,
+    control::VMEXIT_MSR_STORE_ADDR_FULL,
+    control::VMEXIT_MSR_LOAD_ADDR_FULL,
+    control::VMENTRY_MSR_LOAD_ADDR_FULL,
+    control::EXECUTIVE_VMCS_PTR_FULL,
+    control::VM_FUNCTION_CONTROLS_FULL,
+    control::EPTP_LIST_ADDR_FULL,
+    control::VMREAD_BITMAP_ADDR_FULL,
+    control::VMWRITE_BITMAP_ADDR_FULL,
+    control::ENCLS_EXITING_BITMAP_FULL,
+    control::SUBPAGE_PERM_TABLE_PTR_FULL,
+    control::TSC_MULTIPLIER_FULL,
+];
+
+/// Bit 31 of the exit reason, set when VM entry failed.
+const VMENTRY_FAILURE: u32 = 1 << 31;
+
+/// The valid bit of the VM-entry interruption-information field.
+const INTERRUPTION_INFO_VALID: u64 = 1 << 31;
+
+/// The VMCS link pointer value when no shadow VMCS is linked.
+const NO_LINK_POINTER: u64 = u64::MAX;
+
+/// Bits 5:3 of the exit qualification of an EPT violation, reporting the access allowed to the guest physical address.
+const EPT_VIOLATION_ACCESS: u64 = 0x7 << 3;
+
+/// The value of DR7 after a VM exit.
+const DR7_INIT: u64 = 0x400;
+
+/// The value of RFLAGS after a VM exit, with only the reserved bit 1 set.
+const RFLAGS_INIT: u64 = 0x2;
+
+/// The access rights of the host code segment after a VM exit: a 64-bit, accessed, execute/read code segment.
+const HOST_CS_ACCESS_RIGHTS: u64 = 0xA09B;
+
+/// The access rights of the host data segments after a VM exit: an accessed, read/write data segment.
+const HOST_DATA_ACCESS_RIGHTS: u64 = 0xC093;
+
+/// The access rights of the host task-state segment after a VM exit: a busy 64-bit TSS.
+const HOST_TR_ACCESS_RIGHTS: u64 = 0x8B;
+
+/// The segment-unusable bit of access rights.
+const SEGMENT_UNUSABLE: u64 = 1 << 16;
+
+/// An entry of an MSR-load or MSR-store area.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 25-15. Format of an MSR Entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct MsrEntry {
+    index: u32,
+    reserved: u32,
+    data: u64,
+}
+
+/// Checks whether a VM exit of the nested guest is reflected to the guest hypervisor.
+///
+/// The VM exits caused by the controls of this hypervisor are handled by it: the monitor trap flag and the
+/// window exiting controls enabled by its handlers, and the EPT of this hypervisor when the guest hypervisor
+/// doesn't use EPT. All other VM exits are caused by the controls of the guest hypervisor.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+/// * `vmcs12` - The VMCS of the nested guest.
+pub fn is_reflected(basic_exit_reason: VmxBasicExitReason, vmcs12: &Vmcs12) -> bool {
+    let primary_controls = vmcs12.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    let requested = |controls: PrimaryControls| primary_controls & controls.bits() as u64 != 0;
+
+    match basic_exit_reason {
+        VmxBasicExitReason::MonitorTrapFlag => requested(PrimaryControls::MONITOR_TRAP_FLAG),
+        VmxBasicExitReason::InterruptWindow => requested(PrimaryControls::INTERRUPT_WINDOW_EXITING),
+        VmxBasicExitReason::NmiWindow => requested(PrimaryControls::NMI_WINDOW_EXITING),
+        VmxBasicExitReason::EptViolation | VmxBasicExitReason::EptMisconfiguration => {
+            secondary_controls(vmcs12) & SecondaryControls::ENABLE_EPT.bits() as u64 != 0
+        }
+        _ => true,
+    }
+}
+
+/// Enters the nested guest of the current vmcs12, emulating VMLAUNCH or VMRESUME once the instruction checks passed.
+///
+/// vmcs02 becomes the current VMCS, and the VM entry of the VM exit handler enters the nested guest. VM entry fails
+/// with an invalid control field if the bitmaps, the MSR areas or the EPTP of vmcs12 are invalid.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The guest hypervisor resumes after the nested guest exits to it.
+/// * `ExitType::IncrementRIP` - If VM entry failed with VMfailValid.
+#[rustfmt::skip]
+pub fn enter_nested_guest(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return ExitType::Continue;
+    };
+    let Some(vmcs12_pa) = nested.current_vmcs else {
+        return ExitType::Continue;
+    };
+    let Some(vmcs12) = Vmcs12::from_guest_pa(vmcs12_pa) else {
+        return ExitType::Continue;
+    };
+
+    log::trace!("Entering nested guest of VMCS {:#x}", vmcs12_pa);
+
+    let primary_controls12 = vmcs12.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    let secondary_controls12 = secondary_controls(vmcs12);
+    let entry_controls12 = vmcs12.get(control::VMENTRY_CONTROLS);
+    let exit_controls12 = vmcs12.get(control::VMEXIT_CONTROLS);
+
+    // The addresses of vmcs12 are read once, so the guest hypervisor can't change them once they are checked.
+    let msr_bitmap12 = vmcs12.get(control::MSR_BITMAPS_ADDR_FULL);
+    let io_bitmaps12 = [vmcs12.get(control::IO_BITMAP_A_ADDR_FULL), vmcs12.get(control::IO_BITMAP_B_ADDR_FULL)];
+    let entry_msr_load_area = vmcs12.get(control::VMENTRY_MSR_LOAD_ADDR_FULL);
+    let entry_msr_load_count = vmcs12.get(control::VMENTRY_MSR_LOAD_COUNT);
+    let eptp12 = match secondary_controls12 & SecondaryControls::ENABLE_EPT.bits() as u64 != 0 {
+        true => Some(vmcs12.get(control::EPTP_FULL)),
+        false => None,
+    };
+
+    if !check_addresses(vmcs12, primary_controls12, msr_bitmap12, io_bitmaps12, eptp12, &unsafe { vmx.shared_data.as_ref() }.primary_ept) {
+        vm_fail(guest_registers, nested, VmInstructionError::VmEntryInvalidControlFields);
+        return ExitType::IncrementRIP;
+    }
+
+    // The host state and the controls of this hypervisor are read from vmcs01 before vmcs02 becomes current.
+    let mut host_state = [0u64; FIELDS.len()];
+    for (index, &field) in FIELDS.iter().enumerate().filter(|(_, &field)| field_type(field) == FieldType::HostState) {
+        host_state[index] = vmread(field);
+    }
+
+    let primary_controls01 = vmread(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    let tsc_offsetting01 = primary_controls01 & PrimaryControls::USE_TSC_OFFSETTING.bits() as u64 != 0;
+    let tsc_offset01 = if tsc_offsetting01 { vmread(control::TSC_OFFSET_FULL) } else { 0 };
+    let eptp01 = vmread(control::EPTP_FULL);
+    let l1_efer = match vmread(control::VMENTRY_CONTROLS) & EntryControls::LOAD_IA32_EFER.bits() as u64 != 0 {
+        true => vmread(guest::IA32_EFER_FULL),
+        false => rdmsr(msr::IA32_EFER),
+    };
+    let l1_debugctl = vmread(guest::IA32_DEBUGCTL_FULL);
+
+    if !nested.vmcs02_launched {
+        vmclear(vmcs_pa(&nested.vmcs02));
+    }
+    vmptrld(vmcs_pa(&nested.vmcs02));
+
+    // The host state is the one of this hypervisor, with the MSRs it runs with in VMX root operation.
+    for (index, &field) in FIELDS.iter().enumerate().filter(|(_, &field)| field_type(field) == FieldType::HostState) {
+        try_vmwrite(field, host_state[index]);
+    }
+    vmwrite(host::IA32_EFER_FULL, rdmsr(msr::IA32_EFER));
+    vmwrite(host::IA32_PAT_FULL, rdmsr(msr::IA32_PAT));
+
+    // The guest state is the one of vmcs12. EFER and PAT are always loaded, with the values of the guest hypervisor
+    // unless vmcs12 loads them, and the debug controls are inherited from the guest hypervisor unless vmcs12 loads them.
+    for &field in FIELDS.iter().filter(|&&field| field_type(field) == FieldType::GuestState) {
+        try_vmwrite(field, vmcs12.get(field));
+    }
+
+    if entry_controls12 & EntryControls::LOAD_IA32_EFER.bits() as u64 == 0 {
+        const EFER_LME: u64 = 1 << 8;
+        const EFER_LMA: u64 = 1 << 10;
+
+        let long_mode = match entry_controls12 & EntryControls::IA32E_MODE_GUEST.bits() as u64 != 0 {
+            true => EFER_LME | EFER_LMA,
+            false => 0,
+        };
+        vmwrite(guest::IA32_EFER_FULL, (l1_efer & !(EFER_LME | EFER_LMA)) | long_mode);
+    }
+
+    if entry_controls12 & EntryControls::LOAD_IA32_PAT.bits() as u64 == 0 {
+        vmwrite(guest::IA32_PAT_FULL, rdmsr(msr::IA32_PAT));
+    }
+
+    if entry_controls12 & EntryControls::LOAD_DEBUG_CONTROLS.bits() as u64 == 0 {
+        vmwrite(guest::DR7, vmx.guest_dr7);
+        vmwrite(guest::IA32_DEBUGCTL_FULL, l1_debugctl);
+    }
+
+    vmwrite(guest::LINK_PTR_FULL, NO_LINK_POINTER);
+
+    // The controls of vmcs12 apply to the nested guest, merged with the EPT, VPID and TSC offsetting of this hypervisor,
+    // and with the controls that switch EFER and PAT between the nested guest and this hypervisor.
+    for &field in FIELDS.iter().filter(|&&field| field_type(field) == FieldType::Control && !MERGED_CONTROL_FIELDS.contains(&field)) {
+        try_vmwrite(field, vmcs12.get(field));
+    }
+
+    vmwrite(control::MSR_BITMAPS_ADDR_FULL, msr_bitmap12);
+    vmwrite(control::IO_BITMAP_A_ADDR_FULL, io_bitmaps12[0]);
+    vmwrite(control::IO_BITMAP_B_ADDR_FULL, io_bitmaps12[1]);
+
+    let mut primary_controls02 = primary_controls12 | PrimaryControls::SECONDARY_CONTROLS.bits() as u64;
+    if tsc_offsetting01 {
+        primary_controls02 |= PrimaryControls::USE_TSC_OFFSETTING.bits() as u64;
+    }
+
+    let secondary_controls02 = secondary_controls12 | (SecondaryControls::ENABLE_EPT | SecondaryControls::ENABLE_VPID).bits() as u64;
+
+    let exit_controls02 = (ExitControls::HOST_ADDRESS_SPACE_SIZE
+        | ExitControls::SAVE_IA32_EFER
+        | ExitControls::LOAD_IA32_EFER
+        | ExitControls::SAVE_IA32_PAT
+        | ExitControls::LOAD_IA32_PAT
+        | ExitControls::SAVE_DEBUG_CONTROLS).bits() as u64
+        | exit_controls12 & (ExitControls::ACK_INTERRUPT_ON_EXIT | ExitControls::SAVE_VMX_PREEMPTION_TIMER).bits() as u64;
+
+    let entry_controls02 = entry_controls12 | (EntryControls::LOAD_IA32_EFER | EntryControls::LOAD_IA32_PAT).bits() as u64;
+
+    vmwrite(control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls02);
+    vmwrite(control::SECONDARY_PROCBASED_EXEC_CONTROLS, secondary_controls02);
+    vmwrite(control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, exit_controls02));
+    vmwrite(control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, entry_controls02));
+
+    let tsc_offset12 = match primary_controls12 & PrimaryControls::USE_TSC_OFFSETTING.bits() as u64 != 0 {
+        true => vmcs12.get(control::TSC_OFFSET_FULL),
+        false => 0,
+    };
+    vmwrite(control::TSC_OFFSET_FULL, tsc_offset12.wrapping_add(tsc_offset01));
+
+    // The EPT of the guest hypervisor translates to its guest physical addresses, so the nested guest runs with the
+    // shadow EPT, which starts over when the EPTP changes.
+    if nested.eptp12 != eptp12 {
+        nested.eptp12 = eptp12;
+        nested.reset_nested_ept();
+    }
+
+    let eptp02 = match eptp12 {
+        Some(_) => nested.nested_eptp,
+        None => eptp01,
+    };
+    vmwrite(control::EPTP_FULL, eptp02);
+    vmwrite(control::VPID, NESTED_VPID_TAG);
+
+    vmwrite(control::VMEXIT_MSR_STORE_COUNT, 0u32);
+    vmwrite(control::VMEXIT_MSR_LOAD_COUNT, 0u32);
+    vmwrite(control::VMENTRY_MSR_LOAD_COUNT, 0u32);
+
+    // The events of the guest hypervisor wait until the nested guest exits to it.
+    nested.l1_events = core::mem::replace(&mut vmx.pending_events, EventQueue::new());
+
+    if let Err(failed_entry) = load_msr_area(entry_msr_load_area, entry_msr_load_count, vmx) {
+        log::trace!("Nested VM entry failed loading MSR entry {}", failed_entry);
+
+        // The VM entry fails like a VM exit, with the failed entry reported in the exit qualification.
+        vmcs12.set(ro::EXIT_REASON, (VmxBasicExitReason::VmEntryFailureMsrLoading as u32 | VMENTRY_FAILURE) as u64);
+        vmcs12.set(ro::EXIT_QUALIFICATION, failed_entry as u64 + 1);
+        vmcs12.set(control::VMENTRY_INTERRUPTION_INFO_FIELD, vmcs12.get(control::VMENTRY_INTERRUPTION_INFO_FIELD) & !INTERRUPTION_INFO_VALID);
+        vmcs12.set_launch_state(LaunchState::Launched);
+
+        vmptrld(vmcs_pa(&vmx.vmcs_region));
+        return_to_guest_hypervisor(vmcs12, guest_registers, vmx);
+
+        return ExitType::Continue;
+    }
+
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return ExitType::Continue;
+    };
+
+    nested.in_nested_guest = true;
+    vmcs12.set_launch_state(LaunchState::Launched);
+
+    if !nested.vmcs02_launched {
+        nested.vmcs02_launched = true;
+        nested.launch_pending = true;
+    }
+
+    // The translations of every nested guest are tagged with the same VPID.
+    if secondary_controls12 & SecondaryControls::ENABLE_VPID.bits() as u64 == 0 || nested.last_vmcs12 != Some(vmcs12_pa) {
+        invvpid_single_context(NESTED_VPID_TAG);
+    }
+    nested.last_vmcs12 = Some(vmcs12_pa);
+
+    ExitType::Continue
+}
+
+/// Reflects a VM exit of the nested guest to the guest hypervisor, emulating a VM exit from vmcs12.
+///
+/// # Arguments
+///
+/// * `exit_reason` - The exit reason of the VM exit, including the VM-entry failure bit.
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The guest hypervisor resumes at the host RIP of vmcs12.
+#[rustfmt::skip]
+pub fn exit_nested_guest(exit_reason: u32, guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    let Some(vmcs12) = vmx.nested.as_deref().and_then(|nested| nested.current_vmcs).and_then(Vmcs12::from_guest_pa) else {
+        return ExitType::Continue;
+    };
+
+    log::trace!("Reflecting nested VM exit: {:#x}", exit_reason);
+
+    // The guest state and the exit information are saved to vmcs12, keeping the link pointer of the guest hypervisor.
+    for &field in FIELDS.iter().filter(|&&field| matches!(field_type(field), FieldType::GuestState | FieldType::VmExitInformation)) {
+        if field == guest::LINK_PTR_FULL || field == ro::VM_INSTRUCTION_ERROR {
+            continue;
+        }
+
+        if let Some(value) = try_vmread(field) {
+            vmcs12.set(field, value);
+        }
+    }
+
+    vmcs12.set(control::VMENTRY_INTERRUPTION_INFO_FIELD, vmcs12.get(control::VMENTRY_INTERRUPTION_INFO_FIELD) & !INTERRUPTION_INFO_VALID);
+
+    // The exit qualification of an EPT violation reports the access allowed by EPT12 instead of the shadow EPT.
+    let eptp12 = vmx.nested.as_deref().and_then(|nested| nested.eptp12);
+    if let Some(eptp12) = eptp12.filter(|_| exit_reason & 0xFFFF == VmxBasicExitReason::EptViolation as u32) {
+        let ept01 = &unsafe { vmx.shared_data.as_ref() }.primary_ept;
+        let access12 = walk_nested_ept(ept01, eptp12, vmcs12.get(ro::GUEST_PHYSICAL_ADDR_FULL)).map_or(0, |(_, access12)| access12.bits() as u64);
+        vmcs12.set(ro::EXIT_QUALIFICATION, (vmcs12.get(ro::EXIT_QUALIFICATION) & !EPT_VIOLATION_ACCESS) | (access12 << 3));
+    }
+
+    store_msr_area(vmcs12.get(control::VMEXIT_MSR_STORE_ADDR_FULL), vmcs12.get(control::VMEXIT_MSR_STORE_COUNT), vmx);
+
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return ExitType::Continue;
+    };
+
+    // A VMCS whose VM entry failed is not launched.
+    if exit_reason & VMENTRY_FAILURE != 0 {
+        vmclear(vmcs_pa(&nested.vmcs02));
+        nested.vmcs02_launched = false;
+    }
+
+    nested.in_nested_guest = false;
+
+    vmptrld(vmcs_pa(&vmx.vmcs_region));
+    return_to_guest_hypervisor(vmcs12, guest_registers, vmx);
+
+    ExitType::Continue
+}
+
+/// Fails the VMLAUNCH or VMRESUME of the guest hypervisor when the processor fails the VM entry to vmcs02
+/// with VMfailValid, before loading the guest state.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+#[rustfmt::skip]
+pub fn fail_nested_vmentry(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) {
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return;
+    };
+
+    let instruction_error = vmread(ro::VM_INSTRUCTION_ERROR) as u32;
+    log::trace!("Nested VM entry failed with VM-instruction error {}", instruction_error);
+
+    vmclear(vmcs_pa(&nested.vmcs02));
+    nested.vmcs02_launched = false;
+    nested.in_nested_guest = false;
+
+    vmptrld(vmcs_pa(&vmx.vmcs_region));
+    vmx.pending_events = core::mem::take(&mut nested.l1_events);
+
+    // The VMLAUNCH or VMRESUME that caused the last VM exit of the guest hypervisor fails, leaving vmcs12 unchanged.
+    let basic_exit_reason = VmxBasicExitReason::from_u32(vmread(ro::EXIT_REASON) as u32);
+    if let Some(vmcs12) = nested.current_vmcs.and_then(Vmcs12::from_guest_pa) {
+        if basic_exit_reason == Some(VmxBasicExitReason::Vmlaunch) {
+            vmcs12.set_launch_state(LaunchState::Clear);
+        }
+    }
+
+    guest_registers.rip = vmread(guest::RIP);
+    guest_registers.rsp = vmread(guest::RSP);
+    guest_registers.rflags = vmread(guest::RFLAGS);
+
+    let error = VmInstructionError::from_u32(instruction_error).unwrap_or(VmInstructionError::VmEntryInvalidControlFields);
+    vm_fail(guest_registers, nested, error);
+
+    guest_registers.rip += vmread(ro::VMEXIT_INSTRUCTION_LEN);
+    vmwrite(guest::RIP, guest_registers.rip);
+
+    let interruptibility_state = vmread(guest::INTERRUPTIBILITY_STATE);
+    vmwrite(guest::INTERRUPTIBILITY_STATE, interruptibility_state & !(BLOCKING_BY_STI | BLOCKING_BY_MOV_SS));
+}
+
+/// Gets the secondary processor-based controls of vmcs12, which are only used when the primary controls activate them.
+fn secondary_controls(vmcs12: &Vmcs12) -> u64 {
+    match vmcs12.get(control::PRIMARY_PROCBASED_EXEC_CONTROLS)
+        & PrimaryControls::SECONDARY_CONTROLS.bits() as u64
+        != 0
+    {
+        true => vmcs12.get(control::SECONDARY_PROCBASED_EXEC_CONTROLS),
+        false => 0,
+    }
+}
+
+/// Checks the addresses of vmcs12 that vmcs02 and the emulation of the MSR areas use, before the nested guest is entered.
+///
+/// The MSR bitmap and the I/O bitmaps must be memory of the guest hypervisor when their controls are used, the MSR
+/// areas must be 16-byte aligned, and the EPTP must be valid when EPT is enabled.
+///
+/// # Returns
+///
+/// `true` if the addresses are valid, or `false` if VM entry fails with an invalid control field.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.2.1.1 VM-Execution Control Fields
+/// and 27.2.1.2 VM-Exit Control Fields
+fn check_addresses(
+    vmcs12: &Vmcs12,
+    primary_controls12: u64,
+    msr_bitmap12: u64,
+    io_bitmaps12: [u64; 2],
+    eptp12: Option<u64>,
+    ept01: &Ept,
+) -> bool {
+    let uses = |controls: PrimaryControls| primary_controls12 & controls.bits() as u64 != 0;
+    let msr_areas = [
+        (
+            control::VMEXIT_MSR_STORE_ADDR_FULL,
+            control::VMEXIT_MSR_STORE_COUNT,
+        ),
+        (
+            control::VMEXIT_MSR_LOAD_ADDR_FULL,
+            control::VMEXIT_MSR_LOAD_COUNT,
+        ),
+        (
+            control::VMENTRY_MSR_LOAD_ADDR_FULL,
+            control::VMENTRY_MSR_LOAD_COUNT,
+        ),
+    ];
+
+    (!uses(PrimaryControls::USE_MSR_BITMAPS)
+        || is_guest_hypervisor_memory(ept01, msr_bitmap12, AccessType::READ))
+        && (!uses(PrimaryControls::USE_IO_BITMAPS)
+            || io_bitmaps12
+                .iter()
+                .all(|&io_bitmap| is_guest_hypervisor_memory(ept01, io_bitmap, AccessType::READ)))
+        && msr_areas
+            .iter()
+            .all(|&(area, count)| vmcs12.get(count) == 0 || vmcs12.get(area) & 0xF == 0)
+        && eptp12.is_none_or(is_valid_eptp)
+}
+
+/// Resumes the guest hypervisor with vmcs01, which must be the current VMCS, after a VM exit from vmcs12.
+///
+/// Loads the host state of vmcs12 as the guest state, processes the VM-exit MSR-load area, restores the events of
+/// the guest hypervisor and synchronizes the shadow VMCS with vmcs12.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.5 LOADING HOST STATE
+#[rustfmt::skip]
+fn return_to_guest_hypervisor(vmcs12: &mut Vmcs12, guest_registers: &mut GuestRegisters, vmx: &mut Vmx) {
+    let cr0 = vmcs12.get(host::CR0);
+    let cr4 = vmcs12.get(host::CR4);
+
+    vmwrite(control::CR0_READ_SHADOW, cr0);
+    vmwrite(guest::CR0, adjust_fixed_bits(cr0, msr::IA32_VMX_CR0_FIXED0, msr::IA32_VMX_CR0_FIXED1));
+    vmwrite(control::CR4_READ_SHADOW, cr4);
+    vmwrite(guest::CR4, adjust_fixed_bits(cr4, msr::IA32_VMX_CR4_FIXED0, msr::IA32_VMX_CR4_FIXED1));
+    vmwrite(guest::CR3, vmcs12.get(host::CR3));
+
+    // DR7 is reset, keeping the hardware breakpoints owned by this hypervisor.
+    let hardware_breakpoints = unsafe { vmx.shared_data.as_ref() }.hardware_breakpoints.as_deref();
+    vmx.guest_dr7 = DR7_INIT;
+    vmwrite(guest::DR7, hardware_breakpoints.map_or(DR7_INIT, |hardware_breakpoints| hardware_breakpoints.apply_dr7(DR7_INIT)));
+    vmwrite(guest::IA32_DEBUGCTL_FULL, 0u64);
+
+    vmwrite(guest::IA32_SYSENTER_CS, vmcs12.get(host::IA32_SYSENTER_CS));
+    vmwrite(guest::IA32_SYSENTER_ESP, vmcs12.get(host::IA32_SYSENTER_ESP));
+    vmwrite(guest::IA32_SYSENTER_EIP, vmcs12.get(host::IA32_SYSENTER_EIP));
+
+    vmwrite(guest::CS_SELECTOR, vmcs12.get(host::CS_SELECTOR));
+    vmwrite(guest::CS_BASE, 0u64);
+    vmwrite(guest::CS_LIMIT, u32::MAX);
+    vmwrite(guest::CS_ACCESS_RIGHTS, HOST_CS_ACCESS_RIGHTS);
+
+    let data_segments = [
+        (guest::SS_SELECTOR, guest::SS_BASE, guest::SS_LIMIT, guest::SS_ACCESS_RIGHTS, host::SS_SELECTOR, None),
+        (guest::DS_SELECTOR, guest::DS_BASE, guest::DS_LIMIT, guest::DS_ACCESS_RIGHTS, host::DS_SELECTOR, None),
+        (guest::ES_SELECTOR, guest::ES_BASE, guest::ES_LIMIT, guest::ES_ACCESS_RIGHTS, host::ES_SELECTOR, None),
+        (guest::FS_SELECTOR, guest::FS_BASE, guest::FS_LIMIT, guest::FS_ACCESS_RIGHTS, host::FS_SELECTOR, Some(host::FS_BASE)),
+        (guest::GS_SELECTOR, guest::GS_BASE, guest::GS_LIMIT, guest::GS_ACCESS_RIGHTS, host::GS_SELECTOR, Some(host::GS_BASE)),
+    ];
+
+    for (selector_field, base_field, limit_field, access_rights_field, host_selector_field, host_base_field) in data_segments {
+        let selector = vmcs12.get(host_selector_field);
+
+        vmwrite(selector_field, selector);
+        vmwrite(base_field, host_base_field.map_or(0, |field| vmcs12.get(field)));
+        vmwrite(limit_field, u32::MAX);
+        vmwrite(access_rights_field, if selector == 0 { SEGMENT_UNUSABLE } else { HOST_DATA_ACCESS_RIGHTS });
+    }
+
+    vmwrite(guest::TR_SELECTOR, vmcs12.get(host::TR_SELECTOR));
+    vmwrite(guest::TR_BASE, vmcs12.get(host::TR_BASE));
+    vmwrite(guest::TR_LIMIT, 0x67u32);
+    vmwrite(guest::TR_ACCESS_RIGHTS, HOST_TR_ACCESS_RIGHTS);
+
+    vmwrite(guest::LDTR_SELECTOR, 0u16);
+    vmwrite(guest::LDTR_ACCESS_RIGHTS, SEGMENT_UNUSABLE);
+
+    vmwrite(guest::GDTR_BASE, vmcs12.get(host::GDTR_BASE));
+    vmwrite(guest::GDTR_LIMIT, 0xFFFFu32);
+    vmwrite(guest::IDTR_BASE, vmcs12.get(host::IDTR_BASE));
+    vmwrite(guest::IDTR_LIMIT, 0xFFFFu32);
+
+    guest_registers.rip = vmcs12.get(host::RIP);
+    guest_registers.rsp = vmcs12.get(host::RSP);
+    guest_registers.rflags = RFLAGS_INIT;
+    vmwrite(guest::RIP, guest_registers.rip);
+    vmwrite(guest::RSP, guest_registers.rsp);
+    vmwrite(guest::RFLAGS, guest_registers.rflags);
+
+    vmwrite(guest::INTERRUPTIBILITY_STATE, 0u32);
+    vmwrite(guest::ACTIVITY_STATE, 0u32);
+    vmwrite(guest::PENDING_DBG_EXCEPTIONS, 0u64);
+
+    // A failure to load an MSR on VM exit would be a VMX abort, which can't be reported to the guest hypervisor.
+    if let Err(failed_entry) = load_msr_area(vmcs12.get(control::VMEXIT_MSR_LOAD_ADDR_FULL), vmcs12.get(control::VMEXIT_MSR_LOAD_COUNT), vmx) {
+        log::error!("Nested VM exit failed loading MSR entry {}", failed_entry);
+    }
+
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return;
+    };
+
+    // The NMIs queued for the nested guest are delivered to the guest hypervisor instead.
+    let nested_events = core::mem::replace(&mut vmx.pending_events, core::mem::take(&mut nested.l1_events));
+    if nested_events.iter().any(|event| event.interruption_type == InterruptionType::NonMaskableInterrupt) {
+        vmx.pending_events.push_nmi();
+    }
+
+    if let Some(shadowing) = nested.shadowing.as_ref() {
+        shadowing.load(vmcs12, vmcs_pa(&vmx.vmcs_region));
+    }
+}
+
+/// Loads the MSRs of an MSR-load area, as VM entries and VM exits do.
+///
+/// The number of entries is checked again, as vmcs12 is in the memory of the guest hypervisor.
+///
+/// # Arguments
+///
+/// * `area` - The physical address of the MSR-load area.
+/// * `count` - The number of entries in the area.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `Result<(), usize>` - The index of the entry that failed to load.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.4 LOADING MSRS
+fn load_msr_area(area: u64, count: u64, vmx: &mut Vmx) -> Result<(), usize> {
+    // The entries beyond the supported number fail to load.
+    let limit = msr_area_limit();
+    if count > limit {
+        return Err(limit as usize);
+    }
+
+    for index in 0..count as usize {
+        let entry = read_msr_entry(area, index, vmx).ok_or(index)?;
+
+        if entry.reserved != 0 || !load_msr(entry.index, entry.data, vmx) {
+            return Err(index);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the MSRs of an MSR-store area, as VM exits do.
+///
+/// # Arguments
+///
+/// * `area` - The physical address of the MSR-store area.
+/// * `count` - The number of entries in the area, of which at most the supported number is stored.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.4 SAVING MSRS
+fn store_msr_area(area: u64, count: u64, vmx: &mut Vmx) {
+    for index in 0..count.min(msr_area_limit()) as usize {
+        let Some(entry) = read_msr_entry(area, index, vmx) else {
+            return;
+        };
+
+        match store_msr(entry.index, vmx) {
+            Some(value) => write_msr_entry(
+                area,
+                index,
+                MsrEntry {
+                    data: value,
+                    ..entry
+                },
+                vmx,
+            ),
+            None => log::error!("Nested VM exit failed storing MSR {:#x}", entry.index),
+        }
+    }
+}
+
+/// Loads an MSR of an MSR-load area into the current VMCS, or as the guest hypervisor would with WRMSR for the MSRs
+/// that VM entries and VM exits don't switch.
+///
+/// # Returns
+///
+/// `false` if the MSR can't be loaded from an MSR-load area.
+fn load_msr(msr: u32, value: u64, vmx: &mut Vmx) -> bool {
+    match msr {
+        msr::IA32_EFER => vmwrite(guest::IA32_EFER_FULL, value),
+        msr::IA32_PAT => vmwrite(guest::IA32_PAT_FULL, value),
+        msr::IA32_DEBUGCTL
+        | msr::IA32_SYSENTER_CS
+        | msr::IA32_SYSENTER_ESP
+        | msr::IA32_SYSENTER_EIP
+        | msr::IA32_FS_BASE
+        | msr::IA32_GS_BASE => write_guest_msr(msr, value),
+        msr::IA32_KERNEL_GSBASE
+        | msr::IA32_STAR
+        | msr::IA32_LSTAR
+        | msr::IA32_CSTAR
+        | msr::IA32_FMASK
+        | msr::IA32_TSC_AUX => return write_virtual_msr(msr, value, vmx),
+        _ => return false,
+    }
+
+    true
+}
+
+/// Reads an MSR of an MSR-store area from the current VMCS, or as the guest hypervisor would with RDMSR for the MSRs
+/// that VM entries and VM exits don't switch.
+///
+/// # Returns
+///
+/// `None` if the MSR can't be stored to an MSR-store area.
+fn store_msr(msr: u32, vmx: &mut Vmx) -> Option<u64> {
+    match msr {
+        msr::IA32_EFER => Some(vmread(guest::IA32_EFER_FULL)),
+        msr::IA32_PAT => Some(vmread(guest::IA32_PAT_FULL)),
+        msr::IA32_TIME_STAMP_COUNTER => Some(guest_tsc()),
+        msr::IA32_DEBUGCTL
+        | msr::IA32_SYSENTER_CS
+        | msr::IA32_SYSENTER_ESP
+        | msr::IA32_SYSENTER_EIP
+        | msr::IA32_FS_BASE
+        | msr::IA32_GS_BASE => Some(read_guest_msr(msr)),
+        msr::IA32_KERNEL_GSBASE
+        | msr::IA32_STAR
+        | msr::IA32_LSTAR
+        | msr::IA32_CSTAR
+        | msr::IA32_FMASK
+        | msr::IA32_TSC_AUX => read_virtual_msr(msr, vmx),
+        _ => None,
+    }
+}
+
+/// Reads an entry of an MSR area in the memory of the guest hypervisor.
+fn read_msr_entry(area: u64, index: usize, vmx: &Vmx) -> Option<MsrEntry> {
+    match msr_entry_va(area, index, AccessType::READ, vmx)? {
+        0 => None,
+        va => Some(unsafe { (va as *const MsrEntry).read_unaligned() }),
+    }
+}
+
+/// Writes an entry of an MSR area in the memory of the guest hypervisor.
+fn write_msr_entry(area: u64, index: usize, entry: MsrEntry, vmx: &Vmx) {
+    match msr_entry_va(area, index, AccessType::WRITE, vmx) {
+        None | Some(0) => {}
+        Some(va) => unsafe { (va as *mut MsrEntry).write_unaligned(entry) },
+    }
+}
+
+/// Gets the virtual address of an entry of an MSR area, if the area is 16-byte aligned and the entry is memory of
+/// the guest hypervisor.
+fn msr_entry_va(area: u64, index: usize, access_type: AccessType, vmx: &Vmx) -> Option<u64> {
+    let entry_pa = area.checked_add((index * core::mem::size_of::<MsrEntry>()) as u64)?;
+    let ept01 = &unsafe { vmx.shared_data.as_ref() }.primary_ept;
+
+    match area & 0xF == 0 && is_guest_hypervisor_memory(ept01, entry_pa, access_type) {
+        true => Some(PhysicalAddress::va_from_pa(entry_pa)),
+        false => None,
+    }
+}
+
+/// Gets the VMCS of the nested guest running on a processor.
+///
+/// # Arguments
+///
+/// * `vmx` - The Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `Option<&'static mut Vmcs12>` - The vmcs12 of the nested guest, or `None` if the guest hypervisor runs.
+pub fn nested_vmcs12(vmx: &Vmx) -> Option<&'static mut Vmcs12> {
+    vmx.nested
+        .as_deref()
+        .filter(|nested| nested.in_nested_guest)
+        .and_then(|nested| nested.current_vmcs)
+        .and_then(Vmcs12::from_guest_pa)
+}
@@ -0,0 +1,355 @@
+//! The VMCS of a nested guest as seen by its hypervisor, known as vmcs12.
+//!
+//! The guest hypervisor gives VMPTRLD a 4-KByte VMCS region in its own memory. As the format of the VMCS region is
+//! implementation-specific, the field values are kept in that region, after the revision identifier and the
+//! VMX-abort indicator, so a VMCS keeps its state when the guest hypervisor migrates it between processors.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.2 FORMAT OF THE VMCS REGION
+//! and APPENDIX B FIELD ENCODING IN VMCS.
+
+use {
+    crate::utils::addresses::PhysicalAddress,
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        vmx::vmcs::{control, guest, host, ro},
+    },
+};
+
+/// The VMCS fields supported for the nested guest, sorted by encoding.
+///
+/// The high halves of the 64-bit fields (access type 1) are accessed through their full encoding.
+pub const FIELDS: [u32; 157] = [
+    // 16-bit fields.
+    control::VPID,
+    control::POSTED_INTERRUPT_NOTIFICATION_VECTOR,
+    control::EPTP_INDEX,
+    guest::ES_SELECTOR,
+    guest::CS_SELECTOR,
+    guest::SS_SELECTOR,
+    guest::DS_SELECTOR,
+    guest::FS_SELECTOR,
+    guest::GS_SELECTOR,
+    guest::LDTR_SELECTOR,
+    guest::TR_SELECTOR,
+    guest::INTERRUPT_STATUS,
+    guest::PML_INDEX,
+    host::ES_SELECTOR,
+    host::CS_SELECTOR,
+    host::SS_SELECTOR,
+    host::DS_SELECTOR,
+    host::FS_SELECTOR,
+    host::GS_SELECTOR,
+    host::TR_SELECTOR,
+    // 64-bit fields.
+    control::IO_BITMAP_A_ADDR_FULL,
+    control::IO_BITMAP_B_ADDR_FULL,
+    control::MSR_BITMAPS_ADDR_FULL,
+    control::VMEXIT_MSR_STORE_ADDR_FULL,
+    control::VMEXIT_MSR_LOAD_ADDR_FULL,
+    control::VMENTRY_MSR_LOAD_ADDR_FULL,
+    control::EXECUTIVE_VMCS_PTR_FULL,
+    control::PML_ADDR_FULL,
+    control::TSC_OFFSET_FULL,
+    control::VIRT_APIC_ADDR_FULL,
+    control::APIC_ACCESS_ADDR_FULL,
+    control::POSTED_INTERRUPT_DESC_ADDR_FULL,
+    control::VM_FUNCTION_CONTROLS_FULL,
+    control::EPTP_FULL,
+    control::EOI_EXIT0_FULL,
+    control::EOI_EXIT1_FULL,
+    control::EOI_EXIT2_FULL,
+    control::EOI_EXIT3_FULL,
+    control::EPTP_LIST_ADDR_FULL,
+    control::VMREAD_BITMAP_ADDR_FULL,
+    control::VMWRITE_BITMAP_ADDR_FULL,
+    control::VIRT_EXCEPTION_INFO_ADDR_FULL,
+    control::XSS_EXITING_BITMAP_FULL,
+    control::ENCLS_EXITING_BITMAP_FULL,
+    control::SUBPAGE_PERM_TABLE_PTR_FULL,
+    control::TSC_MULTIPLIER_FULL,
+    ro::GUEST_PHYSICAL_ADDR_FULL,
+    guest::LINK_PTR_FULL,
+    guest::IA32_DEBUGCTL_FULL,
+    guest::IA32_PAT_FULL,
+    guest::IA32_EFER_FULL,
+    guest::IA32_PERF_GLOBAL_CTRL_FULL,
+    guest::PDPTE0_FULL,
+    guest::PDPTE1_FULL,
+    guest::PDPTE2_FULL,
+    guest::PDPTE3_FULL,
+    guest::IA32_BNDCFGS_FULL,
+    guest::IA32_RTIT_CTL_FULL,
+    host::IA32_PAT_FULL,
+    host::IA32_EFER_FULL,
+    host::IA32_PERF_GLOBAL_CTRL_FULL,
+    // 32-bit fields.
+    control::PINBASED_EXEC_CONTROLS,
+    control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+    control::EXCEPTION_BITMAP,
+    control::PAGE_FAULT_ERR_CODE_MASK,
+    control::PAGE_FAULT_ERR_CODE_MATCH,
+    control::CR3_TARGET_COUNT,
+    control::VMEXIT_CONTROLS,
+    control::VMEXIT_MSR_STORE_COUNT,
+    control::VMEXIT_MSR_LOAD_COUNT,
+    control::VMENTRY_CONTROLS,
+    control::VMENTRY_MSR_LOAD_COUNT,
+    control::VMENTRY_INTERRUPTION_INFO_FIELD,
+    control::VMENTRY_EXCEPTION_ERR_CODE,
+    control::VMENTRY_INSTRUCTION_LEN,
+    control::TPR_THRESHOLD,
+    control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+    control::PLE_GAP,
+    control::PLE_WINDOW,
+    ro::VM_INSTRUCTION_ERROR,
+    ro::EXIT_REASON,
+    ro::VMEXIT_INTERRUPTION_INFO,
+    ro::VMEXIT_INTERRUPTION_ERR_CODE,
+    ro::IDT_VECTORING_INFO,
+    ro::IDT_VECTORING_ERR_CODE,
+    ro::VMEXIT_INSTRUCTION_LEN,
+    ro::VMEXIT_INSTRUCTION_INFO,
+    guest::ES_LIMIT,
+    guest::CS_LIMIT,
+    guest::SS_LIMIT,
+    guest::DS_LIMIT,
+    guest::FS_LIMIT,
+    guest::GS_LIMIT,
+    guest::LDTR_LIMIT,
+    guest::TR_LIMIT,
+    guest::GDTR_LIMIT,
+    guest::IDTR_LIMIT,
+    guest::ES_ACCESS_RIGHTS,
+    guest::CS_ACCESS_RIGHTS,
+    guest::SS_ACCESS_RIGHTS,
+    guest::DS_ACCESS_RIGHTS,
+    guest::FS_ACCESS_RIGHTS,
+    guest::GS_ACCESS_RIGHTS,
+    guest::LDTR_ACCESS_RIGHTS,
+    guest::TR_ACCESS_RIGHTS,
+    guest::INTERRUPTIBILITY_STATE,
+    guest::ACTIVITY_STATE,
+    guest::SMBASE,
+    guest::IA32_SYSENTER_CS,
+    guest::VMX_PREEMPTION_TIMER_VALUE,
+    host::IA32_SYSENTER_CS,
+    // Natural-width fields.
+    control::CR0_GUEST_HOST_MASK,
+    control::CR4_GUEST_HOST_MASK,
+    control::CR0_READ_SHADOW,
+    control::CR4_READ_SHADOW,
+    control::CR3_TARGET_VALUE0,
+    control::CR3_TARGET_VALUE1,
+    control::CR3_TARGET_VALUE2,
+    control::CR3_TARGET_VALUE3,
+    ro::EXIT_QUALIFICATION,
+    ro::IO_RCX,
+    ro::IO_RSI,
+    ro::IO_RDI,
+    ro::IO_RIP,
+    ro::GUEST_LINEAR_ADDR,
+    guest::CR0,
+    guest::CR3,
+    guest::CR4,
+    guest::ES_BASE,
+    guest::CS_BASE,
+    guest::SS_BASE,
+    guest::DS_BASE,
+    guest::FS_BASE,
+    guest::GS_BASE,
+    guest::LDTR_BASE,
+    guest::TR_BASE,
+    guest::GDTR_BASE,
+    guest::IDTR_BASE,
+    guest::DR7,
+    guest::RSP,
+    guest::RIP,
+    guest::RFLAGS,
+    guest::PENDING_DBG_EXCEPTIONS,
+    guest::IA32_SYSENTER_ESP,
+    guest::IA32_SYSENTER_EIP,
+    host::CR0,
+    host::CR3,
+    host::CR4,
+    host::FS_BASE,
+    host::GS_BASE,
+    host::TR_BASE,
+    host::GDTR_BASE,
+    host::IDTR_BASE,
+    host::IA32_SYSENTER_ESP,
+    host::IA32_SYSENTER_EIP,
+    host::RSP,
+    host::RIP,
+];
+
+/// The access type bit of a field encoding, selecting the high 32 bits of a 64-bit field.
+const ACCESS_TYPE_HIGH: u32 = 1 << 0;
+
+/// The type of a VMCS field, in bits 11:10 of its encoding.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 25-21. Structure of VMCS Component Encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Control = 0,
+    VmExitInformation = 1,
+    GuestState = 2,
+    HostState = 3,
+}
+
+/// The width of a VMCS field, in bits 14:13 of its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldWidth {
+    Word = 0,
+    Quadword = 1,
+    Doubleword = 2,
+    Natural = 3,
+}
+
+/// Gets the type of a VMCS field from its encoding.
+pub fn field_type(field: u32) -> FieldType {
+    match (field >> 10) & 0x3 {
+        0 => FieldType::Control,
+        1 => FieldType::VmExitInformation,
+        2 => FieldType::GuestState,
+        _ => FieldType::HostState,
+    }
+}
+
+/// Gets the width of a VMCS field from its encoding.
+pub fn field_width(field: u32) -> FieldWidth {
+    match (field >> 13) & 0x3 {
+        0 => FieldWidth::Word,
+        1 => FieldWidth::Quadword,
+        2 => FieldWidth::Doubleword,
+        _ => FieldWidth::Natural,
+    }
+}
+
+/// Checks whether a VMCS field is a read-only VM-exit information field.
+pub fn is_read_only(field: u32) -> bool {
+    field_type(field) == FieldType::VmExitInformation
+}
+
+/// The launch state of a VMCS, which selects between VMLAUNCH and VMRESUME.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchState {
+    Clear = 0,
+    Launched = 1,
+}
+
+/// The VMCS region of a nested guest, laid out in the memory of its hypervisor.
+#[repr(C)]
+pub struct Vmcs12 {
+    /// The VMCS revision identifier, with the shadow-VMCS indicator in bit 31.
+    pub revision_id: u32,
+
+    /// The VMX-abort indicator.
+    pub abort_indicator: u32,
+
+    /// The launch state of the VMCS.
+    launch_state: u32,
+
+    /// Reserved, keeps the field values 8-byte aligned.
+    reserved: u32,
+
+    /// The field values, in the order of `FIELDS`.
+    fields: [u64; FIELDS.len()],
+}
+
+const _: () = assert!(core::mem::size_of::<Vmcs12>() <= BASE_PAGE_SIZE);
+
+impl Vmcs12 {
+    /// Gets the VMCS region of the guest hypervisor at a guest physical address.
+    ///
+    /// Guest physical addresses are identity mapped to host physical addresses by the EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 4-KByte aligned guest physical address of the VMCS region.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&'static mut Vmcs12>` - The VMCS region, or `None` if the address isn't mapped by the host.
+    pub fn from_guest_pa(guest_pa: u64) -> Option<&'static mut Self> {
+        match PhysicalAddress::va_from_pa(guest_pa) {
+            0 => None,
+            va => Some(unsafe { &mut *(va as *mut Self) }),
+        }
+    }
+
+    /// Gets the launch state of the VMCS.
+    pub fn launch_state(&self) -> LaunchState {
+        match self.launch_state {
+            0 => LaunchState::Clear,
+            _ => LaunchState::Launched,
+        }
+    }
+
+    /// Sets the launch state of the VMCS.
+    pub fn set_launch_state(&mut self, launch_state: LaunchState) {
+        self.launch_state = launch_state as u32;
+    }
+
+    /// Reads a field, as VMREAD does in 64-bit mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The encoding of the field, which may select the high half of a 64-bit field.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The value of the field, or `None` if the field isn't supported.
+    pub fn read(&self, field: u32) -> Option<u64> {
+        let (index, high) = Self::locate(field)?;
+
+        match high {
+            true => Some(self.fields[index] >> 32),
+            false => Some(self.fields[index]),
+        }
+    }
+
+    /// Writes a field, as VMWRITE does in 64-bit mode, truncating the value to the width of the field.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The encoding of the field, which may select the high half of a 64-bit field.
+    /// * `value` - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<()>` - `None` if the field isn't supported.
+    pub fn write(&mut self, field: u32, value: u64) -> Option<()> {
+        let (index, high) = Self::locate(field)?;
+
+        self.fields[index] = match (field_width(field), high) {
+            (_, true) => (self.fields[index] & 0xFFFF_FFFF) | (value << 32),
+            (FieldWidth::Word, _) => value & 0xFFFF,
+            (FieldWidth::Doubleword, _) => value & 0xFFFF_FFFF,
+            _ => value,
+        };
+
+        Some(())
+    }
+
+    /// Gets the value of a field from `FIELDS`, or 0 for other fields.
+    pub fn get(&self, field: u32) -> u64 {
+        self.read(field).unwrap_or(0)
+    }
+
+    /// Sets the value of a field from `FIELDS`, ignoring other fields.
+    pub fn set(&mut self, field: u32, value: u64) {
+        let _ = self.write(field, value);
+    }
+
+    /// Finds the index of a field in `FIELDS`, and whether the encoding selects the high half of a 64-bit field.
+    fn locate(field: u32) -> Option<(usize, bool)> {
+        let high = field_width(field) == FieldWidth::Quadword && field & ACCESS_TYPE_HIGH != 0;
+        let full = match high {
+            true => field & !ACCESS_TYPE_HIGH,
+            false => field,
+        };
+
+        FIELDS.binary_search(&full).ok().map(|index| (index, high))
+    }
+}
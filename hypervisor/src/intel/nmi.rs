@@ -0,0 +1,128 @@
+//! This module provides the handling of non-maskable interrupts (NMIs) received in VMX root operation.
+//!
+//! With the NMI-exiting and virtual-NMI pin-based controls, NMIs received while the guest runs cause VM exits,
+//! and are queued for injection into the guest once it isn't blocking NMIs. NMIs received while the VM exit
+//! handler runs are delivered through the host IDT, whose NMI entry is replaced by a handler that counts them for
+//! the current processor, so they are injected into the guest as well instead of running the guest's NMI handler on
+//! the host stack. The VM exit handler owns the VM-execution controls, and enables NMI-window exiting for NMIs
+//! counted after its last write to them.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.1 Pin-Based VM-Execution Controls
+//! and 33.2 Virtualization of NMIs.
+
+use {
+    crate::intel::{
+        support::{vmread, vmwrite},
+        vmx::Vmx,
+    },
+    core::sync::atomic::Ordering,
+    x86::vmx::vmcs,
+};
+
+extern "C" {
+    /// The entry point of the host NMI handler, installed in the NMI entry of the host IDT.
+    ///
+    /// Saves the volatile registers, calls `host_nmi_handler` and returns with `IRETQ`, which unblocks NMIs.
+    pub fn host_nmi_entry();
+}
+
+core::arch::global_asm!(
+    r#"
+.global host_nmi_entry
+host_nmi_entry:
+    // The interrupt frame leaves RSP 8 bytes from 16-byte alignment, which the 7 pushes restore.
+    push    rax
+    push    rcx
+    push    rdx
+    push    r8
+    push    r9
+    push    r10
+    push    r11
+
+    // Allocate the shadow space for the handler and save the volatile XMM registers above it.
+    sub     rsp, 0x80
+    movdqa  [rsp + 0x20], xmm0
+    movdqa  [rsp + 0x30], xmm1
+    movdqa  [rsp + 0x40], xmm2
+    movdqa  [rsp + 0x50], xmm3
+    movdqa  [rsp + 0x60], xmm4
+    movdqa  [rsp + 0x70], xmm5
+
+    call    host_nmi_handler
+
+    movdqa  xmm0, [rsp + 0x20]
+    movdqa  xmm1, [rsp + 0x30]
+    movdqa  xmm2, [rsp + 0x40]
+    movdqa  xmm3, [rsp + 0x50]
+    movdqa  xmm4, [rsp + 0x60]
+    movdqa  xmm5, [rsp + 0x70]
+    add     rsp, 0x80
+
+    pop     r11
+    pop     r10
+    pop     r9
+    pop     r8
+    pop     rdx
+    pop     rcx
+    pop     rax
+
+    iretq
+"#
+);
+
+/// The offset of the `Vmx` pointer in the `VmStack` from the host RSP, past the host registers pushed by `launch_vm`.
+const VMX_POINTER_OFFSET: u64 = 0x80;
+
+/// Handles an NMI received in VMX root operation.
+///
+/// The `Vmx` of the current processor is found through the host RSP of the current VMCS. The handler only counts
+/// the NMI: it must not log, allocate or take locks, and must not write the VMCS, as the NMI can interrupt the VM exit
+/// handler at any point, including between the read and the write of the VM-execution controls.
+#[no_mangle]
+pub extern "C" fn host_nmi_handler() {
+    let vmx = unsafe { *((vmread(vmcs::host::RSP) + VMX_POINTER_OFFSET) as *const *const Vmx) };
+
+    if vmx.is_null() {
+        return;
+    }
+
+    unsafe { (*vmx).host_nmis.fetch_add(1, Ordering::Relaxed) };
+}
+
+/// Queues the NMIs received in VMX root operation on the current processor for injection into the guest.
+///
+/// # Arguments
+///
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+pub fn queue_host_nmis(vmx: &mut Vmx) {
+    let host_nmis = vmx.host_nmis.swap(0, Ordering::Relaxed);
+
+    if host_nmis != 0 {
+        log::trace!(
+            "Queueing {} NMI(s) received in VMX root operation",
+            host_nmis
+        );
+        vmx.pending_events.push_nmi();
+    }
+}
+
+/// Enables NMI-window exiting for the NMIs received in VMX root operation after `queue_host_nmis`.
+///
+/// Must be called after the last write to the VM-execution controls of the VM exit, which would clear NMI-window
+/// exiting again. The NMI-window VM exit occurs as soon as the guest can receive an NMI, and queues the counted NMIs.
+/// An NMI received after this call is queued on the next VM exit.
+///
+/// # Arguments
+///
+/// * `vmx` - A reference to the Vmx structure of the current processor.
+pub fn request_host_nmi_window(vmx: &Vmx) {
+    if vmx.host_nmis.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let primary_controls = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    vmwrite(
+        vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+        primary_controls | vmcs::control::PrimaryControls::NMI_WINDOW_EXITING.bits() as u64,
+    );
+}
@@ -7,11 +7,23 @@ use {
         error::HypervisorError,
         intel::{
             ept::{hooks::HookManager, paging::Ept},
-            msr_bitmap::MsrBitmap,
+            exception_hooks::ExceptionHooks,
+            hardware_breakpoints::HardwareBreakpoints,
+            io::IoHooks,
+            io_bitmap::IoBitmap,
+            msr_bitmap::{new_msr_bitmap, MsrBitmap},
+            syscall::SyscallHooks,
+            tsc::HiddenCycles,
+            virtual_msrs::VirtualMsrs,
+            vmexit::{
+                cpuid::CpuidPolicy, cr::ControlRegisterPolicy, registry::ExitHandlerRegistry,
+                vmcall::HypercallPolicy,
+            },
         },
         utils::alloc::PhysicalAllocator,
     },
     alloc::boxed::Box,
+    core::sync::atomic::{AtomicBool, AtomicU64},
 };
 
 /// Represents shared data structures for hypervisor operations.
@@ -23,6 +35,9 @@ pub struct SharedData {
     /// A bitmap for handling MSRs.
     pub msr_bitmap: Box<MsrBitmap, PhysicalAllocator>,
 
+    /// The I/O bitmaps, selecting the ports whose accesses cause VM exits.
+    pub io_bitmap: Box<IoBitmap, PhysicalAllocator>,
+
     /// The primary Extended Page Table.
     pub primary_ept: Box<Ept, PhysicalAllocator>,
 
@@ -39,12 +54,56 @@ pub struct SharedData {
 
     /// The hook manager.
     pub hook_manager: Box<HookManager>,
+
+    /// Incremented whenever the shared EPTs change in VMX root operation, so that every processor invalidates its
+    /// cached EPT translations on its next VM exit.
+    pub ept_generation: AtomicU64,
+
+    /// The syscall hooks, enabling the EFER.SCE syscall hook when present.
+    pub syscall_hooks: Option<Box<SyscallHooks>>,
+
+    /// The hypervisor-owned hardware breakpoints, enabling debug register virtualization when present.
+    pub hardware_breakpoints: Option<Box<HardwareBreakpoints>>,
+
+    /// The callbacks of the I/O ports intercepted through the I/O bitmaps.
+    pub io_hooks: Option<Box<IoHooks>>,
+
+    /// The callbacks of the exception vectors intercepted through the exception bitmap.
+    pub exception_hooks: Option<Box<ExceptionHooks>>,
+
+    /// The views of the MSRs virtualized for the guest.
+    pub virtual_msrs: Option<Box<VirtualMsrs>>,
+
+    /// Whether the latency of VM exits is hidden from the guest through TSC offsetting.
+    pub tsc_compensation: bool,
+
+    /// The cycles of VM exits hidden from the guest by all processors, which keep their TSC offsets in sync.
+    pub tsc_hidden_cycles: HiddenCycles,
+
+    /// Whether VMX instructions are emulated for a hypervisor running in the guest.
+    pub nested_vmx: bool,
+
+    /// The control-register bits and accesses owned by the hypervisor.
+    pub control_register_policy: ControlRegisterPolicy,
+
+    /// The `CPUID` results returned to the guest.
+    pub cpuid_policy: CpuidPolicy,
+
+    /// Who may use the hypercall interface, or `None` if every `VMCALL` raises `#UD`.
+    pub hypercall_policy: Option<HypercallPolicy>,
+
+    /// Set when the hypervisor unloads, so that `HypercallCommand::Devirtualize` is accepted from CPL 0 without the
+    /// key of the hypercall policy, or without a hypercall policy.
+    pub unloading: AtomicBool,
+
+    /// The registered VM exit handlers, which take precedence over the built-in handlers when present.
+    pub exit_handlers: Option<Box<ExitHandlerRegistry>>,
 }
 
 impl SharedData {
     /// Creates a new instance of `SharedData` with primary and optionally secondary EPTs.
     ///
-    /// This function initializes the MSR bitmap and sets up the EPTs.
+    /// This function initializes the MSR and I/O bitmaps and sets up the EPTs.
     ///
     /// # Arguments
     ///
@@ -64,22 +123,34 @@ impl SharedData {
         let primary_eptp = primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
         let secondary_eptp = secondary_ept.create_eptp_with_wb_and_4lvl_walk()?;
 
-        let bitmap = MsrBitmap::new();
-        //bitmap.hook_msr(IA32_EFER);
-
         Ok(Box::new(Self {
-            msr_bitmap: { bitmap },
+            msr_bitmap: new_msr_bitmap(),
+            io_bitmap: IoBitmap::new(),
             primary_ept,
             primary_eptp,
             secondary_ept,
             secondary_eptp,
             hook_manager,
+            ept_generation: AtomicU64::new(0),
+            syscall_hooks: None,
+            hardware_breakpoints: None,
+            io_hooks: None,
+            exception_hooks: None,
+            virtual_msrs: None,
+            tsc_compensation: false,
+            tsc_hidden_cycles: HiddenCycles::default(),
+            nested_vmx: false,
+            control_register_policy: ControlRegisterPolicy::default(),
+            cpuid_policy: CpuidPolicy::default(),
+            hypercall_policy: None,
+            unloading: AtomicBool::new(false),
+            exit_handlers: None,
         }))
     }
 
     /// Creates a new instance of `SharedData` with primary EPTs.
     ///
-    /// This function initializes the MSR bitmap and sets up the EPTs.
+    /// This function initializes the MSR and I/O bitmaps and sets up the EPTs.
     ///
     /// # Arguments
     ///
@@ -96,14 +167,26 @@ impl SharedData {
 
         let primary_eptp = primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
 
-        let bitmap = MsrBitmap::new();
-        //bitmap.hook_msr(IA32_EFER);
-
         Ok(Some(Box::new(Self {
-            msr_bitmap: { bitmap },
+            msr_bitmap: new_msr_bitmap(),
+            io_bitmap: IoBitmap::new(),
             primary_ept,
             primary_eptp,
             hook_manager,
+            ept_generation: AtomicU64::new(0),
+            syscall_hooks: None,
+            hardware_breakpoints: None,
+            io_hooks: None,
+            exception_hooks: None,
+            virtual_msrs: None,
+            tsc_compensation: false,
+            tsc_hidden_cycles: HiddenCycles::default(),
+            nested_vmx: false,
+            control_register_policy: ControlRegisterPolicy::default(),
+            cpuid_policy: CpuidPolicy::default(),
+            hypercall_policy: None,
+            unloading: AtomicBool::new(false),
+            exit_handlers: None,
         })))
     }
 }
@@ -42,3 +42,13 @@ where
 {
     unsafe { x86::bits64::vmx::vmwrite(field, u64::from(val)) }.unwrap();
 }
+
+/// Read a specified field from a VMCS, if the processor supports it.
+pub fn try_vmread(field: u32) -> Option<u64> {
+    unsafe { x86::bits64::vmx::vmread(field) }.ok()
+}
+
+/// Write to a specified field in a VMCS, if the processor supports it.
+pub fn try_vmwrite(field: u32, val: u64) -> bool {
+    unsafe { x86::bits64::vmx::vmwrite(field, val) }.is_ok()
+}
@@ -0,0 +1,327 @@
+//! This module provides syscall hooking through the EFER.SCE technique.
+//!
+//! The guest runs with `IA32_EFER.SCE` cleared through the VM-entry EFER load, so every `SYSCALL` and `SYSRET`
+//! raises an invalid opcode exception (#UD). The hypervisor intercepts the #UD, invokes the callback registered
+//! for the syscall number and emulates the instruction, so the real dispatcher at `IA32_LSTAR` still runs.
+//!
+//! Credits to Satoshi Tanda and the "Syscall Hooking via Extended Feature Enable Register (EFER)" research by Daax Rynd.
+
+use {
+    crate::{
+        intel::support::{vmread, vmwrite},
+        utils::{capture::GuestRegisters, guest_memory::read_guest_memory, instructions::rdmsr},
+    },
+    alloc::{boxed::Box, collections::BTreeMap},
+    x86::{
+        msr,
+        vmx::vmcs::{self, control::EntryControls},
+    },
+};
+
+/// System Call Extensions (SCE) bit of IA32_EFER.
+pub const EFER_SCE: u64 = 1 << 0;
+
+/// Long Mode Enable (LME) bit of IA32_EFER.
+const EFER_LME: u64 = 1 << 8;
+
+/// Long Mode Active (LMA) bit of IA32_EFER.
+const EFER_LMA: u64 = 1 << 10;
+
+/// Execute Disable Bit Enable (NXE) bit of IA32_EFER.
+const EFER_NXE: u64 = 1 << 11;
+
+/// The bits of IA32_EFER that are not reserved on Intel processors.
+const EFER_DEFINED_BITS: u64 = EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE;
+
+/// The L (64-bit mode active) bit of the CS access rights.
+const CS_ACCESS_RIGHTS_L: u64 = 1 << 13;
+
+/// The paging (PG) bit of CR0.
+const CR0_PG: u64 = 1 << 31;
+
+/// A callback invoked with the guest registers before a hooked syscall reaches the real dispatcher.
+///
+/// The syscall number is in `rax` and the arguments are in `r10`, `rdx`, `r8`, `r9` and on the user stack.
+pub type SyscallCallback = fn(guest_registers: &mut GuestRegisters);
+
+/// Manages the per-syscall-number callbacks used by the EFER syscall hook.
+pub struct SyscallHooks {
+    /// The callbacks keyed by syscall number.
+    callbacks: BTreeMap<u32, SyscallCallback>,
+}
+
+impl SyscallHooks {
+    /// Constructs a new, empty `SyscallHooks` table.
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            callbacks: BTreeMap::new(),
+        })
+    }
+
+    /// Registers a callback for the given syscall number, replacing any previous one.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - The syscall number (SSDT index) to hook.
+    /// * `callback` - The callback invoked with the guest registers.
+    pub fn register(&mut self, syscall_number: u32, callback: SyscallCallback) {
+        self.callbacks.insert(syscall_number, callback);
+    }
+
+    /// Finds the callback registered for the given syscall number.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - The syscall number to look up.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<SyscallCallback>` - The callback if one is registered.
+    pub fn find_callback(&self, syscall_number: u32) -> Option<SyscallCallback> {
+        self.callbacks.get(&syscall_number).copied()
+    }
+}
+
+/// The maximum length of an instruction. Longer instructions raise #GP instead of #UD.
+const MAX_INSTRUCTION_LENGTH: u64 = 15;
+
+/// The legacy prefixes, which `SYSCALL` and `SYSRET` ignore, except for LOCK.
+const LEGACY_PREFIXES: [u8; 10] = [0x26, 0x2E, 0x36, 0x3E, 0x64, 0x65, 0x66, 0x67, 0xF2, 0xF3];
+
+/// The instructions that raise #UD while EFER.SCE is cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallInstruction {
+    /// `SYSCALL` (0F 05).
+    Syscall,
+
+    /// `SYSRET` (0F 07), with REX.W (48 0F 07) to return to 64-bit mode.
+    Sysret { rex_w: bool },
+}
+
+impl SyscallInstruction {
+    /// Decodes the instruction at the guest's RIP, which must be executing in 64-bit mode.
+    ///
+    /// The instruction may have legacy prefixes and a REX prefix, which only applies if it immediately precedes the
+    /// opcode. An instruction with a LOCK prefix raises #UD regardless of EFER.SCE, so it is not decoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_rip` - The guest virtual address of the faulting instruction.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(Self, u64)>` - The instruction and its length, or `None` if it is not `SYSCALL`/`SYSRET`.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 2.1.1 Instruction Prefixes and 2.2.1 REX Prefixes
+    pub fn decode(guest_rip: u64) -> Option<(Self, u64)> {
+        let guest_cr3 = vmread(vmcs::guest::CR3);
+
+        let read_byte = |offset: u64| {
+            let mut byte = [0u8; 1];
+            read_guest_memory(guest_cr3, guest_rip + offset, &mut byte).map(|_| byte[0])
+        };
+
+        let mut rex = 0u8;
+
+        // The processor raised #UD, so the instruction is no longer than the maximum length.
+        for offset in 0..MAX_INSTRUCTION_LENGTH - 1 {
+            match read_byte(offset)? {
+                0x0F => {
+                    let instruction = match read_byte(offset + 1)? {
+                        0x05 => Self::Syscall,
+                        0x07 => Self::Sysret {
+                            rex_w: rex & 0x08 != 0,
+                        },
+                        _ => return None,
+                    };
+
+                    return Some((instruction, offset + 2));
+                }
+                prefix @ 0x40..=0x4F => rex = prefix,
+                prefix if LEGACY_PREFIXES.contains(&prefix) => rex = 0,
+                _ => return None,
+            }
+        }
+
+        None
+    }
+}
+
+/// Segment access rights of the flat 64-bit ring 0 code segment loaded by `SYSCALL`.
+const KERNEL_CODE_ACCESS_RIGHTS: u64 = 0xA09B;
+
+/// Segment access rights of the flat ring 0 stack segment loaded by `SYSCALL`.
+const KERNEL_STACK_ACCESS_RIGHTS: u64 = 0xC093;
+
+/// Segment access rights of the flat 64-bit ring 3 code segment loaded by `SYSRET` with REX.W.
+const USER_CODE64_ACCESS_RIGHTS: u64 = 0xA0FB;
+
+/// Segment access rights of the flat 32-bit ring 3 code segment loaded by `SYSRET` without REX.W.
+const USER_CODE32_ACCESS_RIGHTS: u64 = 0xC0FB;
+
+/// Segment access rights of the flat ring 3 stack segment loaded by `SYSRET`.
+const USER_STACK_ACCESS_RIGHTS: u64 = 0xC0F3;
+
+/// The Resume Flag (RF) bit of RFLAGS.
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// The reserved bit 1 of RFLAGS, which is always set.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// The RFLAGS bits restored from R11 by `SYSRET`.
+const SYSRET_RFLAGS_MASK: u64 = 0x3C7FD7;
+
+/// Emulates `SYSCALL` in 64-bit mode.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's register state.
+/// * `instruction_length` - The length of the `SYSCALL` instruction.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: SYSCALL—Fast System Call
+#[rustfmt::skip]
+pub fn emulate_syscall(guest_registers: &mut GuestRegisters, instruction_length: u64) {
+    let star = rdmsr(msr::IA32_STAR);
+    let lstar = rdmsr(msr::IA32_LSTAR);
+    let fmask = rdmsr(msr::IA32_FMASK);
+
+    guest_registers.rcx = guest_registers.rip + instruction_length;
+    guest_registers.r11 = guest_registers.rflags & !RFLAGS_RF;
+    guest_registers.rip = lstar;
+    guest_registers.rflags = (guest_registers.rflags & !(fmask | RFLAGS_RF)) | RFLAGS_RESERVED;
+
+    let kernel_selector = (star >> 32) & 0xFFFC;
+
+    vmwrite(vmcs::guest::RIP, guest_registers.rip);
+    vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags);
+
+    vmwrite(vmcs::guest::CS_SELECTOR, kernel_selector);
+    vmwrite(vmcs::guest::CS_BASE, 0u64);
+    vmwrite(vmcs::guest::CS_LIMIT, u32::MAX as u64);
+    vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, KERNEL_CODE_ACCESS_RIGHTS);
+
+    vmwrite(vmcs::guest::SS_SELECTOR, kernel_selector + 8);
+    vmwrite(vmcs::guest::SS_BASE, 0u64);
+    vmwrite(vmcs::guest::SS_LIMIT, u32::MAX as u64);
+    vmwrite(vmcs::guest::SS_ACCESS_RIGHTS, KERNEL_STACK_ACCESS_RIGHTS);
+}
+
+/// Emulates `SYSRET`.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's register state.
+/// * `rex_w` - Whether the instruction returns to 64-bit mode (REX.W prefix).
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: SYSRET—Return From Fast System Call
+#[rustfmt::skip]
+pub fn emulate_sysret(guest_registers: &mut GuestRegisters, rex_w: bool) {
+    let star = rdmsr(msr::IA32_STAR);
+    let user_selector = (star >> 48) & 0xFFFF;
+
+    guest_registers.rflags = (guest_registers.r11 & SYSRET_RFLAGS_MASK) | RFLAGS_RESERVED;
+
+    let (rip, cs_selector, cs_access_rights) = match rex_w {
+        true => (guest_registers.rcx, (user_selector + 16) | 3, USER_CODE64_ACCESS_RIGHTS),
+        false => (guest_registers.rcx & u32::MAX as u64, user_selector | 3, USER_CODE32_ACCESS_RIGHTS),
+    };
+    guest_registers.rip = rip;
+
+    vmwrite(vmcs::guest::RIP, guest_registers.rip);
+    vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags);
+
+    vmwrite(vmcs::guest::CS_SELECTOR, cs_selector);
+    vmwrite(vmcs::guest::CS_BASE, 0u64);
+    vmwrite(vmcs::guest::CS_LIMIT, u32::MAX as u64);
+    vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, cs_access_rights);
+
+    vmwrite(vmcs::guest::SS_SELECTOR, (user_selector + 8) | 3);
+    vmwrite(vmcs::guest::SS_BASE, 0u64);
+    vmwrite(vmcs::guest::SS_LIMIT, u32::MAX as u64);
+    vmwrite(vmcs::guest::SS_ACCESS_RIGHTS, USER_STACK_ACCESS_RIGHTS);
+}
+
+/// Checks whether the guest is executing in 64-bit mode.
+///
+/// `SYSCALL` and `SYSRET` raise #UD outside 64-bit mode, before any other check.
+///
+/// # Returns
+///
+/// * `bool` - `true` if guest EFER.LMA and CS.L are both set.
+pub fn is_guest_in_64bit_mode() -> bool {
+    vmread(vmcs::guest::IA32_EFER_FULL) & EFER_LMA != 0
+        && vmread(vmcs::guest::CS_ACCESS_RIGHTS) & CS_ACCESS_RIGHTS_L != 0
+}
+
+/// Emulates a guest write to IA32_EFER while the guest's EFER is loaded from the VMCS.
+///
+/// EFER.SCE is kept clear for the syscall hook, so the caller keeps the value written by the guest in
+/// `Vmx::guest_efer_sce`, and EFER.LMA ignores writes, because the processor only changes it when paging is toggled. The IA-32e mode guest VM-entry control is resynchronized with the
+/// resulting EFER.LMA, since VM entry requires both to match.
+///
+/// # Arguments
+///
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// * `bool` - `false` if the write must raise #GP, because it sets reserved bits or changes EFER.LME while paging is enabled.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.8.5 Initializing IA-32e Mode and 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+pub fn write_guest_efer(value: u64) -> bool {
+    let current = vmread(vmcs::guest::IA32_EFER_FULL);
+    let paging_enabled = vmread(vmcs::guest::CR0) & CR0_PG != 0;
+
+    if value & !EFER_DEFINED_BITS != 0 || (paging_enabled && (value ^ current) & EFER_LME != 0) {
+        return false;
+    }
+
+    load_guest_efer((value & !(EFER_SCE | EFER_LMA)) | (current & EFER_LMA));
+
+    true
+}
+
+/// Activates or deactivates IA-32e mode when an emulated write to CR0 toggles paging, like the processor does.
+///
+/// The guest's EFER must be loaded from the VMCS. IA-32e mode is active while both paging and EFER.LME are enabled.
+///
+/// # Arguments
+///
+/// * `paging` - Whether CR0.PG is set by the write.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.8.5 Initializing IA-32e Mode
+pub fn update_guest_long_mode(paging: bool) {
+    let efer = vmread(vmcs::guest::IA32_EFER_FULL);
+
+    load_guest_efer(match paging && efer & EFER_LME != 0 {
+        true => efer | EFER_LMA,
+        false => efer & !EFER_LMA,
+    });
+}
+
+/// Checks whether EFER.LME is set in the guest's EFER loaded from the VMCS.
+pub fn is_guest_long_mode_enabled() -> bool {
+    vmread(vmcs::guest::IA32_EFER_FULL) & EFER_LME != 0
+}
+
+/// Writes the guest's EFER to the VMCS, and resynchronizes the IA-32e mode guest VM-entry control with EFER.LMA,
+/// since VM entry requires both to match.
+fn load_guest_efer(efer: u64) {
+    vmwrite(vmcs::guest::IA32_EFER_FULL, efer);
+
+    let ia32e_mode_guest = EntryControls::IA32E_MODE_GUEST.bits() as u64;
+    let entry_controls = vmread(vmcs::control::VMENTRY_CONTROLS);
+    vmwrite(
+        vmcs::control::VMENTRY_CONTROLS,
+        match efer & EFER_LMA != 0 {
+            true => entry_controls | ia32e_mode_guest,
+            false => entry_controls & !ia32e_mode_guest,
+        },
+    );
+}
+
+/// Checks whether the address is canonical for 48-bit linear addresses.
+pub fn is_canonical(address: u64) -> bool {
+    let upper = (address as i64) >> 47;
+    upper == 0 || upper == -1
+}
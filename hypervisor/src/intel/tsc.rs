@@ -0,0 +1,213 @@
+//! This module provides TSC offsetting to hide the latency of VM exits from timing checks in the guest.
+//!
+//! With TSC compensation enabled, the guest reads the time-stamp counter through the TSC offset of the VMCS. The
+//! cycles spent from the start of a VM exit until VM entry, plus the cost of the VM exit and VM entry transitions
+//! measured on each processor, are subtracted from the offset, so an instruction that causes a VM exit appears to take
+//! about as long as it would without the hypervisor. The VM exits of all processors are accounted in the shared
+//! `HiddenCycles`, so the TSC offsets of the processors move forward together and the TSC stays synchronized across
+//! processors, while the compensation of each VM entry never exceeds the latency of its VM exit, so the TSC read by
+//! the guest stays monotonic. Compensation starts once every processor measured the latency of a VM exit without it.
+//! Guest writes to IA32_TSC_ADJUST move the TSC offset instead of the host TSC.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3 Changes to Instruction Behavior
+//! in VMX Non-Root Operation (RDTSC, RDTSCP, RDMSR) and 27.6.5 Timestamp-Counter Offsetting and Scaling.
+
+pub use common::tsc::HiddenCycles;
+
+use {
+    crate::{
+        intel::support::{vmread, vmwrite},
+        utils::instructions::{rdmsr, rdtsc},
+    },
+    common::tsc::{applied_hidden_cycles, compensated_tsc_offset},
+    x86::{cpuid::cpuid, msr, vmx::vmcs},
+};
+
+/// The number of `CPUID` round trips measured to calibrate the VM exit overhead.
+const CALIBRATION_SAMPLES: usize = 64;
+
+/// The per-processor state of TSC compensation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TscCompensation {
+    /// The cycles of a `CPUID` executed before the processor was virtualized.
+    native_cpuid_latency: u64,
+
+    /// The cycles of the VM exit and VM entry transitions, not covered by the VM exit handler.
+    overhead: u64,
+
+    /// The host TSC at the start of the current VM exit.
+    exit_tsc: u64,
+
+    /// The IA32_TSC_ADJUST value read by the guest, which is emulated through the TSC offset.
+    tsc_adjust: u64,
+
+    /// The shared hidden cycles included in the TSC offset.
+    applied: u64,
+
+    /// Whether VM exits are compensated, once the overhead is calibrated.
+    enabled: bool,
+}
+
+impl TscCompensation {
+    /// Constructs the TSC compensation state of a processor, before it is virtualized.
+    ///
+    /// The latency of a native `CPUID` is measured now, so that it isn't hidden from the guest with the VM exit.
+    pub fn new() -> Self {
+        Self {
+            native_cpuid_latency: measure_cpuid_latency(),
+            overhead: 0,
+            exit_tsc: 0,
+            tsc_adjust: match is_tsc_adjust_supported() {
+                true => rdmsr(msr::IA32_TSC_ADJUST),
+                false => 0,
+            },
+            applied: 0,
+            enabled: false,
+        }
+    }
+
+    /// Sets the cycles of the VM exit and VM entry transitions from the latency of a `CPUID` measured in the guest,
+    /// and enables the compensation of VM exits.
+    ///
+    /// The latency must be measured before, while the VM exits of the processor aren't compensated.
+    ///
+    /// # Arguments
+    ///
+    /// * `hidden` - The cycles of VM exits hidden from the guest by all processors.
+    /// * `cpuid_latency` - The latency of a `CPUID` in the guest, as measured by `measure_cpuid_latency`.
+    pub fn calibrate(&mut self, hidden: &HiddenCycles, cpuid_latency: u64) {
+        if self.enabled {
+            return;
+        }
+
+        self.overhead = cpuid_latency.saturating_sub(self.native_cpuid_latency);
+        self.enabled = true;
+        hidden.calibrated();
+    }
+
+    /// Records the host TSC at the start of a VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_tsc` - The host TSC sampled by the VM exit stub.
+    pub fn start_exit(&mut self, exit_tsc: u64) {
+        self.exit_tsc = exit_tsc;
+    }
+
+    /// Hides the current VM exit and moves the TSC offset back by the cycles hidden since the previous VM entry, right
+    /// before VM entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `hidden` - The cycles of VM exits hidden from the guest by all processors.
+    pub fn finish_exit(&mut self, hidden: &HiddenCycles) {
+        if !self.enabled || !hidden.is_active() {
+            return;
+        }
+
+        let exit_start = self.exit_tsc.wrapping_sub(self.overhead);
+        let exit_end = rdtsc();
+        let hidden_cycles = hidden.hide(exit_start, exit_end);
+        let applied = applied_hidden_cycles(self.applied, hidden_cycles, exit_start, exit_end);
+        let tsc_offset = compensated_tsc_offset(
+            vmread(vmcs::control::TSC_OFFSET_FULL),
+            self.applied,
+            applied,
+        );
+
+        vmwrite(vmcs::control::TSC_OFFSET_FULL, tsc_offset);
+        self.applied = applied;
+    }
+
+    /// Sets the TSC offset so that the guest reads the given TSC value now, for guest writes to IA32_TIME_STAMP_COUNTER.
+    ///
+    /// Like a write to the TSC, IA32_TSC_ADJUST changes by the same amount as the TSC.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_tsc` - The TSC value written by the guest.
+    pub fn write_guest_tsc(&mut self, guest_tsc: u64) {
+        let tsc_offset = guest_tsc.wrapping_sub(rdtsc());
+
+        self.tsc_adjust = self
+            .tsc_adjust
+            .wrapping_add(tsc_offset.wrapping_sub(vmread(vmcs::control::TSC_OFFSET_FULL)));
+        vmwrite(vmcs::control::TSC_OFFSET_FULL, tsc_offset);
+    }
+
+    /// Gets the IA32_TSC_ADJUST value read by the guest.
+    pub fn guest_tsc_adjust(&self) -> u64 {
+        self.tsc_adjust
+    }
+
+    /// Emulates a guest write to IA32_TSC_ADJUST by moving the TSC offset instead of the host TSC.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc_adjust` - The IA32_TSC_ADJUST value written by the guest.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 19.17.3 Time-Stamp Counter Adjustment
+    pub fn write_guest_tsc_adjust(&mut self, tsc_adjust: u64) {
+        let tsc_offset = vmread(vmcs::control::TSC_OFFSET_FULL)
+            .wrapping_add(tsc_adjust.wrapping_sub(self.tsc_adjust));
+
+        self.tsc_adjust = tsc_adjust;
+        vmwrite(vmcs::control::TSC_OFFSET_FULL, tsc_offset);
+    }
+}
+
+/// Measures the latency of `CPUID`, which causes a VM exit unconditionally once the processor is virtualized.
+///
+/// The minimum over several samples is used, so the compensation never exceeds the latency of a VM exit.
+///
+/// # Returns
+///
+/// * `u64` - The latency in cycles.
+pub fn measure_cpuid_latency() -> u64 {
+    (0..CALIBRATION_SAMPLES)
+        .map(|_| {
+            let start = rdtsc();
+            cpuid!(0);
+            rdtsc().wrapping_sub(start)
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+/// Checks whether the processor supports IA32_TSC_ADJUST, reported by CPUID.(EAX=07H, ECX=0):EBX[1].
+pub fn is_tsc_adjust_supported() -> bool {
+    cpuid!(0x7, 0x0).ebx & (1 << 1) != 0
+}
+
+/// Gets the TSC as read by the guest: the host TSC plus the TSC offset, when TSC offsetting is enabled.
+pub fn guest_tsc() -> u64 {
+    host_to_guest_tsc(rdtsc())
+}
+
+/// Converts a host TSC value to the guest TSC.
+///
+/// # Arguments
+///
+/// * `host_tsc` - The host TSC value.
+pub fn host_to_guest_tsc(host_tsc: u64) -> u64 {
+    host_tsc.wrapping_add(tsc_offset())
+}
+
+/// Converts a guest TSC value to the host TSC.
+///
+/// # Arguments
+///
+/// * `guest_tsc` - The guest TSC value.
+pub fn guest_to_host_tsc(guest_tsc: u64) -> u64 {
+    guest_tsc.wrapping_sub(tsc_offset())
+}
+
+/// Gets the TSC offset applied to the guest, or 0 if TSC offsetting is disabled.
+fn tsc_offset() -> u64 {
+    let primary_controls = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+
+    match primary_controls & vmcs::control::PrimaryControls::USE_TSC_OFFSETTING.bits() as u64 != 0 {
+        true => vmread(vmcs::control::TSC_OFFSET_FULL),
+        false => 0,
+    }
+}
@@ -9,8 +9,11 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            invept::invept_all_contexts, invvpid::invvpid_all_contexts, shared_data::SharedData,
-            support,
+            invept::invept_all_contexts,
+            invvpid::invvpid_all_contexts,
+            shared_data::SharedData,
+            tsc::measure_cpuid_latency,
+            vmexit::vmcall::{hypercall, HypercallBlock, HypercallCommand, HypercallStatus},
         },
         utils::{
             capture::CONTEXT,
@@ -23,6 +26,11 @@ use {
     wdk_sys::ntddk::RtlCaptureContext,
 };
 
+/// The number of `HypercallCommand::Devirtualize` hypercalls executed before giving up on a processor.
+///
+/// The hypercall is refused while events wait for injection, which the VM entry completing it delivers.
+const DEVIRTUALIZE_ATTEMPTS: usize = 8;
+
 /// Represents a Virtual CPU (VCPU) and its associated operations.
 pub struct Vcpu {
     /// The processor's unique identifier.
@@ -91,20 +99,40 @@ impl Vcpu {
             // We should never reach this point as the VM should have been launched.
         }
 
+        // The guest resumes here once the processor is virtualized, and measures the latency of a VM exit, which isn't
+        // compensated until calibrated, to compensate for it.
+        if shared_data.tsc_compensation {
+            let cpuid_latency = measure_cpuid_latency();
+            log::trace!(
+                "CPUID latency of processor {}: {} cycles",
+                self.index,
+                cpuid_latency
+            );
+
+            if let Some(tsc_compensation) = self
+                .vmx
+                .get_mut()
+                .and_then(|vmx| vmx.tsc_compensation.as_mut())
+            {
+                tsc_compensation.calibrate(&shared_data.tsc_hidden_cycles, cpuid_latency);
+            }
+        }
+
         Ok(())
     }
 
     /// Devirtualizes the current CPU.
     ///
-    /// Attempts to turn off VMX operation for the processor on which it's called. If the processor is
-    /// already in a non-root operation (devirtualized), the function will return early without performing
-    /// the devirtualization again.
+    /// The code calling this function runs in the guest, so the processor leaves VMX operation through the
+    /// `HypercallCommand::Devirtualize` hypercall, after which it continues without the hypervisor. The hypercall
+    /// is only accepted without the key once `SharedData::unloading` is set. If the processor is already
+    /// devirtualized, the function will return early without performing the devirtualization again.
     ///
     /// # Returns
     ///
     /// A `Result` indicating the success or failure of the operation. Returns `Ok(())` if the processor
     /// was successfully devirtualized or was already in a devirtualized state. Returns an `Err` if the
-    /// `vmxoff` operation fails.
+    /// hypervisor refused to devirtualize the processor, such as while a hypervisor in the guest is in VMX operation.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 30.3 VMXOFF—Leave VMX Operation.
     /// - Describes the `VMXOFF` instruction which is used to devirtualize a processor.
@@ -115,11 +143,20 @@ impl Vcpu {
             return Ok(());
         }
 
-        // Attempt to devirtualize the processor using the VMXOFF instruction.
-        support::vmxoff()?;
-        log::trace!("Processor {} has been devirtualized", self.index);
+        for _ in 0..DEVIRTUALIZE_ATTEMPTS {
+            let mut block = HypercallBlock::new([0; 4]);
 
-        Ok(())
+            if hypercall(0, HypercallCommand::Devirtualize, &mut block)
+                == HypercallStatus::Success as u64
+            {
+                log::trace!("Processor {} has been devirtualized", self.index);
+                return Ok(());
+            }
+        }
+
+        log::error!("Processor {} refused to be devirtualized", self.index);
+
+        Err(HypervisorError::DevirtualizationRefused)
     }
 
     /// Retrieves the processor's unique identifier.
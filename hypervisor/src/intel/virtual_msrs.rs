@@ -0,0 +1,240 @@
+//! This module provides the virtualization of MSRs accessed by the guest.
+//!
+//! The MSRs of the registered views are set in the MSR bitmap, so guest RDMSR and WRMSR instructions accessing them
+//! cause a VM exit. Instead of the hardware MSR, the guest then accesses a per-processor shadow value, a read-only
+//! value, an MSR that doesn't exist, or a callback deciding how the access completes.
+
+use {
+    crate::{
+        intel::{
+            nested::capabilities::capability_msrs,
+            support::{vmread, vmwrite},
+            vmexit::msr::MsrAccessType,
+        },
+        utils::{
+            instructions::{rdmsr, wrmsr},
+            safe_msr::try_rdmsr,
+        },
+    },
+    alloc::{boxed::Box, collections::BTreeMap},
+    x86::{msr, vmx::vmcs},
+};
+
+/// IA32_FEATURE_CONTROL.Lock, which makes the MSR read-only until reset.
+const FEATURE_CONTROL_LOCK: u64 = 1 << 0;
+
+/// IA32_FEATURE_CONTROL bits enabling VMXON inside and outside SMX operation.
+const FEATURE_CONTROL_VMXON: u64 = (1 << 1) | (1 << 2);
+
+/// The VMX capability MSRs, from IA32_VMX_BASIC to IA32_VMX_VMFUNC.
+const VMX_CAPABILITY_MSRS: core::ops::RangeInclusive<u32> =
+    msr::IA32_VMX_BASIC..=msr::IA32_VMX_VMFUNC;
+
+/// An access to a virtualized MSR by the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct MsrAccess {
+    /// The MSR accessed by the guest.
+    pub msr: u32,
+
+    /// Whether the guest reads or writes the MSR.
+    pub access_type: MsrAccessType,
+
+    /// The value written by the guest for WRMSR, or the value returned to the guest for RDMSR.
+    pub value: u64,
+}
+
+/// The action taken after a callback observed an MSR access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAction {
+    /// Performs the access on the hardware MSR: WRMSR writes `MsrAccess::value`, and RDMSR returns the MSR value.
+    Forward,
+
+    /// Completes the access without touching the MSR: WRMSR is dropped, and RDMSR returns `MsrAccess::value`.
+    Complete,
+
+    /// Injects a general protection fault (`#GP(0)`) into the guest.
+    Fault,
+}
+
+/// A callback invoked with an access to a virtualized MSR.
+pub type MsrCallback = fn(access: &mut MsrAccess) -> MsrAction;
+
+/// The view of an MSR presented to the guest.
+#[derive(Debug, Clone, Copy)]
+pub enum MsrView {
+    /// Reads return a per-processor shadow value, and writes update it without reaching the MSR. The shadow value
+    /// starts with the given value, or with the value of the MSR on each processor when virtualization starts.
+    Shadow(Option<u64>),
+
+    /// Reads return the given value, or the value of the MSR, and writes inject `#GP(0)`.
+    ReadOnly(Option<u64>),
+
+    /// Any access injects `#GP(0)`, as if the MSR didn't exist.
+    NotPresent,
+
+    /// The callback decides how the access completes.
+    Callback(MsrCallback),
+}
+
+/// Manages the views of the MSRs virtualized by the MSR VM exit handler.
+pub struct VirtualMsrs {
+    /// The views keyed by MSR.
+    views: BTreeMap<u32, MsrView>,
+}
+
+impl VirtualMsrs {
+    /// Constructs a new, empty `VirtualMsrs` table.
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            views: BTreeMap::new(),
+        })
+    }
+
+    /// Registers the view of an MSR, replacing any previous one.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR to virtualize.
+    /// * `view` - The view presented to the guest.
+    pub fn register(&mut self, msr: u32, view: MsrView) {
+        self.views.insert(msr, view);
+    }
+
+    /// Hides VMX from the guest, matching a `CPUID` result without the VMX feature bit.
+    ///
+    /// IA32_FEATURE_CONTROL reads as locked with VMXON disabled, and the VMX capability MSRs don't exist.
+    pub fn hide_vmx(&mut self) {
+        self.register(
+            msr::IA32_FEATURE_CONTROL,
+            MsrView::Callback(feature_control_without_vmx),
+        );
+
+        for msr in VMX_CAPABILITY_MSRS {
+            self.register(msr, MsrView::NotPresent);
+        }
+    }
+
+    /// Exposes VMX to the guest for nested virtualization, replacing the views registered by `hide_vmx`.
+    ///
+    /// IA32_FEATURE_CONTROL and the VMX capability MSRs read from the processor, except for the controls of the
+    /// features that aren't supported for nested guests.
+    pub fn expose_nested_vmx(&mut self) {
+        self.views.remove(&msr::IA32_FEATURE_CONTROL);
+
+        for msr in VMX_CAPABILITY_MSRS {
+            self.views.remove(&msr);
+        }
+
+        for (msr, value) in capability_msrs() {
+            self.register(msr, MsrView::ReadOnly(Some(value)));
+        }
+    }
+
+    /// Finds the view of an MSR.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR accessed by the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<MsrView>` - The view if the MSR is virtualized.
+    pub fn find_view(&self, msr: u32) -> Option<MsrView> {
+        self.views.get(&msr).copied()
+    }
+
+    /// Iterates over the virtualized MSRs, which must be set in the MSR bitmap.
+    pub fn msrs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.views.keys().copied()
+    }
+
+    /// Creates the shadow values of a processor, initialized from its MSRs where no initial value is given.
+    ///
+    /// An MSR the processor doesn't implement can't be read, so its shadow value starts at 0.
+    ///
+    /// Must be called on the processor before it is virtualized.
+    pub fn create_shadows(&self) -> BTreeMap<u32, u64> {
+        self.views
+            .iter()
+            .filter_map(|(msr, view)| match view {
+                MsrView::Shadow(initial) => Some((
+                    *msr,
+                    initial
+                        .or_else(|| read_shadowed_msr(*msr))
+                        .unwrap_or_default(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Reads the initial shadow value of an MSR, or `None` if the processor doesn't implement it.
+fn read_shadowed_msr(msr: u32) -> Option<u64> {
+    let value = try_rdmsr(msr);
+
+    if value.is_none() {
+        log::warn!(
+            "MSR {:#x} is not implemented, its shadow value starts at 0",
+            msr
+        );
+    }
+
+    value
+}
+
+/// Presents IA32_FEATURE_CONTROL as locked with VMXON disabled.
+fn feature_control_without_vmx(access: &mut MsrAccess) -> MsrAction {
+    match access.access_type {
+        MsrAccessType::Read => {
+            access.value = (read_guest_msr(msr::IA32_FEATURE_CONTROL) & !FEATURE_CONTROL_VMXON)
+                | FEATURE_CONTROL_LOCK;
+            MsrAction::Complete
+        }
+        // Writes to a locked IA32_FEATURE_CONTROL fault.
+        MsrAccessType::Write => MsrAction::Fault,
+    }
+}
+
+/// Reads an MSR as seen by the guest in VMX root operation.
+///
+/// The MSRs loaded from the guest-state area on VM entry are read from the VMCS, as the processor holds the host
+/// values while the VM exit is handled.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR to read.
+pub fn read_guest_msr(msr: u32) -> u64 {
+    match guest_state_field(msr) {
+        Some(field) => vmread(field),
+        None => rdmsr(msr),
+    }
+}
+
+/// Writes an MSR for the guest in VMX root operation, to the VMCS for the MSRs loaded from the guest-state area.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR to write.
+/// * `value` - The value written by the guest.
+pub fn write_guest_msr(msr: u32, value: u64) {
+    match guest_state_field(msr) {
+        Some(field) => vmwrite(field, value),
+        None => wrmsr(msr, value),
+    }
+}
+
+/// Gets the guest-state field holding an MSR that is loaded on VM entry and replaced by the host value on VM exit.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4.1 Guest Register State
+fn guest_state_field(msr: u32) -> Option<u32> {
+    match msr {
+        msr::IA32_DEBUGCTL => Some(vmcs::guest::IA32_DEBUGCTL_FULL),
+        msr::IA32_SYSENTER_CS => Some(vmcs::guest::IA32_SYSENTER_CS),
+        msr::IA32_SYSENTER_ESP => Some(vmcs::guest::IA32_SYSENTER_ESP),
+        msr::IA32_SYSENTER_EIP => Some(vmcs::guest::IA32_SYSENTER_EIP),
+        msr::IA32_FS_BASE => Some(vmcs::guest::FS_BASE),
+        msr::IA32_GS_BASE => Some(vmcs::guest::GS_BASE),
+        _ => None,
+    }
+}
@@ -17,13 +17,16 @@ use {
             segmentation::SegmentDescriptor,
             shared_data::SharedData,
             support::{vmclear, vmptrld, vmread, vmwrite},
+            syscall::EFER_SCE,
             vmerror::ExceptionInterrupt,
+            vmexit::cr::CR4_VMXE,
         },
         utils::capture::GuestRegisters,
         utils::{
             addresses::PhysicalAddress,
             alloc::{KernelAlloc, PhysicalAllocator},
             capture::CONTEXT,
+            processor::current_processor_index,
         },
     },
 
@@ -269,33 +272,105 @@ impl Vmcs {
     ///
     /// # Arguments
     /// * `shared_data` - Shared data between processors.
+    /// * `original_cr4` - The value of CR4 before VMX operation was enabled.
     #[rustfmt::skip]
-    pub fn setup_vmcs_control_fields(shared_data: &mut SharedData) -> Result<(), HypervisorError> {
+    pub fn setup_vmcs_control_fields(shared_data: &mut SharedData, original_cr4: u64) -> Result<(), HypervisorError> {
         log::debug!("Setting up VMCS Control Fields");
 
-        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()) as u64;
+        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits()
+            | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()
+            | vmcs::control::PrimaryControls::USE_IO_BITMAPS.bits()) as u64;
         const SECONDARY_CTL: u64 = (vmcs::control::SecondaryControls::ENABLE_RDTSCP.bits()
             | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS.bits()
             | vmcs::control::SecondaryControls::ENABLE_INVPCID.bits()
             | vmcs::control::SecondaryControls::ENABLE_VPID.bits()
-            | vmcs::control::SecondaryControls::ENABLE_EPT.bits()) as u64;
+            | vmcs::control::SecondaryControls::ENABLE_EPT.bits()
+            | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST.bits()) as u64;
         const ENTRY_CTL: u64 = vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as u64;
         const EXIT_CTL: u64 = vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() as u64;
-        const PINBASED_CTL: u64 = 0;
+        const PINBASED_CTL: u64 = (vmcs::control::PinbasedControls::NMI_EXITING.bits()
+            | vmcs::control::PinbasedControls::VIRTUAL_NMIS.bits()) as u64;
+
+        let control_register_policy = shared_data.control_register_policy;
+        let mut primary_ctl = PRIMARY_CTL;
+        let mut entry_ctl = ENTRY_CTL;
+        let mut exit_ctl = EXIT_CTL;
+        let mut exception_bitmap = 1u64 << (ExceptionInterrupt::Breakpoint as u32);
+
+        // The EFER.SCE syscall hook runs the guest with EFER.SCE cleared so that SYSCALL and SYSRET raise #UD.
+        if shared_data.syscall_hooks.is_some() {
+            entry_ctl |= vmcs::control::EntryControls::LOAD_IA32_EFER.bits() as u64;
+            exit_ctl |= (vmcs::control::ExitControls::SAVE_IA32_EFER.bits() | vmcs::control::ExitControls::LOAD_IA32_EFER.bits()) as u64;
+            exception_bitmap |= 1u64 << (ExceptionInterrupt::InvalidOpcode as u32);
+
+            let efer = unsafe { msr::rdmsr(msr::IA32_EFER) };
+            vmwrite(vmcs::guest::IA32_EFER_FULL, efer & !EFER_SCE);
+            vmwrite(vmcs::host::IA32_EFER_FULL, efer);
+        }
+
+        if control_register_policy.cr3_load_exiting {
+            primary_ctl |= vmcs::control::PrimaryControls::CR3_LOAD_EXITING.bits() as u64;
+        }
+
+        if control_register_policy.cr3_store_exiting {
+            primary_ctl |= vmcs::control::PrimaryControls::CR3_STORE_EXITING.bits() as u64;
+        }
 
-        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL));
+        // Hypervisor-owned hardware breakpoints intercept guest debug register accesses and debug exceptions.
+        if let Some(hardware_breakpoints) = shared_data.hardware_breakpoints.as_ref() {
+            primary_ctl |= vmcs::control::PrimaryControls::MOV_DR_EXITING.bits() as u64;
+            exception_bitmap |= 1u64 << (ExceptionInterrupt::Debug as u32);
+
+            hardware_breakpoints.load();
+            vmwrite(vmcs::guest::DR7, hardware_breakpoints.apply_dr7(vmread(vmcs::guest::DR7)));
+        }
+
+        // TSC compensation starts from the host TSC and moves the offset back by the latency of each VM exit.
+        if shared_data.tsc_compensation {
+            primary_ctl |= vmcs::control::PrimaryControls::USE_TSC_OFFSETTING.bits() as u64;
+            vmwrite(vmcs::control::TSC_OFFSET_FULL, 0u64);
+        }
+
+        // Registered exception hooks intercept their vectors on the selected processors, with page faults filtered by error code.
+        let mut page_fault_filter = (0u32, 0u32);
+        if let Some(exception_hooks) = shared_data.exception_hooks.as_ref() {
+            exception_bitmap |= exception_hooks.exception_bitmap(current_processor_index()) as u64;
+
+            // With the page fault vector clear, page faults whose error code doesn't match the filter cause VM exits,
+            // so the filter only applies to processors that intercept page faults. A mask and match of 0 never exits.
+            if exception_bitmap & (1u64 << (ExceptionInterrupt::PageFault as u32)) != 0 {
+                page_fault_filter = exception_hooks.page_fault_filter();
+            }
+        }
+
+        vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MASK, page_fault_filter.0);
+        vmwrite(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH, page_fault_filter.1);
+
+        vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, primary_ctl));
         vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL));
-        vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL));
-        vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL));
+        vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, entry_ctl));
+        vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, exit_ctl));
         vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL));
 
         unsafe {
             vmwrite(vmcs::control::CR0_READ_SHADOW, controlregs::cr0().bits() as u64);
-            vmwrite(vmcs::control::CR4_READ_SHADOW, Cr4::read_raw());
         };
 
+        // CR4.VMXE was set by VMXON, so the guest reads its value from before VMX operation was enabled.
+        vmwrite(vmcs::control::CR4_READ_SHADOW, (Cr4::read_raw() & !CR4_VMXE) | (original_cr4 & CR4_VMXE));
+
+        // Guest reads of the bits owned by the hypervisor return the read shadows, and writes that change them cause a VM exit.
+        vmwrite(vmcs::control::CR0_GUEST_HOST_MASK, control_register_policy.cr0_guest_host_mask);
+        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, control_register_policy.cr4_guest_host_mask | CR4_VMXE);
+        vmwrite(vmcs::control::CR3_TARGET_COUNT, 0u32);
+
         vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, PhysicalAddress::pa_from_va(shared_data.msr_bitmap.as_ref() as *const _ as _));
-        vmwrite(vmcs::control::EXCEPTION_BITMAP, 1u64 << (ExceptionInterrupt::Breakpoint as u32));
+        vmwrite(vmcs::control::IO_BITMAP_A_ADDR_FULL, PhysicalAddress::pa_from_va(shared_data.io_bitmap.bitmap_a.as_ptr() as _));
+        vmwrite(vmcs::control::IO_BITMAP_B_ADDR_FULL, PhysicalAddress::pa_from_va(shared_data.io_bitmap.bitmap_b.as_ptr() as _));
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+
+        // XSAVES and XRSTORS are executed by the guest without VM exits, which only occur for the XSS bits set in the bitmap.
+        vmwrite(vmcs::control::XSS_EXITING_BITMAP_FULL, 0u64);
 
         vmwrite(vmcs::control::EPTP_FULL, shared_data.primary_eptp);
         vmwrite(vmcs::control::VPID, VPID_TAG);
@@ -414,6 +489,8 @@ impl fmt::Debug for Vmcs {
             .field("VM Entry Controls: ", &vmread(vmcs::control::VMENTRY_CONTROLS))
             .field("VM Exit Controls: ", &vmread(vmcs::control::VMEXIT_CONTROLS))
             .field("Pin Based Execution Controls: ", &vmread(vmcs::control::PINBASED_EXEC_CONTROLS))
+            .field("CR0 Guest/Host Mask: ", &vmread(vmcs::control::CR0_GUEST_HOST_MASK))
+            .field("CR4 Guest/Host Mask: ", &vmread(vmcs::control::CR4_GUEST_HOST_MASK))
             .field("CR0 Read Shadow: ", &vmread(vmcs::control::CR0_READ_SHADOW))
             .field("CR4 Read Shadow: ", &vmread(vmcs::control::CR4_READ_SHADOW))
             .field("MSR Bitmaps Address: ", &vmread(vmcs::control::MSR_BITMAPS_ADDR_FULL))
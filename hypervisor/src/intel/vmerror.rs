@@ -455,6 +455,197 @@ impl core::fmt::Display for EptViolationExitQualification {
     }
 }
 
+/// Represents the type of a control-register access.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-3. Exit Qualification for Control-Register Accesses
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ControlRegisterAccessType {
+    /// MOV to CR.
+    MovToCr = 0,
+    /// MOV from CR.
+    MovFromCr = 1,
+    /// CLTS.
+    Clts = 2,
+    /// LMSW.
+    Lmsw = 3,
+}
+
+/// Represents the type of the source operand of LMSW.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LmswOperandType {
+    /// The source operand is a register.
+    Register = 0,
+    /// The source operand is in memory.
+    Memory = 1,
+}
+
+/// Represents the exit qualification for control-register accesses.
+///
+/// This struct interprets the exit qualification for control-register accesses as described in
+/// Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-3. Exit Qualification for Control-Register Accesses
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisterAccessExitQualification {
+    /// The number of the control register (0 for CLTS and LMSW).
+    pub control_register: u8,
+    pub access_type: ControlRegisterAccessType,
+    /// The operand type of LMSW, undefined for other access types.
+    pub lmsw_operand_type: LmswOperandType,
+    /// The general-purpose register of MOV CR, in the encoding used by `GuestRegisters::gpr`.
+    pub general_purpose_register: u8,
+    /// The source data of LMSW, undefined for other access types.
+    pub lmsw_source_data: u16,
+}
+
+impl ControlRegisterAccessExitQualification {
+    /// Constructs a `ControlRegisterAccessExitQualification` from the raw 64-bit exit qualification value.
+    pub fn from_exit_qualification(value: u64) -> Self {
+        ControlRegisterAccessExitQualification {
+            control_register: (value & 0xF) as u8,
+            access_type: match (value >> 4) & 0x3 {
+                0 => ControlRegisterAccessType::MovToCr,
+                1 => ControlRegisterAccessType::MovFromCr,
+                2 => ControlRegisterAccessType::Clts,
+                _ => ControlRegisterAccessType::Lmsw,
+            },
+            lmsw_operand_type: match (value >> 6) & 0x1 {
+                0 => LmswOperandType::Register,
+                _ => LmswOperandType::Memory,
+            },
+            general_purpose_register: ((value >> 8) & 0xF) as u8,
+            lmsw_source_data: ((value >> 16) & 0xFFFF) as u16,
+        }
+    }
+}
+
+impl core::fmt::Display for ControlRegisterAccessExitQualification {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "Control Register Access Exit Qualification: {{ \
+            Control Register: CR{}, Access Type: {:?}, LMSW Operand Type: {:?}, \
+            General Purpose Register: {}, LMSW Source Data: {:#x} \
+            }}",
+            self.control_register,
+            self.access_type,
+            self.lmsw_operand_type,
+            self.general_purpose_register,
+            self.lmsw_source_data
+        )
+    }
+}
+
+/// Represents the direction of a debug-register access.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-4. Exit Qualification for MOV DR
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugRegisterAccessDirection {
+    /// MOV to DR.
+    MovToDr = 0,
+    /// MOV from DR.
+    MovFromDr = 1,
+}
+
+/// Represents the exit qualification for MOV DR.
+///
+/// This struct interprets the exit qualification for debug-register accesses as described in
+/// Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-4. Exit Qualification for MOV DR
+#[derive(Debug, Clone, Copy)]
+pub struct DebugRegisterAccessExitQualification {
+    /// The number of the debug register.
+    pub debug_register: u8,
+    pub direction: DebugRegisterAccessDirection,
+    /// The general-purpose register, in the encoding used by `GuestRegisters::gpr`.
+    pub general_purpose_register: u8,
+}
+
+impl DebugRegisterAccessExitQualification {
+    /// Constructs a `DebugRegisterAccessExitQualification` from the raw 64-bit exit qualification value.
+    pub fn from_exit_qualification(value: u64) -> Self {
+        DebugRegisterAccessExitQualification {
+            debug_register: (value & 0x7) as u8,
+            direction: match (value >> 4) & 0x1 {
+                0 => DebugRegisterAccessDirection::MovToDr,
+                _ => DebugRegisterAccessDirection::MovFromDr,
+            },
+            general_purpose_register: ((value >> 8) & 0xF) as u8,
+        }
+    }
+}
+
+impl core::fmt::Display for DebugRegisterAccessExitQualification {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "Debug Register Access Exit Qualification: {{ \
+            Debug Register: DR{}, Direction: {:?}, General Purpose Register: {} \
+            }}",
+            self.debug_register, self.direction, self.general_purpose_register
+        )
+    }
+}
+
+/// Represents the direction of an I/O instruction.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-5. Exit Qualification for I/O Instructions
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoAccessDirection {
+    /// OUT or OUTS.
+    Out = 0,
+    /// IN or INS.
+    In = 1,
+}
+
+/// Represents the exit qualification for I/O instructions.
+///
+/// This struct interprets the exit qualification for I/O instructions as described in
+/// Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-5. Exit Qualification for I/O Instructions
+#[derive(Debug, Clone, Copy)]
+pub struct IoInstructionExitQualification {
+    /// The size of the access in bytes: 1, 2 or 4.
+    pub size: u8,
+    pub direction: IoAccessDirection,
+    /// Whether the instruction is INS or OUTS.
+    pub string: bool,
+    /// Whether the instruction has a REP prefix.
+    pub rep: bool,
+    /// Whether the port is an immediate operand rather than DX.
+    pub immediate: bool,
+    pub port: u16,
+}
+
+impl IoInstructionExitQualification {
+    /// Constructs an `IoInstructionExitQualification` from the raw 64-bit exit qualification value.
+    pub fn from_exit_qualification(value: u64) -> Self {
+        IoInstructionExitQualification {
+            size: ((value & 0x7) + 1) as u8,
+            direction: match (value >> 3) & 0x1 {
+                0 => IoAccessDirection::Out,
+                _ => IoAccessDirection::In,
+            },
+            string: value & (1 << 4) != 0,
+            rep: value & (1 << 5) != 0,
+            immediate: value & (1 << 6) != 0,
+            port: ((value >> 16) & 0xFFFF) as u16,
+        }
+    }
+}
+
+impl core::fmt::Display for IoInstructionExitQualification {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "I/O Instruction Exit Qualification: {{ \
+            Size: {}, Direction: {:?}, String: {}, REP: {}, Immediate: {}, Port: {:#x} \
+            }}",
+            self.size, self.direction, self.string, self.rep, self.immediate, self.port
+        )
+    }
+}
+
 /// Represents the various types of exceptions and interrupts.
 ///
 /// References:
@@ -597,6 +788,7 @@ impl InterruptionType {
             0 => Some(Self::ExternalInterrupt),
             2 => Some(Self::NonMaskableInterrupt),
             3 => Some(Self::HardwareException),
+            4 => Some(Self::SoftwareInterrupt),
             5 => Some(Self::PrivilegedSoftwareException),
             6 => Some(Self::SoftwareException),
             _ => None, // Return None if the bits do not correspond to a known interruption type.
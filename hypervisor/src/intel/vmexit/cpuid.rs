@@ -1,74 +1,30 @@
 //! Handles CPU-related virtualization tasks, specifically intercepting and managing
 //! the `CPUID` instruction in a VM to control the exposure of CPU features to the guest.
+//!
+//! The results returned to the guest are described by a `CpuidPolicy`, which lives in the `common` crate so it can
+//! be tested on any host, and is evaluated here against the result of `CPUID` on the host.
 
-#![allow(dead_code)]
+pub use common::cpuid::{CpuidAction, CpuidPolicy, CpuidRegister, CpuidRule};
 
 use {
-    crate::{intel::vmexit::ExitType, utils::capture::GuestRegisters},
-    bitfield::BitMut,
-    x86::cpuid::cpuid,
+    crate::{
+        intel::{vmexit::ExitType, vmx::Vmx},
+        utils::{capture::GuestRegisters, processor::current_processor_index},
+    },
+    common::cpuid::CpuidResult,
+    x86::cpuid::{cpuid, CpuIdResult},
 };
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-/// Enum representing the various CPUID leaves for feature and interface discovery.
-/// Reference: https://learn.microsoft.com/en-us/virtualization/hyper-v-on-windows/tlfs/feature-discovery
-enum CpuidLeaf {
-    /// CPUID function number to retrieve the processor's vendor identification string.
-    VendorInfo = 0x0,
-
-    /// CPUID function for feature information, including hypervisor presence.
-    FeatureInformation = 0x1,
-
-    /// CPUID function for extended feature information.
-    ExtendedFeatureInformation = 0x7,
-
-    /// Hypervisor vendor information leaf.
-    HypervisorVendor = 0x40000000,
-
-    /// Hypervisor interface identification leaf.
-    HypervisorInterface = 0x40000001,
-
-    /// Hypervisor system identity information leaf.
-    HypervisorSystemIdentity = 0x40000002,
-
-    /// Hypervisor feature identification leaf.
-    HypervisorFeatureIdentification = 0x40000003,
-
-    /// Hypervisor implementation recommendations leaf.
-    ImplementationRecommendations = 0x40000004,
-
-    /// Hypervisor implementation limits leaf.
-    HypervisorImplementationLimits = 0x40000005,
-
-    /// Hardware-specific features in use by the hypervisor leaf.
-    ImplementationHardwareFeatures = 0x40000006,
-
-    /// Nested hypervisor feature identification leaf.
-    NestedHypervisorFeatureIdentification = 0x40000009,
-
-    /// Nested virtualization features available leaf.
-    HypervisorNestedVirtualizationFeatures = 0x4000000A,
-}
-
-/// Enumerates specific feature bits in the ECX register for CPUID instruction results.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum FeatureBits {
-    /// Bit 5 of ECX for CPUID with EAX=1, indicating VMX support.
-    HypervisorVmxSupportBit = 5,
-    /// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
-    HypervisorPresentBit = 31,
-}
-
 /// Handles the `CPUID` VM-exit.
 ///
 /// This function is invoked when the guest executes the `CPUID` instruction.
 /// The handler retrieves the results of the `CPUID` instruction executed on
-/// the host and then modifies or masks certain bits, if necessary, before
-/// returning the results to the guest.
+/// the host and applies the `CpuidPolicy` to them before returning the results to the guest.
 ///
 /// # Arguments
 ///
 /// * `registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A reference to the Vmx structure of the current processor.
 ///
 /// # Returns
 ///
@@ -76,54 +32,19 @@ enum FeatureBits {
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 10.
 #[rustfmt::skip]
-pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
+pub fn handle_cpuid(guest_registers: &mut GuestRegisters, vmx: &Vmx) -> ExitType {
     log::trace!("Handling CPUID VM exit...");
 
     let leaf = guest_registers.rax as u32;
     let sub_leaf = guest_registers.rcx as u32;
 
-    // Execute CPUID instruction on the host and retrieve the result
-    let mut cpuid_result = cpuid!(leaf, sub_leaf);
-
-    log::trace!("Before modification: CPUID Leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
-
-    match leaf {
-        // Handle CPUID for standard feature information.
-        leaf if leaf == CpuidLeaf::FeatureInformation as u32 => {
-            log::trace!("CPUID leaf 1 detected (Standard Feature Information).");
-            // Hide hypervisor presence by setting the appropriate bit in ECX.
-            cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresentBit as usize, false);
-
-            // Hide VMX support by setting the appropriate bit in ECX.
-            cpuid_result.ecx.set_bit(FeatureBits::HypervisorVmxSupportBit as usize, false);
-        },
-        // Handle CPUID for hypervisor vendor information.
-        leaf if leaf == CpuidLeaf::HypervisorVendor as u32 => {
-            log::trace!("CPUID leaf 0x40000000 detected (Hypervisor Vendor Information).");
-            // Set the CPUID response to provide the hypervisor's vendor ID signature.
-            // We use the signature "MatrixVisor" encoded in a little-endian format.
-            // cpuid_result.eax = CpuidLeaf::HypervisorInterface as u32; // Maximum supported CPUID leaf range.
-            // cpuid_result.ebx = 0x69727461; // "atri", part of "MatrixVisor" (in reverse order due to little-endian storage).
-            // cpuid_result.ecx = 0x73695678; // "xVis", part of "MatrixVisor" (in reverse order due to little-endian storage).
-            // cpuid_result.edx = 0x0000726f; // "or", the final part of "MatrixVisor" followed by two null bytes (in reverse order).
-        },
-        // Handle CPUID for hypervisor interface identification.
-        leaf if leaf == CpuidLeaf::HypervisorInterface as u32 => {
-            log::trace!("CPUID leaf 0x40000001 detected (Hypervisor Interface Identification).");
-            // Return information indicating the hypervisor's interface.
-            // Here, we specify that our hypervisor does not conform to the Microsoft hypervisor interface ("Hv#1").
-            cpuid_result.eax = 0x00000001; // Interface signature indicating non-conformance to Microsoft interface.
-            cpuid_result.ebx = 0x00000000; // Reserved field set to zero.
-            cpuid_result.ecx = 0x00000000; // Reserved field set to zero.
-            cpuid_result.edx = 0x00000000; // Reserved field set to zero.
-        },
-        leaf if leaf == CpuidLeaf::ExtendedFeatureInformation as u32 => {
-            log::trace!("CPUID leaf 7 detected (Extended Feature Information).");
-        },
-        _ => { /* Pass through other CPUID leaves unchanged. */ }
-    }
+    let cpuid_policy = &unsafe { vmx.shared_data.as_ref() }.cpuid_policy;
+    let cpuid_result = cpuid_policy.evaluate(leaf, sub_leaf, current_processor_index(), |leaf, sub_leaf| {
+        let CpuIdResult { eax, ebx, ecx, edx } = cpuid!(leaf, sub_leaf);
+        CpuidResult { eax, ebx, ecx, edx }
+    });
 
-    log::trace!("After modification: CPUID Leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
+    log::trace!("CPUID Leaf: {:#x}, Sub-leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, sub_leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
 
     // Update the guest registers
     guest_registers.rax = cpuid_result.eax as u64;
@@ -0,0 +1,353 @@
+//! Handles control-register access VM exits, emulating MOV to and from CR0, CR3 and CR4, CLTS and LMSW.
+//!
+//! The CR0 and CR4 bits set in the guest/host masks are owned by the hypervisor: guest reads of them return the
+//! read shadow, and guest writes that change them cause a VM exit. An emulated write stores the value written by the
+//! guest in the read shadow and loads the guest control register with the bits that are required in VMX operation.
+
+use {
+    crate::{
+        intel::{
+            events::EventInjection,
+            invvpid::{invvpid_single_context, VPID_TAG},
+            nested::NestedVmx,
+            support::{vmread, vmwrite},
+            syscall::{is_guest_in_64bit_mode, is_guest_long_mode_enabled, update_guest_long_mode},
+            vmerror::{ControlRegisterAccessExitQualification, ControlRegisterAccessType},
+            vmexit::{set_guest_gpr, ExitType},
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, instructions::rdmsr},
+    },
+    x86::{
+        controlregs::{Cr0, Cr4},
+        cpuid::cpuid,
+        msr,
+        vmx::vmcs::{
+            control::{self, EntryControls, SecondaryControls},
+            guest, ro,
+        },
+    },
+};
+
+/// CR4.VMXE, which is always owned by the hypervisor so that the guest reads its pre-virtualization value.
+pub const CR4_VMXE: u64 = Cr4::CR4_ENABLE_VMX.bits() as u64;
+
+/// The bit of the MOV to CR3 source operand that skips the TLB invalidation when CR4.PCIDE is set.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// The bits of CR0 that are reserved in 64-bit mode.
+const CR0_RESERVED: u64 = 0xFFFF_FFFF_0000_0000;
+
+/// The CR0 bits whose modification invalidates the TLBs.
+const CR0_TLB_FLUSH_BITS: u64 = (Cr0::CR0_ENABLE_PAGING.bits()
+    | Cr0::CR0_WRITE_PROTECT.bits()
+    | Cr0::CR0_CACHE_DISABLE.bits()) as u64;
+
+/// The CR4 bits whose modification invalidates the TLBs.
+const CR4_TLB_FLUSH_BITS: u64 = (Cr4::CR4_ENABLE_GLOBAL_PAGES.bits()
+    | Cr4::CR4_ENABLE_PAE.bits()
+    | Cr4::CR4_ENABLE_PSE.bits()
+    | Cr4::CR4_ENABLE_PCID.bits()
+    | Cr4::CR4_ENABLE_SMEP.bits()
+    | Cr4::CR4_ENABLE_SMAP.bits()) as u64;
+
+/// The control-register bits and accesses owned by the hypervisor.
+///
+/// CR4.VMXE is owned by the hypervisor regardless of the policy.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ControlRegisterPolicy {
+    /// The CR0 bits owned by the hypervisor.
+    pub cr0_guest_host_mask: u64,
+
+    /// The CR4 bits owned by the hypervisor.
+    pub cr4_guest_host_mask: u64,
+
+    /// Whether MOV to CR3 causes a VM exit.
+    pub cr3_load_exiting: bool,
+
+    /// Whether MOV from CR3 causes a VM exit.
+    pub cr3_store_exiting: bool,
+}
+
+impl ControlRegisterPolicy {
+    /// Creates a policy in which the guest owns every control-register bit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes ownership of CR0 bits, so that the guest reads them from the read shadow.
+    ///
+    /// # Arguments
+    ///
+    /// * `bits` - The CR0 bits to own.
+    pub fn own_cr0_bits(mut self, bits: u64) -> Self {
+        self.cr0_guest_host_mask |= bits;
+        self
+    }
+
+    /// Takes ownership of CR4 bits, so that the guest reads them from the read shadow.
+    ///
+    /// # Arguments
+    ///
+    /// * `bits` - The CR4 bits to own.
+    pub fn own_cr4_bits(mut self, bits: u64) -> Self {
+        self.cr4_guest_host_mask |= bits;
+        self
+    }
+
+    /// Intercepts guest writes to CR3.
+    pub fn intercept_cr3_loads(mut self) -> Self {
+        self.cr3_load_exiting = true;
+        self
+    }
+
+    /// Intercepts guest reads of CR3.
+    pub fn intercept_cr3_stores(mut self) -> Self {
+        self.cr3_store_exiting = true;
+        self
+    }
+}
+
+/// Handles a control-register access VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the emulated instruction.
+/// * `ExitType::Continue` - If a general protection fault was injected for an invalid write.
+/// * `ExitType::ExitHypervisor` - If the access is to a control register that is not intercepted.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+/// and Table 28-3. Exit Qualification for Control-Register Accesses.
+#[rustfmt::skip]
+pub fn handle_cr_access(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling control register access VM exit...");
+
+    let qualification = ControlRegisterAccessExitQualification::from_exit_qualification(vmread(ro::EXIT_QUALIFICATION));
+    let gpr = qualification.general_purpose_register;
+
+    log::trace!("{}", qualification);
+
+    let exit_type = match (qualification.access_type, qualification.control_register) {
+        (ControlRegisterAccessType::MovToCr, 0) => write_cr0(guest_registers.gpr(gpr)),
+        (ControlRegisterAccessType::MovToCr, 3) => write_cr3(guest_registers.gpr(gpr)),
+        (ControlRegisterAccessType::MovToCr, 4) => write_cr4(guest_registers.gpr(gpr), vmx.nested.as_deref()),
+        (ControlRegisterAccessType::MovFromCr, 3) => {
+            set_guest_gpr(guest_registers, gpr, vmread(guest::CR3));
+            ExitType::IncrementRIP
+        }
+        (ControlRegisterAccessType::Clts, _) => {
+            write_cr0(guest_cr0() & !(Cr0::CR0_TASK_SWITCHED.bits() as u64))
+        }
+        (ControlRegisterAccessType::Lmsw, _) => {
+            // LMSW loads CR0.PE, CR0.MP, CR0.EM and CR0.TS, but cannot clear CR0.PE.
+            const LMSW_BITS: u64 = 0xF;
+            let cr0 = guest_cr0();
+            write_cr0((cr0 & !LMSW_BITS) | (qualification.lmsw_source_data as u64 & LMSW_BITS) | (cr0 & Cr0::CR0_PROTECTED_MODE.bits() as u64))
+        }
+        _ => {
+            log::error!("Unhandled control register access: {}", qualification);
+            return ExitType::ExitHypervisor;
+        }
+    };
+
+    log::debug!("Control register access VM exit handled successfully!");
+
+    exit_type
+}
+
+/// Gets the CR0 value seen by the guest, combining the guest-owned bits of CR0 with the read shadow.
+pub fn guest_cr0() -> u64 {
+    let mask = vmread(control::CR0_GUEST_HOST_MASK);
+    (vmread(guest::CR0) & !mask) | (vmread(control::CR0_READ_SHADOW) & mask)
+}
+
+/// Gets the CR4 value seen by the guest, combining the guest-owned bits of CR4 with the read shadow.
+pub fn guest_cr4() -> u64 {
+    let mask = vmread(control::CR4_GUEST_HOST_MASK);
+    (vmread(guest::CR4) & !mask) | (vmread(control::CR4_READ_SHADOW) & mask)
+}
+
+/// Emulates a write to CR0.
+///
+/// Paging and protection can only be disabled once an INIT signal reset the guest, which then runs as an unrestricted
+/// guest with its EFER loaded from the VMCS. Toggling paging activates or deactivates IA-32e mode with EFER.LME set.
+///
+/// # Arguments
+///
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// * `ExitType` - `IncrementRIP` if the write was emulated, or `Continue` if a general protection fault was injected.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: MOV—Move to/from Control Registers
+fn write_cr0(value: u64) -> ExitType {
+    let paging = Cr0::CR0_ENABLE_PAGING.bits() as u64;
+    let protection_enable = Cr0::CR0_PROTECTED_MODE.bits() as u64;
+    let cache_disable = Cr0::CR0_CACHE_DISABLE.bits() as u64;
+    let not_write_through = Cr0::CR0_NOT_WRITE_THROUGH.bits() as u64;
+
+    let unrestricted_guest = vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS)
+        & SecondaryControls::UNRESTRICTED_GUEST.bits() as u64
+        != 0;
+    let efer_loaded =
+        vmread(control::VMENTRY_CONTROLS) & EntryControls::LOAD_IA32_EFER.bits() as u64 != 0;
+    let real_mode_allowed = unrestricted_guest && efer_loaded && !is_guest_in_64bit_mode();
+
+    let previous = vmread(guest::CR0);
+    let enables_paging = previous & paging == 0 && value & paging != 0;
+
+    // Paging cannot be disabled in 64-bit mode, and requires protection to be enabled. With EFER.LME set,
+    // enabling paging also requires CR4.PAE to activate IA-32e mode.
+    if value & CR0_RESERVED != 0
+        || (value & paging == 0 && !real_mode_allowed)
+        || (value & protection_enable == 0 && !real_mode_allowed)
+        || (value & paging != 0 && value & protection_enable == 0)
+        || (value & not_write_through != 0 && value & cache_disable == 0)
+        || (enables_paging
+            && is_guest_long_mode_enabled()
+            && guest_cr4() & Cr4::CR4_ENABLE_PAE.bits() as u64 == 0)
+    {
+        log::trace!("Invalid CR0 write: {:#x}", value);
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    // VM entry of an unrestricted guest doesn't require the fixed PE and PG bits, which are loaded as written.
+    let cr0 = (adjust_fixed_bits(value, msr::IA32_VMX_CR0_FIXED0, msr::IA32_VMX_CR0_FIXED1)
+        & !(paging | protection_enable))
+        | (value & (paging | protection_enable));
+
+    log::trace!("CR0 write: {:#x}, loaded as {:#x}", value, cr0);
+
+    vmwrite(control::CR0_READ_SHADOW, value);
+    vmwrite(guest::CR0, cr0);
+
+    if (previous ^ cr0) & paging != 0 {
+        update_guest_long_mode(cr0 & paging != 0);
+    }
+
+    if (previous ^ cr0) & CR0_TLB_FLUSH_BITS != 0 {
+        flush_guest_tlb();
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Emulates a write to CR3.
+///
+/// With CR4.PCIDE set, bit 63 of the value skips the invalidation of the TLB entries of the new PCID and is not loaded
+/// into CR3. Otherwise, bit 63 is reserved like the other bits above the physical-address width.
+///
+/// # Arguments
+///
+/// * `value` - The value written by the guest.
+///
+/// # Returns
+///
+/// * `ExitType` - `IncrementRIP` if the write was emulated, or `Continue` if a general protection fault was injected.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.10.4.1 Operations that Invalidate TLBs and Paging-Structure Caches
+fn write_cr3(value: u64) -> ExitType {
+    let pcid_enabled = vmread(guest::CR4) & Cr4::CR4_ENABLE_PCID.bits() as u64 != 0;
+    let no_flush = pcid_enabled && value & CR3_NO_FLUSH != 0;
+    let cr3 = match pcid_enabled {
+        true => value & !CR3_NO_FLUSH,
+        false => value,
+    };
+
+    let physical_address_width = cpuid!(0x8000_0008).eax & 0xFF;
+
+    if cr3 >> physical_address_width != 0 {
+        log::trace!("Invalid CR3 write: {:#x}", value);
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    log::trace!("CR3 write: {:#x}, no flush: {}", cr3, no_flush);
+
+    vmwrite(guest::CR3, cr3);
+
+    if !no_flush {
+        flush_guest_tlb();
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Emulates a write to CR4.
+///
+/// # Arguments
+///
+/// * `value` - The value written by the guest.
+/// * `nested` - The nested virtualization state, present if VMX is exposed to the guest.
+///
+/// # Returns
+///
+/// * `ExitType` - `IncrementRIP` if the write was emulated, or `Continue` if a general protection fault was injected.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: MOV—Move to/from Control Registers
+fn write_cr4(value: u64, nested: Option<&NestedVmx>) -> ExitType {
+    let pae = Cr4::CR4_ENABLE_PAE.bits() as u64;
+    let pcide = Cr4::CR4_ENABLE_PCID.bits() as u64;
+
+    // Bits that cannot be set in VMX operation are not supported by the processor. VMX support is hidden by CPUID
+    // unless nested virtualization is enabled, and CR4.VMXE cannot be cleared while the guest is in VMX operation.
+    // PAE cannot be disabled in IA-32e mode, and PCIDE can only be set with PCID 0.
+    let vmxe_allowed = match nested {
+        Some(nested) => value & CR4_VMXE != 0 || !nested.in_vmx_operation(),
+        None => value & CR4_VMXE == 0 || guest_cr4() & CR4_VMXE != 0,
+    };
+
+    if value & !rdmsr(msr::IA32_VMX_CR4_FIXED1) != 0
+        || !vmxe_allowed
+        || (value & pae == 0
+            && vmread(control::VMENTRY_CONTROLS) & EntryControls::IA32E_MODE_GUEST.bits() as u64
+                != 0)
+        || (value & pcide != 0 && guest_cr4() & pcide == 0 && vmread(guest::CR3) & 0xFFF != 0)
+    {
+        log::trace!("Invalid CR4 write: {:#x}", value);
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    let previous = vmread(guest::CR4);
+    let cr4 = adjust_fixed_bits(value, msr::IA32_VMX_CR4_FIXED0, msr::IA32_VMX_CR4_FIXED1);
+
+    // CR4.VMXE must remain set in VMX operation, and reads as written by the guest from the read shadow.
+    log::trace!("CR4 write: {:#x}, loaded as {:#x}", value, cr4);
+
+    vmwrite(control::CR4_READ_SHADOW, value);
+    vmwrite(guest::CR4, cr4);
+
+    if (previous ^ cr4) & CR4_TLB_FLUSH_BITS != 0 {
+        flush_guest_tlb();
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Sets the bits of a control register that must be set in VMX operation, and clears those that must be cleared.
+///
+/// # Arguments
+///
+/// * `value` - The value of the control register.
+/// * `fixed0_msr` - The MSR reporting the bits that must be set.
+/// * `fixed1_msr` - The MSR reporting the bits that may be set.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.7 VMX-FIXED BITS IN CR0 and A.8 VMX-FIXED BITS IN CR4
+pub fn adjust_fixed_bits(value: u64, fixed0_msr: u32, fixed1_msr: u32) -> u64 {
+    (value | rdmsr(fixed0_msr)) & rdmsr(fixed1_msr)
+}
+
+/// Invalidates the guest's TLB entries, as the emulated instruction would have.
+fn flush_guest_tlb() {
+    invvpid_single_context(VPID_TAG);
+}
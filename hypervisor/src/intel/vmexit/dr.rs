@@ -0,0 +1,201 @@
+//! Handles MOV DR VM exits, virtualizing the debug registers reserved for hypervisor-owned hardware breakpoints.
+//!
+//! DR0-DR3 and DR6 are not part of the VMCS and are shared with the host, while DR7 is loaded from the VMCS on VM entry.
+//! Guest accesses to reserved debug registers use the values stored in the `Vmx` of the processor, and accesses to the
+//! other debug registers are performed on the processor.
+
+use {
+    crate::{
+        intel::{
+            events::EventInjection,
+            hardware_breakpoints::HardwareBreakpoints,
+            support::{vmread, vmwrite},
+            vmerror::{DebugRegisterAccessDirection, DebugRegisterAccessExitQualification},
+            vmexit::{cr::guest_cr4, set_guest_gpr, ExitType},
+            vmx::Vmx,
+        },
+        utils::{
+            capture::GuestRegisters,
+            instructions::{dr6, dr6_write},
+        },
+    },
+    x86::{
+        controlregs::Cr4,
+        debugregs::Breakpoint,
+        vmx::vmcs::{guest, ro},
+    },
+};
+
+/// Handles a MOV DR VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the emulated `MOV` instruction.
+/// * `ExitType::Continue` - If an exception was injected instead.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 18.2 Debug Registers
+/// and Table 28-4. Exit Qualification for MOV DR.
+#[rustfmt::skip]
+pub fn handle_mov_dr(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling MOV DR VM exit...");
+
+    let qualification = DebugRegisterAccessExitQualification::from_exit_qualification(vmread(ro::EXIT_QUALIFICATION));
+    log::trace!("{}", qualification);
+
+    // DR4 and DR5 are aliases of DR6 and DR7, unless debug extensions are enabled.
+    let debug_extensions = guest_cr4() & Cr4::CR4_DEBUGGING_EXTENSIONS.bits() as u64 != 0;
+    let debug_register = match qualification.debug_register {
+        4 | 5 if debug_extensions => {
+            EventInjection::vmentry_inject_ud();
+            return ExitType::Continue;
+        }
+        4 => 6,
+        5 => 7,
+        debug_register => debug_register,
+    };
+
+    let shared_data = unsafe { vmx.shared_data.as_ref() };
+    let hardware_breakpoints = shared_data.hardware_breakpoints.as_deref();
+    let is_reserved = |debug_register: u8| hardware_breakpoints.is_some_and(|breakpoints| breakpoints.is_reserved(debug_register));
+    let reserved_conditions = hardware_breakpoints.map_or(0, |breakpoints| breakpoints.reserved_conditions());
+
+    match qualification.direction {
+        DebugRegisterAccessDirection::MovFromDr => {
+            let value = match debug_register {
+                0..=3 if is_reserved(debug_register) => vmx.guest_debug_registers[debug_register as usize],
+                0..=3 => unsafe { breakpoint(debug_register).dr() as u64 },
+                6 => dr6() & !reserved_conditions,
+                _ => match hardware_breakpoints {
+                    Some(breakpoints) => breakpoints.hide_dr7(vmread(guest::DR7), vmx.guest_dr7),
+                    None => vmread(guest::DR7),
+                },
+            };
+
+            log::trace!("MOV from DR{}: {:#x}", debug_register, value);
+            set_guest_gpr(guest_registers, qualification.general_purpose_register, value);
+        }
+        DebugRegisterAccessDirection::MovToDr => {
+            let value = guest_registers.gpr(qualification.general_purpose_register);
+
+            // The upper 32 bits of DR6 and DR7 are reserved and must be written as 0.
+            if debug_register >= 6 && value >> 32 != 0 {
+                EventInjection::vmentry_inject_gp(0);
+                return ExitType::Continue;
+            }
+
+            log::trace!("MOV to DR{}: {:#x}", debug_register, value);
+
+            match debug_register {
+                0..=3 if is_reserved(debug_register) => vmx.guest_debug_registers[debug_register as usize] = value,
+                0..=3 => unsafe { breakpoint(debug_register).write(value as usize) },
+                6 => dr6_write((value & !reserved_conditions) | (dr6() & reserved_conditions)),
+                _ => match hardware_breakpoints {
+                    Some(breakpoints) => {
+                        vmx.guest_dr7 = value;
+                        vmwrite(guest::DR7, breakpoints.apply_dr7(value));
+                    }
+                    None => vmwrite(guest::DR7, value),
+                },
+            }
+        }
+    }
+
+    log::debug!("MOV DR VM exit handled successfully!");
+
+    ExitType::IncrementRIP
+}
+
+/// Gets the breakpoint address register for a debug register number from 0 to 3.
+fn breakpoint(debug_register: u8) -> Breakpoint {
+    match debug_register {
+        0 => Breakpoint::Dr0,
+        1 => Breakpoint::Dr1,
+        2 => Breakpoint::Dr2,
+        _ => Breakpoint::Dr3,
+    }
+}
+
+/// Hands the debug registers released with `HardwareBreakpoints::clear` since the last VM exit back to the guest.
+///
+/// The values written by the guest are loaded into the released debug registers and their DR7 fields. Debug
+/// conditions of the released breakpoints that are reported by the current VM exit are discarded.
+///
+/// # Arguments
+///
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+pub fn sync_hardware_breakpoints(vmx: &mut Vmx) {
+    vmx.released_breakpoint_conditions = 0;
+
+    let Some(breakpoints) = unsafe { vmx.shared_data.as_ref() }
+        .hardware_breakpoints
+        .as_deref()
+    else {
+        return;
+    };
+
+    let released = breakpoints.released_conditions() & !vmx.released_breakpoints;
+
+    if released == 0 {
+        return;
+    }
+
+    log::trace!("Releasing hardware breakpoints: {:#x}", released);
+
+    for debug_register in (0..4u8).filter(|debug_register| released & (1 << debug_register) != 0) {
+        unsafe {
+            breakpoint(debug_register)
+                .write(vmx.guest_debug_registers[debug_register as usize] as usize)
+        };
+    }
+
+    vmwrite(
+        guest::DR7,
+        HardwareBreakpoints::release_dr7(vmread(guest::DR7), vmx.guest_dr7, released),
+    );
+
+    vmx.released_breakpoints |= released;
+    vmx.released_breakpoint_conditions = released;
+}
+
+/// Loads the debug register values of an INIT signal: DR0-DR3 are cleared, DR6 is 0xFFFF0FF0 and DR7 is 0x400.
+///
+/// The debug registers reserved for hardware breakpoints keep their values, and the guest's values are reset instead.
+///
+/// # Arguments
+///
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 10-1. IA-32 and Intel 64 Processor
+/// States Following Power-up, Reset, or INIT
+pub fn reset_debug_registers(vmx: &mut Vmx) {
+    const DR6_INIT: u64 = 0xFFFF_0FF0;
+    const DR7_INIT: u64 = 0x400;
+
+    let hardware_breakpoints = unsafe { vmx.shared_data.as_ref() }
+        .hardware_breakpoints
+        .as_deref();
+    let reserved_conditions =
+        hardware_breakpoints.map_or(0, |breakpoints| breakpoints.reserved_conditions());
+
+    for debug_register in 0..4u8 {
+        match reserved_conditions & (1 << debug_register) != 0 {
+            true => vmx.guest_debug_registers[debug_register as usize] = 0,
+            false => unsafe { breakpoint(debug_register).write(0) },
+        }
+    }
+
+    dr6_write((DR6_INIT & !reserved_conditions) | (dr6() & reserved_conditions));
+
+    match hardware_breakpoints {
+        Some(breakpoints) => {
+            vmx.guest_dr7 = DR7_INIT;
+            vmwrite(guest::DR7, breakpoints.apply_dr7(DR7_INIT));
+        }
+        None => vmwrite(guest::DR7, DR7_INIT),
+    }
+}
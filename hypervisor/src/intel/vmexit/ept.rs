@@ -1,19 +1,41 @@
 use {
     crate::{
         intel::{
-            invept::invept_all_contexts, support::vmread, support::vmwrite,
-            vmerror::EptViolationExitQualification, vmexit::ExitType, vmx::Vmx,
+            ept::{
+                hooks::{EptViolationMode, PendingPageWrite},
+                paging::AccessType,
+            },
+            events::BLOCKING_BY_NMI,
+            invept::invept_all_contexts,
+            support::vmread,
+            support::vmwrite,
+            vmerror::EptViolationExitQualification,
+            vmexit::{mtf::single_step_page, ExitType},
+            vmx::Vmx,
         },
-        utils::{addresses::PhysicalAddress, capture::GuestRegisters},
+        utils::{addresses::PhysicalAddress, capture::GuestRegisters, instructions::rdtsc},
     },
     x86::vmx::vmcs,
 };
 
 /// Handle VM exits for EPT violations. Violations are thrown whenever an operation is performed on an EPT entry that does not provide permissions to access that page.
+///
+/// The TSC spent in the VM exit, since the VM exit stub sampled it, is recorded in the statistics of the hooks on the faulting page.
 /// 29.3.3.2 EPT Violations
 /// Table 28-7. Exit Qualification for EPT Violations
+pub fn handle_ept_violation(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    let exit_type = resolve_ept_violation(guest_registers, vmx);
+
+    let original_page = vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL) & !0xFFF;
+    let hook_manager = unsafe { &vmx.shared_data.as_ref().hook_manager };
+    hook_manager.record_ept_violation(original_page, rdtsc() - vmx.vmstack.exit_tsc);
+
+    exit_type
+}
+
+/// Resolves an EPT violation by swapping the EPT view or single-stepping the access.
 #[rustfmt::skip]
-pub fn handle_ept_violation(_guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+fn resolve_ept_violation(_guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
     log::debug!("Handling EPT Violation VM exit...");
 
     let guest_physical_address = vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
@@ -28,8 +50,126 @@ pub fn handle_ept_violation(_guest_registers: &mut GuestRegisters, vmx: &mut Vmx
     let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
     log::debug!("Exit Qualification for EPT Violations: {}", ept_violation_qualification);
 
-    // If the page is Read/Write, then we need to swap it to the secondary EPTP
-    if ept_violation_qualification.readable && ept_violation_qualification.writable && !ept_violation_qualification.executable {
+    // With virtual NMIs, an EPT violation raised by IRET while unblocking NMIs leaves them unblocked although the IRET is restarted.
+    // Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.3 Information About NMI Unblocking Due to IRET
+    if ept_violation_qualification.nmi_unblocking_due_to_iret && vmread(vmcs::ro::IDT_VECTORING_INFO) & (1 << 31) == 0 {
+        let interruptibility_state = vmread(vmcs::guest::INTERRUPTIBILITY_STATE);
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, interruptibility_state | BLOCKING_BY_NMI);
+    }
+
+    // The single-stepped instruction also accesses another protected page, which is mapped with full access in the single-step view as well.
+    if vmx.single_step.is_some() {
+        let original_page = guest_physical_address & !0xFFF;
+        log::trace!("EPT Violation: Adding Guest Physical Address: {:#x} to the single-step view", guest_physical_address);
+
+        if let Err(err) = single_step_page(vmx, original_page, original_page, AccessType::READ_WRITE_EXECUTE) {
+            log::error!("Failed to single-step page {:#x}: {}", original_page, err);
+            return ExitType::ExitHypervisor;
+        }
+
+        if ept_violation_qualification.data_write && unsafe { vmx.shared_data.as_ref() }.hook_manager.watches_pte_page(original_page) {
+            vmx.pending_pte_write = Some(original_page);
+        }
+
+        log::debug!("EPT Violation handled successfully!");
+
+        return ExitType::Continue;
+    }
+
+    // With write tracking, a write to the original page of a function hook is single-stepped so it can be applied to the hooked copy.
+    if ept_violation_qualification.data_write {
+        let shared_data = unsafe { vmx.shared_data.as_mut() };
+        let original_page = guest_physical_address & !0xFFF;
+
+        if shared_data.hook_manager.find_write_tracked_hook(original_page).is_some() {
+            log::trace!("EPT Violation: Tracking write to hooked Guest Physical Address: {:#x}", guest_physical_address);
+
+            // Snapshot the original page to find the modified range once the write has completed.
+            unsafe { core::ptr::copy_nonoverlapping(PhysicalAddress::va_from_pa(original_page) as *const u8, vmx.page_write_snapshot.as_mut_ptr(), vmx.page_write_snapshot.len()) };
+
+            // Allow the write in the single-step view of this processor only, so writes of other processors keep being tracked.
+            // The monitor trap flag VM exit switches back to the shared EPT, where the page is still protected.
+            let access_type = match vmread(vmcs::control::EPTP_FULL) == shared_data.primary_eptp {
+                true => AccessType::READ_WRITE,
+                false => AccessType::READ_WRITE_EXECUTE,
+            };
+
+            if let Err(err) = single_step_page(vmx, original_page, original_page, access_type) {
+                log::error!("Failed to single-step page {:#x}: {}", original_page, err);
+                return ExitType::ExitHypervisor;
+            }
+
+            vmx.pending_page_write = Some(PendingPageWrite { original_page, guest_rip: vmread(vmcs::guest::RIP) });
+
+            log::debug!("EPT Violation handled successfully!");
+
+            return ExitType::Continue;
+        }
+
+        // A write to the page table mapping the page of a process-scoped hook is single-stepped, so the hook can follow the page
+        // to the private copy the process gets when it writes to a copy-on-write page.
+        if shared_data.hook_manager.watches_pte_page(original_page) {
+            log::trace!("EPT Violation: Tracking write to page table of process-scoped hook: {:#x}", guest_physical_address);
+
+            if let Err(err) = single_step_page(vmx, original_page, original_page, AccessType::READ_WRITE) {
+                log::error!("Failed to single-step page {:#x}: {}", original_page, err);
+                return ExitType::ExitHypervisor;
+            }
+
+            vmx.pending_pte_write = Some(original_page);
+
+            log::debug!("EPT Violation handled successfully!");
+
+            return ExitType::Continue;
+        }
+    }
+
+    // An execute access to a page that only has process-scoped hooks for other processes is single-stepped on the original page,
+    // so that processes sharing the page (such as a copy-on-write DLL page) never execute the hooked copy.
+    if ept_violation_qualification.instruction_fetch && ept_violation_qualification.readable && !ept_violation_qualification.executable {
+        let shared_data = unsafe { vmx.shared_data.as_mut() };
+        let original_page = guest_physical_address & !0xFFF;
+        let guest_cr3 = vmread(vmcs::guest::CR3);
+
+        if shared_data.hook_manager.bypasses_page(original_page, guest_cr3) {
+            log::trace!("EPT Violation: Bypassing process-scoped hooks on Guest Physical Address: {:#x} for CR3: {:#x}", guest_physical_address, guest_cr3);
+            // The original page is only executable in the single-step view of this processor, so other processors keep being redirected.
+            if let Err(err) = single_step_page(vmx, original_page, original_page, AccessType::READ_WRITE_EXECUTE) {
+                log::error!("Failed to single-step page {:#x}: {}", original_page, err);
+                return ExitType::ExitHypervisor;
+            }
+
+            log::debug!("EPT Violation handled successfully!");
+
+            return ExitType::Continue;
+        }
+    }
+
+    // In single-step mode, a data access to an Execute-Only hooked page is resolved in the current view instead of swapping the EPTP.
+    if (ept_violation_qualification.data_read || ept_violation_qualification.data_write) && !ept_violation_qualification.readable && ept_violation_qualification.executable {
+        let shared_data = unsafe { vmx.shared_data.as_mut() };
+
+        if shared_data.hook_manager.violation_mode == EptViolationMode::SingleStep {
+            let original_page = guest_physical_address & !0xFFF;
+
+            if shared_data.hook_manager.find_hook_by_page(original_page).is_some() {
+                log::trace!("EPT Violation: Single-stepping data access on Guest Physical Address: {:#x}", guest_physical_address);
+                // Map the original page with full access in the single-step view of this processor only.
+                // After one instruction, the monitor trap flag VM exit switches back to the secondary EPT, where the hooked copy stays Execute-Only.
+                if let Err(err) = single_step_page(vmx, original_page, original_page, AccessType::READ_WRITE_EXECUTE) {
+                    log::error!("Failed to single-step page {:#x}: {}", original_page, err);
+                    return ExitType::ExitHypervisor;
+                }
+
+                log::debug!("EPT Violation handled successfully!");
+
+                return ExitType::Continue;
+            }
+        }
+    }
+
+    // If the page is Read/Write (or Read-Only with write tracking), then we need to swap it to the secondary EPTP
+    if ept_violation_qualification.readable && !ept_violation_qualification.executable {
         log::trace!("EPT Violation: Execute acccess attempted on Guest Physical Address: {:#x} / Guest Virtual Address: {:#x}", guest_physical_address, va);
         // Change to the secondary EPTP and invalidate the EPT cache.
         // The hooked page that is Execute-Only will be executed from the secondary EPTP.
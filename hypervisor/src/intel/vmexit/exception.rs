@@ -5,25 +5,30 @@
 use {
     crate::{
         intel::{
-            ept::hooks::HookType,
-            events::EventInjection,
+            ept::paging::AccessType,
+            events::{EventInjection, BLOCKING_BY_NMI},
+            exception_hooks::{ExceptionAction, InterceptedException},
             support::{vmread, vmwrite},
-            vmerror::{
-                EptViolationExitQualification, ExceptionInterrupt, VmExitInterruptionInformation,
-            },
-            vmexit::ExitType,
+            syscall::{emulate_syscall, emulate_sysret, is_canonical, is_guest_in_64bit_mode, SyscallInstruction},
+            vmerror::{ExceptionInterrupt, InterruptionType, VmExitInterruptionInformation, VmxBasicExitReason},
+            vmexit::{fallback::handle_fatal_exit, mtf::single_step_page, ExitType},
             vmx::Vmx,
         },
-        utils::capture::GuestRegisters,
+        utils::{
+            capture::GuestRegisters,
+            instructions::{dr6, dr6_write, rdtsc},
+            processor::current_processor_index,
+        },
     },
-    x86::vmx::vmcs,
+    x86::{controlregs, vmx::vmcs},
 };
 
 /// Handles exceptions and NMIs that occur during VM execution.
 ///
 /// This function is called when the VM exits due to an exception or NMI.
 /// It determines the type of exception, handles it accordingly, and prepares
-/// the VM for resumption.
+/// the VM for resumption. Exceptions that are not handled by the hypervisor are passed to the registered
+/// exception callbacks, or reflected to the guest.
 ///
 /// # Arguments
 ///
@@ -33,6 +38,7 @@ use {
 /// # Returns
 ///
 /// * `ExitType::Continue` - Indicating that VM execution should continue after handling the exception
+/// * `ExitType::IncrementRIP` - If an exception callback swallowed a software exception.
 #[rustfmt::skip]
 pub fn handle_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
     log::debug!("Handling ExceptionOrNmi VM exit...");
@@ -40,72 +46,219 @@ pub fn handle_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) ->
     let interruption_info_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
     let interruption_error_code_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
 
-    if let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) {
-        if let Some(exception_interrupt) = ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
-            match exception_interrupt {
-                ExceptionInterrupt::PageFault => {
-                    let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
-                    let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
-                    log::trace!("Exit Qualification for EPT Violations: {}", ept_violation_qualification);
-                    EventInjection::vmentry_inject_pf(interruption_error_code_value as u32);
-                },
-                ExceptionInterrupt::GeneralProtectionFault => {
-                    EventInjection::vmentry_inject_gp(interruption_error_code_value as u32);
-                },
-                ExceptionInterrupt::Breakpoint => {
-                    handle_breakpoint_exception(guest_registers, vmx);
-                },
-                ExceptionInterrupt::InvalidOpcode => {
-                    EventInjection::vmentry_inject_ud();
-                },
-                _ => {
-                    panic!("Unhandled exception: {:?}", exception_interrupt);
-                }
-            }
-        } else {
-            panic!("Invalid Exception Interrupt Vector: {}", interruption_info.vector);
-        }
-    } else {
-        panic!("Invalid VM Exit Interruption Information");
+    let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) else {
+        log::error!("Invalid VM Exit Interruption Information: {:#x}", interruption_info_value);
+        handle_fatal_exit(VmxBasicExitReason::ExceptionOrNmi, guest_registers, vmx);
+    };
+
+    // With NMI exiting, NMIs are queued and injected once the guest isn't blocking them, which the virtual NMI
+    // controls track across the guest's NMI handler.
+    if interruption_info.interruption_type == InterruptionType::NonMaskableInterrupt {
+        log::trace!("Queueing NMI for the guest");
+        vmx.pending_events.push_nmi();
+        return ExitType::Continue;
     }
 
+    let error_code = interruption_info.error_code_valid.then_some(interruption_error_code_value as u32);
+    let reflect = || reflect_exception(&interruption_info, interruption_error_code_value as u32);
+
+    let exit_type = match ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
+        Some(ExceptionInterrupt::Debug) => handle_debug_exception(guest_registers, vmx, &interruption_info),
+        Some(ExceptionInterrupt::Breakpoint) if handle_breakpoint_exception(guest_registers, vmx) => ExitType::Continue,
+        Some(ExceptionInterrupt::InvalidOpcode) if handle_syscall_instruction(guest_registers, vmx) => ExitType::Continue,
+        Some(vector) => {
+            let exception = InterceptedException { vector, error_code, exit_qualification: vmread(vmcs::ro::EXIT_QUALIFICATION) };
+            handle_guest_exception(guest_registers, vmx, &exception, &interruption_info, reflect)
+        },
+        None => {
+            reflect();
+            ExitType::Continue
+        }
+    };
+
     log::debug!("Exception Handled successfully!");
 
+    exit_type
+}
+
+/// Passes an exception not handled by the hypervisor to the callback registered for its vector on the current processor.
+///
+/// Without a callback, the exception is reflected to the guest.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+/// * `exception` - The exception passed to the callback.
+/// * `interruption_info` - The VM-exit interruption information of the exception.
+/// * `reflect` - Reflects the exception to the guest.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - If the callback swallowed an exception raised by `INT3`, `INTO` or `INT1`.
+/// * `ExitType::Continue` - Otherwise.
+#[rustfmt::skip]
+fn handle_guest_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx, exception: &InterceptedException, interruption_info: &VmExitInterruptionInformation, reflect: impl FnOnce()) -> ExitType {
+    let exception_hooks = unsafe { vmx.shared_data.as_ref() }.exception_hooks.as_deref();
+    let callback = exception_hooks.and_then(|hooks| hooks.find_callback(interruption_info.vector, current_processor_index()));
+
+    let action = callback.map_or(ExceptionAction::Reflect, |callback| callback(guest_registers, exception));
+    log::trace!("Exception: {:x?}, action: {:?}", exception, action);
+
+    match action {
+        ExceptionAction::Reflect => reflect(),
+        ExceptionAction::Emulate => {
+            vmwrite(vmcs::guest::RIP, guest_registers.rip);
+            vmwrite(vmcs::guest::RSP, guest_registers.rsp);
+            vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags);
+        },
+        ExceptionAction::Swallow => {
+            // Software exceptions are traps reported with the length of their instruction, which is skipped.
+            if matches!(interruption_info.interruption_type, InterruptionType::SoftwareException | InterruptionType::PrivilegedSoftwareException) {
+                return ExitType::IncrementRIP;
+            }
+        },
+    }
+
     ExitType::Continue
 }
 
+/// Reflects an intercepted exception or NMI to the guest, as if it had not caused a VM exit.
+///
+/// The exception is injected with its interruption type and error code. For page faults, CR2 is loaded with
+/// the faulting address from the exit qualification, as the processor doesn't update CR2 when a page fault
+/// causes a VM exit. If the exception was raised by IRET while unblocking NMIs, NMI blocking is restored,
+/// as IRET didn't complete. Debug exceptions update DR6 and are reflected by `handle_debug_exception`.
+///
+/// # Arguments
+///
+/// * `interruption_info` - The VM-exit interruption information of the exception.
+/// * `error_code` - The VM-exit interruption error code, used if the exception has one.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.2 Information for VM Exits Due to Vectored Events
+/// and 28.2.3 Information About NMI Unblocking Due to IRET.
+#[rustfmt::skip]
+fn reflect_exception(interruption_info: &VmExitInterruptionInformation, error_code: u32) {
+    log::trace!("Reflecting exception to the guest: {:?}", interruption_info);
+
+    if interruption_info.vector == ExceptionInterrupt::PageFault as u8 {
+        unsafe { controlregs::cr2_write(vmread(vmcs::ro::EXIT_QUALIFICATION)) };
+    }
+
+    if interruption_info.nmi_unblocking_due_to_iret && interruption_info.vector != ExceptionInterrupt::DoubleFault as u8 {
+        let interruptibility_state = vmread(vmcs::guest::INTERRUPTIBILITY_STATE);
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, interruptibility_state | BLOCKING_BY_NMI);
+    }
+
+    EventInjection::vmentry_inject_event(
+        interruption_info.vector,
+        interruption_info.interruption_type,
+        interruption_info.error_code_valid.then_some(error_code),
+        vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN) as u32,
+    );
+}
+
+/// Handles debug (`#DB`) exceptions.
+///
+/// Breakpoint conditions detected for hypervisor-owned hardware breakpoints invoke their callbacks. Any other
+/// debug condition is passed to the exception callback registered for `#DB`, and reflected to the guest with DR6
+/// updated as the processor would have.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+/// * `interruption_info` - The VM-exit interruption information of the exception.
+///
+/// # Returns
+///
+/// * `ExitType` - The result of the exception callback for the debug conditions of the guest.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.1 Basic VM-Exit Information (Table 28-1. Exit Qualification for Debug Exceptions)
+#[rustfmt::skip]
+fn handle_debug_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx, interruption_info: &VmExitInterruptionInformation) -> ExitType {
+    log::debug!("Debug Exception");
+
+    /// The B0-B3, BD and BS bits of the exit qualification, which have the same positions in DR6.
+    const DR6_CONDITIONS: u64 = 0xF | (1 << 13) | (1 << 14);
+
+    /// The resume flag of RFLAGS, which suppresses instruction breakpoints for the next instruction.
+    const RFLAGS_RF: u64 = 1 << 16;
+
+    let conditions = vmread(vmcs::ro::EXIT_QUALIFICATION) & DR6_CONDITIONS;
+    let mut guest_conditions = conditions;
+
+    if let Some(hardware_breakpoints) = unsafe { vmx.shared_data.as_ref() }.hardware_breakpoints.as_deref() {
+        guest_conditions &= !(hardware_breakpoints.reserved_conditions() | vmx.released_breakpoint_conditions);
+
+        let guest_rip = guest_registers.rip;
+        let mut resume = false;
+
+        for breakpoint in hardware_breakpoints.hits(conditions) {
+            log::trace!("Hardware breakpoint {:?} hit at RIP: {:#x}", breakpoint.slot, guest_registers.rip);
+            (breakpoint.callback)(guest_registers, breakpoint);
+            resume |= breakpoint.is_execute();
+        }
+
+        // Execute breakpoints are faults, so the instruction is resumed without triggering them again.
+        if resume && guest_registers.rip == guest_rip {
+            guest_registers.rflags |= RFLAGS_RF;
+        }
+
+        vmwrite(vmcs::guest::RIP, guest_registers.rip);
+        vmwrite(vmcs::guest::RSP, guest_registers.rsp);
+        vmwrite(vmcs::guest::RFLAGS, guest_registers.rflags);
+    }
+
+    if guest_conditions == 0 {
+        return ExitType::Continue;
+    }
+
+    let exception = InterceptedException { vector: ExceptionInterrupt::Debug, error_code: None, exit_qualification: guest_conditions };
+
+    handle_guest_exception(guest_registers, vmx, &exception, interruption_info, || {
+        // DR6 is not updated by debug exceptions that cause VM exits.
+        dr6_write((dr6() & !0xF) | guest_conditions);
+        EventInjection::vmentry_inject_db();
+        log::debug!("Debug exception reflected to the guest: {:#x}", guest_conditions);
+    })
+}
+
 /// Handles breakpoint (`#BP`) exceptions specifically.
 ///
 /// When a breakpoint exception occurs, this function checks for a registered hook
-/// at the current instruction pointer (RIP). If a hook is found, it transfers control
-/// to the hook's handler. Otherwise, it injects a breakpoint exception into the VM.
+/// at the current instruction pointer (RIP) that takes effect for the guest CR3. If a hook is found, it transfers control
+/// to the hook's handler. If the breakpoint belongs to a process-scoped hook of another process, the original
+/// instruction is single-stepped instead. Otherwise, the breakpoint exception must be reflected to the VM.
 ///
 /// # Arguments
 ///
 /// * `guest_registers` - A mutable reference to the guest's current register state.
 /// * `vmx` - A mutable reference to the Vmx structure.
-fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) {
+///
+/// # Returns
+///
+/// * `bool` - `true` if the breakpoint belongs to a hook, or `false` if it must be reflected to the guest.
+fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> bool {
     log::debug!("Breakpoint Exception");
 
-    let hook_manager = unsafe { vmx.shared_data.as_mut().hook_manager.as_mut() };
+    let start_tsc = vmx.vmstack.exit_tsc;
+    let shared_data = unsafe { vmx.shared_data.as_mut() };
+    let guest_cr3 = vmread(vmcs::guest::CR3);
 
-    log::trace!("Finding hook for RIP: {:#x}", guest_registers.rip);
+    log::trace!("Finding hook for RIP: {:#x} CR3: {:#x}", guest_registers.rip, guest_cr3);
 
     // Find the handler address for the current instruction pointer (RIP) and
     // transfer the execution to it. If we couldn't find a hook, we inject the
     // #BP exception.
     //
-    if let Some(Some(handler)) =
-        hook_manager
-            .find_hook_by_address(guest_registers.rip)
-            .map(|hook| {
+    if let Some((hook, handler)) =
+        shared_data.hook_manager
+            .find_hook_by_address_for_cr3(guest_registers.rip, guest_cr3)
+            .and_then(|hook| {
                 log::trace!("Found hook for RIP: {:#x}", guest_registers.rip);
-                if let HookType::Function { inline_hook } = &hook.hook_type {
-                    log::trace!("Getting handler address");
-                    Some(inline_hook.handler_address())
-                } else {
-                    None
-                }
+                log::trace!("Getting handler address");
+                hook.hook_type.inline_hook().map(|inline_hook| (hook, inline_hook.handler_address()))
             })
     {
         // Call our hook handle function (it will automatically call trampoline).
@@ -113,11 +266,85 @@ fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vmx: &mut V
         guest_registers.rip = handler;
         vmwrite(vmcs::guest::RIP, guest_registers.rip);
 
+        hook.statistics.record_invocation(rdtsc() - start_tsc);
+
         log::debug!("Breakpoint (int3) hook handled successfully!");
+    } else if let Some(hook) = shared_data.hook_manager.find_hook_by_address(guest_registers.rip) {
+        // The breakpoint belongs to a process-scoped hook of another process that shares the page,
+        // so the original instruction is executed from the original page in the single-step view of this processor.
+        let original_page = hook.original_pa.align_down_to_base_page().as_u64();
+        log::trace!("Single-stepping original instruction of process-scoped hook: {:#x}", guest_registers.rip);
+
+        if let Err(err) = single_step_page(vmx, original_page, original_page, AccessType::READ_WRITE_EXECUTE) {
+            log::error!("Failed to single-step page {:#x}: {}", original_page, err);
+        }
+
+        hook.statistics.record_exit(rdtsc() - start_tsc);
+
+        log::debug!("Breakpoint (int3) hook bypassed successfully!");
     } else {
-        EventInjection::vmentry_inject_bp();
-        log::debug!("Breakpoint exception handled successfully!");
+        return false;
+    };
+
+    true
+}
+
+/// Handles `SYSCALL` and `SYSRET` instructions that raised #UD because EFER.SCE is cleared for the guest.
+///
+/// The instruction is only emulated if it would not raise #UD without the hypervisor: the guest's own EFER.SCE must
+/// be set and the guest must execute in 64-bit mode, which is checked before the instruction is read.
+///
+/// For `SYSCALL`, the callback registered for the syscall number in `rax` is invoked before the instruction is emulated,
+/// so the guest continues at the real dispatcher in `IA32_LSTAR`. `SYSRET` is emulated including its #GP conditions.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the instruction was handled, or `false` if the #UD must be reflected to the guest.
+#[rustfmt::skip]
+fn handle_syscall_instruction(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> bool {
+    let Some(syscall_hooks) = unsafe { vmx.shared_data.as_ref() }.syscall_hooks.as_ref() else {
+        return false;
     };
+
+    // SYSCALL and SYSRET raise #UD with the guest's EFER.SCE cleared or outside of 64-bit mode on Intel processors,
+    // before any other check.
+    if !vmx.guest_efer_sce || !is_guest_in_64bit_mode() {
+        return false;
+    }
+
+    let Some((instruction, instruction_length)) = SyscallInstruction::decode(guest_registers.rip) else {
+        return false;
+    };
+
+    log::trace!("Emulating {:?} at RIP: {:#x}", instruction, guest_registers.rip);
+
+    match instruction {
+        SyscallInstruction::Syscall => {
+            if let Some(callback) = syscall_hooks.find_callback(guest_registers.rax as u32) {
+                log::trace!("Invoking syscall callback for: {:#x}", guest_registers.rax as u32);
+                callback(guest_registers);
+            }
+
+            emulate_syscall(guest_registers, instruction_length);
+        },
+        SyscallInstruction::Sysret { rex_w } => {
+            // SYSRET is privileged and requires a canonical return address when returning to 64-bit mode.
+            let cpl = (vmread(vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0x3;
+            if cpl != 0 || (rex_w && !is_canonical(guest_registers.rcx)) {
+                EventInjection::vmentry_inject_gp(0);
+                return true;
+            }
+
+            emulate_sysret(guest_registers, rex_w);
+        },
+    }
+
+    true
 }
 
 /// Handles undefined opcode (`#UD`) exceptions.
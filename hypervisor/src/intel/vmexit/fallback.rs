@@ -0,0 +1,277 @@
+//! Provides the default handlers for the VM exit reasons that have no dedicated module.
+//!
+//! Every basic exit reason is handled in one of three ways:
+//! - Instructions that can be executed on behalf of the guest, or that may complete without effect, are emulated.
+//! - Exits of events the guest can still receive, such as external interrupts, resume the guest unchanged.
+//! - Exits that can't occur with the VM-execution controls in use, or that leave the guest unable to continue,
+//!   are fatal: the VMCS and guest state are dumped, the processor is devirtualized and the system is bugchecked.
+
+use {
+    crate::{
+        intel::{
+            events::EventInjection,
+            invvpid::{invvpid_individual_address, invvpid_single_context, VPID_TAG},
+            support::{vmread, vmwrite, vmxoff},
+            vmerror::VmxBasicExitReason,
+            vmexit::{cr::guest_cr4, set_guest_gpr, ExitType},
+            vmx::Vmx,
+        },
+        utils::capture::GuestRegisters,
+    },
+    x86::{
+        bits64::rflags::RFlags,
+        controlregs::Cr4,
+        cpuid::cpuid,
+        vmx::vmcs::{control, guest, ro},
+    },
+};
+
+/// The HYPERVISOR_ERROR bug check code, indicating that the hypervisor has encountered a fatal error.
+const HYPERVISOR_ERROR: u32 = 0x20001;
+
+/// Handles the exits of instructions that may complete without effect: HLT, PAUSE, MONITOR and MWAIT.
+///
+/// HLT and MWAIT may return on any event, and PAUSE and MONITOR are hints, so skipping them keeps the guest correct.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the instruction in the VM.
+pub fn handle_nop_instruction(basic_exit_reason: VmxBasicExitReason) -> ExitType {
+    log::debug!("Handling {:?} VM exit as a no-op...", basic_exit_reason);
+
+    ExitType::IncrementRIP
+}
+
+/// Handles the INVLPG VM exit by invalidating the guest mappings of the linear address.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `INVLPG` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-1. Exit Qualification for INVLPG
+pub fn handle_invlpg() -> ExitType {
+    log::debug!("Handling INVLPG VM exit...");
+
+    let linear_address = vmread(ro::EXIT_QUALIFICATION);
+    invvpid_individual_address(VPID_TAG, linear_address);
+
+    log::debug!("INVLPG VM exit handled successfully!");
+
+    ExitType::IncrementRIP
+}
+
+/// Handles the INVPCID VM exit by invalidating all guest mappings.
+///
+/// Every INVPCID type invalidates a subset of the mappings of the guest's VPID, so a single-context INVVPID
+/// is always sufficient, at the cost of invalidating more than requested.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `INVPCID` instruction in the VM.
+pub fn handle_invpcid() -> ExitType {
+    log::debug!("Handling INVPCID VM exit...");
+
+    invvpid_single_context(VPID_TAG);
+
+    log::debug!("INVPCID VM exit handled successfully!");
+
+    ExitType::IncrementRIP
+}
+
+/// Handles the RDPMC VM exit by reading the requested performance-monitoring counter.
+///
+/// The counter index is validated against CPUID leaf 0AH first, as an invalid index would cause a #GP in the host.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDPMC` instruction in the VM.
+/// * `ExitType::Continue` - If a #GP was injected for an inaccessible or invalid counter.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: RDPMC—Read Performance-Monitoring Counters
+#[rustfmt::skip]
+pub fn handle_rdpmc(guest_registers: &mut GuestRegisters) -> ExitType {
+    log::debug!("Handling RDPMC VM exit...");
+
+    const FIXED_COUNTER: u32 = 1 << 30;
+
+    let counter = guest_registers.rcx as u32;
+    let pce = guest_cr4() & Cr4::CR4_ENABLE_PPMC.bits() as u64 != 0;
+    let cpl = (vmread(guest::SS_ACCESS_RIGHTS) >> 5) & 0x3;
+
+    let architectural_pmu = cpuid!(0xA);
+    let valid = match counter & FIXED_COUNTER != 0 {
+        true => (counter & !FIXED_COUNTER) < architectural_pmu.edx & 0x1F,
+        false => counter < (architectural_pmu.eax >> 8) & 0xFF,
+    };
+
+    if (cpl != 0 && !pce) || !valid {
+        log::trace!("RDPMC of counter {:#x} is not allowed", counter);
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    let (low, high): (u32, u32);
+    unsafe { core::arch::asm!("rdpmc", in("ecx") counter, out("eax") low, out("edx") high, options(nomem, nostack)) };
+
+    guest_registers.rax = low as u64;
+    guest_registers.rdx = high as u64;
+
+    log::debug!("RDPMC VM exit handled successfully!");
+
+    ExitType::IncrementRIP
+}
+
+/// Handles the RDRAND and RDSEED VM exits by executing the instruction for the guest.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `basic_exit_reason` - Either `VmxBasicExitReason::Rdrand` or `VmxBasicExitReason::Rdseed`.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-12. Format of the VM-Exit
+/// Instruction-Information Field as Used for RDRAND, RDSEED, TPAUSE, and UMWAIT
+#[rustfmt::skip]
+pub fn handle_random_instruction(guest_registers: &mut GuestRegisters, basic_exit_reason: VmxBasicExitReason) -> ExitType {
+    log::debug!("Handling {:?} VM exit...", basic_exit_reason);
+
+    // Bits 6:3 of the VM-exit instruction information hold the destination register, and bits 12:11 the operand size.
+    let instruction_info = vmread(ro::VMEXIT_INSTRUCTION_INFO);
+    let register = ((instruction_info >> 3) & 0xF) as u8;
+    let operand_size = (instruction_info >> 11) & 0x3;
+
+    let (random, success): (u64, u8);
+    unsafe {
+        match basic_exit_reason {
+            VmxBasicExitReason::Rdseed => core::arch::asm!("rdseed {}", "setc {}", out(reg) random, out(reg_byte) success, options(nomem, nostack)),
+            _ => core::arch::asm!("rdrand {}", "setc {}", out(reg) random, out(reg_byte) success, options(nomem, nostack)),
+        }
+    };
+
+    // A 16-bit destination preserves the upper bits of the register, while a 32-bit destination zero-extends it.
+    let value = match operand_size {
+        0 => (guest_registers.gpr(register) & !0xFFFF) | (random & 0xFFFF),
+        1 => random & 0xFFFF_FFFF,
+        _ => random,
+    };
+    set_guest_gpr(guest_registers, register, value);
+
+    // CF reports whether a random value was available, and OF, SF, ZF, AF and PF are cleared.
+    let status_flags = RFlags::FLAGS_OF | RFlags::FLAGS_SF | RFlags::FLAGS_ZF | RFlags::FLAGS_AF | RFlags::FLAGS_PF | RFlags::FLAGS_CF;
+    guest_registers.rflags &= !status_flags.bits();
+    if success != 0 {
+        guest_registers.rflags |= RFlags::FLAGS_CF.bits();
+    }
+    vmwrite(guest::RFLAGS, guest_registers.rflags);
+
+    log::debug!("{:?} VM exit handled successfully!", basic_exit_reason);
+
+    ExitType::IncrementRIP
+}
+
+/// Handles the interrupt-window and NMI-window VM exits by disabling the exiting control that caused them.
+///
+/// The queued event the guest is now ready to receive is injected at the end of the VM exit by `EventQueue::deliver`,
+/// which enables the control again while blocked events remain.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - Either `VmxBasicExitReason::InterruptWindow` or `VmxBasicExitReason::NmiWindow`.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - To resume the guest, which is ready to receive the event.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.7.5 Interrupt-Window Exiting and Virtual-Interrupt Delivery
+/// and 26.7.6 NMI-Window Exiting.
+#[rustfmt::skip]
+pub fn handle_event_window(basic_exit_reason: VmxBasicExitReason) -> ExitType {
+    log::debug!("Handling {:?} VM exit...", basic_exit_reason);
+
+    let window_exiting = match basic_exit_reason {
+        VmxBasicExitReason::NmiWindow => control::PrimaryControls::NMI_WINDOW_EXITING,
+        _ => control::PrimaryControls::INTERRUPT_WINDOW_EXITING,
+    };
+
+    let primary_controls = vmread(control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    vmwrite(control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls & !(window_exiting.bits() as u64));
+
+    log::debug!("{:?} VM exit handled successfully!", basic_exit_reason);
+
+    ExitType::Continue
+}
+
+/// Handles the VM exits of events that are still delivered to the guest, or that only report a condition:
+/// external interrupts, VMX-preemption timer expiry and bus locks.
+///
+/// External interrupts are not acknowledged on VM exit, so they are delivered through the guest IDT on VM entry.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - To resume the guest without changes.
+pub fn handle_resume(basic_exit_reason: VmxBasicExitReason) -> ExitType {
+    log::debug!("Resuming the guest after {:?} VM exit", basic_exit_reason);
+
+    ExitType::Continue
+}
+
+/// Handles VM exits the guest can't continue from, or that can't occur with the VM-execution controls in use.
+///
+/// The VM exit information, the guest registers and the VMCS are logged, the current processor leaves VMX operation,
+/// and the system is bugchecked with `HYPERVISOR_ERROR`, so that the failure is reported in a crash dump instead
+/// of resuming the guest in an undefined state.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+/// * `guest_registers` - A reference to the guest's current register state.
+/// * `vmx` - A reference to the Vmx structure of the current processor.
+///
+/// Bug check parameters:
+/// 1. The exit reason.
+/// 2. The exit qualification.
+/// 3. The guest RIP.
+/// 4. The address of the guest registers.
+#[rustfmt::skip]
+pub fn handle_fatal_exit(basic_exit_reason: VmxBasicExitReason, guest_registers: &GuestRegisters, vmx: &Vmx) -> ! {
+    let exit_reason = vmread(ro::EXIT_REASON);
+    let exit_qualification = vmread(ro::EXIT_QUALIFICATION);
+
+    log::error!("Fatal VM exit: {:?} ({})", basic_exit_reason, basic_exit_reason);
+    log::error!("Exit reason: {:#x}, exit qualification: {:#x}", exit_reason, exit_qualification);
+    log::error!("VM-exit interruption information: {:#x}, error code: {:#x}", vmread(ro::VMEXIT_INTERRUPTION_INFO), vmread(ro::VMEXIT_INTERRUPTION_ERR_CODE));
+    log::error!("IDT-vectoring information: {:#x}, error code: {:#x}", vmread(ro::IDT_VECTORING_INFO), vmread(ro::IDT_VECTORING_ERR_CODE));
+    log::error!("Guest linear address: {:#x}, guest physical address: {:#x}", vmread(ro::GUEST_LINEAR_ADDR), vmread(ro::GUEST_PHYSICAL_ADDR_FULL));
+    log::error!("VM-instruction error: {:#x}", vmread(ro::VM_INSTRUCTION_ERROR));
+    log::error!("Guest registers: {:#x?}", guest_registers);
+    log::error!("VMCS: {:#x?}", vmx.vmcs_region);
+
+    if let Err(error) = vmxoff() {
+        log::error!("Failed to devirtualize the processor: {}", error);
+    }
+
+    unsafe {
+        wdk_sys::ntddk::KeBugCheckEx(
+            HYPERVISOR_ERROR,
+            exit_reason,
+            exit_qualification,
+            guest_registers.rip,
+            guest_registers as *const _ as u64,
+        )
+    }
+}
@@ -0,0 +1,217 @@
+//! Handles INIT signal and start-up IPI (SIPI) VM exits, emulating the reset of the processor.
+//!
+//! An INIT signal received in VMX non-root operation causes a VM exit instead of resetting the processor. The guest
+//! state is reset as the INIT signal would have, and the guest waits for a SIPI in the wait-for-SIPI activity state.
+//! The SIPI then starts the guest in real mode at the start-up vector, which requires the unrestricted guest control.
+
+use {
+    crate::{
+        intel::{
+            events::ACTIVITY_STATE_WAIT_FOR_SIPI,
+            invvpid::{invvpid_single_context, VPID_TAG},
+            support::{vmread, vmwrite},
+            vmerror::VmxBasicExitReason,
+            vmexit::{
+                cr::{adjust_fixed_bits, guest_cr0},
+                dr::reset_debug_registers,
+                fallback::handle_fatal_exit,
+                ExitType,
+            },
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, instructions::rdmsr},
+    },
+    x86::{
+        controlregs::Cr0,
+        cpuid::cpuid,
+        msr,
+        vmx::vmcs::{
+            control::{self, EntryControls, ExitControls, SecondaryControls},
+            guest, host, ro,
+        },
+    },
+};
+
+/// The active activity state.
+const ACTIVITY_STATE_ACTIVE: u64 = 0;
+
+/// The bit of IA32_VMX_MISC reporting support for the wait-for-SIPI activity state.
+const VMX_MISC_WAIT_FOR_SIPI: u64 = 1 << 8;
+
+/// The access rights of CS after INIT: present, accessed, readable code segment.
+const CS_ACCESS_RIGHTS_INIT: u64 = 0x9B;
+
+/// The access rights of the data segments after INIT: present, accessed, writable data segment.
+const DATA_ACCESS_RIGHTS_INIT: u64 = 0x93;
+
+/// The access rights of LDTR after INIT: present LDT.
+const LDTR_ACCESS_RIGHTS_INIT: u64 = 0x82;
+
+/// The access rights of TR after INIT: present, busy 32-bit TSS.
+const TR_ACCESS_RIGHTS_INIT: u64 = 0x8B;
+
+/// The data segments, as their selector, base, limit and access rights fields.
+const DATA_SEGMENTS: [(u32, u32, u32, u32); 5] = [
+    (
+        guest::SS_SELECTOR,
+        guest::SS_BASE,
+        guest::SS_LIMIT,
+        guest::SS_ACCESS_RIGHTS,
+    ),
+    (
+        guest::DS_SELECTOR,
+        guest::DS_BASE,
+        guest::DS_LIMIT,
+        guest::DS_ACCESS_RIGHTS,
+    ),
+    (
+        guest::ES_SELECTOR,
+        guest::ES_BASE,
+        guest::ES_LIMIT,
+        guest::ES_ACCESS_RIGHTS,
+    ),
+    (
+        guest::FS_SELECTOR,
+        guest::FS_BASE,
+        guest::FS_LIMIT,
+        guest::FS_ACCESS_RIGHTS,
+    ),
+    (
+        guest::GS_SELECTOR,
+        guest::GS_BASE,
+        guest::GS_LIMIT,
+        guest::GS_ACCESS_RIGHTS,
+    ),
+];
+
+/// Handles the INIT signal VM exit by resetting the guest state and waiting for a SIPI.
+///
+/// INIT signals are blocked while the guest is in VMX root operation of nested virtualization, so they are discarded.
+/// The guest's EFER is loaded from the VMCS from then on, since the host's EFER can't be cleared in 64-bit mode.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - To resume the guest in the wait-for-SIPI activity state.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 10.1.1 Processor State After Reset,
+/// Table 10-1. IA-32 and Intel 64 Processor States Following Power-up, Reset, or INIT and 26.2 OTHER CAUSES OF VM EXITS
+#[rustfmt::skip]
+pub fn handle_init_signal(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling INIT signal VM exit...");
+
+    if vmx.nested.as_deref().is_some_and(|nested| nested.in_vmx_operation()) {
+        log::trace!("INIT signal blocked in VMX root operation of the guest");
+        return ExitType::Continue;
+    }
+
+    let unrestricted_guest = vmread(control::SECONDARY_PROCBASED_EXEC_CONTROLS) & SecondaryControls::UNRESTRICTED_GUEST.bits() as u64 != 0;
+    if !unrestricted_guest || rdmsr(msr::IA32_VMX_MISC) & VMX_MISC_WAIT_FOR_SIPI == 0 {
+        log::error!("INIT signal can't be emulated without unrestricted guest and wait-for-SIPI support");
+        handle_fatal_exit(VmxBasicExitReason::InitSignal, guest_registers, vmx);
+    }
+
+    // CR0.CD and CR0.NW are unchanged by INIT, and CR0.ET is set. PE and PG are cleared, which an unrestricted guest allows.
+    let paging_and_protection = (Cr0::CR0_ENABLE_PAGING | Cr0::CR0_PROTECTED_MODE).bits() as u64;
+    let cr0 = (guest_cr0() & (Cr0::CR0_CACHE_DISABLE | Cr0::CR0_NOT_WRITE_THROUGH).bits() as u64) | Cr0::CR0_EXTENSION_TYPE.bits() as u64;
+    vmwrite(control::CR0_READ_SHADOW, cr0);
+    vmwrite(guest::CR0, adjust_fixed_bits(cr0, msr::IA32_VMX_CR0_FIXED0, msr::IA32_VMX_CR0_FIXED1) & !paging_and_protection);
+    vmwrite(control::CR4_READ_SHADOW, 0u64);
+    vmwrite(guest::CR4, adjust_fixed_bits(0, msr::IA32_VMX_CR4_FIXED0, msr::IA32_VMX_CR4_FIXED1));
+    vmwrite(guest::CR3, 0u64);
+    unsafe { x86::controlregs::cr2_write(0) };
+
+    // The guest's EFER is cleared, which deactivates IA-32e mode.
+    vmwrite(control::VMENTRY_CONTROLS, (vmread(control::VMENTRY_CONTROLS) | EntryControls::LOAD_IA32_EFER.bits() as u64) & !(EntryControls::IA32E_MODE_GUEST.bits() as u64));
+    vmwrite(control::VMEXIT_CONTROLS, vmread(control::VMEXIT_CONTROLS) | (ExitControls::SAVE_IA32_EFER | ExitControls::LOAD_IA32_EFER).bits() as u64);
+    vmwrite(host::IA32_EFER_FULL, rdmsr(msr::IA32_EFER));
+    vmwrite(guest::IA32_EFER_FULL, 0u64);
+    vmx.guest_efer_sce = false;
+
+    vmwrite(guest::CS_SELECTOR, 0xF000u64);
+    vmwrite(guest::CS_BASE, 0xFFFF_0000u64);
+    vmwrite(guest::CS_LIMIT, 0xFFFFu64);
+    vmwrite(guest::CS_ACCESS_RIGHTS, CS_ACCESS_RIGHTS_INIT);
+
+    for (selector, base, limit, access_rights) in DATA_SEGMENTS {
+        vmwrite(selector, 0u64);
+        vmwrite(base, 0u64);
+        vmwrite(limit, 0xFFFFu64);
+        vmwrite(access_rights, DATA_ACCESS_RIGHTS_INIT);
+    }
+
+    vmwrite(guest::LDTR_SELECTOR, 0u64);
+    vmwrite(guest::LDTR_BASE, 0u64);
+    vmwrite(guest::LDTR_LIMIT, 0xFFFFu64);
+    vmwrite(guest::LDTR_ACCESS_RIGHTS, LDTR_ACCESS_RIGHTS_INIT);
+    vmwrite(guest::TR_SELECTOR, 0u64);
+    vmwrite(guest::TR_BASE, 0u64);
+    vmwrite(guest::TR_LIMIT, 0xFFFFu64);
+    vmwrite(guest::TR_ACCESS_RIGHTS, TR_ACCESS_RIGHTS_INIT);
+    vmwrite(guest::GDTR_BASE, 0u64);
+    vmwrite(guest::GDTR_LIMIT, 0xFFFFu64);
+    vmwrite(guest::IDTR_BASE, 0u64);
+    vmwrite(guest::IDTR_LIMIT, 0xFFFFu64);
+
+    reset_debug_registers(vmx);
+
+    // EDX holds the processor signature, and the other general-purpose registers are cleared.
+    *guest_registers = GuestRegisters {
+        rax: 0, rbx: 0, rcx: 0, rdx: cpuid!(0x1).eax as u64, rdi: 0, rsi: 0, rbp: 0,
+        r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0,
+        rip: 0xFFF0, rsp: 0, rflags: 0x2,
+        ..*guest_registers
+    };
+    vmwrite(guest::RIP, guest_registers.rip);
+    vmwrite(guest::RSP, guest_registers.rsp);
+    vmwrite(guest::RFLAGS, guest_registers.rflags);
+
+    // Events pending before the INIT signal are discarded, and the guest waits for a SIPI.
+    vmx.pending_events.clear();
+    vmwrite(control::VMENTRY_INTERRUPTION_INFO_FIELD, 0u32);
+    vmwrite(guest::INTERRUPTIBILITY_STATE, 0u32);
+    vmwrite(guest::PENDING_DBG_EXCEPTIONS, 0u64);
+    vmwrite(guest::ACTIVITY_STATE, ACTIVITY_STATE_WAIT_FOR_SIPI);
+
+    invvpid_single_context(VPID_TAG);
+
+    log::debug!("INIT signal VM exit handled successfully!");
+
+    ExitType::Continue
+}
+
+/// Handles the start-up IPI VM exit by starting the guest in real mode at the start-up vector.
+///
+/// SIPIs only cause VM exits in the wait-for-SIPI activity state, in which the guest was placed by the INIT signal.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - To resume the guest at the start-up routine.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 12.4.4.2 MP Initialization Protocol Algorithm
+/// and Table 28-1. Exit Qualification for SIPI
+pub fn handle_startup_ipi(guest_registers: &mut GuestRegisters) -> ExitType {
+    log::debug!("Handling SIPI VM exit...");
+
+    // Bits 7:0 of the exit qualification hold the start-up vector, which selects the 4KB page of the start-up routine.
+    let vector = vmread(ro::EXIT_QUALIFICATION) & 0xFF;
+
+    vmwrite(guest::CS_SELECTOR, vector << 8);
+    vmwrite(guest::CS_BASE, vector << 12);
+
+    guest_registers.rip = 0;
+    vmwrite(guest::RIP, guest_registers.rip);
+    vmwrite(guest::ACTIVITY_STATE, ACTIVITY_STATE_ACTIVE);
+
+    log::debug!("SIPI VM exit handled successfully!");
+
+    ExitType::Continue
+}
@@ -24,3 +24,20 @@ pub fn handle_invd(_guest_registers: &mut GuestRegisters) -> ExitType {
 
     ExitType::IncrementRIP
 }
+
+/// Manages the WBINVD and WBNOINVD instruction VM exits by performing a WBINVD,
+/// which writes back the modified cache lines like both instructions.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `WBINVD` or `WBNOINVD` instruction in the VM.
+pub fn handle_wbinvd() -> ExitType {
+    log::debug!("Handling WBINVD VM exit...");
+
+    // WBINVD also invalidates the caches, which WBNOINVD retains, but the result is the same as seen by the guest.
+    wbinvd();
+
+    log::debug!("WBINVD VMEXIT handled successfully!");
+
+    ExitType::IncrementRIP
+}
@@ -0,0 +1,216 @@
+//! Handles I/O instruction VM exits for the ports set in the I/O bitmaps.
+//!
+//! The access is decoded from the exit qualification and passed to the callback registered for its port and direction,
+//! which decides whether it's forwarded to the port or completed by the hypervisor. INS and OUTS are emulated element
+//! by element on guest memory, including their REP forms.
+
+use {
+    crate::{
+        intel::{
+            events::EventInjection,
+            io::{IoAccess, IoAction, IoCallback},
+            support::vmread,
+            vmerror::{IoAccessDirection, IoInstructionExitQualification},
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::{
+            capture::GuestRegisters,
+            guest_memory::{read_guest_memory, write_guest_memory},
+        },
+    },
+    x86::{
+        bits64::rflags::RFlags,
+        vmx::vmcs::{guest, ro},
+    },
+};
+
+/// The maximum number of elements of a REP INS or REP OUTS emulated per VM exit.
+///
+/// The instruction is restarted while elements remain, so pending interrupts are delivered in between.
+const MAX_STRING_ELEMENTS: u64 = 64;
+
+/// Handles an I/O instruction VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the emulated instruction.
+/// * `ExitType::Continue` - If a REP string instruction has elements left, or an exception was injected.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+/// and Table 28-5. Exit Qualification for I/O Instructions.
+#[rustfmt::skip]
+pub fn handle_io_instruction(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling I/O instruction VM exit...");
+
+    let qualification = IoInstructionExitQualification::from_exit_qualification(vmread(ro::EXIT_QUALIFICATION));
+    log::trace!("{}", qualification);
+
+    let shared_data = unsafe { vmx.shared_data.as_ref() };
+    let callback = shared_data.io_hooks.as_deref().and_then(|hooks| hooks.find_callback(qualification.port, qualification.direction));
+
+    let exit_type = match qualification.string {
+        true => handle_string_io(guest_registers, &qualification, callback),
+        false => {
+            let mut access = IoAccess {
+                port: qualification.port,
+                size: qualification.size,
+                direction: qualification.direction,
+                value: (guest_registers.rax & size_mask(qualification.size)) as u32,
+            };
+
+            perform_access(&mut access, callback);
+
+            if access.direction == IoAccessDirection::In {
+                // Like other 32-bit register writes, a 32-bit IN zero-extends into RAX.
+                guest_registers.rax = match access.size {
+                    4 => access.value as u64,
+                    _ => (guest_registers.rax & !size_mask(access.size)) | access.value as u64,
+                };
+            }
+
+            ExitType::IncrementRIP
+        }
+    };
+
+    log::debug!("I/O instruction VM exit handled successfully!");
+
+    exit_type
+}
+
+/// Emulates INS, OUTS and their REP forms on guest memory.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `qualification` - The decoded exit qualification of the instruction.
+/// * `callback` - The callback registered for the port and direction, if any.
+///
+/// # Returns
+///
+/// * `ExitType` - `ExitType::Continue` to restart the instruction, or `ExitType::IncrementRIP` once it completes.
+#[rustfmt::skip]
+fn handle_string_io(guest_registers: &mut GuestRegisters, qualification: &IoInstructionExitQualification, callback: Option<IoCallback>) -> ExitType {
+    // Bits 9:7 of the VM-exit instruction information hold the address size: 0 = 16-bit, 1 = 32-bit, 2 = 64-bit.
+    let address_mask = match (vmread(ro::VMEXIT_INSTRUCTION_INFO) >> 7) & 0x7 {
+        0 => 0xFFFF,
+        1 => 0xFFFF_FFFF,
+        _ => u64::MAX,
+    };
+
+    let guest_cr3 = vmread(guest::CR3);
+    let linear_address = vmread(ro::GUEST_LINEAR_ADDR);
+    let step = match RFlags::from_bits_truncate(guest_registers.rflags).contains(RFlags::FLAGS_DF) {
+        true => (qualification.size as u64).wrapping_neg(),
+        false => qualification.size as u64,
+    };
+
+    let remaining = match qualification.rep {
+        true => guest_registers.rcx & address_mask,
+        false => 1,
+    };
+
+    for element in 0..remaining.min(MAX_STRING_ELEMENTS) {
+        let address = linear_address.wrapping_add(element.wrapping_mul(step));
+        let mut buffer = [0u8; 4];
+        let bytes = &mut buffer[..qualification.size as usize];
+
+        let mut access = IoAccess {
+            port: qualification.port,
+            size: qualification.size,
+            direction: qualification.direction,
+            value: 0,
+        };
+
+        let completed = match access.direction {
+            IoAccessDirection::Out => read_guest_memory(guest_cr3, address, bytes).map(|_| {
+                access.value = u32::from_le_bytes(buffer);
+                perform_access(&mut access, callback);
+            }),
+            // The destination is translated before the port is read, so a page fault doesn't consume the data of the port.
+            IoAccessDirection::In => read_guest_memory(guest_cr3, address, bytes).and_then(|_| {
+                perform_access(&mut access, callback);
+                write_guest_memory(guest_cr3, address, &access.value.to_le_bytes()[..access.size as usize])
+            }),
+        };
+
+        if completed.is_none() {
+            log::trace!("I/O string operand at {:#x} is not mapped", address);
+            inject_page_fault(address, access.direction);
+            return ExitType::Continue;
+        }
+
+        // Registers are updated per element so a fault on a later element restarts the instruction where it stopped.
+        match access.direction {
+            IoAccessDirection::Out => guest_registers.rsi = advance(guest_registers.rsi, step, address_mask),
+            IoAccessDirection::In => guest_registers.rdi = advance(guest_registers.rdi, step, address_mask),
+        }
+
+        if qualification.rep {
+            guest_registers.rcx = advance(guest_registers.rcx, u64::MAX, address_mask);
+        }
+    }
+
+    match remaining > MAX_STRING_ELEMENTS {
+        true => ExitType::Continue,
+        false => ExitType::IncrementRIP,
+    }
+}
+
+/// Invokes the callback of an access, and performs it on the port unless the callback completed it.
+fn perform_access(access: &mut IoAccess, callback: Option<IoCallback>) {
+    let action = callback.map_or(IoAction::Forward, |callback| callback(access));
+
+    log::trace!("I/O access: {:x?}, action: {:?}", access, action);
+
+    if action == IoAction::Forward {
+        access.forward();
+    }
+}
+
+/// Injects a page fault for an unmapped string operand of INS or OUTS.
+///
+/// # Arguments
+///
+/// * `address` - The faulting linear address, reported to the guest in CR2.
+/// * `direction` - The direction of the access; INS writes to memory and OUTS reads from it.
+fn inject_page_fault(address: u64, direction: IoAccessDirection) {
+    const PF_WRITE: u32 = 1 << 1;
+    const PF_USER: u32 = 1 << 2;
+
+    // The current privilege level is the DPL of SS, in bits 6:5 of its access rights.
+    let cpl = (vmread(guest::SS_ACCESS_RIGHTS) >> 5) & 0x3;
+
+    let mut error_code = 0;
+    if direction == IoAccessDirection::In {
+        error_code |= PF_WRITE;
+    }
+    if cpl == 3 {
+        error_code |= PF_USER;
+    }
+
+    unsafe { x86::controlregs::cr2_write(address) };
+    EventInjection::vmentry_inject_pf(error_code);
+}
+
+/// Gets the mask of the bits of RAX accessed by an I/O instruction of the given size.
+fn size_mask(size: u8) -> u64 {
+    (1u64 << (size as u64 * 8)) - 1
+}
+
+/// Adds a step to an index or count register, wrapping within the address size of the instruction.
+///
+/// 16-bit updates preserve the upper bits of the register, and 32-bit updates zero-extend it.
+fn advance(register: u64, step: u64, address_mask: u64) -> u64 {
+    let value = register.wrapping_add(step) & address_mask;
+
+    match address_mask {
+        0xFFFF => (register & !address_mask) | value,
+        _ => value,
+    }
+}
@@ -8,16 +8,37 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            events::{PendingEvent, BLOCKING_BY_MOV_SS, BLOCKING_BY_STI},
+            invept::sync_ept_generation,
+            nested::{
+                apply_nested_window_exiting,
+                ept::resolve_nested_ept_violation,
+                transitions::{exit_nested_guest, is_reflected, nested_vmcs12},
+            },
+            nmi::{queue_host_nmis, request_host_nmi_window},
             support::vmread,
             vmexit::{
                 cpuid::handle_cpuid,
+                cr::handle_cr_access,
+                dr::{handle_mov_dr, sync_hardware_breakpoints},
                 ept::{handle_ept_misconfiguration, handle_ept_violation},
                 exception::{handle_exception, handle_undefined_opcode_exception},
-                invd::handle_invd,
+                fallback::{
+                    handle_event_window, handle_fatal_exit, handle_invlpg, handle_invpcid,
+                    handle_nop_instruction, handle_random_instruction, handle_rdpmc, handle_resume,
+                },
+                init::{handle_init_signal, handle_startup_ipi},
+                invd::{handle_invd, handle_wbinvd},
                 invept::handle_invept,
                 invvpid::handle_invvpid,
+                io::handle_io_instruction,
                 msr::{handle_msr_access, MsrAccessType},
-                rdtsc::handle_rdtsc,
+                mtf::handle_monitor_trap_flag,
+                nested::handle_vmx_instruction,
+                rdtsc::{handle_rdtsc, handle_rdtscp},
+                registry::VmExitInfo,
+                vectoring::reinject_vectored_event,
+                vmcall::handle_vmcall,
                 xsetbv::handle_xsetbv,
             },
             vmx::Vmx,
@@ -28,23 +49,52 @@ use {
 };
 
 pub mod cpuid;
+pub mod cr;
+pub mod dr;
 pub mod ept;
 pub mod exception;
+pub mod fallback;
+pub mod init;
 pub mod invd;
 pub mod invept;
 pub mod invvpid;
+pub mod io;
 pub mod msr;
+pub mod mtf;
+pub mod nested;
 pub mod rdtsc;
+pub mod registry;
+pub mod vectoring;
+pub mod vmcall;
 pub mod xsetbv;
 
 /// Represents the type of VM exit.
-#[derive(PartialOrd, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
 pub enum ExitType {
     ExitHypervisor,
     IncrementRIP,
     Continue,
 }
 
+/// Sets a general-purpose register of the guest by its encoding in VM exit qualifications.
+///
+/// RSP is also written to the VMCS, from which it is loaded on VM entry.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `index` - The register number, as for `GuestRegisters::gpr`.
+/// * `value` - The new value of the register.
+pub fn set_guest_gpr(guest_registers: &mut GuestRegisters, index: u8, value: u64) {
+    const RSP_INDEX: u8 = 4;
+
+    guest_registers.set_gpr(index, value);
+
+    if index & 0xF == RSP_INDEX {
+        vmwrite(guest::RSP, value);
+    }
+}
+
 /// Represents a VM exit, which can be caused by various reasons.
 ///
 /// A VM exit transfers control from the guest to the host (hypervisor).
@@ -76,8 +126,18 @@ impl VmExit {
         guest_registers: &mut GuestRegisters,
         vmx: &mut Vmx,
     ) -> Result<(), HypervisorError> {
+        if let Some(tsc_compensation) = vmx.tsc_compensation.as_mut() {
+            tsc_compensation.start_exit(vmx.vmstack.exit_tsc);
+        }
+
         log::debug!("Handling VMEXIT...");
 
+        // Drop the cached translations of shared EPT entries that another processor changed since the last VM exit.
+        sync_ept_generation(vmx);
+
+        // Hand the debug registers of hardware breakpoints released since the last VM exit back to the guest.
+        sync_hardware_breakpoints(vmx);
+
         // Upon VM-exit, transfer the guest register values from VMCS to `self.registers` to ensure it reflects the latest and complete state.
         guest_registers.rip = vmread(guest::RIP);
         guest_registers.rsp = vmread(guest::RSP);
@@ -97,6 +157,81 @@ impl VmExit {
             guest_registers
         );
 
+        // VM exits of a nested guest caused by the controls of the guest hypervisor are reflected to it, which also
+        // receives the event being delivered in the exit information.
+        let reflected = nested_vmcs12(vmx).is_some_and(|vmcs12| is_reflected(basic_exit_reason, vmcs12));
+
+        // EPT violations of a nested guest on pages the guest hypervisor maps are resolved in the shadow EPT.
+        let nested_ept_resolved = reflected && basic_exit_reason == VmxBasicExitReason::EptViolation && resolve_nested_ept_violation(vmx);
+        let reflected = reflected && !nested_ept_resolved;
+
+        // The event being delivered when the VM exit occurred, which must be injected again.
+        let vectored_event = PendingEvent::from_idt_vectoring().filter(|_| !reflected);
+
+        // Registered handlers take precedence and may chain to the built-in handler.
+        let exit_handlers = unsafe { vmx.shared_data.as_ref() }.exit_handlers.as_deref();
+
+        let exit_type = match exit_handlers {
+            _ if reflected => exit_nested_guest(exit_reason, guest_registers, vmx),
+            _ if nested_ept_resolved => ExitType::Continue,
+            Some(exit_handlers) if exit_handlers.is_registered(basic_exit_reason) => {
+                let info = VmExitInfo::from_vmcs(basic_exit_reason);
+                exit_handlers.dispatch(guest_registers, vmx, &info)
+            }
+            _ => Self::handle_builtin(basic_exit_reason, guest_registers, vmx)?,
+        };
+
+        if let Some(event) = vectored_event {
+            reinject_vectored_event(basic_exit_reason, event, exit_type, guest_registers, vmx);
+        }
+
+        if exit_type == ExitType::IncrementRIP {
+            self.advance_guest_rip(guest_registers);
+        }
+
+        // Queue the NMIs received while handling the VM exit, then inject the next queued event the guest can receive,
+        // now that its state is final.
+        queue_host_nmis(vmx);
+        vmx.pending_events.deliver();
+
+        if let Some(nested) = vmx.nested.as_deref() {
+            apply_nested_window_exiting(nested);
+        }
+
+        // NMIs received since they were queued cause an NMI-window VM exit, now that the controls are final.
+        request_host_nmi_window(vmx);
+
+        log::debug!(
+            "Guest registers after handling vmexit: {:#x?}",
+            guest_registers
+        );
+        log::debug!("VMEXIT handled successfully.");
+
+        // Hide the time spent handling the VM exit from the guest's TSC, as late as possible before VM entry.
+        if let Some(tsc_compensation) = vmx.tsc_compensation.as_mut() {
+            tsc_compensation.finish_exit(&unsafe { vmx.shared_data.as_ref() }.tsc_hidden_cycles);
+        }
+
+        return Ok(());
+    }
+
+    /// Handles a VM exit with the built-in handler of its exit reason.
+    ///
+    /// # Arguments
+    ///
+    /// * `basic_exit_reason` - The basic exit reason of the VM exit.
+    /// * `guest_registers` - A mutable reference to the guest's current register state.
+    /// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+    ///
+    /// # Returns
+    ///
+    /// The `ExitType` of the built-in handler. Every exit reason has a built-in handler, and exits the guest can't
+    /// continue from don't return: they devirtualize the processor and bugcheck the system (see `handle_fatal_exit`).
+    pub fn handle_builtin(
+        basic_exit_reason: VmxBasicExitReason,
+        guest_registers: &mut GuestRegisters,
+        vmx: &mut Vmx,
+    ) -> Result<ExitType, HypervisorError> {
         // Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.2 Instructions That Cause VM Exits Unconditionally:
         // - The following instructions cause VM exits when they are executed in VMX non-root operation: CPUID, GETSEC, INVD, and XSETBV.
         // - This is also true of instructions introduced with VMX, which include: INVEPT, INVVPID, VMCALL, VMCLEAR, VMLAUNCH, VMPTRLD, VMPTRST, VMRESUME, VMXOFF, and VMXON.
@@ -104,42 +239,110 @@ impl VmExit {
         // 26.1.3 Instructions That Cause VM Exits Conditionally: Certain instructions cause VM exits in VMX non-root operation depending on the setting of the VM-execution controls.
         let exit_type = match basic_exit_reason {
             VmxBasicExitReason::ExceptionOrNmi => handle_exception(guest_registers, vmx),
-            VmxBasicExitReason::Cpuid => handle_cpuid(guest_registers),
+            VmxBasicExitReason::Cpuid => handle_cpuid(guest_registers, vmx),
+            VmxBasicExitReason::ControlRegisterAccesses => handle_cr_access(guest_registers, vmx),
+            VmxBasicExitReason::MovDr => handle_mov_dr(guest_registers, vmx),
+            VmxBasicExitReason::IoInstruction => handle_io_instruction(guest_registers, vmx),
+
+            VmxBasicExitReason::Getsec => handle_undefined_opcode_exception(),
+            VmxBasicExitReason::Vmcall => handle_vmcall(guest_registers, vmx),
 
-            // Grouping multiple exit reasons that are handled by the same function
-            VmxBasicExitReason::Getsec
-            | VmxBasicExitReason::Vmcall
-            | VmxBasicExitReason::Vmclear
+            // VMX instructions are emulated for nested virtualization, and raise #UD without it.
+            VmxBasicExitReason::Vmclear
             | VmxBasicExitReason::Vmlaunch
             | VmxBasicExitReason::Vmptrld
             | VmxBasicExitReason::Vmptrst
+            | VmxBasicExitReason::Vmread
             | VmxBasicExitReason::Vmresume
-            | VmxBasicExitReason::Vmxon
-            | VmxBasicExitReason::Vmxoff => handle_undefined_opcode_exception(),
+            | VmxBasicExitReason::Vmwrite
+            | VmxBasicExitReason::Vmxoff
+            | VmxBasicExitReason::Vmxon => {
+                handle_vmx_instruction(basic_exit_reason, guest_registers, vmx)
+            }
+            VmxBasicExitReason::Invept | VmxBasicExitReason::Invvpid if vmx.nested.is_some() => {
+                handle_vmx_instruction(basic_exit_reason, guest_registers, vmx)
+            }
 
-            VmxBasicExitReason::Rdmsr => handle_msr_access(guest_registers, MsrAccessType::Read),
-            VmxBasicExitReason::Wrmsr => handle_msr_access(guest_registers, MsrAccessType::Write),
+            VmxBasicExitReason::Rdmsr => {
+                handle_msr_access(guest_registers, vmx, MsrAccessType::Read)
+            }
+            VmxBasicExitReason::Wrmsr => {
+                handle_msr_access(guest_registers, vmx, MsrAccessType::Write)
+            }
             VmxBasicExitReason::Invd => handle_invd(guest_registers),
             VmxBasicExitReason::Rdtsc => handle_rdtsc(guest_registers),
             VmxBasicExitReason::EptViolation => handle_ept_violation(guest_registers, vmx),
             VmxBasicExitReason::EptMisconfiguration => handle_ept_misconfiguration(),
+            VmxBasicExitReason::MonitorTrapFlag => handle_monitor_trap_flag(guest_registers, vmx),
             VmxBasicExitReason::Invept => handle_invept(),
             VmxBasicExitReason::Invvpid => handle_invvpid(),
             VmxBasicExitReason::Xsetbv => handle_xsetbv(guest_registers),
-            _ => return Err(HypervisorError::UnhandledVmExit),
-        };
+            VmxBasicExitReason::Rdtscp => handle_rdtscp(guest_registers),
+            VmxBasicExitReason::WbinvdOrWbnoinvd => handle_wbinvd(),
+            VmxBasicExitReason::Invlpg => handle_invlpg(),
+            VmxBasicExitReason::Invpcid => handle_invpcid(),
+            VmxBasicExitReason::Rdpmc => handle_rdpmc(guest_registers),
+            VmxBasicExitReason::Rdrand | VmxBasicExitReason::Rdseed => {
+                handle_random_instruction(guest_registers, basic_exit_reason)
+            }
 
-        if exit_type == ExitType::IncrementRIP {
-            self.advance_guest_rip(guest_registers);
-        }
+            // Instructions that may complete without effect.
+            VmxBasicExitReason::Hlt
+            | VmxBasicExitReason::Pause
+            | VmxBasicExitReason::Monitor
+            | VmxBasicExitReason::Mwait => handle_nop_instruction(basic_exit_reason),
 
-        log::debug!(
-            "Guest registers after handling vmexit: {:#x?}",
-            guest_registers
-        );
-        log::debug!("VMEXIT handled successfully.");
+            // Instructions of features that are not exposed to the guest raise #UD, like on a processor without them.
+            VmxBasicExitReason::Vmfunc
+            | VmxBasicExitReason::Encls
+            | VmxBasicExitReason::Enclv
+            | VmxBasicExitReason::Pconfig
+            | VmxBasicExitReason::Loadiwkey
+            | VmxBasicExitReason::Umwait
+            | VmxBasicExitReason::Tpause => handle_undefined_opcode_exception(),
 
-        return Ok(());
+            VmxBasicExitReason::InterruptWindow | VmxBasicExitReason::NmiWindow => {
+                handle_event_window(basic_exit_reason)
+            }
+
+            // Events that are still delivered to the guest, or that only report a condition.
+            VmxBasicExitReason::ExternalInterrupt
+            | VmxBasicExitReason::VmxPreemptionTimerExpired
+            | VmxBasicExitReason::BusLock => handle_resume(basic_exit_reason),
+
+            // INIT and SIPI reset the processor and start it in real mode, as when the guest starts an application processor.
+            VmxBasicExitReason::InitSignal => handle_init_signal(guest_registers, vmx),
+            VmxBasicExitReason::StartupIpi => handle_startup_ipi(guest_registers),
+
+            // Exits the guest can't continue from: a triple fault or a failed VM entry, and task switches which don't
+            // exist in 64-bit mode. XSAVES and XRSTORS only exit for the bits set in the XSS-exiting bitmap, which is
+            // cleared. The remaining exit reasons require VM-execution controls that are never enabled.
+            VmxBasicExitReason::TripleFault
+            | VmxBasicExitReason::IoSystemManagementInterrupt
+            | VmxBasicExitReason::OtherSmi
+            | VmxBasicExitReason::TaskSwitch
+            | VmxBasicExitReason::Rsm
+            | VmxBasicExitReason::VmEntryFailureInvalidGuestState
+            | VmxBasicExitReason::VmEntryFailureMsrLoading
+            | VmxBasicExitReason::VmEntryFailureMachineCheckEvent
+            | VmxBasicExitReason::TprBelowThreshold
+            | VmxBasicExitReason::ApicAccess
+            | VmxBasicExitReason::VirtualizedEoi
+            | VmxBasicExitReason::ApicWrite
+            | VmxBasicExitReason::AccessToGdtrOrIdtr
+            | VmxBasicExitReason::AccessToLdtrOrTr
+            | VmxBasicExitReason::PageModificationLogFull
+            | VmxBasicExitReason::SppRelatedEvent
+            | VmxBasicExitReason::Xsaves
+            | VmxBasicExitReason::Xrstors
+            | VmxBasicExitReason::EnqcmdPasidTranslationFailure
+            | VmxBasicExitReason::EnqcmdsPasidTranslationFailure
+            | VmxBasicExitReason::InstructionTimeout => {
+                handle_fatal_exit(basic_exit_reason, guest_registers, vmx)
+            }
+        };
+
+        Ok(exit_type)
     }
 
     /// Advances the guest's instruction pointer (RIP) after a VM exit.
@@ -153,6 +356,10 @@ impl VmExit {
         let len = vmread(ro::VMEXIT_INSTRUCTION_LEN);
         guest_registers.rip += len;
         vmwrite(guest::RIP, guest_registers.rip);
+
+        // Blocking by STI and MOV SS only lasts for the instruction that was skipped.
+        let interruptibility_state = vmread(guest::INTERRUPTIBILITY_STATE);
+        vmwrite(guest::INTERRUPTIBILITY_STATE, interruptibility_state & !(BLOCKING_BY_STI | BLOCKING_BY_MOV_SS));
         log::trace!("Guest RIP advanced to: {:#x}", vmread(guest::RIP));
     }
 }
@@ -2,14 +2,26 @@
 //! read and write operations. It ensures that guest MSR accesses are properly
 //! intercepted and handled, with support for injecting faults for unauthorized accesses.
 
-use crate::{
-    intel::{events::EventInjection, vmexit::ExitType},
-    utils::capture::GuestRegisters,
+use {
+    crate::{
+        intel::{
+            events::EventInjection,
+            support::vmread,
+            syscall::{write_guest_efer, EFER_SCE},
+            tsc::{guest_to_host_tsc, guest_tsc, host_to_guest_tsc},
+            virtual_msrs::{read_guest_msr, write_guest_msr, MsrAccess, MsrAction, MsrView},
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::capture::GuestRegisters,
+    },
+    x86::{msr, vmx::vmcs},
 };
 
 /// Enum representing the type of MSR access.
 ///
 /// There are two types of MSR access: reading from an MSR and writing to an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsrAccessType {
     Read,
     Write,
@@ -26,6 +38,7 @@ pub enum MsrAccessType {
 /// # Arguments
 ///
 /// * `registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
 /// * `access_type` - The type of MSR access (read or write).
 ///
 /// # Returns
@@ -36,6 +49,7 @@ pub enum MsrAccessType {
 /// and Table C-1. Basic Exit Reasons 31 and 32.
 pub fn handle_msr_access(
     guest_registers: &mut GuestRegisters,
+    vmx: &mut Vmx,
     access_type: MsrAccessType,
 ) -> ExitType {
     log::debug!("Handling MSR VM exit...");
@@ -61,6 +75,111 @@ pub fn handle_msr_access(
         }
     */
 
+    // The guest's IA32_EFER lives in the VMCS while the EFER.SCE syscall hook is enabled.
+    // EFER.SCE is never loaded into the guest, which reads the value it last wrote.
+    if msr_id == msr::IA32_EFER as u64
+        && unsafe { vmx.shared_data.as_ref() }.syscall_hooks.is_some()
+    {
+        log::trace!("IA32_EFER access attempted");
+        match access_type {
+            MsrAccessType::Read => {
+                let msr_value = match vmx.guest_efer_sce {
+                    true => vmread(vmcs::guest::IA32_EFER_FULL) | EFER_SCE,
+                    false => vmread(vmcs::guest::IA32_EFER_FULL),
+                };
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            MsrAccessType::Write => {
+                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                if !write_guest_efer(msr_value) {
+                    EventInjection::vmentry_inject_gp(0);
+                    return ExitType::Continue;
+                }
+                vmx.guest_efer_sce = msr_value & EFER_SCE != 0;
+            }
+        }
+
+        log::debug!("MSR VMEXIT handled successfully.");
+
+        return ExitType::IncrementRIP;
+    }
+
+    // With TSC compensation, the guest's TSC and TSC deadline are relative to the TSC offset, which guest writes to
+    // the TSC and IA32_TSC_ADJUST move instead of the host TSC.
+    let tsc_msr = msr_id == msr::IA32_TIME_STAMP_COUNTER as u64
+        || msr_id == msr::IA32_TSC_DEADLINE as u64
+        || msr_id == msr::IA32_TSC_ADJUST as u64;
+
+    if let Some(tsc_compensation) = vmx.tsc_compensation.as_mut().filter(|_| tsc_msr) {
+        log::trace!("TSC MSR access attempted: {:#x}", msr_id);
+        match (access_type, msr_id as u32) {
+            (MsrAccessType::Read, msr::IA32_TIME_STAMP_COUNTER) => {
+                let msr_value = guest_tsc();
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            (MsrAccessType::Write, msr::IA32_TIME_STAMP_COUNTER) => {
+                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                tsc_compensation.write_guest_tsc(msr_value);
+            }
+            (MsrAccessType::Read, msr::IA32_TSC_ADJUST) => {
+                let msr_value = tsc_compensation.guest_tsc_adjust();
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            (MsrAccessType::Write, msr::IA32_TSC_ADJUST) => {
+                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
+                tsc_compensation.write_guest_tsc_adjust(msr_value);
+            }
+            (MsrAccessType::Read, _) => {
+                // A disarmed deadline of 0 is not translated.
+                let msr_value = match unsafe { x86::msr::rdmsr(msr::IA32_TSC_DEADLINE) } {
+                    0 => 0,
+                    deadline => host_to_guest_tsc(deadline),
+                };
+                guest_registers.rdx = msr_value >> 32;
+                guest_registers.rax = msr_value & MSR_MASK_LOW;
+            }
+            (MsrAccessType::Write, _) => {
+                let msr_value =
+                    match (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW) {
+                        0 => 0,
+                        deadline => guest_to_host_tsc(deadline).max(1),
+                    };
+                unsafe { x86::msr::wrmsr(msr::IA32_TSC_DEADLINE, msr_value) };
+            }
+        }
+
+        log::debug!("MSR VMEXIT handled successfully.");
+
+        return ExitType::IncrementRIP;
+    }
+
+    // Virtualized MSRs are accessed through the view registered for them.
+    if let Some(view) = find_msr_view(msr_id as u32, vmx) {
+        let mut access = MsrAccess {
+            msr: msr_id as u32,
+            access_type,
+            value: (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW),
+        };
+
+        if !access_virtual_msr(&mut access, view, vmx) {
+            log::trace!("Virtualized MSR access faulted: {:#x}", msr_id);
+            EventInjection::vmentry_inject_gp(0);
+            return ExitType::Continue;
+        }
+
+        if access_type == MsrAccessType::Read {
+            guest_registers.rdx = access.value >> 32;
+            guest_registers.rax = access.value & MSR_MASK_LOW;
+        }
+
+        log::debug!("MSR VMEXIT handled successfully.");
+
+        return ExitType::IncrementRIP;
+    }
+
     // Determine if the MSR address is in a valid, reserved, or synthetic range.
     // If the MSR address is valid, execute the appropriate read or write operation.
     if (msr_id <= MSR_RANGE_LOW_END)
@@ -70,13 +189,13 @@ pub fn handle_msr_access(
         log::trace!("Valid MSR access attempted: {:#x}", msr_id);
         match access_type {
             MsrAccessType::Read => {
-                let msr_value = unsafe { x86::msr::rdmsr(msr_id as _) };
+                let msr_value = read_guest_msr(msr_id as u32);
                 guest_registers.rdx = msr_value >> 32;
                 guest_registers.rax = msr_value & MSR_MASK_LOW;
             }
             MsrAccessType::Write => {
                 let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
-                unsafe { x86::msr::wrmsr(msr_id as _, msr_value) };
+                write_guest_msr(msr_id as u32, msr_value);
             }
         }
     } else {
@@ -90,3 +209,111 @@ pub fn handle_msr_access(
 
     ExitType::IncrementRIP
 }
+
+/// Reads an MSR as the guest would with RDMSR, through the view registered for it if it is virtualized.
+///
+/// Used for the accesses the processor makes on behalf of the guest, such as the MSR-store areas of nested VM exits.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR to read.
+/// * `vmx` - A mutable reference to the Vmx structure holding the shadow values of the current processor.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The value read by the guest, or `None` if the read would inject a general protection fault.
+pub fn read_virtual_msr(msr: u32, vmx: &mut Vmx) -> Option<u64> {
+    let mut access = MsrAccess {
+        msr,
+        access_type: MsrAccessType::Read,
+        value: 0,
+    };
+
+    access_msr(&mut access, vmx).then_some(access.value)
+}
+
+/// Writes an MSR as the guest would with WRMSR, through the view registered for it if it is virtualized.
+///
+/// Used for the accesses the processor makes on behalf of the guest, such as the MSR-load areas of nested VM entries
+/// and VM exits.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR to write.
+/// * `value` - The value written by the guest.
+/// * `vmx` - A mutable reference to the Vmx structure holding the shadow values of the current processor.
+///
+/// # Returns
+///
+/// * `bool` - `false` if the write would inject a general protection fault.
+pub fn write_virtual_msr(msr: u32, value: u64, vmx: &mut Vmx) -> bool {
+    let mut access = MsrAccess {
+        msr,
+        access_type: MsrAccessType::Write,
+        value,
+    };
+
+    access_msr(&mut access, vmx)
+}
+
+/// Performs an access to an MSR through its view, or on the MSR of the guest if it is not virtualized.
+fn access_msr(access: &mut MsrAccess, vmx: &mut Vmx) -> bool {
+    if let Some(view) = find_msr_view(access.msr, vmx) {
+        return access_virtual_msr(access, view, vmx);
+    }
+
+    match access.access_type {
+        MsrAccessType::Read => access.value = read_guest_msr(access.msr),
+        MsrAccessType::Write => write_guest_msr(access.msr, access.value),
+    }
+
+    true
+}
+
+/// Finds the view registered for a virtualized MSR.
+fn find_msr_view(msr: u32, vmx: &Vmx) -> Option<MsrView> {
+    unsafe { vmx.shared_data.as_ref() }
+        .virtual_msrs
+        .as_deref()
+        .and_then(|virtual_msrs| virtual_msrs.find_view(msr))
+}
+
+/// Performs an access to a virtualized MSR through its view.
+///
+/// # Arguments
+///
+/// * `access` - The access by the guest, whose value is updated for reads.
+/// * `view` - The view registered for the MSR.
+/// * `vmx` - A mutable reference to the Vmx structure holding the shadow values of the current processor.
+///
+/// # Returns
+///
+/// * `bool` - `false` if the access must inject a general protection fault.
+fn access_virtual_msr(access: &mut MsrAccess, view: MsrView, vmx: &mut Vmx) -> bool {
+    match (view, access.access_type) {
+        (MsrView::Shadow(_), MsrAccessType::Read) => {
+            access.value = vmx
+                .msr_shadows
+                .get(&access.msr)
+                .copied()
+                .unwrap_or_default();
+        }
+        (MsrView::Shadow(_), MsrAccessType::Write) => {
+            vmx.msr_shadows.insert(access.msr, access.value);
+        }
+        (MsrView::ReadOnly(value), MsrAccessType::Read) => {
+            access.value = value.unwrap_or_else(|| read_guest_msr(access.msr));
+        }
+        (MsrView::ReadOnly(_), MsrAccessType::Write) | (MsrView::NotPresent, _) => return false,
+        (MsrView::Callback(callback), _) => match callback(access) {
+            MsrAction::Forward => match access.access_type {
+                MsrAccessType::Read => access.value = read_guest_msr(access.msr),
+                MsrAccessType::Write => write_guest_msr(access.msr, access.value),
+            },
+            MsrAction::Complete => {}
+            MsrAction::Fault => return false,
+        },
+    }
+
+    true
+}
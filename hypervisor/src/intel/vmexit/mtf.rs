@@ -0,0 +1,153 @@
+//! Manages Monitor Trap Flag (MTF) VM exits used to single-step the guest over accesses to hooked pages.
+//!
+//! When the monitor trap flag is set, a VM exit occurs after the guest completes one instruction
+//! (or delivers an event at the instruction boundary), which allows the EPT hook permissions to be re-armed.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::paging::AccessType,
+            invept::{invept_all_processors, invept_single_context},
+            support::{vmread, vmwrite},
+            vmexit::ExitType,
+            vmx::Vmx,
+        },
+        utils::{capture::GuestRegisters, instructions::rdtsc},
+    },
+    x86::vmx::vmcs,
+};
+
+/// A single step of the guest with the single-step view of a processor.
+#[derive(Debug, Clone, Copy)]
+pub struct SingleStep {
+    /// The EPTP of the shared EPT that is restored when the step completes.
+    pub shared_eptp: u64,
+
+    /// The first page mapped differently in the view, to which the step is attributed in the hook statistics.
+    pub page: u64,
+}
+
+/// Enables or disables the monitor trap flag in the primary processor-based VM-execution controls.
+///
+/// # Arguments
+///
+/// * `enable` - Whether the monitor trap flag should be set.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag
+pub fn set_monitor_trap_flag(enable: bool) {
+    let mut primary_controls = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+
+    if enable {
+        primary_controls |= vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG.bits() as u64;
+    } else {
+        primary_controls &= !(vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG.bits() as u64);
+    }
+
+    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls);
+}
+
+/// Single-steps the guest with a page mapped differently from the shared EPT the processor runs with.
+///
+/// The page is mapped in the single-step view of the processor, which becomes the current EPT until the monitor trap flag
+/// VM exit restores the shared EPT. Other processors keep running with the shared EPT, so they never observe the
+/// permissions granted for the single step. Pages accessed by the same instruction are added to the view.
+///
+/// # Arguments
+///
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+/// * `guest_pa` - The 4KB aligned guest physical address of the page.
+/// * `host_pa` - The 4KB aligned host physical address the page is mapped to for the single step.
+/// * `access_type` - The type of access allowed for the page for the single step.
+///
+/// # Returns
+///
+/// A `Result<(), HypervisorError>` indicating if the operation was successful.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag
+#[rustfmt::skip]
+pub fn single_step_page(vmx: &mut Vmx, guest_pa: u64, host_pa: u64, access_type: AccessType) -> Result<(), HypervisorError> {
+    let shared_data = unsafe { vmx.shared_data.as_ref() };
+
+    let single_step = vmx.single_step.unwrap_or_else(|| SingleStep { shared_eptp: vmread(vmcs::control::EPTP_FULL), page: guest_pa });
+    let shared_ept = match single_step.shared_eptp == shared_data.primary_eptp {
+        true => &shared_data.primary_ept,
+        false => &shared_data.secondary_ept,
+    };
+
+    if vmx.single_step.is_none() {
+        vmx.single_step_view.reset(shared_ept);
+    }
+
+    vmx.single_step_view.map_page(shared_ept, guest_pa, host_pa, access_type)?;
+
+    let view_eptp = vmx.single_step_view.create_eptp_with_wb_and_4lvl_walk()?;
+    vmx.single_step = Some(single_step);
+
+    // The view is only used by the current processor, so only its cached translations are invalidated.
+    vmwrite(vmcs::control::EPTP_FULL, view_eptp);
+    invept_single_context(view_eptp);
+    set_monitor_trap_flag(true);
+
+    Ok(())
+}
+
+/// Handles the Monitor Trap Flag VM exit.
+///
+/// The guest has executed the single instruction that accessed a hooked page, so the shared EPT replaces the
+/// single-step view and the monitor trap flag is cleared. A tracked write to a hooked
+/// original page is applied to the hooked copy, and the process-scoped hooks of a written page table follow their page
+/// if it was remapped.
+///
+/// # Arguments
+///
+/// * `_guest_registers` - A mutable reference to the guest's register state.
+/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The instruction has already completed, so RIP must not be advanced.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag
+#[rustfmt::skip]
+pub fn handle_monitor_trap_flag(_guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling Monitor Trap Flag VM exit...");
+
+    let attributed_page = vmx.pending_page_write.map(|write| write.original_page).or(vmx.single_step.map(|step| step.page));
+
+    if let Some(single_step) = vmx.single_step.take() {
+        log::trace!("Restoring shared EPT: {:#x}", single_step.shared_eptp);
+        vmwrite(vmcs::control::EPTP_FULL, single_step.shared_eptp);
+    }
+
+    // The write was only allowed in the single-step view, which was discarded above.
+    if let Some(write) = vmx.pending_page_write.take() {
+        let shared_data = unsafe { vmx.shared_data.as_mut() };
+
+        if let Some(event) = shared_data.hook_manager.resync_page_write(&write, vmx.page_write_snapshot.as_slice()) {
+            log::debug!("Applied guest write to hooked copy: {:#x} {:x?} (RIP {:#x})", event.original_page, event.modified, event.guest_rip);
+        }
+    }
+
+    // The hooks on a page the process remapped, such as by privatizing a copy-on-write page, follow it to the new page.
+    if let Some(pte_page) = vmx.pending_pte_write.take() {
+        let shared_data = unsafe { vmx.shared_data.as_mut() };
+
+        match shared_data.hook_manager.rehook_remapped_pages(pte_page, &mut shared_data.primary_ept, &mut shared_data.secondary_ept) {
+            Ok(true) => invept_all_processors(vmx),
+            Ok(false) => {}
+            Err(err) => log::error!("Failed to rehook remapped pages of page table {:#x}: {}", pte_page, err),
+        }
+    }
+
+    set_monitor_trap_flag(false);
+
+    if let Some(original_page) = attributed_page {
+        let hook_manager = unsafe { &vmx.shared_data.as_ref().hook_manager };
+        hook_manager.record_exit(original_page, rdtsc() - vmx.vmstack.exit_tsc);
+    }
+
+    log::debug!("Monitor Trap Flag handled successfully!");
+
+    ExitType::Continue
+}
@@ -0,0 +1,691 @@
+//! Handles the VM exits of VMX instructions executed by the guest hypervisor, emulating them for nested virtualization.
+//!
+//! The guest hypervisor runs in VMX non-root operation, so its VMX instructions cause VM exits. They are emulated on
+//! the state of the processor in `NestedVmx`: VMXON and VMXOFF enter and leave virtual VMX operation, VMCLEAR,
+//! VMPTRLD and VMPTRST manage the current vmcs12, VMREAD and VMWRITE access its fields, and VMLAUNCH and VMRESUME
+//! enter the nested guest. Instructions executed without nested virtualization raise `#UD`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: CHAPTER 31 VMX INSTRUCTION REFERENCE
+
+use {
+    crate::{
+        intel::{
+            events::{EventInjection, BLOCKING_BY_MOV_SS},
+            invvpid::{invvpid_individual_address, invvpid_single_context},
+            nested::{
+                capabilities::check_controls,
+                transitions::enter_nested_guest,
+                vm_fail, vm_fail_invalid, vm_succeed,
+                vmcs12::{is_read_only, LaunchState, Vmcs12},
+                vmcs_pa, NestedVmx, NESTED_VPID_TAG,
+            },
+            support::{try_vmread, vmread},
+            vmcs::Vmcs,
+            vmerror::{VmInstructionError, VmxBasicExitReason},
+            vmexit::{
+                cr::{guest_cr0, guest_cr4, CR4_VMXE},
+                exception::handle_undefined_opcode_exception,
+                msr::read_virtual_msr,
+                set_guest_gpr, ExitType,
+            },
+            vmx::Vmx,
+        },
+        utils::{
+            addresses::PhysicalAddress,
+            capture::GuestRegisters,
+            guest_memory::{read_guest_memory, write_guest_memory},
+            instructions::rdmsr,
+        },
+    },
+    x86::{
+        cpuid::cpuid,
+        msr,
+        vmx::vmcs::{guest, ro},
+    },
+};
+
+/// The L bit of the CS access rights, set for 64-bit code segments.
+const CS_LONG_MODE: u64 = 1 << 13;
+
+/// IA32_VMX_MISC bit 29, reporting that VMWRITE can write to the VM-exit information fields.
+const VMX_MISC_VMWRITE_ALL_FIELDS: u64 = 1 << 29;
+
+/// IA32_FEATURE_CONTROL bits 0 and 2, the lock and the enable of VMXON outside SMX operation, both required by VMXON.
+const FEATURE_CONTROL_VMXON_OUTSIDE_SMX: u64 = (1 << 0) | (1 << 2);
+
+/// The bits of IA32_VMX_EPT_VPID_CAP reporting the supported INVEPT types, indexed by type.
+const INVEPT_TYPES: [u64; 3] = [0, 1 << 25, 1 << 26];
+
+/// The bits of IA32_VMX_EPT_VPID_CAP reporting the supported INVVPID types, indexed by type.
+const INVVPID_TYPES: [u64; 4] = [1 << 40, 1 << 41, 1 << 42, 1 << 43];
+
+/// The operand of a VMX instruction, decoded from the VM-exit instruction-information field.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-13. Format of the VM-Exit
+/// Instruction-Information Field as Used for VMCLEAR, VMPTRLD, VMPTRST, VMXON, XRSTORS, and XSAVES
+/// and Table 28-14. Format of the VM-Exit Instruction-Information Field as Used for VMREAD and VMWRITE.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    /// A general-purpose register, by its encoding.
+    Register(u8),
+
+    /// A linear address in the address space of the guest hypervisor.
+    Memory(u64),
+}
+
+impl Operand {
+    /// Decodes the register or memory operand of the instruction that caused the VM exit.
+    fn decode(instruction_info: u32, guest_registers: &GuestRegisters) -> Self {
+        if instruction_info & (1 << 10) != 0 {
+            return Self::Register(((instruction_info >> 3) & 0xF) as u8);
+        }
+
+        let scaling = instruction_info & 0x3;
+        let address_size = (instruction_info >> 7) & 0x7;
+        let segment = (instruction_info >> 15) & 0x7;
+        let index = ((instruction_info >> 18) & 0xF) as u8;
+        let index_invalid = instruction_info & (1 << 22) != 0;
+        let base = ((instruction_info >> 23) & 0xF) as u8;
+        let base_invalid = instruction_info & (1 << 27) != 0;
+
+        // The exit qualification holds the displacement of the memory operand.
+        let mut address = vmread(ro::EXIT_QUALIFICATION);
+
+        if !base_invalid {
+            address = address.wrapping_add(guest_registers.gpr(base));
+        }
+
+        if !index_invalid {
+            address = address.wrapping_add(guest_registers.gpr(index) << scaling);
+        }
+
+        address &= match address_size {
+            0 => 0xFFFF,
+            1 => 0xFFFF_FFFF,
+            _ => u64::MAX,
+        };
+
+        // Only the FS and GS segments have a base address in 64-bit mode.
+        let segment_base = match segment {
+            4 => vmread(guest::FS_BASE),
+            5 => vmread(guest::GS_BASE),
+            _ => 0,
+        };
+
+        Self::Memory(segment_base.wrapping_add(address))
+    }
+}
+
+/// Handles the VM exit of a VMX instruction executed by the guest hypervisor.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The basic exit reason, identifying the VMX instruction.
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - If the instruction completed, successfully or with a VMfail status.
+/// * `ExitType::Continue` - If an exception was injected, or the nested guest was entered.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.3 VMX INSTRUCTIONS
+#[rustfmt::skip]
+pub fn handle_vmx_instruction(basic_exit_reason: VmxBasicExitReason, guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling VMX instruction VM exit...");
+    log::trace!("VMX instruction: {}", basic_exit_reason);
+
+    // VMXON is enabled by IA32_FEATURE_CONTROL as the guest hypervisor reads it.
+    let feature_control = match basic_exit_reason {
+        VmxBasicExitReason::Vmxon => read_virtual_msr(msr::IA32_FEATURE_CONTROL, vmx).unwrap_or(0),
+        _ => 0,
+    };
+
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return handle_undefined_opcode_exception();
+    };
+
+    // VMX instructions raise #UD outside of VMX operation, except VMXON with CR4.VMXE set, and outside of 64-bit mode.
+    let in_vmx_operation = nested.in_vmx_operation();
+    if (!in_vmx_operation && (basic_exit_reason != VmxBasicExitReason::Vmxon || guest_cr4() & CR4_VMXE == 0))
+        || vmread(guest::CS_ACCESS_RIGHTS) & CS_LONG_MODE == 0
+    {
+        return handle_undefined_opcode_exception();
+    }
+
+    // The current privilege level is the DPL of SS, in bits 6:5 of its access rights.
+    if (vmread(guest::SS_ACCESS_RIGHTS) >> 5) & 0x3 != 0 {
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    let instruction_info = vmread(ro::VMEXIT_INSTRUCTION_INFO) as u32;
+    let operand = Operand::decode(instruction_info, guest_registers);
+    let vmcs01_pa = vmcs_pa(&vmx.vmcs_region);
+
+    let exit_type = match basic_exit_reason {
+        VmxBasicExitReason::Vmxon => emulate_vmxon(operand, feature_control, guest_registers, nested),
+        VmxBasicExitReason::Vmxoff => emulate_vmxoff(guest_registers, nested, vmcs01_pa),
+        VmxBasicExitReason::Vmclear => emulate_vmclear(operand, guest_registers, nested, vmcs01_pa),
+        VmxBasicExitReason::Vmptrld => emulate_vmptrld(operand, guest_registers, nested, vmcs01_pa),
+        VmxBasicExitReason::Vmptrst => emulate_vmptrst(operand, guest_registers, nested),
+        VmxBasicExitReason::Vmread => emulate_vmread(operand, instruction_info, guest_registers, nested),
+        VmxBasicExitReason::Vmwrite => emulate_vmwrite(operand, instruction_info, guest_registers, nested),
+        VmxBasicExitReason::Invept => emulate_invept(operand, instruction_info, guest_registers, nested),
+        VmxBasicExitReason::Invvpid => emulate_invvpid(operand, instruction_info, guest_registers, nested),
+        VmxBasicExitReason::Vmlaunch | VmxBasicExitReason::Vmresume => {
+            return emulate_vmentry(basic_exit_reason, guest_registers, vmx);
+        }
+        _ => return handle_undefined_opcode_exception(),
+    };
+
+    log::debug!("VMX instruction VM exit handled successfully!");
+
+    exit_type
+}
+
+/// Emulates VMXON, entering virtual VMX operation.
+fn emulate_vmxon(
+    operand: Operand,
+    feature_control: u64,
+    guest_registers: &mut GuestRegisters,
+    nested: &mut NestedVmx,
+) -> ExitType {
+    if nested.in_vmx_operation() {
+        vm_fail(guest_registers, nested, VmInstructionError::VmxonInRoot);
+        return ExitType::IncrementRIP;
+    }
+
+    // CR0 and CR4 must have the bits set that are fixed to 1 in VMX operation, and IA32_FEATURE_CONTROL must be
+    // locked with VMXON enabled outside SMX operation.
+    if guest_cr0() & rdmsr(msr::IA32_VMX_CR0_FIXED0) != rdmsr(msr::IA32_VMX_CR0_FIXED0)
+        || guest_cr4() & rdmsr(msr::IA32_VMX_CR4_FIXED0) != rdmsr(msr::IA32_VMX_CR4_FIXED0)
+        || feature_control & FEATURE_CONTROL_VMXON_OUTSIDE_SMX != FEATURE_CONTROL_VMXON_OUTSIDE_SMX
+    {
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    let Some(vmxon_pointer) = read_pointer_operand(operand) else {
+        return ExitType::Continue;
+    };
+
+    if !is_valid_pointer(vmxon_pointer)
+        || read_revision_id(vmxon_pointer) != Some(Vmcs::get_vmcs_revision_id())
+    {
+        vm_fail_invalid(guest_registers);
+        return ExitType::IncrementRIP;
+    }
+
+    log::trace!(
+        "Guest hypervisor entered VMX operation with VMXON region {:#x}",
+        vmxon_pointer
+    );
+
+    nested.vmxon_pointer = Some(vmxon_pointer);
+    nested.current_vmcs = None;
+
+    if let Some(shadowing) = nested.shadowing.as_mut() {
+        shadowing.enable();
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMXOFF, leaving virtual VMX operation.
+fn emulate_vmxoff(
+    guest_registers: &mut GuestRegisters,
+    nested: &mut NestedVmx,
+    vmcs01_pa: u64,
+) -> ExitType {
+    release_current_vmcs(nested, vmcs01_pa);
+
+    if let Some(shadowing) = nested.shadowing.as_ref() {
+        shadowing.disable();
+    }
+
+    log::trace!("Guest hypervisor left VMX operation");
+
+    nested.vmxon_pointer = None;
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMCLEAR, setting the launch state of a VMCS to clear.
+fn emulate_vmclear(
+    operand: Operand,
+    guest_registers: &mut GuestRegisters,
+    nested: &mut NestedVmx,
+    vmcs01_pa: u64,
+) -> ExitType {
+    let Some(vmcs_pointer) = read_pointer_operand(operand) else {
+        return ExitType::Continue;
+    };
+
+    let vmcs12 = match is_valid_pointer(vmcs_pointer) {
+        true => Vmcs12::from_guest_pa(vmcs_pointer),
+        false => None,
+    };
+
+    let Some(vmcs12) = vmcs12 else {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmclearInvalidAddress,
+        );
+        return ExitType::IncrementRIP;
+    };
+
+    if nested.vmxon_pointer == Some(vmcs_pointer) {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmclearWithVmxonPointer,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    if nested.current_vmcs == Some(vmcs_pointer) {
+        release_current_vmcs(nested, vmcs01_pa);
+    }
+
+    // The VMCS may be launched on another processor, so its translations are no longer those of the nested VPID.
+    if nested.last_vmcs12 == Some(vmcs_pointer) {
+        nested.last_vmcs12 = None;
+    }
+
+    vmcs12.set_launch_state(LaunchState::Clear);
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMPTRLD, making a VMCS the current vmcs12.
+fn emulate_vmptrld(
+    operand: Operand,
+    guest_registers: &mut GuestRegisters,
+    nested: &mut NestedVmx,
+    vmcs01_pa: u64,
+) -> ExitType {
+    let Some(vmcs_pointer) = read_pointer_operand(operand) else {
+        return ExitType::Continue;
+    };
+
+    let vmcs12 = match is_valid_pointer(vmcs_pointer) {
+        true => Vmcs12::from_guest_pa(vmcs_pointer),
+        false => None,
+    };
+
+    let Some(vmcs12) = vmcs12 else {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmptrldInvalidAddress,
+        );
+        return ExitType::IncrementRIP;
+    };
+
+    if nested.vmxon_pointer == Some(vmcs_pointer) {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmptrldWithVmxonPointer,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    // Shadow VMCSs, with bit 31 of the revision identifier set, are not supported for the guest hypervisor.
+    if vmcs12.revision_id != Vmcs::get_vmcs_revision_id() {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmptrldIncorrectVmcsRevision,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    if nested.current_vmcs != Some(vmcs_pointer) {
+        release_current_vmcs(nested, vmcs01_pa);
+        nested.current_vmcs = Some(vmcs_pointer);
+
+        if let Some(shadowing) = nested.shadowing.as_ref() {
+            shadowing.activate(vmcs12, vmcs01_pa);
+        }
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMPTRST, storing the pointer to the current vmcs12.
+fn emulate_vmptrst(
+    operand: Operand,
+    guest_registers: &mut GuestRegisters,
+    nested: &NestedVmx,
+) -> ExitType {
+    let Operand::Memory(address) = operand else {
+        return handle_undefined_opcode_exception();
+    };
+
+    if !write_memory(address, nested.current_vmcs.unwrap_or(u64::MAX)) {
+        return ExitType::Continue;
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMREAD of a field of the current vmcs12 that isn't held in the shadow VMCS.
+fn emulate_vmread(
+    operand: Operand,
+    instruction_info: u32,
+    guest_registers: &mut GuestRegisters,
+    nested: &NestedVmx,
+) -> ExitType {
+    let Some(vmcs12) = nested.current_vmcs.and_then(Vmcs12::from_guest_pa) else {
+        vm_fail_invalid(guest_registers);
+        return ExitType::IncrementRIP;
+    };
+
+    let Some(field) = field_operand(instruction_info, guest_registers) else {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmreadVmwriteUnsupportedVmcsComponent,
+        );
+        return ExitType::IncrementRIP;
+    };
+
+    let Some(value) = vmcs12.read(field) else {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmreadVmwriteUnsupportedVmcsComponent,
+        );
+        return ExitType::IncrementRIP;
+    };
+
+    match operand {
+        Operand::Register(register) => set_guest_gpr(guest_registers, register, value),
+        Operand::Memory(address) => {
+            if !write_memory(address, value) {
+                return ExitType::Continue;
+            }
+        }
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMWRITE to a field of the current vmcs12 that isn't held in the shadow VMCS.
+fn emulate_vmwrite(
+    operand: Operand,
+    instruction_info: u32,
+    guest_registers: &mut GuestRegisters,
+    nested: &NestedVmx,
+) -> ExitType {
+    let Some(vmcs12) = nested.current_vmcs.and_then(Vmcs12::from_guest_pa) else {
+        vm_fail_invalid(guest_registers);
+        return ExitType::IncrementRIP;
+    };
+
+    let value = match operand {
+        Operand::Register(register) => guest_registers.gpr(register),
+        Operand::Memory(address) => match read_memory(address) {
+            Some(value) => value,
+            None => return ExitType::Continue,
+        },
+    };
+
+    let Some(field) = field_operand(instruction_info, guest_registers) else {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmreadVmwriteUnsupportedVmcsComponent,
+        );
+        return ExitType::IncrementRIP;
+    };
+
+    if is_read_only(field) && rdmsr(msr::IA32_VMX_MISC) & VMX_MISC_VMWRITE_ALL_FIELDS == 0 {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmwriteReadonlyVmcsComponent,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    if vmcs12.write(field, value).is_none() {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::VmreadVmwriteUnsupportedVmcsComponent,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates VMLAUNCH and VMRESUME, entering the nested guest of the current vmcs12.
+#[rustfmt::skip]
+fn emulate_vmentry(basic_exit_reason: VmxBasicExitReason, guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    let vmcs01_pa = vmcs_pa(&vmx.vmcs_region);
+    let Some(nested) = vmx.nested.as_deref_mut() else {
+        return handle_undefined_opcode_exception();
+    };
+
+    let Some(vmcs12) = nested.current_vmcs.and_then(Vmcs12::from_guest_pa) else {
+        vm_fail_invalid(guest_registers);
+        return ExitType::IncrementRIP;
+    };
+
+    if vmread(guest::INTERRUPTIBILITY_STATE) & BLOCKING_BY_MOV_SS != 0 {
+        vm_fail(guest_registers, nested, VmInstructionError::VmEntryEventsBlockedByMovSs);
+        return ExitType::IncrementRIP;
+    }
+
+    // The fields written through the shadow VMCS are needed to build vmcs02.
+    if let Some(shadowing) = nested.shadowing.as_ref() {
+        shadowing.store(vmcs12, vmcs01_pa);
+    }
+
+    let error = match (basic_exit_reason, vmcs12.launch_state()) {
+        (VmxBasicExitReason::Vmlaunch, LaunchState::Launched) => Some(VmInstructionError::VmlaunchNonClearVmcs),
+        (VmxBasicExitReason::Vmresume, LaunchState::Clear) => Some(VmInstructionError::VmresumeNonLaunchedVmcs),
+        _ if !check_controls(vmcs12) => Some(VmInstructionError::VmEntryInvalidControlFields),
+        _ => None,
+    };
+
+    if let Some(error) = error {
+        vm_fail(guest_registers, nested, error);
+        return ExitType::IncrementRIP;
+    }
+
+    enter_nested_guest(guest_registers, vmx)
+}
+
+/// Emulates INVEPT, invalidating the translations derived from the EPT of the guest hypervisor.
+///
+/// The processor only caches the translations of the shadow EPT, which is reset to be filled again from the EPT of
+/// the guest hypervisor.
+fn emulate_invept(
+    operand: Operand,
+    instruction_info: u32,
+    guest_registers: &mut GuestRegisters,
+    nested: &mut NestedVmx,
+) -> ExitType {
+    let Operand::Memory(address) = operand else {
+        return handle_undefined_opcode_exception();
+    };
+
+    let invept_type = guest_registers.gpr((instruction_info >> 28) as u8);
+    let ept_vpid_capabilities = rdmsr(msr::IA32_VMX_EPT_VPID_CAP);
+
+    let supported = INVEPT_TYPES
+        .get(invept_type as usize)
+        .is_some_and(|&capability| capability != 0 && ept_vpid_capabilities & capability != 0);
+    if !supported {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::InvalidOperandToInveptInvvpid,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    let Some(eptp) = read_memory(address) else {
+        return ExitType::Continue;
+    };
+
+    // A single-context invalidation of another EPTP leaves the shadow EPT of the current one valid.
+    match invept_type {
+        1 if nested
+            .eptp12
+            .is_some_and(|eptp12| eptp12 & !0xFFF != eptp & !0xFFF) => {}
+        _ => nested.reset_nested_ept(),
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Emulates INVVPID, invalidating the translations of the nested guests, which share the nested VPID.
+fn emulate_invvpid(
+    operand: Operand,
+    instruction_info: u32,
+    guest_registers: &mut GuestRegisters,
+    nested: &NestedVmx,
+) -> ExitType {
+    let Operand::Memory(address) = operand else {
+        return handle_undefined_opcode_exception();
+    };
+
+    let invvpid_type = guest_registers.gpr((instruction_info >> 28) as u8);
+    let ept_vpid_capabilities = rdmsr(msr::IA32_VMX_EPT_VPID_CAP);
+
+    let supported = INVVPID_TYPES
+        .get(invvpid_type as usize)
+        .is_some_and(|&capability| ept_vpid_capabilities & capability != 0);
+    if !supported {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::InvalidOperandToInveptInvvpid,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    let (Some(vpid), Some(linear_address)) =
+        (read_memory(address), read_memory(address.wrapping_add(8)))
+    else {
+        return ExitType::Continue;
+    };
+
+    // Bits 63:16 of the descriptor are reserved, and VPID 0 is invalid except when invalidating all contexts.
+    if vpid >> 16 != 0 || (vpid == 0 && invvpid_type != 2) {
+        vm_fail(
+            guest_registers,
+            nested,
+            VmInstructionError::InvalidOperandToInveptInvvpid,
+        );
+        return ExitType::IncrementRIP;
+    }
+
+    match invvpid_type {
+        0 => invvpid_individual_address(NESTED_VPID_TAG, linear_address),
+        _ => invvpid_single_context(NESTED_VPID_TAG),
+    }
+
+    vm_succeed(guest_registers);
+    ExitType::IncrementRIP
+}
+
+/// Stores the shadow VMCS to the current vmcs12 and unlinks it, leaving no current vmcs12.
+fn release_current_vmcs(nested: &mut NestedVmx, vmcs01_pa: u64) {
+    let Some(vmcs12) = nested.current_vmcs.take().and_then(Vmcs12::from_guest_pa) else {
+        return;
+    };
+
+    if let Some(shadowing) = nested.shadowing.as_ref() {
+        shadowing.store(vmcs12, vmcs01_pa);
+        shadowing.deactivate();
+    }
+}
+
+/// Reads the VMCS field encoding from the register selected by bits 31:28 of the instruction information.
+///
+/// # Returns
+///
+/// * `Option<u32>` - The field, or `None` if it isn't supported by the processor.
+fn field_operand(instruction_info: u32, guest_registers: &GuestRegisters) -> Option<u32> {
+    let field = u32::try_from(guest_registers.gpr((instruction_info >> 28) as u8)).ok()?;
+
+    try_vmread(field).map(|_| field)
+}
+
+/// Reads the 64-bit physical address operand of VMXON, VMCLEAR or VMPTRLD from memory.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The address, or `None` if a page fault was injected.
+fn read_pointer_operand(operand: Operand) -> Option<u64> {
+    match operand {
+        Operand::Memory(address) => read_memory(address),
+        Operand::Register(_) => {
+            handle_undefined_opcode_exception();
+            None
+        }
+    }
+}
+
+/// Checks that a VMXON or VMCS pointer is 4-KByte aligned and within the physical-address width.
+fn is_valid_pointer(pointer: u64) -> bool {
+    let physical_address_width = cpuid!(0x8000_0008).eax & 0xFF;
+
+    pointer & 0xFFF == 0 && pointer >> physical_address_width == 0
+}
+
+/// Reads the revision identifier of a VMXON region, including the shadow-VMCS indicator.
+fn read_revision_id(pointer: u64) -> Option<u32> {
+    match PhysicalAddress::va_from_pa(pointer) {
+        0 => None,
+        va => Some(unsafe { (va as *const u32).read_volatile() }),
+    }
+}
+
+/// Reads a 64-bit memory operand of the guest hypervisor, injecting a page fault if it isn't mapped.
+fn read_memory(address: u64) -> Option<u64> {
+    let mut buffer = [0u8; 8];
+
+    match read_guest_memory(vmread(guest::CR3), address, &mut buffer) {
+        Some(()) => Some(u64::from_le_bytes(buffer)),
+        None => {
+            inject_page_fault(address, false);
+            None
+        }
+    }
+}
+
+/// Writes a 64-bit memory operand of the guest hypervisor, injecting a page fault if it isn't mapped.
+fn write_memory(address: u64, value: u64) -> bool {
+    match write_guest_memory(vmread(guest::CR3), address, &value.to_le_bytes()) {
+        Some(()) => true,
+        None => {
+            inject_page_fault(address, true);
+            false
+        }
+    }
+}
+
+/// Injects a page fault for an unmapped memory operand, accessed by the guest hypervisor at CPL 0.
+fn inject_page_fault(address: u64, write: bool) {
+    const PF_WRITE: u32 = 1 << 1;
+
+    unsafe { x86::controlregs::cr2_write(address) };
+    EventInjection::vmentry_inject_pf(if write { PF_WRITE } else { 0 });
+}
@@ -2,9 +2,12 @@
 //! the `RDTSC` (Read Time-Stamp Counter) instruction in a VM to ensure appropriate time
 //! information is provided to the guest while maintaining the integrity of the hypervisor.
 
-use {
-    crate::{intel::vmexit::ExitType, utils::capture::GuestRegisters},
-    x86::time::rdtsc,
+use crate::{
+    intel::{
+        tsc::{guest_tsc, host_to_guest_tsc},
+        vmexit::ExitType,
+    },
+    utils::capture::GuestRegisters,
 };
 
 /*
@@ -16,8 +19,8 @@ User can add the following later:
 /// Handles the `RDTSC` VM-exit.
 ///
 /// This function is invoked when the guest executes the `RDTSC` instruction.
-/// It reads the current value of the host's time-stamp counter and updates the guest's
-/// RAX and RDX registers with the low and high 32-bits of the counter, respectively.
+/// It reads the current value of the host's time-stamp counter, adjusted by the TSC offset when TSC offsetting
+/// is enabled, and updates the guest's RAX and RDX registers with the low and high 32-bits of the counter, respectively.
 ///
 /// # Arguments
 ///
@@ -31,8 +34,8 @@ User can add the following later:
 pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
     log::debug!("Handling RDTSC VM exit...");
 
-    // Read the time stamp counter.
-    let rdtsc_value: u64 = unsafe { rdtsc() };
+    // Read the time stamp counter as seen by the guest.
+    let rdtsc_value: u64 = guest_tsc();
 
     // Update the guest's RAX and RDX registers.
     guest_registers.rax = rdtsc_value & 0xFFFFFFFF; // Low 32 bits
@@ -42,3 +45,36 @@ pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
 
     ExitType::IncrementRIP
 }
+
+/// Handles the `RDTSCP` VM-exit.
+///
+/// This function is invoked when the guest executes the `RDTSCP` instruction.
+/// It updates the guest's RAX and RDX registers with the low and high 32-bits of the host's
+/// time-stamp counter adjusted by the TSC offset, and RCX with the value of the IA32_TSC_AUX MSR.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDTSCP` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 51.
+pub fn handle_rdtscp(guest_registers: &mut GuestRegisters) -> ExitType {
+    log::debug!("Handling RDTSCP VM exit...");
+
+    // Read the time stamp counter and IA32_TSC_AUX.
+    let mut tsc_aux: u32 = 0;
+    let rdtscp_value: u64 =
+        host_to_guest_tsc(unsafe { core::arch::x86_64::__rdtscp(&mut tsc_aux) });
+
+    // Update the guest's RAX, RDX and RCX registers.
+    guest_registers.rax = rdtscp_value & 0xFFFFFFFF; // Low 32 bits
+    guest_registers.rdx = rdtscp_value >> 32; // High 32 bits
+    guest_registers.rcx = tsc_aux as u64;
+
+    log::debug!("RDTSCP VMEXIT handled successfully!");
+
+    ExitType::IncrementRIP
+}
@@ -0,0 +1,260 @@
+//! A registry of VM exit handlers, allowing callers to override or extend the built-in handling of any exit reason.
+//!
+//! Handlers registered for an exit reason form a chain in registration order. Each handler receives the guest
+//! registers, the `Vmx` of the current processor, typed information about the VM exit and a `NextHandler` that
+//! invokes the rest of the chain, ending with the built-in handler. A handler overrides the built-in handling by
+//! not calling `next`, or chains with it by calling `next` before or after its own logic.
+
+use {
+    crate::{
+        intel::{
+            support::vmread,
+            vmerror::{
+                ControlRegisterAccessExitQualification, DebugRegisterAccessExitQualification,
+                EptViolationExitQualification, IoInstructionExitQualification,
+                VmExitInterruptionInformation, VmxBasicExitReason,
+            },
+            vmexit::{ExitType, VmExit},
+            vmx::Vmx,
+        },
+        utils::capture::GuestRegisters,
+    },
+    alloc::{boxed::Box, collections::BTreeMap, vec::Vec},
+    x86::vmx::vmcs::ro,
+};
+
+/// The exit qualification of a VM exit, decoded for the exit reasons that have a typed representation.
+#[derive(Debug, Clone, Copy)]
+pub enum ExitQualification {
+    /// The exit qualification of an EPT violation.
+    EptViolation(EptViolationExitQualification),
+
+    /// The interruption information and error code of an exception or NMI.
+    Exception {
+        interruption_info: VmExitInterruptionInformation,
+        error_code: Option<u32>,
+    },
+
+    /// The exit qualification of a control-register access.
+    ControlRegisterAccess(ControlRegisterAccessExitQualification),
+
+    /// The exit qualification of a MOV DR.
+    DebugRegisterAccess(DebugRegisterAccessExitQualification),
+
+    /// The exit qualification of an I/O instruction.
+    IoInstruction(IoInstructionExitQualification),
+
+    /// The raw exit qualification of any other exit reason.
+    Raw(u64),
+}
+
+/// Information about the VM exit being handled.
+#[derive(Debug, Clone, Copy)]
+pub struct VmExitInfo {
+    /// The basic exit reason.
+    pub basic_exit_reason: VmxBasicExitReason,
+
+    /// The decoded exit qualification.
+    pub exit_qualification: ExitQualification,
+
+    /// The length of the instruction that caused the VM exit, if any.
+    pub instruction_length: u64,
+}
+
+impl VmExitInfo {
+    /// Reads the information about the current VM exit from the VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `basic_exit_reason` - The basic exit reason of the VM exit.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.1 Basic VM-Exit Information
+    pub fn from_vmcs(basic_exit_reason: VmxBasicExitReason) -> Self {
+        let exit_qualification = vmread(ro::EXIT_QUALIFICATION);
+
+        let exit_qualification = match basic_exit_reason {
+            VmxBasicExitReason::EptViolation => ExitQualification::EptViolation(
+                EptViolationExitQualification::from_exit_qualification(exit_qualification),
+            ),
+            VmxBasicExitReason::ControlRegisterAccesses => {
+                ExitQualification::ControlRegisterAccess(
+                    ControlRegisterAccessExitQualification::from_exit_qualification(
+                        exit_qualification,
+                    ),
+                )
+            }
+            VmxBasicExitReason::MovDr => ExitQualification::DebugRegisterAccess(
+                DebugRegisterAccessExitQualification::from_exit_qualification(exit_qualification),
+            ),
+            VmxBasicExitReason::IoInstruction => ExitQualification::IoInstruction(
+                IoInstructionExitQualification::from_exit_qualification(exit_qualification),
+            ),
+            VmxBasicExitReason::ExceptionOrNmi => {
+                match VmExitInterruptionInformation::from_u32(
+                    vmread(ro::VMEXIT_INTERRUPTION_INFO) as u32
+                ) {
+                    Some(interruption_info) => ExitQualification::Exception {
+                        interruption_info,
+                        error_code: interruption_info
+                            .error_code_valid
+                            .then(|| vmread(ro::VMEXIT_INTERRUPTION_ERR_CODE) as u32),
+                    },
+                    None => ExitQualification::Raw(exit_qualification),
+                }
+            }
+            _ => ExitQualification::Raw(exit_qualification),
+        };
+
+        Self {
+            basic_exit_reason,
+            exit_qualification,
+            instruction_length: vmread(ro::VMEXIT_INSTRUCTION_LEN),
+        }
+    }
+}
+
+/// A handler for VM exits of a specific exit reason.
+///
+/// Closures and functions with the signature of `handle` implement this trait.
+pub trait VmExitHandler: Send + Sync {
+    /// Handles a VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_registers` - A mutable reference to the guest's current register state.
+    /// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+    /// * `info` - Information about the VM exit, including the typed exit qualification.
+    /// * `next` - The rest of the handler chain, ending with the built-in handler.
+    ///
+    /// # Returns
+    ///
+    /// * `ExitType` - How the guest continues after the VM exit.
+    fn handle(
+        &self,
+        guest_registers: &mut GuestRegisters,
+        vmx: &mut Vmx,
+        info: &VmExitInfo,
+        next: NextHandler,
+    ) -> ExitType;
+}
+
+impl<F> VmExitHandler for F
+where
+    F: Fn(&mut GuestRegisters, &mut Vmx, &VmExitInfo, NextHandler) -> ExitType + Send + Sync,
+{
+    fn handle(
+        &self,
+        guest_registers: &mut GuestRegisters,
+        vmx: &mut Vmx,
+        info: &VmExitInfo,
+        next: NextHandler,
+    ) -> ExitType {
+        self(guest_registers, vmx, info, next)
+    }
+}
+
+/// The rest of a handler chain, ending with the built-in handler.
+pub struct NextHandler<'a> {
+    /// The remaining handlers of the chain.
+    chain: &'a [Box<dyn VmExitHandler>],
+
+    /// The information about the VM exit.
+    info: &'a VmExitInfo,
+}
+
+impl<'a> NextHandler<'a> {
+    /// Invokes the next handler of the chain, or the built-in handler at the end of the chain.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_registers` - A mutable reference to the guest's current register state.
+    /// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+    ///
+    /// # Returns
+    ///
+    /// * `ExitType` - How the guest continues after the VM exit, or `ExitType::ExitHypervisor`
+    ///   if there is no built-in handler for the exit reason.
+    pub fn call(self, guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+        match self.chain.split_first() {
+            Some((handler, chain)) => handler.handle(
+                guest_registers,
+                vmx,
+                self.info,
+                NextHandler {
+                    chain,
+                    info: self.info,
+                },
+            ),
+            None => VmExit::handle_builtin(self.info.basic_exit_reason, guest_registers, vmx)
+                .unwrap_or_else(|error| {
+                    log::error!(
+                        "No built-in handler for {}: {}",
+                        self.info.basic_exit_reason,
+                        error
+                    );
+                    ExitType::ExitHypervisor
+                }),
+        }
+    }
+}
+
+/// The VM exit handlers registered per exit reason.
+#[derive(Default)]
+pub struct ExitHandlerRegistry {
+    /// The handler chains, keyed by basic exit reason.
+    handlers: BTreeMap<u16, Vec<Box<dyn VmExitHandler>>>,
+}
+
+impl ExitHandlerRegistry {
+    /// Creates an empty registry, in which every exit reason is handled by its built-in handler.
+    pub fn new() -> Box<Self> {
+        Box::new(Self::default())
+    }
+
+    /// Registers a handler for an exit reason, after the handlers already registered for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `basic_exit_reason` - The exit reason to handle.
+    /// * `handler` - The handler.
+    pub fn register(
+        &mut self,
+        basic_exit_reason: VmxBasicExitReason,
+        handler: Box<dyn VmExitHandler>,
+    ) {
+        self.handlers
+            .entry(basic_exit_reason as u16)
+            .or_default()
+            .push(handler);
+    }
+
+    /// Checks whether any handler is registered for an exit reason.
+    pub fn is_registered(&self, basic_exit_reason: VmxBasicExitReason) -> bool {
+        self.handlers.contains_key(&(basic_exit_reason as u16))
+    }
+
+    /// Dispatches a VM exit to the handler chain of its exit reason.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_registers` - A mutable reference to the guest's current register state.
+    /// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+    /// * `info` - Information about the VM exit.
+    ///
+    /// # Returns
+    ///
+    /// * `ExitType` - How the guest continues after the VM exit.
+    pub fn dispatch(
+        &self,
+        guest_registers: &mut GuestRegisters,
+        vmx: &mut Vmx,
+        info: &VmExitInfo,
+    ) -> ExitType {
+        let chain = self
+            .handlers
+            .get(&(info.basic_exit_reason as u16))
+            .map_or(&[][..], |handlers| handlers.as_slice());
+
+        NextHandler { chain, info }.call(guest_registers, vmx)
+    }
+}
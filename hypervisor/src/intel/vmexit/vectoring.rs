@@ -0,0 +1,111 @@
+//! Re-injects events whose delivery was interrupted by a VM exit.
+//!
+//! A VM exit can occur while the processor delivers an event through the guest IDT, for example an EPT violation
+//! on the guest stack while delivering an interrupt. The event is recorded in the IDT-vectoring information fields
+//! and is lost unless it's injected again on VM entry. When the handler of the VM exit also injects an exception,
+//! both are combined following the rules the processor applies to exceptions raised during event delivery.
+
+use crate::{
+    intel::{
+        events::{EventInjection, PendingEvent},
+        vmerror::{ExceptionInterrupt, InterruptionType, VmxBasicExitReason},
+        vmexit::{fallback::handle_fatal_exit, ExitType},
+        vmx::Vmx,
+    },
+    utils::capture::GuestRegisters,
+};
+
+/// The classes of exceptions used to detect double faults.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 6-4. Interrupt and Exception Classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExceptionClass {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+impl ExceptionClass {
+    /// Classifies a hardware exception by its vector.
+    fn from_vector(vector: u8) -> Self {
+        match ExceptionInterrupt::from_u32(vector as u32) {
+            Some(
+                ExceptionInterrupt::DivisionError
+                | ExceptionInterrupt::InvalidTSS
+                | ExceptionInterrupt::SegmentNotPresent
+                | ExceptionInterrupt::StackSegmentFault
+                | ExceptionInterrupt::GeneralProtectionFault
+                | ExceptionInterrupt::ControlProtectionException,
+            ) => Self::Contributory,
+            Some(ExceptionInterrupt::PageFault | ExceptionInterrupt::VirtualizationException) => {
+                Self::PageFault
+            }
+            Some(ExceptionInterrupt::DoubleFault) => Self::DoubleFault,
+            _ => Self::Benign,
+        }
+    }
+}
+
+/// Re-injects the event whose delivery was interrupted by the current VM exit, after the VM exit was handled.
+///
+/// If the handler didn't inject an event, the original event is injected again. Otherwise:
+/// - A contributory exception raised while delivering a contributory exception, or a contributory exception or
+///   page fault raised while delivering a page fault, becomes a double fault.
+/// - A contributory exception or page fault raised while delivering a double fault shuts the guest down, which is fatal.
+/// - Any other exception is delivered first. Faults and software interrupts occur again when their instruction is
+///   restarted, while external interrupts and NMIs are queued ahead of the other pending events. A full queue is
+///   fatal, since the event can't be discarded.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+/// * `event` - The event read from the IDT-vectoring information fields at the start of the VM exit.
+/// * `exit_type` - The result of the VM exit handler.
+/// * `guest_registers` - A reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.15 Exception and Interrupt Reference
+/// (Table 6-5. Conditions for Generating a Double Fault) and 28.2.4 Information for VM Exits During Event Delivery.
+#[rustfmt::skip]
+pub fn reinject_vectored_event(basic_exit_reason: VmxBasicExitReason, event: PendingEvent, exit_type: ExitType, guest_registers: &GuestRegisters, vmx: &mut Vmx) {
+    // The handler skipped the instruction that was delivering a software interrupt or exception.
+    let software_event = matches!(event.interruption_type, InterruptionType::SoftwareInterrupt | InterruptionType::PrivilegedSoftwareException | InterruptionType::SoftwareException);
+    if exit_type == ExitType::IncrementRIP && software_event {
+        log::trace!("Dropping vectored event of skipped instruction: {:?}", event);
+        return;
+    }
+
+    let Some(injected) = PendingEvent::from_vmentry() else {
+        log::trace!("Re-injecting vectored event: {:?}", event);
+        event.inject();
+        return;
+    };
+
+    log::trace!("Vectored event: {:?}, injected event: {:?}", event, injected);
+
+    match event.interruption_type {
+        InterruptionType::HardwareException if injected.interruption_type == InterruptionType::HardwareException => {
+            match (ExceptionClass::from_vector(event.vector), ExceptionClass::from_vector(injected.vector)) {
+                (ExceptionClass::DoubleFault, ExceptionClass::Contributory | ExceptionClass::PageFault) => {
+                    log::error!("Exception {:#x} raised while delivering a double fault", injected.vector);
+                    handle_fatal_exit(VmxBasicExitReason::TripleFault, guest_registers, vmx);
+                }
+                (ExceptionClass::Contributory, ExceptionClass::Contributory)
+                | (ExceptionClass::PageFault, ExceptionClass::Contributory | ExceptionClass::PageFault) => {
+                    log::trace!("Escalating to double fault");
+                    EventInjection::vmentry_inject_event(ExceptionInterrupt::DoubleFault as u8, InterruptionType::HardwareException, Some(0), 0);
+                }
+                _ => {}
+            }
+        }
+        InterruptionType::ExternalInterrupt | InterruptionType::NonMaskableInterrupt => {
+            log::trace!("Queueing vectored event behind the injected event: {:?}", event);
+            if vmx.pending_events.push_front(event).is_err() {
+                log::error!("Failed to queue vectored event: {:?}", event);
+                handle_fatal_exit(basic_exit_reason, guest_registers, vmx);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,480 @@
+//! Handles VMCALL VM exits, providing a versioned hypercall interface to the guest.
+//!
+//! # ABI
+//!
+//! | Register | Input                                                | Output                    |
+//! |----------|------------------------------------------------------|---------------------------|
+//! | RCX      | The authentication key of the `HypercallPolicy`.     | Unchanged.                |
+//! | RDX      | The `HypercallCommand`.                              | Unchanged.                |
+//! | R8       | The guest virtual address of a `HypercallBlock`.     | Unchanged.                |
+//! | RAX      | Ignored.                                             | The `HypercallStatus`.    |
+//!
+//! The block is read from the address space of the caller, the command is executed, and the block is written back
+//! with the results of the command. The caller sets `version` to `HYPERCALL_VERSION` and `size` to the size of the
+//! block. Other registers and memory are unchanged.
+//!
+//! A VMCALL with the wrong key, an unknown command, a block that can't be accessed or has another version, or from
+//! CPL 3 unless user mode is allowed, raises `#UD` like it does without a hypervisor. Without a hypercall policy,
+//! every VMCALL raises `#UD`, except `HypercallCommand::Devirtualize` from CPL 0 while the hypervisor unloads.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.3 VMX INSTRUCTIONS: VMCALL—Call to VM Monitor
+
+use {
+    crate::{
+        intel::{
+            invept::{invept_all_contexts, invept_all_processors},
+            invvpid::invvpid_all_contexts,
+            support::{vmread, vmxoff},
+            syscall::EFER_SCE,
+            vmexit::{
+                cr::{guest_cr0, guest_cr4, CR4_VMXE},
+                exception::handle_undefined_opcode_exception,
+                ExitType,
+            },
+            vmlaunch::restore_guest_registers,
+            vmx::Vmx,
+        },
+        utils::{
+            capture::GuestRegisters,
+            guest_memory::{read_guest_memory, write_guest_memory},
+            instructions::wrmsr,
+            processor::clear_virtualized,
+        },
+    },
+    core::sync::atomic::Ordering,
+    x86::{
+        controlregs::{cr0_write, cr3_write, cr4_write, Cr0, Cr4},
+        debugregs::{dr7_write, Breakpoint, Dr7},
+        dtables::{lgdt, lidt, DescriptorTablePointer},
+        msr,
+        vmx::vmcs::{
+            control::{self, ExitControls},
+            guest, ro,
+        },
+    },
+};
+
+/// The version of the hypercall interface, incremented when the layout of `HypercallBlock` or the meaning of a
+/// command changes.
+pub const HYPERCALL_VERSION: u32 = 1;
+
+/// The value returned by `HypercallCommand::Ping` in the first result.
+pub const HYPERCALL_PONG: u64 = u64::from_le_bytes(*b"hvrspong");
+
+/// The lowest address that isn't a user-mode address, which blocks passed from CPL 3 must be below.
+const USER_ADDRESS_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+/// The commands of the hypercall interface, passed in RDX.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallCommand {
+    /// Checks that the hypervisor is present. Results: `HYPERCALL_PONG`.
+    Ping = 0,
+
+    /// Gets the versions of the interface and the hypervisor.
+    /// Results: `HYPERCALL_VERSION`, and the major, minor and patch versions of the hypervisor.
+    Version = 1,
+
+    /// Gets the number of hooks. Results: the number of hooks.
+    HookCount = 2,
+
+    /// Gets a hook. Arguments: the index of the hook.
+    /// Results: the original virtual address, the hook virtual address, the original physical address, and 1 if the
+    /// hook is enabled or 0 if it is disabled.
+    HookInfo = 3,
+
+    /// Enables the hooks on the page of a hook. Arguments: the index of the hook.
+    EnableHook = 4,
+
+    /// Disables the hooks on the page of a hook, so that the original code executes. Arguments: the index of the hook.
+    DisableHook = 5,
+
+    /// Gets the statistics of a hook aggregated across processors. Arguments: the index of the hook.
+    /// Results: the invocations, the EPT violations, the TSC ticks spent in VM exits, and the average per VM exit.
+    HookStatistics = 6,
+
+    /// Resets the statistics of every hook.
+    ResetStatistics = 7,
+
+    /// Leaves VMX operation on the current processor, which continues after VMCALL without the hypervisor.
+    /// Must be executed at CPL 0 on every processor to devirtualize the system. `Hypervisor::devirtualize_system`
+    /// executes it on the processors that are still virtualized when the hypervisor unloads.
+    Devirtualize = 8,
+}
+
+impl HypercallCommand {
+    /// Gets a command from its number.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Ping),
+            1 => Some(Self::Version),
+            2 => Some(Self::HookCount),
+            3 => Some(Self::HookInfo),
+            4 => Some(Self::EnableHook),
+            5 => Some(Self::DisableHook),
+            6 => Some(Self::HookStatistics),
+            7 => Some(Self::ResetStatistics),
+            8 => Some(Self::Devirtualize),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of a hypercall, returned in RAX.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallStatus {
+    /// The command was executed.
+    Success = 0,
+
+    /// An argument of the command is out of range.
+    InvalidArgument = 1,
+
+    /// The command must be executed at CPL 0.
+    AccessDenied = 2,
+
+    /// The command can't be executed in the current state of the processor.
+    Unavailable = 3,
+}
+
+/// The request and response of a hypercall, in the memory of the caller.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HypercallBlock {
+    /// The version of the interface the caller uses, `HYPERCALL_VERSION`.
+    pub version: u32,
+
+    /// The size of the block in bytes.
+    pub size: u32,
+
+    /// The arguments of the command.
+    pub arguments: [u64; 4],
+
+    /// The results of the command, written by the hypervisor.
+    pub results: [u64; 4],
+}
+
+impl HypercallBlock {
+    /// Creates a block for a command of the current version.
+    ///
+    /// # Arguments
+    ///
+    /// * `arguments` - The arguments of the command.
+    pub fn new(arguments: [u64; 4]) -> Self {
+        Self {
+            version: HYPERCALL_VERSION,
+            size: core::mem::size_of::<Self>() as u32,
+            arguments,
+            results: [0; 4],
+        }
+    }
+
+    /// Gets the bytes of the block, as they are laid out in guest memory.
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>())
+        }
+    }
+
+    /// Gets the mutable bytes of the block, as they are laid out in guest memory.
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self as *mut _ as *mut u8, core::mem::size_of::<Self>())
+        }
+    }
+}
+
+/// Who may use the hypercall interface.
+#[derive(Debug, Clone, Copy)]
+pub struct HypercallPolicy {
+    /// The authentication key, which callers pass in RCX.
+    pub key: u64,
+
+    /// Whether hypercalls are accepted from CPL 3.
+    pub allow_user_mode: bool,
+}
+
+impl HypercallPolicy {
+    /// Creates a policy accepting hypercalls with a key from CPL 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The authentication key, which should be a secret shared with the callers.
+    pub fn new(key: u64) -> Self {
+        Self {
+            key,
+            allow_user_mode: false,
+        }
+    }
+
+    /// Accepts hypercalls from CPL 3. Every user-mode process that knows the key can then use the interface,
+    /// except for `HypercallCommand::Devirtualize`.
+    pub fn allow_user_mode(mut self) -> Self {
+        self.allow_user_mode = true;
+        self
+    }
+}
+
+/// Executes a hypercall in the guest.
+///
+/// # Arguments
+///
+/// * `key` - The authentication key of the `HypercallPolicy`.
+/// * `command` - The command to execute.
+/// * `block` - The block holding the arguments of the command, which receives its results.
+///
+/// # Returns
+///
+/// * `u64` - The `HypercallStatus` returned in RAX.
+///
+/// Raises `#UD` if the processor isn't virtualized or the hypercall is rejected.
+pub fn hypercall(key: u64, command: HypercallCommand, block: &mut HypercallBlock) -> u64 {
+    let status;
+
+    // The hypervisor may store RFLAGS and RIP below RSP when it devirtualizes the processor, so the stack is used.
+    unsafe {
+        core::arch::asm!(
+            "vmcall",
+            in("rcx") key,
+            in("rdx") command as u64,
+            in("r8") block as *mut HypercallBlock,
+            lateout("rax") status,
+        );
+    }
+
+    status
+}
+
+/// Handles a VMCALL VM exit.
+///
+/// # Arguments
+///
+/// * `guest_registers` - A mutable reference to the guest's current register state.
+/// * `vmx` - A mutable reference to the Vmx structure of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - If the hypercall completed, with its status in RAX.
+/// * `ExitType::Continue` - If `#UD` was injected for an invalid hypercall.
+///
+/// Does not return for `HypercallCommand::Devirtualize` when the processor leaves VMX operation.
+#[rustfmt::skip]
+pub fn handle_vmcall(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ExitType {
+    log::debug!("Handling VMCALL VM exit...");
+
+    let shared_data = unsafe { vmx.shared_data.as_ref() };
+
+    // The current privilege level is the DPL of SS, in bits 6:5 of its access rights.
+    let cpl = (vmread(guest::SS_ACCESS_RIGHTS) >> 5) & 0x3;
+
+    // While the hypervisor unloads, `Hypervisor::devirtualize_system` devirtualizes every processor from CPL 0.
+    let unloading = shared_data.unloading.load(Ordering::Acquire) && guest_registers.rdx == HypercallCommand::Devirtualize as u64;
+
+    let authorized = match shared_data.hypercall_policy {
+        _ if unloading => cpl == 0,
+        Some(policy) => guest_registers.rcx == policy.key && (cpl == 0 || policy.allow_user_mode),
+        None => false,
+    };
+
+    if !authorized {
+        return handle_undefined_opcode_exception();
+    }
+
+    let Some(command) = HypercallCommand::from_u64(guest_registers.rdx) else {
+        return handle_undefined_opcode_exception();
+    };
+
+    // User-mode callers can only pass blocks in user-mode memory.
+    let block_address = guest_registers.r8;
+    let block_size = core::mem::size_of::<HypercallBlock>() as u64;
+    if cpl != 0 && block_address.checked_add(block_size).map_or(true, |end| end > USER_ADDRESS_LIMIT) {
+        return handle_undefined_opcode_exception();
+    }
+
+    // The block is written back before the command executes, so that a command never completes without its results.
+    let guest_cr3 = vmread(guest::CR3);
+    let mut block = HypercallBlock::default();
+    if read_guest_memory(guest_cr3, block_address, block.as_bytes_mut()).is_none()
+        || write_guest_memory(guest_cr3, block_address, block.as_bytes()).is_none()
+        || block.version != HYPERCALL_VERSION
+        || (block.size as u64) < block_size
+    {
+        return handle_undefined_opcode_exception();
+    }
+
+    log::trace!("Hypercall {:?} with arguments {:#x?}", command, block.arguments);
+
+    let status = match command {
+        HypercallCommand::Devirtualize if cpl != 0 => HypercallStatus::AccessDenied,
+        HypercallCommand::Devirtualize if !can_devirtualize(vmx) => HypercallStatus::Unavailable,
+        HypercallCommand::Devirtualize => devirtualize(guest_registers, vmx),
+        _ => execute_command(command, &mut block, vmx),
+    };
+
+    write_guest_memory(guest_cr3, block_address, block.as_bytes());
+    guest_registers.rax = status as u64;
+
+    log::debug!("VMCALL VM exit handled successfully!");
+
+    ExitType::IncrementRIP
+}
+
+/// Executes a command that returns to the guest through a VM entry.
+#[rustfmt::skip]
+fn execute_command(command: HypercallCommand, block: &mut HypercallBlock, vmx: &mut Vmx) -> HypercallStatus {
+    let shared_data = unsafe { vmx.shared_data.as_mut() };
+    let hook_manager = &mut shared_data.hook_manager;
+    let hook = hook_manager.hooks.get(block.arguments[0] as usize);
+
+    match command {
+        HypercallCommand::Ping => {
+            block.results[0] = HYPERCALL_PONG;
+        }
+        HypercallCommand::Version => {
+            block.results = [
+                HYPERCALL_VERSION as u64,
+                env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            ];
+        }
+        HypercallCommand::HookCount => {
+            block.results[0] = hook_manager.hooks.len() as u64;
+        }
+        HypercallCommand::HookInfo => {
+            let Some(hook) = hook else {
+                return HypercallStatus::InvalidArgument;
+            };
+
+            let original_page = hook.original_pa.align_down_to_base_page().as_u64();
+            block.results = [hook.original_va, hook.hook_va, hook.original_pa.as_u64(), hook_manager.is_page_enabled(original_page) as u64];
+        }
+        HypercallCommand::EnableHook | HypercallCommand::DisableHook => {
+            let Some(hook) = hook else {
+                return HypercallStatus::InvalidArgument;
+            };
+
+            let original_page = hook.original_pa.align_down_to_base_page().as_u64();
+            hook_manager.set_page_enabled(original_page, command == HypercallCommand::EnableHook);
+
+            let access_type = hook_manager.primary_access(original_page);
+            log::trace!("Hooks on page {:#x} enabled: {}", original_page, hook_manager.is_page_enabled(original_page));
+
+            if let Err(err) = shared_data.primary_ept.swap_page(original_page, original_page, access_type) {
+                log::error!("Failed to change primary EPT permissions for {:#x}: {}", original_page, err);
+                return HypercallStatus::Unavailable;
+            }
+
+            // The primary EPT is shared, so the other processors must not keep executing with the previous permissions.
+            invept_all_processors(vmx);
+        }
+        HypercallCommand::HookStatistics => {
+            let Some(hook) = hook else {
+                return HypercallStatus::InvalidArgument;
+            };
+
+            let total = hook.statistics.total();
+            block.results = [total.invocations, total.ept_violations, total.exit_tsc, total.average_exit_tsc()];
+        }
+        HypercallCommand::ResetStatistics => {
+            hook_manager.reset_statistics();
+        }
+        HypercallCommand::Devirtualize => return HypercallStatus::Unavailable,
+    }
+
+    HypercallStatus::Success
+}
+
+/// Checks whether the current processor can leave VMX operation without losing guest state.
+///
+/// The guest must not have events waiting for injection, and a nested hypervisor must neither be in VMX operation
+/// nor have set CR4.VMXE, which is cleared when the processor leaves VMX operation.
+fn can_devirtualize(vmx: &Vmx) -> bool {
+    vmx.pending_events.is_empty()
+        && guest_cr4() & CR4_VMXE == 0
+        && vmx
+            .nested
+            .as_deref()
+            .map_or(true, |nested| !nested.in_vmx_operation())
+}
+
+/// Leaves VMX operation on the current processor and continues the guest after VMCALL.
+///
+/// The state that VM exits replace with the host state is loaded from the guest state: the control registers, the
+/// descriptor tables, the FS and GS bases, IA32_EFER, IA32_DEBUGCTL, IA32_PERF_GLOBAL_CTRL and the debug registers
+/// owned by the hypervisor. CR4.VMXE, set by VMXON, is cleared once the processor left VMX operation.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.5 LOADING HOST STATE
+#[rustfmt::skip]
+fn devirtualize(guest_registers: &mut GuestRegisters, vmx: &mut Vmx) -> ! {
+    let shared_data = unsafe { vmx.shared_data.as_ref() };
+
+    let cr0 = guest_cr0();
+    let cr3 = vmread(guest::CR3);
+    let cr4 = guest_cr4();
+    let gdtr = DescriptorTablePointer { limit: vmread(guest::GDTR_LIMIT) as u16, base: vmread(guest::GDTR_BASE) as *const u64 };
+    let idtr = DescriptorTablePointer { limit: vmread(guest::IDTR_LIMIT) as u16, base: vmread(guest::IDTR_BASE) as *const u64 };
+    let fs_base = vmread(guest::FS_BASE);
+    let gs_base = vmread(guest::GS_BASE);
+
+    // VM exits clear IA32_DEBUGCTL, and load IA32_PERF_GLOBAL_CTRL if the VM-exit controls ask for it.
+    let debugctl = vmread(guest::IA32_DEBUGCTL_FULL);
+    let perf_global_ctrl = match vmread(control::VMEXIT_CONTROLS) & ExitControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits() as u64 != 0 {
+        true => Some(vmread(guest::IA32_PERF_GLOBAL_CTRL_FULL)),
+        false => None,
+    };
+
+    // EFER.SCE is cleared in the guest while the syscall hook is enabled.
+    let efer = match shared_data.syscall_hooks.is_some() && vmx.guest_efer_sce {
+        true => vmread(guest::IA32_EFER_FULL) | EFER_SCE,
+        false => vmread(guest::IA32_EFER_FULL),
+    };
+
+    let dr7 = match shared_data.hardware_breakpoints.as_deref() {
+        Some(breakpoints) => breakpoints.hide_dr7(vmread(guest::DR7), vmx.guest_dr7),
+        None => vmread(guest::DR7),
+    };
+
+    guest_registers.rip += vmread(ro::VMEXIT_INSTRUCTION_LEN);
+    guest_registers.rax = HypercallStatus::Success as u64;
+
+    log::trace!("Devirtualizing the processor at guest RIP {:#x}", guest_registers.rip);
+
+    // The processor won't enter the guest again, so the cached translations of the guest are discarded.
+    invept_all_contexts();
+    invvpid_all_contexts();
+
+    if let Err(error) = vmxoff() {
+        panic!("Failed to devirtualize the processor: {}", error);
+    }
+
+    clear_virtualized();
+
+    unsafe {
+        cr3_write(cr3);
+        cr4_write(Cr4::from_bits_truncate((cr4 & !CR4_VMXE) as usize));
+        cr0_write(Cr0::from_bits_truncate(cr0 as usize));
+        lgdt(&gdtr);
+        lidt(&idtr);
+
+        wrmsr(msr::IA32_EFER, efer);
+        wrmsr(msr::IA32_FS_BASE, fs_base);
+        wrmsr(msr::IA32_GS_BASE, gs_base);
+        wrmsr(msr::IA32_DEBUGCTL, debugctl);
+
+        if let Some(perf_global_ctrl) = perf_global_ctrl {
+            wrmsr(msr::IA32_PERF_GLOBAL_CTRL, perf_global_ctrl);
+        }
+
+        if let Some(breakpoints) = shared_data.hardware_breakpoints.as_deref() {
+            for (index, breakpoint) in [Breakpoint::Dr0, Breakpoint::Dr1, Breakpoint::Dr2, Breakpoint::Dr3].iter().enumerate() {
+                if breakpoints.is_reserved(index as u8) {
+                    breakpoint.write(vmx.guest_debug_registers[index] as usize);
+                }
+            }
+        }
+        dr7_write(Dr7(dr7 as usize));
+
+        restore_guest_registers(guest_registers)
+    }
+}
@@ -10,7 +10,13 @@
 //! Drew: https://github.com/drew-gpf
 
 use crate::{
-    intel::{support::vmread, vmerror::VmInstructionError, vmexit::VmExit, vmx::Vmx},
+    intel::{
+        nested::{transitions::fail_nested_vmentry, NestedVmx},
+        support::vmread,
+        vmerror::VmInstructionError,
+        vmexit::VmExit,
+        vmx::Vmx,
+    },
     utils::capture::GuestRegisters,
 };
 
@@ -30,6 +36,16 @@ extern "C" {
 
     /// Assembly stub for handling VM exits.
     pub fn vmexit_stub();
+
+    /// Continues the guest outside of VMX operation, after the processor was devirtualized.
+    ///
+    /// Loads the general-purpose and XMM registers, RSP, RFLAGS and RIP from the guest register state. RFLAGS and RIP
+    /// are passed through the 16 bytes below the guest RSP. Every other register must already hold the guest state.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_registers` - The guest register state to continue with.
+    pub fn restore_guest_registers(guest_registers: &GuestRegisters) -> !;
 }
 
 core::arch::global_asm!(
@@ -166,6 +182,13 @@ vmexit_stub:
     mov     [r15 + registers_r13], r13
     mov     [r15 + registers_r14], r14
 
+    // Sample the TSC as early as possible into `VmStack::exit_tsc`, 8 (0x8) bytes after the `Vmx` pointer.
+    // RAX and RDX were saved above.
+    rdtsc
+    shl     rdx, 32
+    or      rax, rdx
+    mov     [rsp + 0x88], rax
+
     // Save guest XMM registers.
     movdqa  [r15 + registers_xmm0], xmm0
     movdqa  [r15 + registers_xmm1], xmm1
@@ -205,6 +228,11 @@ vmexit_stub:
     // Restore stack pointer after VM exit handling.
     add rsp, 0x20
 
+.Lvm_entry:
+    // The handler returns whether the next VM entry launches the current VMCS, which is the case the first time
+    // a nested guest is entered. The flags are kept while the guest registers are restored.
+    test    al, al
+
     // Retrieve pointer to guest registers for restoration.
     mov     r15, [rsp]
 
@@ -244,11 +272,73 @@ vmexit_stub:
     // Do this last to avoid overwriting r15.
     mov     r15, [r15 + registers_r15]
 
-    // Attempt to resume the guest virtual machine.
+    // Attempt to resume or launch the guest virtual machine.
+    jnz     2f
     vmresume
+    jmp     3f
+2:
+    vmlaunch
+3:
+
+    // If VM entry fails, handle the failure, then enter the guest hypervisor again.
+    mov     rcx, [rsp]
+    mov     rdx, [rsp + 0x80]
+    sub     rsp, 0x20
+    call    vmentry_failed
+    add     rsp, 0x20
+    jmp     .Lvm_entry
+
+.global restore_guest_registers
+restore_guest_registers:
+    // Load pointer to guest's register state into r15.
+    mov     r15, rcx
+
+    movdqa  xmm0, [r15 + registers_xmm0]
+    movdqa  xmm1, [r15 + registers_xmm1]
+    movdqa  xmm2, [r15 + registers_xmm2]
+    movdqa  xmm3, [r15 + registers_xmm3]
+    movdqa  xmm4, [r15 + registers_xmm4]
+    movdqa  xmm5, [r15 + registers_xmm5]
+    movdqa  xmm6, [r15 + registers_xmm6]
+    movdqa  xmm7, [r15 + registers_xmm7]
+    movdqa  xmm8, [r15 + registers_xmm8]
+    movdqa  xmm9, [r15 + registers_xmm9]
+    movdqa  xmm10, [r15 + registers_xmm10]
+    movdqa  xmm11, [r15 + registers_xmm11]
+    movdqa  xmm12, [r15 + registers_xmm12]
+    movdqa  xmm13, [r15 + registers_xmm13]
+    movdqa  xmm14, [r15 + registers_xmm14]
+    movdqa  xmm15, [r15 + registers_xmm15]
+
+    // Switch to the guest stack, with RFLAGS and RIP pushed below the guest RSP for popfq and ret.
+    mov     rax, [r15 + registers_rsp]
+    sub     rax, 0x10
+    mov     rbx, [r15 + registers_rflags]
+    mov     [rax], rbx
+    mov     rbx, [r15 + registers_rip]
+    mov     [rax + 0x8], rbx
+    mov     rsp, rax
+
+    mov     rax, [r15 + registers_rax]
+    mov     rbx, [r15 + registers_rbx]
+    mov     rcx, [r15 + registers_rcx]
+    mov     rdx, [r15 + registers_rdx]
+    mov     rdi, [r15 + registers_rdi]
+    mov     rsi, [r15 + registers_rsi]
+    mov     rbp, [r15 + registers_rbp]
+    mov      r8, [r15 + registers_r8]
+    mov      r9, [r15 + registers_r9]
+    mov     r10, [r15 + registers_r10]
+    mov     r11, [r15 + registers_r11]
+    mov     r12, [r15 + registers_r12]
+    mov     r13, [r15 + registers_r13]
+    mov     r14, [r15 + registers_r14]
 
-    // If VMRESUME fails, handle the failure.
-    call vmresume_failed
+    // Do this last to avoid overwriting r15.
+    mov     r15, [r15 + registers_r15]
+
+    popfq
+    ret
 "#
 );
 
@@ -261,11 +351,15 @@ vmexit_stub:
 ///
 /// * `registers` - A pointer to `GuestRegisters` representing the guest's state at VM exit.
 ///
+/// # Returns
+///
+/// `true` if the next VM entry must use VMLAUNCH, for a nested guest entered for the first time, or `false` for VMRESUME.
+///
 /// # Panics
 ///
 /// Panics if `registers` is a null pointer.
 #[no_mangle]
-pub unsafe extern "C" fn vmexit_handler(registers: *mut GuestRegisters, vmx: *mut u64) {
+pub unsafe extern "C" fn vmexit_handler(registers: *mut GuestRegisters, vmx: *mut u64) -> bool {
     if registers.is_null() {
         panic!("vmexit_handler received a null pointer for registers.");
     }
@@ -280,6 +374,40 @@ pub unsafe extern "C" fn vmexit_handler(registers: *mut GuestRegisters, vmx: *mu
     if let Err(e) = vmexit.handle_vmexit(registers, vmx) {
         panic!("Failed to handle VMEXIT: {:?}", e);
     }
+
+    vmx.nested
+        .as_deref_mut()
+        .is_some_and(NestedVmx::take_launch)
+}
+
+/// Handles the failure of a VM entry after a VM exit.
+///
+/// A failed VM entry to a nested guest completes the VMLAUNCH or VMRESUME of the guest hypervisor with VMfailValid,
+/// and the guest hypervisor is resumed. Any other failure is reported as a failure of `VMRESUME`.
+///
+/// # Arguments
+///
+/// * `registers` - A pointer to `GuestRegisters` representing the guest's state.
+/// * `vmx` - A pointer to the `Vmx` structure of the current processor.
+///
+/// # Returns
+///
+/// `false`, as the guest hypervisor is resumed with VMRESUME.
+#[no_mangle]
+pub unsafe extern "C" fn vmentry_failed(registers: *mut GuestRegisters, vmx: *mut u64) -> bool {
+    let registers = &mut *registers;
+    let vmx = &mut *(vmx as *mut Vmx);
+
+    match vmx
+        .nested
+        .as_deref()
+        .is_some_and(|nested| nested.in_nested_guest)
+    {
+        true => fail_nested_vmentry(registers, vmx),
+        false => vmresume_failed(),
+    }
+
+    false
 }
 
 /// Handles the failure of the `VMLAUNCH` instruction.
@@ -5,8 +5,22 @@ use {
         error::HypervisorError,
         intel::{
             ept::{hooks::HookManager, paging::Ept},
+            exception_hooks::ExceptionHooks,
+            hardware_breakpoints::HardwareBreakpoints,
+            io::IoHooks,
+            msr_bitmap::MsrBitmap,
             shared_data::SharedData,
+            syscall::SyscallHooks,
+            tsc::{is_tsc_adjust_supported, HiddenCycles},
             vcpu::Vcpu,
+            virtual_msrs::VirtualMsrs,
+            vmerror::VmxBasicExitReason,
+            vmexit::{
+                cpuid::CpuidPolicy,
+                cr::ControlRegisterPolicy,
+                registry::{ExitHandlerRegistry, VmExitHandler},
+                vmcall::HypercallPolicy,
+            },
         },
         utils::{
             alloc::PhysicalAllocator,
@@ -14,6 +28,7 @@ use {
         },
     },
     alloc::{boxed::Box, vec::Vec},
+    core::{mem::ManuallyDrop, sync::atomic::Ordering},
 };
 
 #[derive(Default)]
@@ -27,6 +42,39 @@ pub struct HypervisorBuilder {
 
     /// The hook manager.
     hook_manager: Option<Box<HookManager>>,
+
+    /// The syscall hooks for the EFER.SCE syscall hook.
+    syscall_hooks: Option<Box<SyscallHooks>>,
+
+    /// The hypervisor-owned hardware breakpoints.
+    hardware_breakpoints: Option<Box<HardwareBreakpoints>>,
+
+    /// The callbacks of the intercepted I/O ports.
+    io_hooks: Option<Box<IoHooks>>,
+
+    /// The callbacks of the intercepted exception vectors.
+    exception_hooks: Option<Box<ExceptionHooks>>,
+
+    /// The views of the MSRs virtualized for the guest.
+    virtual_msrs: Option<Box<VirtualMsrs>>,
+
+    /// Whether the latency of VM exits is hidden from the guest through TSC offsetting.
+    tsc_compensation: bool,
+
+    /// Whether VMX is exposed to the guest, which can then run its own hypervisor.
+    nested_vmx: bool,
+
+    /// The control-register bits and accesses owned by the hypervisor.
+    control_register_policy: Option<ControlRegisterPolicy>,
+
+    /// The `CPUID` results returned to the guest.
+    cpuid_policy: Option<CpuidPolicy>,
+
+    /// Who may use the hypercall interface.
+    hypercall_policy: Option<HypercallPolicy>,
+
+    /// The VM exit handlers registered on top of the built-in handlers.
+    exit_handlers: Option<Box<ExitHandlerRegistry>>,
 }
 
 impl HypervisorBuilder {
@@ -35,7 +83,7 @@ impl HypervisorBuilder {
     /// # Returns
     ///
     /// A `Result` which is `Ok` if hypervisor initialization was successful, or `Err` if there was an error.
-    pub fn build(self) -> Result<Hypervisor, HypervisorError> {
+    pub fn build(mut self) -> Result<Hypervisor, HypervisorError> {
         log::debug!("Building hypervisor");
 
         Hypervisor::check_supported_cpu()?;
@@ -60,7 +108,7 @@ impl HypervisorBuilder {
         let mut shared_data = SharedData::new(primary_ept, hook_manager)?;
 
         #[cfg(feature = "secondary-ept")]
-        let shared_data = {
+        let mut shared_data = {
             let secondary_ept = self
                 .secondary_ept
                 .ok_or(HypervisorError::SecondaryEPTNotProvided)?;
@@ -68,9 +116,71 @@ impl HypervisorBuilder {
             SharedData::new(primary_ept, secondary_ept, hook_manager)?
         };
 
+        // Guest accesses to IA32_EFER must be intercepted to hide that EFER.SCE is cleared.
+        if let Some(syscall_hooks) = self.syscall_hooks {
+            shared_data.msr_bitmap.hook_msr(x86::msr::IA32_EFER);
+            shared_data.syscall_hooks = Some(syscall_hooks);
+        }
+
+        if let Some(hardware_breakpoints) = self.hardware_breakpoints {
+            shared_data.hardware_breakpoints = Some(hardware_breakpoints);
+        }
+
+        if let Some(io_hooks) = self.io_hooks {
+            for port in io_hooks.ports() {
+                shared_data.io_bitmap.hook_port(port);
+            }
+            shared_data.io_hooks = Some(io_hooks);
+        }
+
+        if let Some(exception_hooks) = self.exception_hooks {
+            shared_data.exception_hooks = Some(exception_hooks);
+        }
+
+        // `CPUID` reports VMX support, and the VMX capability MSRs report the features supported for nested guests.
+        if self.nested_vmx {
+            self.cpuid_policy = Some(self.cpuid_policy.take().unwrap_or_default().expose_vmx());
+            self.virtual_msrs
+                .get_or_insert_with(VirtualMsrs::new)
+                .expose_nested_vmx();
+            shared_data.nested_vmx = true;
+        }
+
+        if let Some(virtual_msrs) = self.virtual_msrs {
+            for msr in virtual_msrs.msrs() {
+                shared_data.msr_bitmap.hook_msr(msr);
+            }
+            shared_data.virtual_msrs = Some(virtual_msrs);
+        }
+
+        // Guest accesses to the TSC and the TSC deadline are translated by the TSC offset, which IA32_TSC_ADJUST moves.
+        if self.tsc_compensation {
+            shared_data
+                .msr_bitmap
+                .hook_msr(x86::msr::IA32_TIME_STAMP_COUNTER);
+            shared_data.msr_bitmap.hook_msr(x86::msr::IA32_TSC_DEADLINE);
+            if is_tsc_adjust_supported() {
+                shared_data.msr_bitmap.hook_msr(x86::msr::IA32_TSC_ADJUST);
+            }
+            shared_data.tsc_compensation = true;
+            shared_data.tsc_hidden_cycles = HiddenCycles::new(processors.len() as u32);
+        }
+
+        if let Some(control_register_policy) = self.control_register_policy {
+            shared_data.control_register_policy = control_register_policy;
+        }
+
+        if let Some(cpuid_policy) = self.cpuid_policy {
+            shared_data.cpuid_policy = cpuid_policy;
+        }
+
+        shared_data.hypercall_policy = self.hypercall_policy;
+
+        shared_data.exit_handlers = self.exit_handlers;
+
         Ok(Hypervisor {
             processors,
-            shared_data,
+            shared_data: ManuallyDrop::new(shared_data),
         })
     }
 
@@ -89,6 +199,138 @@ impl HypervisorBuilder {
         self.hook_manager = Some(hook_manager);
         self
     }
+
+    /// Sets the syscall hooks, enabling the EFER.SCE syscall hook on every processor.
+    ///
+    /// The guest runs with EFER.SCE cleared, so `SYSCALL` and `SYSRET` raise #UD and are emulated after the
+    /// callback registered for the syscall number is invoked.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_hooks` - The callbacks invoked for the hooked syscall numbers.
+    pub fn syscall_hooks(mut self, syscall_hooks: Box<SyscallHooks>) -> Self {
+        self.syscall_hooks = Some(syscall_hooks);
+        self
+    }
+
+    /// Sets the hypervisor-owned hardware breakpoints, enabling debug register virtualization on every processor.
+    ///
+    /// The debug registers reserved for the breakpoints are loaded on each processor when it is virtualized.
+    ///
+    /// # Arguments
+    ///
+    /// * `hardware_breakpoints` - The breakpoints and the callbacks invoked when they are hit.
+    pub fn hardware_breakpoints(mut self, hardware_breakpoints: Box<HardwareBreakpoints>) -> Self {
+        self.hardware_breakpoints = Some(hardware_breakpoints);
+        self
+    }
+
+    /// Sets the I/O hooks, whose ports are intercepted through the I/O bitmaps of every processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `io_hooks` - The callbacks invoked for IN, OUT, INS and OUTS accesses to the hooked ports.
+    pub fn io_hooks(mut self, io_hooks: Box<IoHooks>) -> Self {
+        self.io_hooks = Some(io_hooks);
+        self
+    }
+
+    /// Sets the exception hooks, whose vectors are intercepted through the exception bitmap of the selected processors.
+    ///
+    /// The page fault filter of the hooks only applies to processors that intercept page faults.
+    ///
+    /// # Arguments
+    ///
+    /// * `exception_hooks` - The callbacks invoked for the hooked exceptions.
+    pub fn exception_hooks(mut self, exception_hooks: Box<ExceptionHooks>) -> Self {
+        self.exception_hooks = Some(exception_hooks);
+        self
+    }
+
+    /// Sets the virtualized MSRs, whose RDMSR and WRMSR are intercepted through the MSR bitmap of every processor.
+    ///
+    /// The shadow values are created on each processor before it is virtualized.
+    ///
+    /// # Arguments
+    ///
+    /// * `virtual_msrs` - The views of the MSRs presented to the guest.
+    pub fn virtual_msrs(mut self, virtual_msrs: Box<VirtualMsrs>) -> Self {
+        self.virtual_msrs = Some(virtual_msrs);
+        self
+    }
+
+    /// Hides the latency of VM exits from the guest through TSC offsetting.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the time spent in VM exits is subtracted from the TSC read by the guest.
+    pub fn tsc_compensation(mut self, enabled: bool) -> Self {
+        self.tsc_compensation = enabled;
+        self
+    }
+
+    /// Exposes VMX to the guest, emulating the VMX instructions so that it can run its own hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether `CPUID`, the VMX capability MSRs and CR4.VMXE report VMX support to the guest.
+    pub fn nested_vmx(mut self, enabled: bool) -> Self {
+        self.nested_vmx = enabled;
+        self
+    }
+
+    /// Sets the control-register bits and accesses owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `control_register_policy` - The policy, applied to every processor.
+    pub fn control_register_policy(
+        mut self,
+        control_register_policy: ControlRegisterPolicy,
+    ) -> Self {
+        self.control_register_policy = Some(control_register_policy);
+        self
+    }
+
+    /// Sets the `CPUID` results returned to the guest, replacing the default policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpuid_policy` - The policy, applied on every processor.
+    pub fn cpuid_policy(mut self, cpuid_policy: CpuidPolicy) -> Self {
+        self.cpuid_policy = Some(cpuid_policy);
+        self
+    }
+
+    /// Enables the hypercall interface, through which the guest can manage hooks and devirtualize the processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `hypercall_policy` - The authentication key and privilege levels accepted for `VMCALL`.
+    pub fn hypercall_policy(mut self, hypercall_policy: HypercallPolicy) -> Self {
+        self.hypercall_policy = Some(hypercall_policy);
+        self
+    }
+
+    /// Registers a VM exit handler for an exit reason.
+    ///
+    /// Handlers registered for the same exit reason are chained in registration order, ending with the
+    /// built-in handler. A handler overrides the built-in handling by not calling `next`.
+    ///
+    /// # Arguments
+    ///
+    /// * `basic_exit_reason` - The exit reason to handle.
+    /// * `handler` - The handler, such as a function taking the guest registers, the `Vmx`, the `VmExitInfo` and the `NextHandler`.
+    pub fn exit_handler(
+        mut self,
+        basic_exit_reason: VmxBasicExitReason,
+        handler: impl VmExitHandler + 'static,
+    ) -> Self {
+        self.exit_handlers
+            .get_or_insert_with(ExitHandlerRegistry::new)
+            .register(basic_exit_reason, Box::new(handler));
+        self
+    }
 }
 
 /// The main struct representing the hypervisor.
@@ -96,8 +338,8 @@ pub struct Hypervisor {
     /// The processors to virtualize.
     processors: Vec<Vcpu>,
 
-    /// The shared data between processors.
-    shared_data: Box<SharedData>,
+    /// The shared data between processors, which is leaked if a processor can't be devirtualized.
+    shared_data: ManuallyDrop<Box<SharedData>>,
 }
 
 impl Hypervisor {
@@ -129,23 +371,49 @@ impl Hypervisor {
 
     /// Reverts the virtualization of the system's processors.
     ///
+    /// Every processor that wasn't devirtualized with `HypercallCommand::Devirtualize` is devirtualized with the same
+    /// hypercall, which is accepted from CPL 0 without the key from then on. The memory of the hypervisor can only be
+    /// freed once this succeeded, since a virtualized processor keeps using it.
+    ///
     /// # Returns
     ///
-    /// A `Result` which is `Ok` if the devirtualization was successful, or `Err` if there was an error.
+    /// A `Result` which is `Ok` if every processor was devirtualized, or `Err` with the last error otherwise.
     pub fn devirtualize_system(&mut self) -> Result<(), HypervisorError> {
         log::trace!("Devirtualizing processors");
 
+        self.shared_data.unloading.store(true, Ordering::Release);
+
+        let mut result = Ok(());
+
         for processor in self.processors.iter_mut() {
             let Some(executor) = ProcessorExecutor::switch_to_processor(processor.id()) else {
-                return Err(HypervisorError::ProcessorSwitchFailed);
+                result = Err(HypervisorError::ProcessorSwitchFailed);
+                continue;
             };
 
-            processor.devirtualize_cpu()?;
+            if let Err(err) = processor.devirtualize_cpu() {
+                result = Err(err);
+            }
 
             drop(executor);
         }
 
-        Ok(())
+        result
+    }
+
+    /// Gets the MSR Bitmap shared by the processors.
+    ///
+    /// Changes to the interception of MSRs take effect on every processor.
+    pub fn msr_bitmap(&self) -> &MsrBitmap {
+        &self.shared_data.msr_bitmap
+    }
+
+    /// Gets the hardware breakpoints shared by the processors, if any were provided to the builder.
+    ///
+    /// Breakpoints released with `HardwareBreakpoints::clear` are handed back to the guest on the next VM exit of
+    /// every processor.
+    pub fn hardware_breakpoints(&self) -> Option<&HardwareBreakpoints> {
+        self.shared_data.hardware_breakpoints.as_deref()
     }
 
     /// Check if the CPU is supported.
@@ -218,10 +486,20 @@ impl Drop for Hypervisor {
     ///
     /// When a `Hypervisor` instance goes out of scope or is explicitly dropped,
     /// this method attempts to devirtualize the system and logs the result.
+    /// If a processor is still virtualized, the memory of the hypervisor is leaked instead of being freed.
     fn drop(&mut self) {
         match self.devirtualize_system() {
-            Ok(_) => log::trace!("Devirtualized successfully!"),
-            Err(err) => log::trace!("Failed to devirtualize {}", err),
+            Ok(_) => {
+                log::trace!("Devirtualized successfully!");
+                unsafe { ManuallyDrop::drop(&mut self.shared_data) };
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to devirtualize {}, leaking the hypervisor memory",
+                    err
+                );
+                core::mem::forget(core::mem::take(&mut self.processors));
+            }
         }
     }
 }
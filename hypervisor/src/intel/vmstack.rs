@@ -26,8 +26,9 @@ pub struct VmStack {
     /// Padding to ensure the Host RSP remains 16-byte aligned.
     pub vmx: *mut u64,
 
-    /// Padding to ensure the Host RSP remains 16-byte aligned.
-    pub padding_3: u64,
+    /// The host TSC sampled by `vmexit_stub` on every VM exit, as soon as the guest registers it clobbers are saved.
+    /// Also keeps the Host RSP 16-byte aligned.
+    pub exit_tsc: u64,
 
     /// Padding to ensure the Host RSP remains 16-byte aligned.
     pub padding_2: u64,
@@ -59,7 +60,7 @@ impl VmStack {
 
         // We don't null `vmx` because it should already be populated and we don't want to overwrite it.
 
-        vmstack.padding_3 = u64::MAX;
+        vmstack.exit_tsc = 0;
         vmstack.padding_2 = u64::MAX;
         vmstack.padding_1 = u64::MAX;
 
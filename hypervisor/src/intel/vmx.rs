@@ -7,10 +7,16 @@ use {
         error::HypervisorError,
         intel::{
             descriptor::DescriptorTables,
+            ept::{hooks::PendingPageWrite, paging::SingleStepView},
+            events::EventQueue,
+            nested::NestedVmx,
             paging::PageTables,
             shared_data::SharedData,
+            syscall::EFER_SCE,
+            tsc::TscCompensation,
             vcpu::Vcpu,
             vmcs::Vmcs,
+            vmexit::mtf::SingleStep,
             vmlaunch::launch_vm,
             vmstack::{VmStack, STACK_CONTENTS_SIZE},
             vmxon::Vmxon,
@@ -19,10 +25,13 @@ use {
         utils::{
             alloc::{KernelAlloc, PhysicalAllocator},
             capture::CONTEXT,
+            instructions::rdmsr,
         },
     },
-    alloc::boxed::Box,
-    core::ptr::NonNull,
+    alloc::{boxed::Box, collections::BTreeMap},
+    core::{ptr::NonNull, sync::atomic::AtomicU32},
+    x86::{bits64::paging::BASE_PAGE_SIZE, msr},
+    x86_64::registers::control::Cr4,
 };
 
 /// Represents the VMX structure with essential components for VMX virtualization.
@@ -67,6 +76,58 @@ pub struct Vmx {
 
     /// The shared data between processors.
     pub shared_data: NonNull<SharedData>,
+
+    /// The private EPT the processor single-steps the guest with, mapping the accessed hooked pages differently from the shared EPT.
+    /// Allocated using `MmAllocateContiguousMemorySpecifyCacheNode`.
+    pub single_step_view: Box<SingleStepView, PhysicalAllocator>,
+
+    /// The single step in progress with the single-step view, completed on the next monitor trap flag VM exit.
+    pub single_step: Option<SingleStep>,
+
+    /// The page table of a process-scoped hook written by the single-stepped instruction, whose hooks follow their
+    /// page to a new physical page on the next monitor trap flag VM exit.
+    pub pending_pte_write: Option<u64>,
+
+    /// The EPT generation of the shared data the cached EPT translations of the processor were last invalidated for.
+    pub ept_generation: u64,
+
+    /// The guest write to a hooked original page that is applied to the hooked copy on the next monitor trap flag VM exit.
+    pub pending_page_write: Option<PendingPageWrite>,
+
+    /// The values written by the guest to the debug registers reserved for hypervisor-owned hardware breakpoints.
+    pub guest_debug_registers: [u64; 4],
+
+    /// The DR7 value last written by the guest, before the fields of the reserved debug registers were applied.
+    pub guest_dr7: u64,
+
+    /// The EFER.SCE value of the guest, which the EFER.SCE syscall hook keeps cleared in the guest's EFER in the VMCS.
+    pub guest_efer_sce: bool,
+
+    /// The B0-B3 bits of the hardware breakpoints whose debug registers were handed back to the guest on this processor.
+    pub released_breakpoints: u64,
+
+    /// The B0-B3 bits of the hardware breakpoints released on the current VM exit, whose pending hits are discarded.
+    pub released_breakpoint_conditions: u64,
+
+    /// The events waiting to be injected into the guest.
+    pub pending_events: EventQueue,
+
+    /// The number of NMIs received in VMX root operation that are yet to be queued for the guest.
+    /// Incremented by the host NMI handler, which can interrupt the VM exit handler at any point.
+    pub host_nmis: AtomicU32,
+
+    /// The shadow values of the MSRs virtualized with a shadow view, keyed by MSR.
+    pub msr_shadows: BTreeMap<u32, u64>,
+
+    /// The state of TSC compensation, present when the latency of VM exits is hidden from the guest.
+    pub tsc_compensation: Option<TscCompensation>,
+
+    /// The state of nested virtualization, present when VMX is exposed to the guest.
+    pub nested: Option<Box<NestedVmx>>,
+
+    /// The contents of the original page before the pending write, used to find the modified range.
+    /// Allocated using `ExAllocatePool` or `ExAllocatePoolWithTag`.
+    pub page_write_snapshot: Box<[u8; BASE_PAGE_SIZE], KernelAlloc>,
 }
 
 impl Vmx {
@@ -88,6 +149,10 @@ impl Vmx {
         let vmstack = unsafe { Box::try_new_zeroed_in(KernelAlloc)?.assume_init() };
         let mut host_paging: Box<PageTables, PhysicalAllocator> = unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
         let guest_registers = GuestRegisters::default();
+        let page_write_snapshot = unsafe { Box::try_new_zeroed_in(KernelAlloc)?.assume_init() };
+        let single_step_view = unsafe { Box::try_new_zeroed_in(PhysicalAllocator)?.assume_init() };
+        let nested = shared_data.nested_vmx.then(NestedVmx::new).transpose()?;
+        let msr_shadows = shared_data.virtual_msrs.as_ref().map_or_else(BTreeMap::new, |virtual_msrs| virtual_msrs.create_shadows());
 
         // To capture the current GDT and IDT for the guest the order is important so we can setup up a new GDT and IDT for the host.
         // This is done here instead of `setup_virtualization` because it uses a vec to allocate memory for the new GDT
@@ -107,6 +172,22 @@ impl Vmx {
             host_paging,
             guest_registers,
             shared_data: unsafe { NonNull::new_unchecked(shared_data as *mut _) },
+            single_step_view,
+            single_step: None,
+            pending_pte_write: None,
+            ept_generation: 0,
+            pending_page_write: None,
+            guest_debug_registers: [context.Dr0, context.Dr1, context.Dr2, context.Dr3],
+            guest_dr7: context.Dr7,
+            guest_efer_sce: rdmsr(msr::IA32_EFER) & EFER_SCE != 0,
+            released_breakpoints: 0,
+            released_breakpoint_conditions: 0,
+            pending_events: EventQueue::new(),
+            host_nmis: AtomicU32::new(0),
+            msr_shadows,
+            tsc_compensation: shared_data.tsc_compensation.then(|| TscCompensation::new()),
+            nested,
+            page_write_snapshot,
         };
 
         let mut instance = Box::new(instance);
@@ -140,6 +221,9 @@ impl Vmx {
     ) -> Result<(), HypervisorError> {
         log::debug!("Setting up virtualization");
 
+        // CR4.VMXE is hidden from the guest, so its value is captured before VMXON sets it.
+        let original_cr4 = Cr4::read_raw();
+
         Vmxon::setup(&mut self.vmxon_region)?;
         Vcpu::invalidate_contexts();
 
@@ -163,7 +247,7 @@ impl Vmx {
          * - 25.7 VM-EXIT CONTROL FIELDS
          * - 25.8 VM-ENTRY CONTROL FIELDS
          */
-        Vmcs::setup_vmcs_control_fields(shared_data)?;
+        Vmcs::setup_vmcs_control_fields(shared_data, original_cr4)?;
 
         log::debug!("Virtualization setup successfully!");
 
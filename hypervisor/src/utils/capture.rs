@@ -241,6 +241,69 @@ const_assert_eq!(
     0x190 /* 400 bytes */
 );
 
+impl GuestRegisters {
+    /// Gets a general-purpose register by its encoding in VM exit qualifications and instruction information.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The register number: 0 = RAX, 1 = RCX, 2 = RDX, 3 = RBX, 4 = RSP, 5 = RBP, 6 = RSI, 7 = RDI, 8-15 = R8-R15.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The value of the register.
+    pub fn gpr(&self, index: u8) -> u64 {
+        match index & 0xF {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => self.rsp,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            _ => self.r15,
+        }
+    }
+
+    /// Sets a general-purpose register by its encoding in VM exit qualifications and instruction information.
+    ///
+    /// The guest RSP is loaded from the VMCS on VM entry, so the caller must also write `guest::RSP` when `index` is 4.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The register number, as for `gpr`.
+    /// * `value` - The new value of the register.
+    pub fn set_gpr(&mut self, index: u8, value: u64) {
+        let register = match index & 0xF {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        };
+
+        *register = value;
+    }
+}
+
 #[repr(C)]
 #[repr(align(16))]
 #[derive(Clone, Copy, Default)]
@@ -316,6 +316,17 @@ impl FunctionHook {
     pub const fn handler_address(&self) -> u64 {
         self.handler
     }
+
+    /// Provides a function to retrieve the number of bytes written at the hook address when the hook is enabled.
+    ///
+    /// ## Returns
+    /// Returns the length of the jmp or breakpoint shellcode.
+    pub fn shellcode_len(&self) -> usize {
+        match self.hook_type {
+            HookType::Jmp => JMP_SHELLCODE_LEN,
+            HookType::Breakpoint => BP_SHELLCODE_LEN,
+        }
+    }
 }
 
 /// Implementation of the Drop trait for FunctionHook.
@@ -0,0 +1,198 @@
+//! Provides access to guest virtual memory from VMX root operation.
+//!
+//! The host runs with the system process CR3, so guest virtual addresses (especially user-mode ones)
+//! are translated by walking the guest's own paging structures instead of dereferencing them directly.
+
+use {
+    crate::utils::addresses::PhysicalAddress,
+    x86::bits64::paging::{
+        pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SIZE, HUGE_PAGE_SIZE,
+        LARGE_PAGE_SIZE,
+    },
+};
+
+/// Mask for the physical address bits (51:12) of a paging-structure entry or CR3.
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Present (bit 0) of a paging-structure entry.
+const ENTRY_PRESENT: u64 = 1 << 0;
+
+/// Page size (bit 7) of a PDPTE or PDE.
+const ENTRY_PAGE_SIZE: u64 = 1 << 7;
+
+/// Reads the paging-structure entry at the given index of the table located at the given physical address.
+fn read_entry(table_pa: u64, index: usize) -> Option<u64> {
+    let entry_va =
+        PhysicalAddress::va_from_pa(table_pa + (index * core::mem::size_of::<u64>()) as u64);
+
+    if entry_va == 0 {
+        return None;
+    }
+
+    let entry = unsafe { (entry_va as *const u64).read_volatile() };
+
+    if entry & ENTRY_PRESENT == 0 {
+        return None;
+    }
+
+    Some(entry)
+}
+
+/// Translates a guest virtual address to a guest physical address using 4-level paging.
+///
+/// # Arguments
+///
+/// * `guest_cr3` - The guest's CR3 value, defining the address space to translate in.
+/// * `guest_va` - The guest virtual address to translate.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The guest physical address, or `None` if the address is not mapped.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.5 4-LEVEL PAGING AND 5-LEVEL PAGING
+pub fn translate_guest_virtual_address(guest_cr3: u64, guest_va: u64) -> Option<u64> {
+    let va = VAddr::from(guest_va);
+
+    let pml4e = read_entry(guest_cr3 & ENTRY_ADDRESS_MASK, pml4_index(va))?;
+
+    let pdpte = read_entry(pml4e & ENTRY_ADDRESS_MASK, pdpt_index(va))?;
+    if pdpte & ENTRY_PAGE_SIZE != 0 {
+        return Some(
+            (pdpte & ENTRY_ADDRESS_MASK & !(HUGE_PAGE_SIZE as u64 - 1))
+                + (guest_va & (HUGE_PAGE_SIZE as u64 - 1)),
+        );
+    }
+
+    let pde = read_entry(pdpte & ENTRY_ADDRESS_MASK, pd_index(va))?;
+    if pde & ENTRY_PAGE_SIZE != 0 {
+        return Some(
+            (pde & ENTRY_ADDRESS_MASK & !(LARGE_PAGE_SIZE as u64 - 1))
+                + (guest_va & (LARGE_PAGE_SIZE as u64 - 1)),
+        );
+    }
+
+    let pte = read_entry(pde & ENTRY_ADDRESS_MASK, pt_index(va))?;
+
+    Some((pte & ENTRY_ADDRESS_MASK) + (guest_va & (BASE_PAGE_SIZE as u64 - 1)))
+}
+
+/// Locates the page table entry that maps a guest virtual address using 4-level paging.
+///
+/// # Arguments
+///
+/// * `guest_cr3` - The guest's CR3 value, defining the address space to translate in.
+/// * `guest_va` - The guest virtual address whose page table entry is located.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The guest physical address of the page table entry, or `None` if the page table is not
+///   present or the address is mapped by a large page.
+pub fn locate_guest_pte(guest_cr3: u64, guest_va: u64) -> Option<u64> {
+    let va = VAddr::from(guest_va);
+
+    let pml4e = read_entry(guest_cr3 & ENTRY_ADDRESS_MASK, pml4_index(va))?;
+
+    let pdpte = read_entry(pml4e & ENTRY_ADDRESS_MASK, pdpt_index(va))?;
+    if pdpte & ENTRY_PAGE_SIZE != 0 {
+        return None;
+    }
+
+    let pde = read_entry(pdpte & ENTRY_ADDRESS_MASK, pd_index(va))?;
+    if pde & ENTRY_PAGE_SIZE != 0 {
+        return None;
+    }
+
+    Some((pde & ENTRY_ADDRESS_MASK) + (pt_index(va) * core::mem::size_of::<u64>()) as u64)
+}
+
+/// Reads a page table entry located with `locate_guest_pte`.
+///
+/// # Arguments
+///
+/// * `pte_pa` - The guest physical address of the page table entry.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The 4KB aligned guest physical address of the mapped page, or `None` if the entry is not present.
+pub fn read_guest_pte(pte_pa: u64) -> Option<u64> {
+    let table_pa = pte_pa & ENTRY_ADDRESS_MASK;
+    let index = (pte_pa & (BASE_PAGE_SIZE as u64 - 1)) as usize / core::mem::size_of::<u64>();
+
+    read_entry(table_pa, index).map(|pte| pte & ENTRY_ADDRESS_MASK)
+}
+
+/// Reads guest virtual memory into the provided buffer, translating each page separately.
+///
+/// # Arguments
+///
+/// * `guest_cr3` - The guest's CR3 value, defining the address space to read from.
+/// * `guest_va` - The guest virtual address to start reading at.
+/// * `buffer` - The buffer to fill with the guest memory.
+///
+/// # Returns
+///
+/// * `Option<()>` - `Some(())` if the whole buffer was read, or `None` if any page is not mapped.
+pub fn read_guest_memory(guest_cr3: u64, guest_va: u64, buffer: &mut [u8]) -> Option<()> {
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let va = guest_va + offset as u64;
+        let page_remaining = BASE_PAGE_SIZE - (va as usize & (BASE_PAGE_SIZE - 1));
+        let chunk = core::cmp::min(page_remaining, buffer.len() - offset);
+
+        let pa = translate_guest_virtual_address(guest_cr3, va)?;
+        let host_va = PhysicalAddress::va_from_pa(pa);
+
+        if host_va == 0 {
+            return None;
+        }
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                host_va as *const u8,
+                buffer[offset..].as_mut_ptr(),
+                chunk,
+            )
+        };
+
+        offset += chunk;
+    }
+
+    Some(())
+}
+
+/// Writes the provided buffer to guest virtual memory, translating each page separately.
+///
+/// # Arguments
+///
+/// * `guest_cr3` - The guest's CR3 value, defining the address space to write to.
+/// * `guest_va` - The guest virtual address to start writing at.
+/// * `buffer` - The data to write to the guest memory.
+///
+/// # Returns
+///
+/// * `Option<()>` - `Some(())` if the whole buffer was written, or `None` if any page is not mapped.
+pub fn write_guest_memory(guest_cr3: u64, guest_va: u64, buffer: &[u8]) -> Option<()> {
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let va = guest_va + offset as u64;
+        let page_remaining = BASE_PAGE_SIZE - (va as usize & (BASE_PAGE_SIZE - 1));
+        let chunk = core::cmp::min(page_remaining, buffer.len() - offset);
+
+        let pa = translate_guest_virtual_address(guest_cr3, va)?;
+        let host_va = PhysicalAddress::va_from_pa(pa);
+
+        if host_va == 0 {
+            return None;
+        }
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(buffer[offset..].as_ptr(), host_va as *mut u8, chunk)
+        };
+
+        offset += chunk;
+    }
+
+    Some(())
+}
@@ -61,11 +61,37 @@ pub fn cr4_write(val: Cr4) {
     unsafe { x86::controlregs::cr4_write(val) };
 }
 
+/// Reads the DR6 register, including its reserved bits.
+pub fn dr6() -> u64 {
+    let dr6: u64;
+    unsafe {
+        asm!("mov {0}, dr6", out(reg) dr6, options(nomem, nostack));
+    }
+    dr6
+}
+
+/// Writes a value to the DR6 register.
+pub fn dr6_write(val: u64) {
+    unsafe {
+        asm!("mov dr6, {0}", in(reg) val, options(nomem, nostack));
+    }
+}
+
 /// Disables maskable interrupts.
 pub fn cli() {
     unsafe { x86::irq::disable() };
 }
 
+/// Enables maskable interrupts.
+pub fn sti() {
+    unsafe { x86::irq::enable() };
+}
+
+/// Checks whether maskable interrupts are enabled (RFLAGS.IF).
+pub fn interrupts_enabled() -> bool {
+    x86::bits64::rflags::read().contains(x86::bits64::rflags::RFlags::FLAGS_IF)
+}
+
 /// Halts execution of the processor.
 pub fn hlt() {
     unsafe { x86::halt() };
@@ -81,6 +107,26 @@ pub fn outb(port: u16, val: u8) {
     unsafe { x86::io::outb(port, val) };
 }
 
+/// Reads 16-bits from an IO port.
+pub fn inw(port: u16) -> u16 {
+    unsafe { x86::io::inw(port) }
+}
+
+/// Writes 16-bits to an IO port.
+pub fn outw(port: u16, val: u16) {
+    unsafe { x86::io::outw(port, val) };
+}
+
+/// Reads 32-bits from an IO port.
+pub fn inl(port: u16) -> u32 {
+    unsafe { x86::io::inl(port) }
+}
+
+/// Writes 32-bits to an IO port.
+pub fn outl(port: u16, val: u32) {
+    unsafe { x86::io::outl(port, val) };
+}
+
 /// Reads the IDTR register.
 pub fn sidt() -> DescriptorTablePointer<u64> {
     let mut idtr = DescriptorTablePointer::<u64>::default();
@@ -88,6 +134,11 @@ pub fn sidt() -> DescriptorTablePointer<u64> {
     idtr
 }
 
+/// Loads the IDTR register.
+pub fn lidt(idtr: &DescriptorTablePointer<u64>) {
+    unsafe { x86::dtables::lidt(idtr) };
+}
+
 /// Reads the GDTR.
 pub fn sgdt() -> DescriptorTablePointer<u64> {
     let mut gdtr = DescriptorTablePointer::<u64>::default();
@@ -0,0 +1,74 @@
+//! Provides memory descriptor lists (MDLs) that keep pages locked in memory while they are held.
+//!
+//! A locked page is resident and its physical page doesn't change, which hooks that redirect
+//! the physical page of pageable memory, such as session space, rely on.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::{guest_memory::translate_guest_virtual_address, instructions::cr3},
+    },
+    wdk_sys::{
+        ntddk::{IoAllocateMdl, IoFreeMdl, MmProbeAndLockPages, MmUnlockPages},
+        _LOCK_OPERATION::IoReadAccess,
+        _MODE::KernelMode,
+        PMDL,
+    },
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// Pages locked in memory through an MDL until dropped.
+pub struct LockedPages {
+    /// The memory descriptor list describing the locked pages.
+    mdl: PMDL,
+}
+
+impl LockedPages {
+    /// Locks the pages spanned by a kernel-mode address range for read access.
+    ///
+    /// Session space must be locked while attached to a process of the session. The pages stay
+    /// locked after detaching.
+    ///
+    /// `MmProbeAndLockPages` reports pages it can't lock by raising an exception, which can't be caught,
+    /// so every page of the range must be present in the page tables of the current address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The start of the address range.
+    /// * `size` - The size of the address range in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, HypervisorError>` - The locked pages, `HypervisorError::PageNotResident` if a page of the
+    ///   range is not present, or `HypervisorError::MdlAllocationFailed`.
+    pub fn lock(address: u64, size: usize) -> Result<Self, HypervisorError> {
+        let cr3 = cr3();
+        let first_page = address & !(BASE_PAGE_SIZE as u64 - 1);
+
+        if (first_page..address + size as u64)
+            .step_by(BASE_PAGE_SIZE)
+            .any(|page| translate_guest_virtual_address(cr3, page).is_none())
+        {
+            return Err(HypervisorError::PageNotResident);
+        }
+
+        let mdl = unsafe { IoAllocateMdl(address as _, size as _, false as _, false as _, 0 as _) };
+
+        if mdl.is_null() {
+            return Err(HypervisorError::MdlAllocationFailed);
+        }
+
+        unsafe { MmProbeAndLockPages(mdl, KernelMode as _, IoReadAccess) };
+
+        Ok(Self { mdl })
+    }
+}
+
+impl Drop for LockedPages {
+    fn drop(&mut self) {
+        unsafe {
+            MmUnlockPages(self.mdl);
+            IoFreeMdl(self.mdl);
+        }
+    }
+}
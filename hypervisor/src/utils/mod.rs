@@ -2,7 +2,12 @@ pub mod addresses;
 pub mod alloc;
 pub mod capture;
 pub mod function_hook;
+pub mod guest_memory;
 pub mod instructions;
+pub mod mdl;
 pub mod nt;
+pub mod process;
 pub mod processor;
+pub mod safe_msr;
+pub mod session;
 pub mod ssdt;
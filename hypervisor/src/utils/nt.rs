@@ -8,8 +8,10 @@ use {
     wdk_sys::{
         ntddk::{
             KeLowerIrql, KeStackAttachProcess, KeUnstackDetachProcess, MmGetSystemRoutineAddress,
+            RtlGetVersion,
         },
-        KIRQL, PEPROCESS, PRKPROCESS, PVOID, UNICODE_STRING, _KAPC_STATE,
+        KIRQL, NT_SUCCESS, PEPROCESS, PRKPROCESS, PVOID, RTL_OSVERSIONINFOW, UNICODE_STRING,
+        _KAPC_STATE,
     },
 };
 
@@ -70,6 +72,19 @@ pub fn lower_irql_to_old_level(old_irql: KIRQL) {
     unsafe { KeLowerIrql(old_irql) };
 }
 
+/// Gets the build number of the running Windows version, such as 19045.
+///
+/// # Returns
+/// The build number, or `None` if `RtlGetVersion` failed.
+pub fn get_windows_build_number() -> Option<u32> {
+    let mut version_info: RTL_OSVERSIONINFOW = unsafe { core::mem::zeroed() };
+    version_info.dwOSVersionInfoSize = core::mem::size_of::<RTL_OSVERSIONINFOW>() as u32;
+
+    let status = unsafe { RtlGetVersion(&mut version_info) };
+
+    NT_SUCCESS(status).then_some(version_info.dwBuildNumber)
+}
+
 /// Represents the CR3 (Directory Table Base) of the system process.
 ///
 /// This is typically used to store the page table root physical address
@@ -0,0 +1,290 @@
+//! Provides access to the address space of a target process, used for process-scoped hooks on user-mode code.
+//!
+//! A process is identified by the CR3 values it runs with: the kernel `DirectoryTableBase` and, with
+//! KVA shadowing, the `UserDirectoryTableBase` that is loaded while the process runs in user mode.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::{instructions::cr3, nt::get_windows_build_number},
+    },
+    alloc::boxed::Box,
+    common::pe::image_size,
+    wdk_sys::{
+        ntddk::{
+            KeStackAttachProcess, KeUnstackDetachProcess, ObfDereferenceObject, ObfReferenceObject,
+            PsLookupProcessByProcessId,
+        },
+        _KAPC_STATE, HANDLE, NT_SUCCESS, PEPROCESS, PRKPROCESS,
+    },
+};
+
+/// Offsets of `UserDirectoryTableBase` in the `KPROCESS`, by the first and last Windows build they apply to.
+///
+/// The `KPROCESS` layout of later builds is unknown, so KVA shadowing of their processes is not supported.
+const KPROCESS_USER_DIRECTORY_TABLE_BASE: [(u32, u32, usize); 3] = [
+    // Windows 10 1803 and 1809.
+    (17134, 17763, 0x278),
+    // Windows 10 1903 and 1909.
+    (18362, 18363, 0x280),
+    // Windows 10 2004 to Windows 11 23H2.
+    (19041, 22631, 0x388),
+];
+
+/// Offset of `Ldr` in the 64-bit `PEB`.
+const PEB_LDR: usize = 0x18;
+
+/// Offset of `InLoadOrderModuleList` in the `PEB_LDR_DATA`.
+const PEB_LDR_DATA_IN_LOAD_ORDER_MODULE_LIST: usize = 0x10;
+
+/// Offset of `DllBase` in the `LDR_DATA_TABLE_ENTRY`.
+const LDR_DATA_TABLE_ENTRY_DLL_BASE: usize = 0x30;
+
+/// Offset of `BaseDllName` in the `LDR_DATA_TABLE_ENTRY`.
+const LDR_DATA_TABLE_ENTRY_BASE_DLL_NAME: usize = 0x58;
+
+/// The maximum number of loader entries walked, protecting against a corrupted module list.
+const MAX_MODULES: usize = 0x400;
+
+/// The bits of CR3 that hold the PML4 base address, excluding the PCID and the no-flush bit.
+const CR3_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// A referenced target process and the CR3 values it runs with.
+pub struct TargetProcess {
+    /// The referenced process object.
+    process: PEPROCESS,
+
+    /// The CR3 of the process in kernel mode.
+    directory_table_base: u64,
+
+    /// The CR3 of the process in user mode with KVA shadowing, or `None` if the process is not shadowed.
+    user_directory_table_base: Option<u64>,
+}
+
+impl TargetProcess {
+    /// Looks up a target process by its process ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The ID of the process.
+    ///
+    /// # Returns
+    ///
+    /// The referenced process, or `HypervisorError::ProcessNotFound` if there is no such process.
+    pub fn from_process_id(process_id: u64) -> Result<Self, HypervisorError> {
+        let mut process: PEPROCESS = core::ptr::null_mut();
+
+        let status = unsafe { PsLookupProcessByProcessId(process_id as HANDLE, &mut process) };
+
+        if !NT_SUCCESS(status) || process.is_null() {
+            return Err(HypervisorError::ProcessNotFound);
+        }
+
+        Ok(Self::from_referenced_process(process))
+    }
+
+    /// Creates a target process from a process object, taking a reference to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `process` - The process object.
+    ///
+    /// # Returns
+    ///
+    /// The referenced process, or `HypervisorError::ProcessNotFound` if `process` is null.
+    pub fn from_eprocess(process: PEPROCESS) -> Result<Self, HypervisorError> {
+        if process.is_null() {
+            return Err(HypervisorError::ProcessNotFound);
+        }
+
+        unsafe { ObfReferenceObject(process as _) };
+
+        Ok(Self::from_referenced_process(process))
+    }
+
+    /// Records the CR3 values of an already referenced process.
+    fn from_referenced_process(process: PEPROCESS) -> Self {
+        let directory_table_base = {
+            let _attach = attach_process(process);
+            cr3()
+        };
+
+        // Without KVA shadowing, the user directory table base is 0 or has bit 0 set.
+        let user_directory_table_base = user_directory_table_base_offset()
+            .map(|offset| unsafe { ((process as *const u8).add(offset) as *const u64).read() })
+            .filter(|&user_cr3| user_cr3 & 1 == 0 && user_cr3 != 0);
+
+        log::debug!(
+            "Target process {:p}: CR3 {:#x}, user CR3 {:x?}",
+            process,
+            directory_table_base,
+            user_directory_table_base
+        );
+
+        Self {
+            process,
+            directory_table_base,
+            user_directory_table_base,
+        }
+    }
+
+    /// Gets the CR3 of the process in kernel mode.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The kernel `DirectoryTableBase` of the process.
+    pub fn directory_table_base(&self) -> u64 {
+        self.directory_table_base
+    }
+
+    /// Attaches the current thread to the process until the returned guard is dropped.
+    ///
+    /// # Returns
+    ///
+    /// A guard that detaches from the process when dropped.
+    pub fn attach(&self) -> ProcessAttachGuard {
+        attach_process(self.process)
+    }
+
+    /// Checks whether a guest CR3 belongs to the process, ignoring the PCID and the no-flush bit.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_cr3` - The guest CR3.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the CR3 is the kernel or user CR3 of the process.
+    pub fn matches_cr3(&self, guest_cr3: u64) -> bool {
+        let guest_cr3 = guest_cr3 & CR3_ADDRESS_MASK;
+
+        guest_cr3 == self.directory_table_base & CR3_ADDRESS_MASK
+            || self
+                .user_directory_table_base
+                .is_some_and(|user_cr3| guest_cr3 == user_cr3 & CR3_ADDRESS_MASK)
+    }
+
+    /// Finds a loaded module of the process by walking the loader data of its PEB.
+    ///
+    /// The caller must be attached to the process.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_name` - The case-insensitive name of the module, such as `ntdll.dll`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(u64, usize)>` - The base address and size of the module, or `None` if it is not loaded.
+    pub fn find_module(&self, module_name: &str) -> Option<(u64, usize)> {
+        let peb = unsafe { PsGetProcessPeb(self.process) } as *const u8;
+
+        if peb.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let ldr = (peb.add(PEB_LDR) as *const *const u8).read();
+
+            if ldr.is_null() {
+                return None;
+            }
+
+            let head = ldr.add(PEB_LDR_DATA_IN_LOAD_ORDER_MODULE_LIST);
+            let mut entry = (head as *const *const u8).read();
+
+            for _ in 0..MAX_MODULES {
+                if entry.is_null() || entry == head {
+                    break;
+                }
+
+                let name = entry.add(LDR_DATA_TABLE_ENTRY_BASE_DLL_NAME);
+                let length = (name as *const u16).read() as usize / 2;
+                let buffer = (name.add(8) as *const *const u16).read();
+
+                if !buffer.is_null() {
+                    let name = core::slice::from_raw_parts(buffer, length);
+
+                    let matches = name.len() == module_name.len()
+                        && name
+                            .iter()
+                            .zip(module_name.bytes())
+                            .all(|(&a, b)| a < 0x80 && (a as u8).eq_ignore_ascii_case(&b));
+
+                    if matches {
+                        let base = (entry.add(LDR_DATA_TABLE_ENTRY_DLL_BASE) as *const u64).read();
+                        return Some((base, image_size(base as *const u8)?));
+                    }
+                }
+
+                entry = (entry as *const *const u8).read();
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for TargetProcess {
+    fn drop(&mut self) {
+        unsafe { ObfDereferenceObject(self.process as _) };
+    }
+}
+
+/// Resolves the offset of `UserDirectoryTableBase` in the `KPROCESS` for the running Windows build.
+///
+/// # Returns
+///
+/// * `Option<usize>` - The offset, or `None` if the build is not known.
+fn user_directory_table_base_offset() -> Option<usize> {
+    let build_number = get_windows_build_number()?;
+
+    let offset = KPROCESS_USER_DIRECTORY_TABLE_BASE
+        .iter()
+        .find(|(first, last, _)| (*first..=*last).contains(&build_number))
+        .map(|(_, _, offset)| *offset);
+
+    if offset.is_none() {
+        log::warn!(
+            "Unknown KPROCESS layout of build {}, the user CR3 of processes is not tracked",
+            build_number
+        );
+    }
+
+    offset
+}
+
+/// Attaches the current thread to a process until the returned guard is dropped.
+///
+/// # Arguments
+///
+/// * `process` - The process to attach to.
+///
+/// # Returns
+///
+/// A guard that detaches from the process when dropped.
+pub fn attach_process(process: PEPROCESS) -> ProcessAttachGuard {
+    // The APC state must not move while the thread is attached, so it is boxed.
+    let mut apc_state = Box::new(_KAPC_STATE::default());
+
+    unsafe { KeStackAttachProcess(process as PRKPROCESS, apc_state.as_mut()) };
+
+    ProcessAttachGuard { apc_state }
+}
+
+/// Keeps the current thread attached to a process while alive.
+pub struct ProcessAttachGuard {
+    /// The saved APC state of the thread.
+    apc_state: Box<_KAPC_STATE>,
+}
+
+impl Drop for ProcessAttachGuard {
+    fn drop(&mut self) {
+        unsafe { KeUnstackDetachProcess(self.apc_state.as_mut()) };
+    }
+}
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// Gets the user-mode PEB of a process.
+    pub fn PsGetProcessPeb(process: PEPROCESS) -> *mut u64;
+}
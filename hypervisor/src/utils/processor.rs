@@ -43,6 +43,13 @@ pub fn set_virtualized() {
     VIRTUALIZED_BITSET.fetch_or(bit, core::sync::atomic::Ordering::Relaxed);
 }
 
+/// Marks the current processor as no longer virtualized.
+pub fn clear_virtualized() {
+    let bit = 1 << current_processor_index();
+
+    VIRTUALIZED_BITSET.fetch_and(!bit, core::sync::atomic::Ordering::Relaxed);
+}
+
 /// Returns the number of active logical processors in a specified group in a multiprocessor system or in the entire system.
 pub fn processor_count() -> u32 {
     unsafe { KeQueryActiveProcessorCountEx(ALL_PROCESSOR_GROUPS as _) }
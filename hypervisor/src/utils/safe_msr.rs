@@ -0,0 +1,107 @@
+//! Reads MSRs that may not exist on the processor without crashing it.
+//!
+//! RDMSR of an MSR the processor doesn't implement raises a general protection fault (`#GP(0)`). To read such an
+//! MSR, the current processor temporarily loads a copy of its IDT whose `#GP` entry resumes after the faulting
+//! RDMSR, with maskable interrupts disabled so nothing else runs with that IDT.
+
+use {
+    crate::{
+        intel::descriptor::DescriptorTables,
+        utils::instructions::{cli, interrupts_enabled, lidt, sidt, sti},
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86::dtables::DescriptorTablePointer,
+};
+
+/// The vector of the general protection fault.
+const GP_VECTOR: usize = 13;
+
+/// The `#GP` handler of the IDT replaced while probing, to which faults not raised by the probed RDMSR are forwarded.
+static ORIGINAL_GP_HANDLER: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    /// Reads an MSR, storing its value and returning 1, or returning 0 if RDMSR faulted.
+    ///
+    /// Must be called with the `#GP` entry of the IDT pointing to `try_rdmsr_gp_entry`.
+    fn try_rdmsr_raw(msr: u32, value: *mut u64) -> u8;
+
+    /// The `#GP` entry installed while probing, which resumes after a faulting RDMSR of `try_rdmsr_raw`.
+    fn try_rdmsr_gp_entry();
+}
+
+core::arch::global_asm!(
+    r#"
+.global try_rdmsr_raw
+try_rdmsr_raw:
+    // RDMSR overwrites RDX, which holds the pointer to the value.
+    mov     r8, rdx
+try_rdmsr_instruction:
+    rdmsr
+    shl     rdx, 32
+    or      rax, rdx
+    mov     [r8], rax
+    mov     eax, 1
+    ret
+try_rdmsr_fault:
+    xor     eax, eax
+    ret
+
+.global try_rdmsr_gp_entry
+try_rdmsr_gp_entry:
+    // The interrupt frame holds the error code, followed by RIP, CS, RFLAGS, RSP and SS.
+    push    rax
+    lea     rax, [rip + try_rdmsr_instruction]
+    cmp     [rsp + 0x10], rax
+    jne     2f
+
+    // Resume at the fault path of `try_rdmsr_raw`, discarding the error code.
+    lea     rax, [rip + try_rdmsr_fault]
+    mov     [rsp + 0x10], rax
+    pop     rax
+    add     rsp, 8
+    iretq
+
+2:
+    // Any other fault is handled by the original handler, with the interrupt frame untouched.
+    pop     rax
+    jmp     qword ptr [rip + {original_gp_handler}]
+"#,
+    original_gp_handler = sym ORIGINAL_GP_HANDLER,
+);
+
+/// Reads an MSR, tolerating MSRs that don't exist on the current processor.
+///
+/// Must be called in the context of the operating system, before the processor is virtualized, as it loads the IDT.
+///
+/// # Arguments
+///
+/// * `msr` - The MSR to read.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The value of the MSR, or `None` if RDMSR raised a general protection fault.
+pub fn try_rdmsr(msr: u32) -> Option<u64> {
+    let original_idtr = sidt();
+    let mut idt = DescriptorTables::from_pointer(&original_idtr).to_vec();
+
+    ORIGINAL_GP_HANDLER.store(
+        DescriptorTables::gate_handler(&idt, GP_VECTOR),
+        Ordering::Relaxed,
+    );
+    DescriptorTables::set_gate_handler(&mut idt, GP_VECTOR, try_rdmsr_gp_entry as *const () as u64);
+    let probe_idtr = DescriptorTablePointer::new_from_slice(idt.as_slice());
+
+    let mut value = 0;
+    let restore_interrupts = interrupts_enabled();
+
+    cli();
+    lidt(&probe_idtr);
+    let read = unsafe { try_rdmsr_raw(msr, &mut value) } != 0;
+    lidt(&original_idtr);
+
+    if restore_interrupts {
+        sti();
+    }
+
+    read.then_some(value)
+}
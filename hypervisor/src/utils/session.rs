@@ -0,0 +1,125 @@
+//! Provides access to session space through a process of an interactive (GUI) session.
+//!
+//! Win32k's session-space memory, such as `W32pServiceTable` and the win32k syscall implementations,
+//! is not mapped in the system process context where the driver runs. Resolving or hooking it requires
+//! attaching to a process of a GUI session, whose CR3 maps the session space.
+
+use {
+    crate::{
+        error::HypervisorError,
+        utils::{
+            instructions::cr3,
+            process::{attach_process, ProcessAttachGuard},
+        },
+    },
+    wdk_sys::{
+        ntddk::{ObfDereferenceObject, PsLookupProcessByProcessId},
+        HANDLE, NT_SUCCESS, PEPROCESS,
+    },
+};
+
+/// The image name of the process used to attach to a GUI session. `winlogon.exe` only runs in interactive sessions.
+const GUI_SESSION_PROCESS_NAME: &[u8] = b"winlogon.exe";
+
+/// The highest process ID probed when searching for a GUI session process.
+const MAX_PROCESS_ID: usize = 0x10000;
+
+/// A referenced process of a GUI session, used to access session space.
+pub struct SessionProcess {
+    /// The referenced process object.
+    process: PEPROCESS,
+
+    /// The session ID of the process.
+    session_id: u32,
+
+    /// The CR3 of the process, for which the session space mapping is valid.
+    cr3: u64,
+}
+
+impl SessionProcess {
+    /// Finds a process of an interactive session (session ID other than 0).
+    ///
+    /// # Returns
+    ///
+    /// The referenced session process, or `HypervisorError::SessionProcessNotFound` if there is none.
+    pub fn find_gui_process() -> Result<Self, HypervisorError> {
+        for process_id in (4..MAX_PROCESS_ID).step_by(4) {
+            let mut process: PEPROCESS = core::ptr::null_mut();
+
+            let status = unsafe { PsLookupProcessByProcessId(process_id as HANDLE, &mut process) };
+
+            if !NT_SUCCESS(status) || process.is_null() {
+                continue;
+            }
+
+            let session_id = unsafe { PsGetProcessSessionId(process) };
+            let image_name =
+                unsafe { core::ffi::CStr::from_ptr(PsGetProcessImageFileName(process) as _) };
+
+            if session_id != 0
+                && image_name
+                    .to_bytes()
+                    .eq_ignore_ascii_case(GUI_SESSION_PROCESS_NAME)
+            {
+                let mut instance = Self {
+                    process,
+                    session_id,
+                    cr3: 0,
+                };
+
+                // Record the CR3 in which the session space is mapped.
+                instance.cr3 = {
+                    let _attach = instance.attach();
+                    cr3()
+                };
+
+                log::debug!(
+                    "Found GUI session process: {:#x} (session {}, CR3 {:#x})",
+                    process_id,
+                    session_id,
+                    instance.cr3
+                );
+
+                return Ok(instance);
+            }
+
+            unsafe { ObfDereferenceObject(process as _) };
+        }
+
+        Err(HypervisorError::SessionProcessNotFound)
+    }
+
+    /// Attaches the current thread to the session process until the returned guard is dropped.
+    ///
+    /// # Returns
+    ///
+    /// A guard that detaches from the session process when dropped.
+    pub fn attach(&self) -> ProcessAttachGuard {
+        attach_process(self.process)
+    }
+
+    /// Gets the session ID of the process.
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Gets the CR3 of the process, for which the session space mapping is valid.
+    pub fn cr3(&self) -> u64 {
+        self.cr3
+    }
+}
+
+impl Drop for SessionProcess {
+    fn drop(&mut self) {
+        unsafe { ObfDereferenceObject(self.process as _) };
+    }
+}
+
+#[link(name = "ntoskrnl")]
+extern "system" {
+    /// Gets the session ID of a process.
+    pub fn PsGetProcessSessionId(process: PEPROCESS) -> u32;
+
+    /// Gets the image file name (up to 15 characters) of a process.
+    pub fn PsGetProcessImageFileName(process: PEPROCESS) -> *const u8;
+}
@@ -1,18 +1,129 @@
 use crate::error::HypervisorError;
+use crate::utils::instructions::rdmsr;
 use crate::utils::ssdt::sys_info::Sysinfo;
 use alloc::vec::Vec;
+use common::pe::sections;
+use common::scanner::{Extraction, SignatureSet};
+use core::ops::Range;
+use iced_x86::{Code, Decoder, DecoderOptions, FlowControl, Instruction, Register};
+
+/// The number of bytes decoded from the system call entry point or a followed branch target.
+const LSTAR_DECODE_WINDOW: usize = 0x400;
+
+/// The maximum number of branches followed from the system call entry point,
+/// enough to leave `KiSystemCall64Shadow` for `KiSystemServiceUser`.
+const LSTAR_MAX_BRANCH_DEPTH: usize = 2;
+
+/// The strategy used to locate `KeServiceDescriptorTableShadow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsdtFindStrategy {
+    /// Signature scan of ntoskrnl for the start of `KiSystemServiceStart`.
+    KiSystemServiceStartPattern,
+
+    /// Disassembly of the system call entry point (`KiSystemCall64` or `KiSystemCall64Shadow`) read from `IA32_LSTAR`.
+    LstarDisassembly,
+}
+
+impl core::fmt::Display for SsdtFindStrategy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SsdtFindStrategy::KiSystemServiceStartPattern => {
+                write!(f, "KiSystemServiceStart pattern scan")
+            }
+            SsdtFindStrategy::LstarDisassembly => write!(f, "IA32_LSTAR disassembly"),
+        }
+    }
+}
+
+/// The reasons that every SSDT discovery strategy failed, in the order the strategies were tried.
+#[derive(Debug)]
+pub struct SsdtFindFailures(pub Vec<(SsdtFindStrategy, HypervisorError)>);
+
+impl core::fmt::Display for SsdtFindFailures {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (index, (strategy, error)) in self.0.iter().enumerate() {
+            if index != 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{} failed: {}", strategy, error)?;
+        }
+
+        Ok(())
+    }
+}
 
 pub struct SsdtFind {
     pub nt_table: *const u64,
     pub win32k_table: *const u64,
+
+    /// The strategy that located the tables.
+    pub strategy: SsdtFindStrategy,
 }
 
 impl SsdtFind {
+    /// Finds the SSDT and the win32k shadow SSDT.
+    ///
+    /// The `KiSystemServiceStart` pattern scan is tried first. If the pattern doesn't match the running build,
+    /// the system call entry point from `IA32_LSTAR` is disassembled instead.
+    ///
+    /// # Returns
+    ///
+    /// The located tables and the strategy that found them, or `HypervisorError::SsdtFindFailed` with the
+    /// reason that each strategy failed.
     pub fn find_ssdt() -> Result<Self, HypervisorError> {
         let (kernel_base, kernel_size) = Self::get_kernel_base()?;
         log::debug!("Kernel base address: {:p}", kernel_base);
         log::debug!("Kernel size: {}", kernel_size);
 
+        let mut failures = Vec::new();
+
+        for strategy in [
+            SsdtFindStrategy::KiSystemServiceStartPattern,
+            SsdtFindStrategy::LstarDisassembly,
+        ] {
+            let result = match strategy {
+                SsdtFindStrategy::KiSystemServiceStartPattern => {
+                    Self::find_ssdt_by_pattern(kernel_base, kernel_size)
+                }
+                SsdtFindStrategy::LstarDisassembly => {
+                    Self::find_ssdt_by_lstar(kernel_base, kernel_size)
+                }
+            };
+
+            match result {
+                Ok(ssdt) => {
+                    log::info!("SSDT found using strategy: {}", ssdt.strategy);
+                    log::info!("NtTable address: {:p}", ssdt.nt_table);
+                    log::info!("Win32kTable address: {:p}", ssdt.win32k_table);
+
+                    return Ok(ssdt);
+                }
+                Err(error) => {
+                    log::warn!("SSDT discovery using {} failed: {}", strategy, error);
+                    failures.push((strategy, error));
+                }
+            }
+        }
+
+        Err(HypervisorError::SsdtFindFailed(SsdtFindFailures(failures)))
+    }
+
+    /// Finds the SSDT by scanning ntoskrnl for the start of `KiSystemServiceStart`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kernel_base` - The base address of ntoskrnl.
+    /// * `kernel_size` - The size of ntoskrnl.
+    ///
+    /// # Returns
+    ///
+    /// The located tables, or `HypervisorError::PatternNotFound` if no signature matched.
+    pub fn find_ssdt_by_pattern(
+        kernel_base: *mut u8,
+        kernel_size: u32,
+    ) -> Result<Self, HypervisorError> {
+        let strategy = SsdtFindStrategy::KiSystemServiceStartPattern;
+
         /*
            14042ba50  uint64_t KiSystemServiceStart(int64_t arg1, int64_t arg2, uint64_t arg3, int64_t arg4, int32_t arg5 @ rax, uint64_t arg6 @ rbx, int128_t* arg7 @ rbp, uint64_t arg8 @ ssp)
 
@@ -25,30 +136,35 @@ impl SsdtFind {
            14042ba64  4c8d15555e9d00     lea     r10, [rel KeServiceDescriptorTable]
            14042ba6b  4c8d1d8e368f00     lea     r11, [rel KeServiceDescriptorTableShadow]
         */
-        let ki_service_system_start_pattern = "8B F8 C1 EF 07 83 E7 20 25 FF 0F 00 00";
-        let signature_size = 13;
+        let signatures = SignatureSet::new(&[".text"])
+            .signature(
+                "KiSystemServiceStart",
+                "8B F8 C1 EF 07 83 E7 20 25 FF 0F 00 00 4C 8D 15 ?? ?? ?? ?? 4C 8D 1D ?? ?? ?? ??",
+                Extraction::FromStart(20),
+            )?
+            .signature(
+                "KiSystemServiceStart (relaxed)",
+                "C1 EF 07 83 E7 20 25 FF 0F 00 00 [0-16] 4C 8D 15 ?? ?? ?? ?? 4C 8D 1D ?? ?? ?? ??",
+                Extraction::FromEnd(-7),
+            )?;
 
         // Read Windows Kernel (ntoskrnl.exe) from memory
         let ntoskrnl_data =
             unsafe { core::slice::from_raw_parts(kernel_base as *const u8, kernel_size as usize) };
 
-        // Find the KiServiceSystemStart signature
-        let offset = Self::pattern_scan(ntoskrnl_data, ki_service_system_start_pattern)?
+        // Find the KiSystemServiceStart signature, which extracts the `lea r11` instruction.
+        let signature_match = signatures
+            .find(ntoskrnl_data)
             .ok_or(HypervisorError::PatternNotFound)?;
 
-        // Calculate the address of KiServiceSystemStart using .add(),
-        // which is, `14042ba57  8bf8               mov     edi, eax` in this case.
-        let ki_service_system_start = unsafe { kernel_base.add(offset) };
         log::info!(
-            "KiServiceSystemStart address: {:p}",
-            ki_service_system_start
+            "KiSystemServiceStart signature '{}' matched at {:p}",
+            signature_match.name,
+            unsafe { kernel_base.add(signature_match.range.start) }
         );
 
-        // Address of the 'lea r10, [rel KeServiceDescriptorTable]' instruction
-        let lea_r10_address = unsafe { ki_service_system_start.add(signature_size) };
-
         // Address of the 'lea r11, [rel KeServiceDescriptorTableShadow]' instruction
-        let lea_r11_address = unsafe { lea_r10_address.add(7) }; // 7 bytes after lea r10
+        let lea_r11_address = unsafe { kernel_base.add(signature_match.offset) };
 
         // Reading the 4-byte relative offset for KeServiceDescriptorTableShadow
         let relative_offset = unsafe { *(lea_r11_address.add(3) as *const i32) }; // 3 bytes after the opcode
@@ -59,6 +175,103 @@ impl SsdtFind {
         let ke_service_descriptor_table_shadow =
             unsafe { lea_r11_address.add(7).offset(relative_offset as isize) };
 
+        Ok(Self::from_shadow_table(
+            ke_service_descriptor_table_shadow,
+            strategy,
+        ))
+    }
+
+    /// Finds the SSDT by disassembling the system call entry point stored in `IA32_LSTAR`.
+    ///
+    /// With KVA shadowing enabled, `IA32_LSTAR` points to `KiSystemCall64Shadow`, which switches CR3 and then jumps
+    /// to `KiSystemServiceUser` inside `KiSystemCall64`. The decoder follows such unconditional branches until it
+    /// finds the `lea r10, [KeServiceDescriptorTable]` / `lea r11, [KeServiceDescriptorTableShadow]` pair.
+    ///
+    /// Only code inside the resident code sections of ntoskrnl is decoded, and every read is bounded by the end of
+    /// its section, so that it never runs into unmapped padding or a discarded section such as `INIT`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kernel_base` - The base address of ntoskrnl.
+    /// * `kernel_size` - The size of ntoskrnl.
+    ///
+    /// # Returns
+    ///
+    /// The located tables, or the reason that the tables couldn't be located.
+    pub fn find_ssdt_by_lstar(
+        kernel_base: *mut u8,
+        kernel_size: u32,
+    ) -> Result<Self, HypervisorError> {
+        let strategy = SsdtFindStrategy::LstarDisassembly;
+
+        let kernel_start = kernel_base as u64;
+        let kernel =
+            unsafe { core::slice::from_raw_parts(kernel_base as *const u8, kernel_size as usize) };
+
+        let code_sections: Vec<Range<u64>> = sections(kernel)
+            .ok_or(HypervisorError::InvalidPeImage)?
+            .iter()
+            .filter(|section| section.is_resident_code())
+            .map(|section| {
+                let start = kernel_start + section.virtual_address as u64;
+                start..start + section.virtual_size as u64
+            })
+            .collect();
+
+        let lstar = rdmsr(x86::msr::IA32_LSTAR);
+        log::info!("IA32_LSTAR: {:#x}", lstar);
+
+        if !code_sections.iter().any(|section| section.contains(&lstar)) {
+            return Err(HypervisorError::LstarOutsideKernel);
+        }
+
+        let mut pending = Vec::from([(lstar, 0)]);
+
+        while let Some((address, depth)) = pending.pop() {
+            // Only decode code that belongs to a resident code section of ntoskrnl, up to the end of the section.
+            let Some(section) = code_sections
+                .iter()
+                .find(|section| section.contains(&address))
+            else {
+                continue;
+            };
+
+            let length = LSTAR_DECODE_WINDOW.min((section.end - address) as usize);
+            let code = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+
+            let scan = scan_service_table_references(code, address);
+
+            if let Some((_ke_service_descriptor_table, ke_service_descriptor_table_shadow)) =
+                scan.tables
+            {
+                log::info!(
+                    "Service table references found in code decoded from {:#x}",
+                    address
+                );
+                return Ok(Self::from_shadow_table(
+                    ke_service_descriptor_table_shadow as *const u8,
+                    strategy,
+                ));
+            }
+
+            if depth < LSTAR_MAX_BRANCH_DEPTH {
+                pending.extend(
+                    scan.branch_targets
+                        .into_iter()
+                        .rev()
+                        .map(|target| (target, depth + 1)),
+                );
+            }
+        }
+
+        Err(HypervisorError::ServiceTableReferencesNotFound)
+    }
+
+    /// Creates the table pointers from the address of `KeServiceDescriptorTableShadow`.
+    fn from_shadow_table(
+        ke_service_descriptor_table_shadow: *const u8,
+        strategy: SsdtFindStrategy,
+    ) -> Self {
         // Extracting nt!KiServiceTable and win32k!W32pServiceTable addresses
         let shadow = ke_service_descriptor_table_shadow;
 
@@ -68,13 +281,11 @@ impl SsdtFind {
         // Win32kTable Address of Win32k Syscall Table
         let win32k_table = unsafe { shadow.offset(0x20) as *const u64 };
 
-        log::info!("NtTable address: {:p}", nt_table);
-        log::info!("Win32kTable address: {:p}", win32k_table);
-
-        Ok(Self {
+        Self {
             nt_table,
             win32k_table,
-        })
+            strategy,
+        }
     }
 
     /// Gets the base address and size of the kernel module.
@@ -91,36 +302,79 @@ impl SsdtFind {
 
         Ok((kernel_base as _, kernel_size))
     }
+}
 
-    /// Convert a combo pattern to bytes without wildcards
-    pub fn get_bytes_as_hex(pattern: &str) -> Result<Vec<Option<u8>>, HypervisorError> {
-        let mut pattern_bytes = Vec::new();
-
-        for x in pattern.split_whitespace() {
-            match x {
-                "?" => pattern_bytes.push(None),
-                _ => pattern_bytes.push(
-                    u8::from_str_radix(x, 16)
-                        .map(Some)
-                        .map_err(|_| HypervisorError::HexParseError)?,
-                ),
-            }
-        }
+/// The result of scanning decoded code for the service descriptor table references.
+pub struct ServiceTableScan {
+    /// The addresses of `KeServiceDescriptorTable` and `KeServiceDescriptorTableShadow`, if found.
+    pub tables: Option<(u64, u64)>,
+
+    /// The targets of unconditional near branches that leave the decoded code, in decoding order.
+    pub branch_targets: Vec<u64>,
+}
 
-        Ok(pattern_bytes)
+/// Returns the target of the instruction if it is `lea <register>, [rip+disp32]`.
+fn lea_rip_relative(instruction: &Instruction, register: Register) -> Option<u64> {
+    match instruction.code() == Code::Lea_r64_m
+        && instruction.op0_register() == register
+        && instruction.is_ip_rel_memory_operand()
+    {
+        true => Some(instruction.ip_rel_memory_address()),
+        false => None,
     }
+}
 
-    /// Pattern or Signature scan a region of memory
-    pub fn pattern_scan(data: &[u8], pattern: &str) -> Result<Option<usize>, HypervisorError> {
-        let pattern_bytes = Self::get_bytes_as_hex(pattern)?;
+/// Decodes code and looks for `lea r10, [KeServiceDescriptorTable]` immediately followed by
+/// `lea r11, [KeServiceDescriptorTableShadow]`, as found in `KiSystemServiceStart`.
+///
+/// # Arguments
+///
+/// * `code` - The bytes to decode.
+/// * `ip` - The address of the first byte.
+///
+/// # Returns
+///
+/// * `ServiceTableScan` - The table addresses if found, and the branch targets outside of `code` to follow otherwise.
+pub fn scan_service_table_references(code: &[u8], ip: u64) -> ServiceTableScan {
+    let end = ip + code.len() as u64;
+    let mut decoder = Decoder::with_ip(64, code, ip, DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    let mut previous_lea_r10: Option<u64> = None;
+    let mut branch_targets = Vec::new();
 
-        let offset = data.windows(pattern_bytes.len()).position(|window| {
-            window
-                .iter()
-                .zip(&pattern_bytes)
-                .all(|(byte, pattern_byte)| pattern_byte.map_or(true, |b| *byte == b))
-        });
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+
+        if instruction.is_invalid() {
+            previous_lea_r10 = None;
+            continue;
+        }
+
+        if let (Some(table), Some(shadow)) = (
+            previous_lea_r10,
+            lea_rip_relative(&instruction, Register::R11),
+        ) {
+            return ServiceTableScan {
+                tables: Some((table, shadow)),
+                branch_targets,
+            };
+        }
+
+        previous_lea_r10 = lea_rip_relative(&instruction, Register::R10);
+
+        if instruction.flow_control() == FlowControl::UnconditionalBranch
+            && instruction.is_jmp_near()
+        {
+            let target = instruction.near_branch_target();
+
+            if (target < ip || target >= end) && !branch_targets.contains(&target) {
+                branch_targets.push(target);
+            }
+        }
+    }
 
-        Ok(offset)
+    ServiceTableScan {
+        tables: None,
+        branch_targets,
     }
 }
@@ -1,5 +1,12 @@
 use crate::error::HypervisorError;
+use crate::utils::nt::get_ntoskrnl_export;
 use crate::utils::ssdt::ssdt_find::SsdtFind;
+use alloc::format;
+use common::pe::{get_export_rva, image_size};
+use common::syscall_stub::{
+    decode_syscall_number, is_win32k_syscall_number, MAX_SYSCALL_NUMBER, SYSCALL_STUB_SIZE,
+    WIN32K_SYSCALL_BASE,
+};
 
 #[repr(C)]
 struct SSDTStruct {
@@ -20,19 +27,26 @@ pub struct SsdtHook {
 /// Find entry from SSDT table of Nt functions and Win32k syscalls
 impl SsdtHook {
     pub fn find_ssdt_function_address(
-        mut api_number: i32,
+        api_number: i32,
         get_from_win32k: bool,
     ) -> Result<Self, HypervisorError> {
         log::debug!("Finding SSDT function address");
 
+        // Win32k APIs start from 0x1000 and must not index the nt table, or the other way around.
+        let syscall_number = api_number as u32;
+        if syscall_number > MAX_SYSCALL_NUMBER
+            || is_win32k_syscall_number(syscall_number) != get_from_win32k
+        {
+            return Err(HypervisorError::SyscallNumberOutOfRange(syscall_number));
+        }
+
         let ssdt = SsdtFind::find_ssdt()?;
 
         // Index of the function to hook
+        let api_number = (syscall_number & !WIN32K_SYSCALL_BASE) as i32;
         let ssdt = if !get_from_win32k {
             unsafe { &*(ssdt.nt_table as *const SSDTStruct) }
         } else {
-            // Win32k APIs start from 0x1000
-            api_number = api_number - 0x1000;
             unsafe { &*(ssdt.win32k_table as *const SSDTStruct) }
         };
 
@@ -44,6 +58,10 @@ impl SsdtHook {
 
         log::info!("SSDT base address: {:p}", ssdt_base);
 
+        if api_number as u64 >= ssdt.number_of_services {
+            return Err(HypervisorError::SyscallNumberOutOfRange(syscall_number));
+        }
+
         // Calculate offset
         let offset = unsafe { ssdt.p_service_table.add(api_number as usize).read() as usize >> 4 };
 
@@ -57,4 +75,90 @@ impl SsdtHook {
             api_number,
         })
     }
+
+    /// Finds the SSDT entry of an Nt function by name, e.g. `NtCreateFile`.
+    ///
+    /// The syscall number is derived from the `mov eax, imm32` of the matching `Zw*` stub exported by ntoskrnl,
+    /// so it doesn't need to be hardcoded per Windows build. Win32k syscall numbers are rejected, since ntoskrnl
+    /// only exports stubs of the nt service table.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_name` - The name of the function, with either the `Nt` or the `Zw` prefix.
+    ///
+    /// # Returns
+    ///
+    /// The SSDT entry of the function if found, or an error if the stub or the SSDT couldn't be resolved.
+    pub fn find_by_name(function_name: &str) -> Result<Self, HypervisorError> {
+        let zw_name = format!("Zw{}", Self::strip_service_prefix(function_name));
+
+        let stub = get_ntoskrnl_export(&zw_name);
+
+        if stub.is_null() {
+            log::error!("Failed to find syscall stub: {}", zw_name);
+            return Err(HypervisorError::SyscallStubNotFound);
+        }
+
+        let stub_bytes =
+            unsafe { core::slice::from_raw_parts(stub as *const u8, SYSCALL_STUB_SIZE) };
+
+        let api_number =
+            decode_syscall_number(stub_bytes).ok_or(HypervisorError::SyscallNumberNotFound)?;
+
+        log::debug!("Syscall number of {}: {:#x}", function_name, api_number);
+
+        Self::find_ssdt_function_address(api_number as i32, false)
+    }
+
+    /// Finds the SSDT entry of an Nt function by name using the stub of a mapped ntdll or win32u image.
+    ///
+    /// This is useful for services that don't have a `Zw*` export in ntoskrnl. Win32k syscall numbers
+    /// (0x1000 and above), which the stubs of win32u load, are resolved against the win32k shadow table.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_name` - The name of the function, with either the `Nt` or the `Zw` prefix.
+    /// * `ntdll_base` - The base address of ntdll or win32u mapped in the current process context.
+    ///
+    /// # Returns
+    ///
+    /// The SSDT entry of the function if found, or an error if the stub or the SSDT couldn't be resolved.
+    ///
+    /// # Safety
+    ///
+    /// The caller must be attached to a process in which ntdll is mapped at `ntdll_base`. For win32u, the process
+    /// must also be a GUI process of a session, in which the win32k shadow table is mapped.
+    pub unsafe fn find_by_name_in_ntdll(
+        function_name: &str,
+        ntdll_base: *const u8,
+    ) -> Result<Self, HypervisorError> {
+        let nt_name = format!("Nt{}", Self::strip_service_prefix(function_name));
+
+        let size = image_size(ntdll_base).ok_or(HypervisorError::InvalidPeImage)?;
+        let ntdll = core::slice::from_raw_parts(ntdll_base, size);
+
+        let stub_rva = get_export_rva(ntdll, &nt_name).ok_or_else(|| {
+            log::error!("Failed to find syscall stub: {}", nt_name);
+            HypervisorError::SyscallStubNotFound
+        })? as usize;
+
+        let stub_bytes = ntdll
+            .get(stub_rva..stub_rva + SYSCALL_STUB_SIZE)
+            .ok_or(HypervisorError::SyscallStubNotFound)?;
+
+        let api_number =
+            decode_syscall_number(stub_bytes).ok_or(HypervisorError::SyscallNumberNotFound)?;
+
+        log::debug!("Syscall number of {}: {:#x}", function_name, api_number);
+
+        Self::find_ssdt_function_address(api_number as i32, is_win32k_syscall_number(api_number))
+    }
+
+    /// Strips the `Nt` or `Zw` prefix from a system service name.
+    fn strip_service_prefix(function_name: &str) -> &str {
+        function_name
+            .strip_prefix("Nt")
+            .or_else(|| function_name.strip_prefix("Zw"))
+            .unwrap_or(function_name)
+    }
 }